@@ -0,0 +1,37 @@
+use ble_weatherstation_central::bench::LogBench;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+/// Days of the log the range queries run on
+const DAYS: u32 = 7;
+
+fn log_txn(c: &mut Criterion) {
+    let mut group = c.benchmark_group("log_txn");
+    for &sensors in &[1, 10, 50] {
+        let dir = tempfile::tempdir().unwrap();
+        let mut bench = LogBench::open(dir.path(), sensors).unwrap();
+        group.throughput(Throughput::Elements(u64::from(sensors)));
+        group.bench_with_input(BenchmarkId::from_parameter(sensors), &sensors, |b, _| {
+            b.iter(|| bench.write(1).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn range_query(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut bench = LogBench::open(dir.path(), 10).unwrap();
+    for _ in 0..DAYS {
+        bench.write(24 * 60).unwrap();
+    }
+
+    let mut day = 0;
+    c.bench_function("range_query_one_day", |b| {
+        b.iter(|| {
+            day = (day + 1) % DAYS;
+            bench.query_day(0, day * 24 * 60 * 60).unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, log_txn, range_query);
+criterion_main!(benches);
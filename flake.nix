@@ -11,6 +11,61 @@
   };
 
   outputs = { self, nixpkgs, naersk, flake-utils }:
+    {
+      nixosModule = { config, lib, pkgs, ... }:
+        let
+          cfg = config.services.ble-weatherstation-central;
+        in
+        {
+          options.services.ble-weatherstation-central = {
+            enable = lib.mkEnableOption "ble-weatherstation-central";
+            listen = lib.mkOption {
+              type = lib.types.listOf lib.types.str;
+              default = [ "8080" ];
+              description = "ListenStream entries of the socket unit, the service starts on the first request";
+            };
+            environment = lib.mkOption {
+              type = lib.types.attrsOf lib.types.str;
+              default = { };
+              description = "Config values as environment variables like DB_PATH";
+            };
+          };
+
+          config = lib.mkIf cfg.enable {
+            systemd.sockets.ble-weatherstation-central = {
+              wantedBy = [ "sockets.target" ];
+              listenStreams = cfg.listen;
+            };
+            systemd.services.ble-weatherstation-central = {
+              requires = [ "ble-weatherstation-central.socket" ];
+              after = [ "bluetooth.service" ];
+              environment = {
+                DB_PATH = "/var/lib/ble-weatherstation-central/db.mdb";
+                CRASH_DIR = "/var/lib/ble-weatherstation-central/crashes";
+              } // cfg.environment;
+              serviceConfig = {
+                ExecStart = "${self.defaultPackage.${pkgs.system}}/bin/ble-weatherstation-central";
+                DynamicUser = true;
+                StateDirectory = "ble-weatherstation-central";
+                # the http sockets come from systemd, bluetooth goes over dbus
+                RestrictAddressFamilies = [ "AF_UNIX" "AF_INET" "AF_INET6" ];
+                CapabilityBoundingSet = "";
+                NoNewPrivileges = true;
+                ProtectSystem = "strict";
+                ProtectHome = true;
+                PrivateDevices = true;
+                PrivateTmp = true;
+                ProtectKernelTunables = true;
+                ProtectKernelModules = true;
+                ProtectControlGroups = true;
+                LockPersonality = true;
+                MemoryDenyWriteExecute = true;
+                SystemCallArchitectures = "native";
+              };
+            };
+          };
+        };
+    } //
     flake-utils.lib.eachDefaultSystem (system:
       let
         pkgs = import nixpkgs { inherit system; };
@@ -29,11 +29,6 @@ async function oneshotChange(
   }
 }
 
-async function inputModal(text: string): Promise<string> {
-  // TODO: make this pretty
-  return prompt(text);
-}
-
 async function confirmModal(text: string): Promise<boolean> {
   // TODO: make this pretty
   return confirm(text);
@@ -44,30 +39,178 @@ function displayError(e) {
   alert(e);
 }
 
+interface Layout {
+  pinned: string[];
+  hidden: string[];
+  metrics: string[];
+  chart_window: number | null;
+}
+
+async function changeLayout(
+  dashboard: string,
+  change: (layout: Layout) => void
+) {
+  const endpoint = `api/dashboard/${dashboard}`;
+  const resp = await fetchJson(endpoint);
+  if (resp.status !== 200) {
+    displayError(`Could not load dashboard ${dashboard}`);
+    return;
+  }
+  const layout: Layout = await resp.json();
+  change(layout);
+  oneshotChange("PUT", endpoint, "Could not change dashboard", layout);
+}
+
 function overview() {
+  const dashboard = document.body.dataset.dashboard;
+  document.querySelector(".unhide")?.addEventListener("click", () => {
+    changeLayout(dashboard, (layout) => {
+      layout.hidden = [];
+    });
+  });
   for (const sensor of document.querySelectorAll(".sensor")) {
     const addr = sensor.querySelector(".addr").textContent.trim();
-    const labelNode = sensor.querySelector(".label");
-    const label = labelNode.textContent.trim();
-    labelNode.addEventListener("click", async () => {
-      const newLabel = await inputModal(`Change label for ${addr}`);
-      if (newLabel !== null) {
-        oneshotChange("PUT", "/api/change_label", "Could not change label", {
+    const placementNode = sensor.querySelector(".placement") as HTMLElement;
+    placementNode.addEventListener("click", () => {
+      const placement =
+        placementNode.dataset.placement === "indoor" ? "outdoor" : "indoor";
+      oneshotChange(
+        "PUT",
+        "api/change_placement",
+        "Could not change placement",
+        { addr, placement }
+      );
+    });
+    const pinNode = sensor.querySelector(".pin") as HTMLElement;
+    pinNode.addEventListener("click", () => {
+      changeLayout(dashboard, (layout) => {
+        layout.pinned = layout.pinned.filter((pinned) => pinned !== addr);
+        if (pinNode.dataset.pinned !== "true") {
+          layout.pinned.push(addr);
+        }
+      });
+    });
+    sensor.querySelector(".hide").addEventListener("click", () => {
+      changeLayout(dashboard, (layout) => {
+        layout.hidden.push(addr);
+      });
+    });
+    sensor.querySelector(".forget").addEventListener("click", async () => {
+      if (
+        await confirmModal(`Are you sure you want to forget sensor ${addr}?`)
+      ) {
+        oneshotChange("DELETE", "api/forget", `Failed deleting ${addr}`, {
           addr,
-          new_label: newLabel,
         });
       }
     });
-    sensor.querySelector(".forget").addEventListener("click", async () => {
+  }
+}
+
+function admin() {
+  for (const row of document.querySelectorAll(".sensor-settings")) {
+    const addr = (row as HTMLElement).dataset.addr;
+    const input = (name: string) =>
+      row.querySelector(`[name="${name}"]`) as HTMLInputElement;
+    const optional = (name: string) => input(name).value.trim() || null;
+    row.querySelector(".save").addEventListener("click", () => {
+      oneshotChange("PUT", `api/sensor/${addr}`, `Could not save ${addr}`, {
+        label: optional("label"),
+        room: optional("room"),
+        icon: optional("icon"),
+        sort_order:
+          optional("sort_order") === null
+            ? null
+            : Number(input("sort_order").value),
+        placement: input("placement").value,
+        calibration: {
+          temperature: Number(input("temperature").value),
+          humidity: Number(input("humidity").value),
+          pressure: Number(input("pressure").value),
+        },
+        log: input("log").checked,
+        sync_clock: input("sync_clock").checked,
+        measurement_interval: Number(optional("measurement_interval")) || null,
+        require_encryption: input("require_encryption").checked,
+        purchased: optional("purchased"),
+        battery_type: optional("battery_type"),
+        battery_changed: optional("battery_changed"),
+        notes: optional("notes"),
+      });
+    });
+    row.querySelector(".forget").addEventListener("click", async () => {
       if (
         await confirmModal(`Are you sure you want to forget sensor ${addr}?`)
       ) {
-        oneshotChange("DELETE", "/api/forget", `Failed deleting ${addr}`, {
+        oneshotChange("DELETE", "api/forget", `Failed deleting ${addr}`, {
           addr,
         });
       }
     });
   }
+  for (const row of document.querySelectorAll(".pending-sensor")) {
+    const addr = (row as HTMLElement).dataset.addr;
+    row.querySelector(".approve").addEventListener("click", () => {
+      oneshotChange(
+        "POST",
+        "api/pending/approve",
+        `Could not approve ${addr}`,
+        { addr }
+      );
+    });
+    row.querySelector(".dismiss").addEventListener("click", () => {
+      oneshotChange("DELETE", "api/pending", `Could not dismiss ${addr}`, {
+        addr,
+      });
+    });
+  }
+}
+
+interface GattCharacteristic {
+  service: string;
+  uuid: string;
+  flags: string[];
+}
+
+async function gatt() {
+  const addr = document.querySelector(".addr").textContent.trim();
+  const resp = await fetchJson(`api/debug/gatt/${addr}`);
+  if (resp.status !== 200) {
+    displayError(`Could not list characteristics of ${addr}: ${await resp.text()}`);
+    return;
+  }
+  const characteristics: GattCharacteristic[] = await resp.json();
+  const template = document.querySelector(
+    "template.characteristic"
+  ) as HTMLTemplateElement;
+  const body = document.querySelector(".characteristics");
+  for (const characteristic of characteristics) {
+    const row = template.content.cloneNode(true) as DocumentFragment;
+    row.querySelector(".service").textContent = characteristic.service;
+    row.querySelector(".uuid").textContent = characteristic.uuid;
+    row.querySelector(".flags").textContent = characteristic.flags.join(", ");
+    const value = row.querySelector('[name="value"]') as HTMLInputElement;
+    const endpoint = `api/debug/gatt/${addr}/${characteristic.uuid}`;
+    row.querySelector(".read").addEventListener("click", async () => {
+      const resp = await fetchJson(endpoint);
+      if (resp.status === 200) {
+        value.value = (await resp.json()).value;
+      } else {
+        displayError(`Could not read ${characteristic.uuid}: ${await resp.text()}`);
+      }
+    });
+    row.querySelector(".write").addEventListener("click", async () => {
+      const resp = await fetchJson(
+        endpoint,
+        { value: value.value },
+        { method: "PUT" }
+      );
+      if (resp.status !== 200) {
+        displayError(`Could not write ${characteristic.uuid}: ${await resp.text()}`);
+      }
+    });
+    body.appendChild(row);
+  }
 }
 
 function format(n: number, precision: number, unit: string): string {
@@ -101,9 +244,29 @@ interface Value {
   pressure: number;
 }
 
+interface Gap {
+  start: number;
+  end: number;
+  ongoing: boolean;
+}
+
+interface Percentiles {
+  p10: number;
+  p50: number;
+  p90: number;
+}
+
+interface BandPoint {
+  time: number;
+  temperature: Percentiles;
+  humidity: Percentiles;
+  pressure: Percentiles;
+}
+
 async function detail() {
   const addr = document.querySelector(".addr").textContent.trim();
-  const req = await fetchJson(`/api/log/${addr}`);
+  const chartWindow = document.querySelector(".window").textContent.trim();
+  const req = await fetchJson(`api/log/${addr}?window=${chartWindow}`);
   //m.route(document.getElementsByName("body"), "/", {
   //    "/": "/temperature",
   //    "/temperature": ViewGraph(View.Temperature),
@@ -122,17 +285,67 @@ async function detail() {
       });
     }
   }
+
+  // outages get drawn as separate lines along the x axis
+  const gapsReq = await fetchJson(`api/gaps/${addr}?window=${chartWindow}`);
+  const gaps: Gap[] = (await gapsReq.json()).gaps;
+  const outages = gaps.flatMap(({ start, end }) => [
+    { x: start, y: 0 },
+    { x: end, y: 0 },
+    { x: NaN, y: NaN },
+  ]);
+
+  // what the temperature usually is at this time of day, today's trace gets drawn against it
+  const bandsReq = await fetchJson(`api/bands/${addr}?window=${chartWindow}`);
+  const bands: BandPoint[] = bandsReq.ok ? (await bandsReq.json()).points : [];
+  const band = (p: keyof Percentiles) =>
+    bands.map(({ time, temperature }) => ({ x: time, y: temperature[p] }));
   new Chart(ctx, {
     type: "scatter",
     data: {
-      datasets: Object.items(datasets).map(([k, v]) => {
-        return {
-          label: k.titleCase(),
-          data: v,
-        };
-      }),
+      datasets: [
+        ...Object.items(datasets).map(([k, v]) => {
+          return {
+            label: k.titleCase(),
+            data: v,
+          };
+        }),
+        {
+          label: "Outages",
+          data: outages,
+          showLine: true,
+          borderColor: "red",
+        },
+        {
+          label: "Typical low",
+          data: band("p10"),
+          showLine: true,
+          pointRadius: 0,
+          borderColor: "rgba(0, 0, 0, 0.1)",
+          fill: false,
+        },
+        {
+          label: "Typical high",
+          data: band("p90"),
+          showLine: true,
+          pointRadius: 0,
+          borderColor: "rgba(0, 0, 0, 0.1)",
+          backgroundColor: "rgba(0, 0, 0, 0.1)",
+          fill: "-1",
+        },
+        {
+          label: "Median",
+          data: band("p50"),
+          showLine: true,
+          pointRadius: 0,
+          borderDash: [4, 4],
+          fill: false,
+        },
+      ],
     },
     options: {
+      responsive: true,
+      maintainAspectRatio: false,
       scales: {
         xAxes: [
           {
@@ -157,7 +370,37 @@ async function detail() {
   });
 }
 
+const THEME_KEY = "theme";
+
+function applyTheme(theme: string | null) {
+  if (theme === null) {
+    delete document.documentElement.dataset.theme;
+  } else {
+    document.documentElement.dataset.theme = theme;
+  }
+}
+
+function isDark(): boolean {
+  const theme = document.documentElement.dataset.theme;
+  if (theme !== undefined) {
+    return theme === "dark";
+  }
+  return window.matchMedia("(prefers-color-scheme: dark)").matches;
+}
+
+function themeToggle() {
+  document.querySelector(".theme-toggle")?.addEventListener("click", () => {
+    const theme = isDark() ? "light" : "dark";
+    localStorage.setItem(THEME_KEY, theme);
+    applyTheme(theme);
+  });
+}
+
+// applied right away so the page doesn't flash in the wrong theme
+applyTheme(localStorage.getItem(THEME_KEY));
+
 window.addEventListener("load", () => {
+  themeToggle();
   const view = document.querySelector("body")?.id;
   switch (view) {
     case "overview":
@@ -166,6 +409,13 @@ window.addEventListener("load", () => {
     case "detail":
       detail();
       break;
+    case "admin":
+      admin();
+      break;
+    case "gatt":
+      gatt();
+      break;
+    case "error":
     case null:
       break;
     default:
@@ -0,0 +1,84 @@
+//! systemd socket activation. With a `.socket` unit systemd binds the listening sockets itself
+//! and hands them over as fds 3 and up, announced by `LISTEN_PID` and `LISTEN_FDS`, so the
+//! service needs no network privileges and starts on the first request.
+
+use std::net::TcpListener;
+
+#[cfg(unix)]
+const FIRST_FD: std::os::unix::io::RawFd = 3;
+
+/// Listening sockets systemd passed to this process, none without socket activation
+#[cfg(unix)]
+pub(crate) fn listeners() -> Result<Vec<TcpListener>, eyre::Error> {
+    use nix::{
+        fcntl::{fcntl, FcntlArg, FdFlag},
+        sys::socket::{getsockopt, sockopt, SockType},
+    };
+    use std::{env, os::unix::io::FromRawFd};
+
+    let count = match passed_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    )? {
+        Some(count) => count,
+        None => return Ok(Vec::new()),
+    };
+    // hooks and other children must not think they got the sockets
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    (FIRST_FD..FIRST_FD + count)
+        .map(|fd| {
+            if getsockopt(fd, sockopt::SockType)? != SockType::Stream {
+                return Err(eyre::format_err!("Passed fd {} isn't a stream socket", fd));
+            }
+            fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            // fails for anything but tcp sockets, like unix ones
+            listener
+                .local_addr()
+                .map_err(|e| eyre::format_err!("Passed fd {} isn't a tcp socket: {}", fd, e))?;
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn listeners() -> Result<Vec<TcpListener>, eyre::Error> {
+    Ok(Vec::new())
+}
+
+/// Number of fds passed to the process `pid`, none if they were meant for another one
+#[cfg_attr(not(unix), allow(dead_code))]
+fn passed_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    pid: u32,
+) -> Result<Option<i32>, eyre::Error> {
+    match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) if listen_pid.parse() == Ok(pid) => {
+            let count = listen_fds
+                .parse::<i32>()
+                .map_err(|e| eyre::format_err!("Invalid LISTEN_FDS {}: {}", listen_fds, e))?;
+            Ok(Some(count).filter(|&count| count > 0))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fds_are_only_taken_by_their_process() {
+        assert_eq!(passed_fds(Some("42"), Some("2"), 42).unwrap(), Some(2));
+        assert_eq!(passed_fds(Some("43"), Some("2"), 42).unwrap(), None);
+        assert_eq!(passed_fds(None, Some("2"), 42).unwrap(), None);
+        assert_eq!(passed_fds(Some("42"), Some("0"), 42).unwrap(), None);
+        assert!(passed_fds(Some("42"), Some("two"), 42).is_err());
+    }
+}
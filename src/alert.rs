@@ -0,0 +1,429 @@
+mod action;
+mod schedule;
+
+pub(crate) use action::ActionConfig;
+
+use crate::{
+    bluetooth::BluetoothAddress,
+    sensor::{Quantity, SensorState},
+    timestamp::Timestamp,
+};
+use action::Action;
+use schedule::{QuietHours, QuietMode};
+use std::{sync::Mutex, time::Duration};
+
+/// Time between two evaluations of all rules
+const EVAL_INTERVAL: Duration = Duration::from_secs(10);
+/// Events of a rule kept during quiet hours, older ones get dropped first
+const MAX_QUEUED: usize = 16;
+/// Logged for every event and sent by chat actions without their own message
+const DEFAULT_MESSAGE: &str = "Rule {rule} {event} for {label} at {value}";
+
+/// A threshold on a value of one sensor, read from the `[[rule]]` tables of the config file
+#[derive(serde::Deserialize, Clone)]
+pub(crate) struct RuleConfig {
+    name: String,
+    sensor: BluetoothAddress,
+    /// what `above`, `below` and `anomaly` look at, battery rules don't have one
+    quantity: Option<Quantity>,
+    /// fires when the value rises above this
+    above: Option<f64>,
+    /// fires when the value falls below this
+    below: Option<f64>,
+    /// fires while the anomaly detector finds the value unusual
+    #[serde(default)]
+    anomaly: bool,
+    /// fires when the battery is predicted to run empty in fewer days than this
+    battery_days: Option<f64>,
+    /// how far the value has to get back past the threshold before the rule clears
+    #[serde(default)]
+    hysteresis: f64,
+    /// local time window like `22:00-07:00` during which the actions don't run
+    quiet_hours: Option<QuietHours>,
+    /// whether events during quiet hours get dropped or delivered afterwards
+    #[serde(default)]
+    quiet_mode: QuietMode,
+    #[serde(rename = "action", default)]
+    actions: Vec<ActionConfig>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Threshold {
+    Above(f64),
+    Below(f64),
+    Anomaly,
+    /// days until the battery runs empty
+    BatteryDays(f64),
+}
+
+pub(crate) struct Rule {
+    name: String,
+    sensor: BluetoothAddress,
+    /// none for battery rules
+    quantity: Option<Quantity>,
+    threshold: Threshold,
+    hysteresis: f64,
+    quiet_hours: Option<QuietHours>,
+    quiet_mode: QuietMode,
+    actions: Vec<ActionConfig>,
+}
+
+impl Rule {
+    pub fn from_config(config: RuleConfig) -> Result<Self, eyre::Error> {
+        let threshold = match (
+            config.quantity,
+            config.above,
+            config.below,
+            config.anomaly,
+            config.battery_days,
+        ) {
+            (Some(_), Some(above), None, false, None) => Threshold::Above(above),
+            (Some(_), None, Some(below), false, None) => Threshold::Below(below),
+            (Some(_), None, None, true, None) => Threshold::Anomaly,
+            (None, None, None, false, Some(days)) => Threshold::BatteryDays(days),
+            _ => {
+                return Err(eyre::format_err!(
+                    "Rule {} needs a quantity with exactly one of above, below or anomaly, or \
+                     only battery_days",
+                    config.name
+                ))
+            }
+        };
+        if config.hysteresis < 0. {
+            return Err(eyre::format_err!(
+                "Hysteresis of rule {} can't be negative",
+                config.name
+            ));
+        }
+
+        Ok(Self {
+            name: config.name,
+            sensor: config.sensor,
+            quantity: config.quantity,
+            threshold,
+            hysteresis: config.hysteresis,
+            quiet_hours: config.quiet_hours,
+            quiet_mode: config.quiet_mode,
+            actions: config.actions,
+        })
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn needs_anomalies(&self) -> bool {
+        matches!(self.threshold, Threshold::Anomaly)
+    }
+
+    fn is_quiet(&self, time: chrono::NaiveTime) -> bool {
+        self.quiet_hours.map_or(false, |quiet| quiet.contains(time))
+    }
+
+    /// What happens to the rule when `value` comes in while it's `firing`, `anomalous` is what
+    /// the anomaly detector thinks of it
+    fn transition(&self, firing: bool, value: f64, anomalous: bool) -> Option<EventKind> {
+        let (exceeded, recovered) = match self.threshold {
+            Threshold::Above(limit) => (value > limit, value <= limit - self.hysteresis),
+            Threshold::Below(limit) | Threshold::BatteryDays(limit) => {
+                (value < limit, value >= limit + self.hysteresis)
+            }
+            Threshold::Anomaly => (anomalous, !anomalous),
+        };
+        match (firing, exceeded, recovered) {
+            (false, true, _) => Some(EventKind::Fired),
+            (true, _, true) => Some(EventKind::Cleared),
+            _ => None,
+        }
+    }
+}
+
+/// A rule and whether it fires right now
+#[derive(Clone, Debug)]
+pub(crate) struct RuleStatus {
+    pub(crate) rule: String,
+    pub(crate) sensor: BluetoothAddress,
+    /// none for battery rules
+    pub(crate) quantity: Option<Quantity>,
+    /// when the rule started firing, none while it doesn't
+    pub(crate) firing_since: Option<Timestamp>,
+}
+
+/// What [`run`] makes of the rules, for the apis
+#[derive(Default)]
+pub(crate) struct AlertStatus(Mutex<Vec<RuleStatus>>);
+
+impl AlertStatus {
+    pub(crate) fn rules(&self) -> Vec<RuleStatus> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn set(&self, rules: &[Rule]) {
+        *self.0.lock().unwrap() = rules
+            .iter()
+            .map(|rule| RuleStatus {
+                rule: rule.name.clone(),
+                sensor: rule.sensor,
+                quantity: rule.quantity,
+                firing_since: None,
+            })
+            .collect();
+    }
+
+    fn fire(&self, index: usize, firing: bool) {
+        if let Some(status) = self.0.lock().unwrap().get_mut(index) {
+            status.firing_since = if firing { Some(Timestamp::now()) } else { None };
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum EventKind {
+    Fired,
+    Cleared,
+}
+
+impl EventKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Fired => "fired",
+            EventKind::Cleared => "cleared",
+        }
+    }
+}
+
+/// A rule that started or stopped firing
+pub(crate) struct Event<'a> {
+    pub(crate) rule: &'a str,
+    pub(crate) sensor: BluetoothAddress,
+    pub(crate) label: Option<String>,
+    pub(crate) value: f64,
+    pub(crate) kind: EventKind,
+}
+
+impl Event<'_> {
+    /// Replaces `{rule}`, `{sensor}`, `{label}`, `{value}` and `{event}` in `template`
+    pub(crate) fn render(&self, template: &str) -> String {
+        template
+            .replace("{rule}", self.rule)
+            .replace("{sensor}", &self.sensor.to_string())
+            .replace(
+                "{label}",
+                self.label.as_deref().unwrap_or(&self.sensor.to_string()),
+            )
+            .replace("{value}", &format!("{:.2}", self.value))
+            .replace("{event}", self.kind.as_str())
+    }
+}
+
+/// An event kept for after the quiet hours of its rule
+struct Queued {
+    label: Option<String>,
+    value: f64,
+    kind: EventKind,
+}
+
+/// The actions of a rule or anything else that raises events
+pub(crate) struct Actions {
+    owner: String,
+    actions: Vec<Action>,
+}
+
+impl Actions {
+    /// Actions that can't be set up get logged and left out
+    pub(crate) fn new(
+        owner: &str,
+        configs: &[ActionConfig],
+        mqtt: Option<crate::MqttConnection>,
+        client: &reqwest::Client,
+    ) -> Self {
+        let actions = configs
+            .iter()
+            .filter_map(
+                |config| match Action::new(config, mqtt.clone(), client.clone()) {
+                    Ok(action) => Some(action),
+                    Err(e) => {
+                        tracing::error!("Disabled an action of {}: {}", owner, e);
+                        None
+                    }
+                },
+            )
+            .collect();
+        Self {
+            owner: owner.to_owned(),
+            actions,
+        }
+    }
+
+    pub(crate) async fn perform(&mut self, event: &Event<'_>) {
+        for action in &mut self.actions {
+            if let Err(e) = action.perform(event).await {
+                tracing::error!("Action of {} failed: {}", self.owner, e);
+            }
+        }
+    }
+}
+
+pub(crate) fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Could not create http client")
+}
+
+/// Periodically checks all `rules` against the current sensor values and runs the actions of
+/// rules that start or stop firing
+pub(crate) async fn run(
+    ctx: super::Context,
+    rules: Vec<Rule>,
+    mqtt: Option<crate::MqttConnection>,
+) {
+    let client = http_client();
+    ctx.alerts.set(&rules);
+    let mut rules = rules
+        .into_iter()
+        .map(|rule| {
+            let owner = format!("rule {}", rule.name);
+            let actions = Actions::new(&owner, &rule.actions, mqtt.clone(), &client);
+            (rule, actions, false, Vec::<Queued>::new())
+        })
+        .collect::<Vec<_>>();
+
+    let mut interval = tokio::time::interval(EVAL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let sensors = ctx.sensors.read().await.clone();
+        let now = chrono::Local::now().time();
+        for (index, (rule, actions, firing, queued)) in rules.iter_mut().enumerate() {
+            let quiet = rule.is_quiet(now);
+            if !quiet {
+                for Queued { label, value, kind } in queued.drain(..) {
+                    let event = Event {
+                        rule: &rule.name,
+                        sensor: rule.sensor,
+                        label,
+                        value,
+                        kind,
+                    };
+                    actions.perform(&event).await;
+                }
+            }
+
+            let value = match (rule.quantity, sensors.get(&rule.sensor)) {
+                (Some(quantity), Some(SensorState::Connected(values))) => quantity.of(*values),
+                (None, _) => match ctx.batteries.prediction(rule.sensor) {
+                    Some(prediction) => prediction.empty_in_days,
+                    None => continue,
+                },
+                _ => continue,
+            };
+            let anomalous = match (rule.quantity, ctx.anomalies.as_ref()) {
+                (Some(quantity), Some(anomalies)) => anomalies.is_anomalous(rule.sensor, quantity),
+                _ => false,
+            };
+            let kind = match rule.transition(*firing, value, anomalous) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            *firing = kind == EventKind::Fired;
+            ctx.alerts.fire(index, *firing);
+
+            let label = match ctx
+                .db
+                .read_txn()
+                .and_then(|txn| ctx.db.get_addr(&txn, rule.sensor))
+            {
+                Ok(entry) => entry.and_then(|entry| entry.label),
+                Err(e) => {
+                    tracing::error!("Could not look up label of {}: {}", rule.sensor, e);
+                    None
+                }
+            };
+            let event = Event {
+                rule: &rule.name,
+                sensor: rule.sensor,
+                label,
+                value,
+                kind,
+            };
+            tracing::warn!("{}", event.render(DEFAULT_MESSAGE));
+
+            match (quiet, rule.quiet_mode) {
+                (false, _) => actions.perform(&event).await,
+                (true, QuietMode::Suppress) => {
+                    tracing::info!("Suppressed actions of rule {} in quiet hours", rule.name);
+                }
+                (true, QuietMode::Queue) => {
+                    if queued.len() >= MAX_QUEUED {
+                        queued.remove(0);
+                    }
+                    queued.push(Queued {
+                        label: event.label,
+                        value,
+                        kind,
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rule(above: Option<f64>, below: Option<f64>) -> Result<Rule, eyre::Error> {
+        Rule::from_config(RuleConfig {
+            name: "test".to_owned(),
+            sensor: BluetoothAddress::from(1),
+            quantity: Some(Quantity::Humidity),
+            above,
+            below,
+            anomaly: false,
+            battery_days: None,
+            hysteresis: 2.,
+            quiet_hours: None,
+            quiet_mode: QuietMode::Suppress,
+            actions: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn rule_hysteresis() {
+        let rule = rule(Some(60.), None).unwrap();
+        assert_eq!(rule.transition(false, 59., false), None);
+        assert_eq!(rule.transition(false, 61., false), Some(EventKind::Fired));
+        assert_eq!(rule.transition(true, 59., false), None);
+        assert_eq!(rule.transition(true, 58., false), Some(EventKind::Cleared));
+    }
+
+    #[test]
+    fn rule_needs_one_threshold() {
+        assert!(rule(None, None).is_err());
+        assert!(rule(Some(60.), Some(40.)).is_err());
+        assert!(rule(None, Some(40.)).is_ok());
+    }
+
+    #[test]
+    fn battery_rules_fire_when_running_low() {
+        let config = |quantity| RuleConfig {
+            name: "battery".to_owned(),
+            sensor: BluetoothAddress::from(1),
+            quantity,
+            above: None,
+            below: None,
+            anomaly: false,
+            battery_days: Some(14.),
+            hysteresis: 1.,
+            quiet_hours: None,
+            quiet_mode: QuietMode::Suppress,
+            actions: Vec::new(),
+        };
+        assert!(Rule::from_config(config(Some(Quantity::Humidity))).is_err());
+        let rule = Rule::from_config(config(None)).unwrap();
+        assert_eq!(rule.transition(false, 20., false), None);
+        assert_eq!(rule.transition(false, 13.5, false), Some(EventKind::Fired));
+        assert_eq!(rule.transition(true, 14.5, false), None);
+        assert_eq!(rule.transition(true, 90., false), Some(EventKind::Cleared));
+    }
+}
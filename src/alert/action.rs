@@ -0,0 +1,478 @@
+use super::{Event, EventKind, DEFAULT_MESSAGE};
+#[cfg(target_os = "linux")]
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// Something to do when a rule fires or clears, read from the `[[rule.action]]` tables
+#[derive(serde::Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum ActionConfig {
+    /// drives a gpio line high while the rule fires
+    #[cfg(target_os = "linux")]
+    Gpio {
+        chip: PathBuf,
+        line: u32,
+        #[serde(default)]
+        active_low: bool,
+    },
+    /// publishes `fire` when the rule fires and `clear` when it clears
+    #[cfg(feature = "mqtt")]
+    Mqtt {
+        topic: String,
+        fire: String,
+        clear: Option<String>,
+    },
+    /// sends `body` to `url`, with placeholders like `{value}` filled in
+    Webhook {
+        url: url::Url,
+        #[serde(default = "default_method")]
+        method: String,
+        body: String,
+    },
+    /// sends `message` to a chat through a telegram bot
+    Telegram {
+        token: String,
+        chat_id: String,
+        #[serde(default = "default_message")]
+        message: String,
+        /// seconds that have to pass between two messages, later ones get dropped
+        #[serde(default = "default_min_interval")]
+        min_interval: u64,
+    },
+    /// sends `message` to a matrix room as the user of `access_token`
+    Matrix {
+        homeserver: url::Url,
+        access_token: String,
+        room: String,
+        #[serde(default = "default_message")]
+        message: String,
+        /// seconds that have to pass between two messages, later ones get dropped
+        #[serde(default = "default_min_interval")]
+        min_interval: u64,
+    },
+    /// publishes `message` to an ntfy topic
+    Ntfy {
+        #[serde(default = "default_ntfy_server")]
+        server: url::Url,
+        topic: String,
+        /// access token for topics that need one
+        token: Option<String>,
+        /// 1 to 5, ntfy's default of 3 if unset
+        priority: Option<u8>,
+        #[serde(default = "default_message")]
+        message: String,
+        /// seconds that have to pass between two messages, later ones get dropped
+        #[serde(default = "default_min_interval")]
+        min_interval: u64,
+    },
+    /// sends `message` to a gotify server as the application of `token`
+    Gotify {
+        server: url::Url,
+        token: String,
+        /// gotify's default of 5 if unset
+        priority: Option<u8>,
+        #[serde(default = "default_message")]
+        message: String,
+        /// seconds that have to pass between two messages, later ones get dropped
+        #[serde(default = "default_min_interval")]
+        min_interval: u64,
+    },
+}
+
+fn default_method() -> String {
+    "POST".to_owned()
+}
+
+fn default_message() -> String {
+    DEFAULT_MESSAGE.to_owned()
+}
+
+fn default_min_interval() -> u64 {
+    300
+}
+
+fn default_ntfy_server() -> url::Url {
+    "https://ntfy.sh".parse().unwrap()
+}
+
+/// Appends `segments` to the path of `base`
+fn join(base: &url::Url, segments: &[&str]) -> Result<url::Url, eyre::Error> {
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .map_err(|_| eyre::format_err!("Invalid server url {}", base))?
+        .pop_if_empty()
+        .extend(segments);
+    Ok(url)
+}
+
+/// Title of push notifications, the message has the details
+fn title(event: &Event<'_>) -> String {
+    event.render("Rule {rule} {event}")
+}
+
+/// reqwest errors contain the url, which contains the token for telegram
+fn redacted(e: reqwest::Error) -> eyre::Error {
+    match e.status() {
+        Some(status) => eyre::format_err!("Request failed with status {}", status),
+        None if e.is_timeout() => eyre::format_err!("Request timed out"),
+        None => eyre::format_err!("Request failed"),
+    }
+}
+
+/// Drops messages coming in faster than `min_interval` so a flapping sensor doesn't flood a chat
+struct Throttle {
+    min_interval: Duration,
+    last: Option<Instant>,
+}
+
+impl Throttle {
+    fn new(min_interval: u64) -> Self {
+        Self {
+            min_interval: Duration::from_secs(min_interval),
+            last: None,
+        }
+    }
+
+    fn allow(&mut self, now: Instant) -> bool {
+        match self.last {
+            Some(last) if now.duration_since(last) < self.min_interval => false,
+            _ => {
+                self.last = Some(now);
+                true
+            }
+        }
+    }
+}
+
+pub(super) enum Action {
+    #[cfg(target_os = "linux")]
+    Gpio(LineHandle),
+    #[cfg(feature = "mqtt")]
+    Mqtt {
+        cxn: tokio_mqtt::Connection,
+        topic: tokio_mqtt::TopicName,
+        fire: String,
+        clear: Option<String>,
+    },
+    Webhook {
+        client: reqwest::Client,
+        url: url::Url,
+        method: reqwest::Method,
+        body: String,
+    },
+    Telegram {
+        client: reqwest::Client,
+        url: url::Url,
+        chat_id: String,
+        message: String,
+        throttle: Throttle,
+    },
+    Matrix {
+        client: reqwest::Client,
+        /// the send endpoint of the room, without transaction id
+        url: url::Url,
+        access_token: String,
+        message: String,
+        throttle: Throttle,
+        /// makes transaction ids unique together with the start time
+        sent: u64,
+        started: u64,
+    },
+    Ntfy {
+        client: reqwest::Client,
+        url: url::Url,
+        token: Option<String>,
+        priority: Option<u8>,
+        message: String,
+        throttle: Throttle,
+    },
+    Gotify {
+        client: reqwest::Client,
+        url: url::Url,
+        token: String,
+        priority: Option<u8>,
+        message: String,
+        throttle: Throttle,
+    },
+}
+
+fn check_priority(priority: u8) -> Result<u8, eyre::Error> {
+    if (1..=5).contains(&priority) {
+        Ok(priority)
+    } else {
+        Err(eyre::format_err!("ntfy priority must be between 1 and 5"))
+    }
+}
+
+impl Action {
+    #[cfg_attr(not(feature = "mqtt"), allow(unused_variables))]
+    pub(super) fn new(
+        config: &ActionConfig,
+        mqtt: Option<crate::MqttConnection>,
+        client: reqwest::Client,
+    ) -> Result<Self, eyre::Error> {
+        match config {
+            #[cfg(target_os = "linux")]
+            ActionConfig::Gpio {
+                chip,
+                line,
+                active_low,
+            } => {
+                let mut flags = LineRequestFlags::OUTPUT;
+                if *active_low {
+                    flags |= LineRequestFlags::ACTIVE_LOW;
+                }
+                let handle =
+                    Chip::new(chip)?
+                        .get_line(*line)?
+                        .request(flags, 0, env!("CARGO_PKG_NAME"))?;
+                Ok(Action::Gpio(handle))
+            }
+            #[cfg(feature = "mqtt")]
+            ActionConfig::Mqtt { topic, fire, clear } => Ok(Action::Mqtt {
+                cxn: mqtt.ok_or_else(|| eyre::format_err!("No mqtt server configured"))?,
+                topic: tokio_mqtt::TopicName::new(topic.clone())?,
+                fire: fire.clone(),
+                clear: clear.clone(),
+            }),
+            ActionConfig::Webhook { url, method, body } => Ok(Action::Webhook {
+                client,
+                url: url.clone(),
+                method: method.parse()?,
+                body: body.clone(),
+            }),
+            ActionConfig::Telegram {
+                token,
+                chat_id,
+                message,
+                min_interval,
+            } => Ok(Action::Telegram {
+                client,
+                url: format!("https://api.telegram.org/bot{}/sendMessage", token).parse()?,
+                chat_id: chat_id.clone(),
+                message: message.clone(),
+                throttle: Throttle::new(*min_interval),
+            }),
+            ActionConfig::Matrix {
+                homeserver,
+                access_token,
+                room,
+                message,
+                min_interval,
+            } => {
+                let url = join(
+                    homeserver,
+                    &[
+                        "_matrix",
+                        "client",
+                        "r0",
+                        "rooms",
+                        room,
+                        "send",
+                        "m.room.message",
+                    ],
+                )?;
+                Ok(Action::Matrix {
+                    client,
+                    url,
+                    access_token: access_token.clone(),
+                    message: message.clone(),
+                    throttle: Throttle::new(*min_interval),
+                    sent: 0,
+                    started: crate::timestamp::Timestamp::now().as_u32().into(),
+                })
+            }
+            ActionConfig::Ntfy {
+                server,
+                topic,
+                token,
+                priority,
+                message,
+                min_interval,
+            } => Ok(Action::Ntfy {
+                client,
+                url: join(server, &[topic.as_str()])?,
+                token: token.clone(),
+                priority: priority.map(check_priority).transpose()?,
+                message: message.clone(),
+                throttle: Throttle::new(*min_interval),
+            }),
+            ActionConfig::Gotify {
+                server,
+                token,
+                priority,
+                message,
+                min_interval,
+            } => Ok(Action::Gotify {
+                client,
+                url: join(server, &["message"])?,
+                token: token.clone(),
+                priority: *priority,
+                message: message.clone(),
+                throttle: Throttle::new(*min_interval),
+            }),
+        }
+    }
+
+    pub(super) async fn perform(&mut self, event: &Event<'_>) -> Result<(), eyre::Error> {
+        match self {
+            #[cfg(target_os = "linux")]
+            Action::Gpio(handle) => {
+                handle.set_value(u8::from(event.kind == EventKind::Fired))?;
+            }
+            #[cfg(feature = "mqtt")]
+            Action::Mqtt {
+                cxn,
+                topic,
+                fire,
+                clear,
+            } => {
+                let payload = match event.kind {
+                    EventKind::Fired => fire,
+                    EventKind::Cleared => match clear {
+                        Some(clear) => clear,
+                        None => return Ok(()),
+                    },
+                };
+                cxn.publish(topic.clone(), event.render(payload).into_bytes())
+                    .await?;
+            }
+            Action::Webhook {
+                client,
+                url,
+                method,
+                body,
+            } => {
+                client
+                    .request(method.clone(), url.clone())
+                    .body(event.render(body))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Action::Telegram {
+                client,
+                url,
+                chat_id,
+                message,
+                throttle,
+            } => {
+                if !throttle.allow(Instant::now()) {
+                    tracing::info!("Dropped telegram message for rule {}", event.rule);
+                    return Ok(());
+                }
+                client
+                    .post(url.clone())
+                    .json(&serde_json::json!({
+                        "chat_id": chat_id,
+                        "text": event.render(message),
+                    }))
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(redacted)?;
+            }
+            Action::Matrix {
+                client,
+                url,
+                access_token,
+                message,
+                throttle,
+                sent,
+                started,
+            } => {
+                if !throttle.allow(Instant::now()) {
+                    tracing::info!("Dropped matrix message for rule {}", event.rule);
+                    return Ok(());
+                }
+                *sent += 1;
+                let mut url = url.clone();
+                url.path_segments_mut()
+                    .unwrap()
+                    .push(&format!("{}-{}", started, sent));
+                client
+                    .put(url)
+                    .bearer_auth(access_token)
+                    .json(&serde_json::json!({
+                        "msgtype": "m.text",
+                        "body": event.render(message),
+                    }))
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(redacted)?;
+            }
+            Action::Ntfy {
+                client,
+                url,
+                token,
+                priority,
+                message,
+                throttle,
+            } => {
+                if !throttle.allow(Instant::now()) {
+                    tracing::info!("Dropped ntfy message for rule {}", event.rule);
+                    return Ok(());
+                }
+                let mut request = client
+                    .post(url.clone())
+                    .header("Title", title(event))
+                    .body(event.render(message));
+                if let Some(priority) = priority {
+                    request = request.header("Priority", priority.to_string());
+                }
+                if let Some(token) = token {
+                    request = request.bearer_auth(token);
+                }
+                request.send().await?.error_for_status()?;
+            }
+            Action::Gotify {
+                client,
+                url,
+                token,
+                priority,
+                message,
+                throttle,
+            } => {
+                if !throttle.allow(Instant::now()) {
+                    tracing::info!("Dropped gotify message for rule {}", event.rule);
+                    return Ok(());
+                }
+                let mut body = serde_json::json!({
+                    "title": title(event),
+                    "message": event.render(message),
+                });
+                if let Some(priority) = priority {
+                    body["priority"] = (*priority).into();
+                }
+                client
+                    .post(url.clone())
+                    .header("X-Gotify-Key", token.as_str())
+                    .json(&body)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn throttle_drops_bursts() {
+        let mut throttle = Throttle::new(60);
+        let start = Instant::now();
+        assert!(throttle.allow(start));
+        assert!(!throttle.allow(start + Duration::from_secs(59)));
+        assert!(throttle.allow(start + Duration::from_secs(60)));
+        assert!(!throttle.allow(start + Duration::from_secs(61)));
+    }
+}
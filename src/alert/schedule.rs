@@ -0,0 +1,89 @@
+use chrono::NaiveTime;
+use std::{convert::TryFrom, str::FromStr};
+
+/// A daily time window like `22:00-07:00` in local time, wraps around midnight if it ends before
+/// it starts
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl FromStr for QuietHours {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || eyre::format_err!("Invalid quiet hours `{}`, expected e.g. 22:00-07:00", s);
+        let mut parts = s.splitn(2, '-');
+        let mut time = || {
+            let part = parts.next().ok_or_else(invalid)?;
+            NaiveTime::parse_from_str(part.trim(), "%H:%M").map_err(|_| invalid())
+        };
+        Ok(Self {
+            start: time()?,
+            end: time()?,
+        })
+    }
+}
+
+impl TryFrom<String> for QuietHours {
+    type Error = eyre::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl QuietHours {
+    pub(crate) fn contains(self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+/// What happens to the events of a rule during its quiet hours
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum QuietMode {
+    /// events get dropped
+    Suppress,
+    /// events get delivered once the quiet hours are over
+    Queue,
+}
+
+impl Default for QuietMode {
+    fn default() -> Self {
+        QuietMode::Suppress
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn time(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms(h, m, 0)
+    }
+
+    #[test]
+    fn quiet_hours_wrap_around_midnight() {
+        let night: QuietHours = "22:00-07:00".parse().unwrap();
+        assert!(night.contains(time(23, 30)));
+        assert!(night.contains(time(3, 0)));
+        assert!(!night.contains(time(7, 0)));
+        assert!(!night.contains(time(12, 0)));
+
+        let lunch: QuietHours = "12:00-13:30".parse().unwrap();
+        assert!(lunch.contains(time(12, 45)));
+        assert!(!lunch.contains(time(13, 30)));
+        assert!(!lunch.contains(time(3, 0)));
+
+        assert!("22:00".parse::<QuietHours>().is_err());
+        assert!("25:00-07:00".parse::<QuietHours>().is_err());
+    }
+}
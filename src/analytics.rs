@@ -0,0 +1,310 @@
+use crate::{
+    bluetooth::BluetoothAddress,
+    db::Placement,
+    sensor::{RawSensorValues, SensorValues},
+    timestamp::Timestamp,
+};
+use std::collections::BTreeMap;
+
+/// Indoor air needs to be at least this much more humid than the outdoor air in g/m³ for
+/// ventilating to make a difference
+const VENTILATION_MARGIN: f64 = 1.;
+
+/// Below this relative humidity in percent there's no need to get rid of moisture
+const VENTILATION_MIN_HUMIDITY: f64 = 50.;
+
+/// Seconds of log the rate of change is taken over
+pub(crate) const TREND_WINDOW: u32 = 30 * 60;
+
+/// Trends need log entries spanning at least this many seconds, fewer are mostly noise
+const TREND_MIN_SPAN: u32 = 5 * 60;
+
+/// °C per hour below which the temperature counts as steady
+#[cfg(feature = "web-ui")]
+const STEADY_TEMPERATURE: f64 = 0.5;
+
+/// Percentage points of relative humidity per hour below which the humidity counts as steady
+#[cfg(feature = "web-ui")]
+const STEADY_HUMIDITY: f64 = 2.;
+
+#[derive(serde::Serialize, Debug, Clone, Copy)]
+pub(crate) struct Comfort {
+    /// perceived temperature in °C
+    pub(crate) humidex: f64,
+    /// water vapor in g/m³
+    pub(crate) absolute_humidity: f64,
+    /// whether opening the windows would dry the air, unknown without a connected outdoor sensor
+    pub(crate) ventilate: Option<bool>,
+}
+
+#[cfg(feature = "web-ui")]
+impl Comfort {
+    pub(crate) fn should_ventilate(&self) -> bool {
+        self.ventilate == Some(true)
+    }
+}
+
+/// How fast a sensor's values change right now, a window left open shows as a steep drop
+#[derive(serde::Serialize, Debug, Clone, Copy)]
+pub(crate) struct Trend {
+    /// °C per hour
+    pub(crate) temperature: f64,
+    /// percentage points of relative humidity per hour
+    pub(crate) humidity: f64,
+}
+
+#[cfg(feature = "web-ui")]
+impl Trend {
+    pub(crate) fn temperature_arrow(&self) -> &'static str {
+        arrow(self.temperature, STEADY_TEMPERATURE)
+    }
+
+    pub(crate) fn humidity_arrow(&self) -> &'static str {
+        arrow(self.humidity, STEADY_HUMIDITY)
+    }
+}
+
+#[cfg(feature = "web-ui")]
+fn arrow(per_hour: f64, steady: f64) -> &'static str {
+    if per_hour >= steady {
+        "↑"
+    } else if per_hour <= -steady {
+        "↓"
+    } else {
+        "→"
+    }
+}
+
+struct Climate {
+    /// °C
+    temperature: f64,
+    /// percent
+    humidity: f64,
+}
+
+impl From<SensorValues> for Climate {
+    fn from(values: SensorValues) -> Self {
+        let raw = RawSensorValues::from(values);
+        Self {
+            temperature: f64::from(raw.temperature) / 100.,
+            humidity: f64::from(raw.humidity) / 100.,
+        }
+    }
+}
+
+impl Climate {
+    /// Partial pressure of water vapor in hPa, Magnus formula
+    fn vapor_pressure(&self) -> f64 {
+        let saturation = 6.112 * (17.62 * self.temperature / (243.12 + self.temperature)).exp();
+        saturation * self.humidity / 100.
+    }
+
+    fn humidex(&self) -> f64 {
+        self.temperature + 5. / 9. * (self.vapor_pressure() - 10.)
+    }
+
+    fn absolute_humidity(&self) -> f64 {
+        // vapor pressure in Pa over the specific gas constant of water vapor
+        self.vapor_pressure() * 100. / (461.5 * (self.temperature + 273.15)) * 1000.
+    }
+}
+
+/// Comfort indicators for every connected sensor in `sensors`, indoor sensors get compared
+/// against the average of the outdoor ones
+pub(crate) fn comfort(
+    sensors: &[(BluetoothAddress, Placement, SensorValues)],
+) -> BTreeMap<BluetoothAddress, Comfort> {
+    let outdoor = sensors
+        .iter()
+        .filter(|(_, placement, _)| *placement == Placement::Outdoor)
+        .map(|(_, _, values)| Climate::from(*values).absolute_humidity())
+        .collect::<Vec<_>>();
+    let outdoor_humidity = if outdoor.is_empty() {
+        None
+    } else {
+        Some(outdoor.iter().sum::<f64>() / outdoor.len() as f64)
+    };
+
+    sensors
+        .iter()
+        .map(|(addr, placement, values)| {
+            let climate = Climate::from(*values);
+            let absolute_humidity = climate.absolute_humidity();
+            let ventilate = match placement {
+                Placement::Indoor => outdoor_humidity.map(|outdoor| {
+                    climate.humidity >= VENTILATION_MIN_HUMIDITY
+                        && outdoor + VENTILATION_MARGIN < absolute_humidity
+                }),
+                Placement::Outdoor => None,
+            };
+            (
+                *addr,
+                Comfort {
+                    humidex: climate.humidex(),
+                    absolute_humidity,
+                    ventilate,
+                },
+            )
+        })
+        .collect()
+}
+
+/// Least squares slope of temperature and humidity over `log`, None if it spans too little time
+pub(crate) fn trend(log: &[(Timestamp, SensorValues)]) -> Option<Trend> {
+    let (first, last) = (log.first()?.0, log.last()?.0);
+    if last.bottoming_sub(first).as_u32() < TREND_MIN_SPAN {
+        return None;
+    }
+
+    // hours since the first entry, centered so the slope is a plain ratio of sums
+    let samples = log
+        .iter()
+        .map(|(time, values)| {
+            let hours = f64::from(time.bottoming_sub(first).as_u32()) / 3600.;
+            (hours, Climate::from(*values))
+        })
+        .collect::<Vec<_>>();
+    let n = samples.len() as f64;
+    let mean_hours = samples.iter().map(|(hours, _)| hours).sum::<f64>() / n;
+    let mean_temperature = samples.iter().map(|(_, c)| c.temperature).sum::<f64>() / n;
+    let mean_humidity = samples.iter().map(|(_, c)| c.humidity).sum::<f64>() / n;
+
+    let (mut variance, mut temperature, mut humidity) = (0., 0., 0.);
+    for (hours, climate) in &samples {
+        let dt = hours - mean_hours;
+        variance += dt * dt;
+        temperature += dt * (climate.temperature - mean_temperature);
+        humidity += dt * (climate.humidity - mean_humidity);
+    }
+    Some(Trend {
+        temperature: temperature / variance,
+        humidity: humidity / variance,
+    })
+}
+
+/// Heating and cooling degree days of one local calendar day, from the mean of its log entries
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct DegreeDay {
+    /// like `2021-01-31`
+    pub(crate) date: String,
+    /// mean temperature in °C
+    pub(crate) mean: f64,
+    /// how far the mean stayed below the base temperature
+    pub(crate) heating: f64,
+    /// how far the mean went above the base temperature
+    pub(crate) cooling: f64,
+}
+
+/// Local midnight of the latest July 1st, heating seasons span the winter so they're counted
+/// from July to June
+pub(crate) fn season_start(now: Timestamp) -> Timestamp {
+    use chrono::{Datelike, TimeZone};
+    let today = chrono::Local.timestamp(i64::from(now.as_u32()), 0).date();
+    let year = if today.month() >= 7 {
+        today.year()
+    } else {
+        today.year() - 1
+    };
+    let start = chrono::Local.ymd(year, 7, 1).and_hms(0, 0, 0);
+    Timestamp::from(start.timestamp().max(0) as u32)
+}
+
+/// Degree days of every day `log` has entries for, relative to `base` in °C
+pub(crate) fn degree_days(log: &[(Timestamp, SensorValues)], base: f64) -> Vec<DegreeDay> {
+    use chrono::TimeZone;
+    let mut days = BTreeMap::<_, (f64, u32)>::new();
+    for (time, values) in log {
+        let date = chrono::Local
+            .timestamp(i64::from(time.as_u32()), 0)
+            .date()
+            .naive_local();
+        let (sum, count) = days.entry(date).or_default();
+        *sum += Climate::from(*values).temperature;
+        *count += 1;
+    }
+    days.into_iter()
+        .map(|(date, (sum, count))| {
+            let mean = sum / f64::from(count);
+            DegreeDay {
+                date: date.format("%Y-%m-%d").to_string(),
+                mean,
+                heating: (base - mean).max(0.),
+                cooling: (mean - base).max(0.),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sensor::test_values;
+
+    #[test]
+    fn humidex_and_absolute_humidity() {
+        let climate = Climate::from(test_values(30_00, 70_00));
+        assert!((climate.humidex() - 41.).abs() < 1.);
+
+        let climate = Climate::from(test_values(20_00, 50_00));
+        assert!((climate.absolute_humidity() - 8.6).abs() < 0.1);
+    }
+
+    #[test]
+    fn ventilate_when_outside_is_drier() {
+        let (indoor, outdoor) = (BluetoothAddress::from(1), BluetoothAddress::from(2));
+        let indicators = comfort(&[
+            (indoor, Placement::Indoor, test_values(22_00, 65_00)),
+            (outdoor, Placement::Outdoor, test_values(5_00, 90_00)),
+        ]);
+        assert_eq!(indicators[&indoor].ventilate, Some(true));
+        assert_eq!(indicators[&outdoor].ventilate, None);
+
+        let indicators = comfort(&[
+            (indoor, Placement::Indoor, test_values(22_00, 65_00)),
+            (outdoor, Placement::Outdoor, test_values(30_00, 80_00)),
+        ]);
+        assert_eq!(indicators[&indoor].ventilate, Some(false));
+
+        let indicators = comfort(&[(indoor, Placement::Indoor, test_values(22_00, 65_00))]);
+        assert_eq!(indicators[&indoor].ventilate, None);
+    }
+
+    #[test]
+    fn degree_days_from_daily_means() {
+        use chrono::TimeZone;
+        let at = |day, hour| {
+            let time = chrono::Local.ymd(2021, 1, day).and_hms(hour, 0, 0);
+            Timestamp::from(time.timestamp() as u32)
+        };
+        let days = degree_days(
+            &[
+                (at(1, 6), test_values(2_00, 50_00)),
+                (at(1, 18), test_values(8_00, 50_00)),
+                (at(2, 12), test_values(21_00, 50_00)),
+            ],
+            18.,
+        );
+        assert_eq!(days.len(), 2);
+        assert_eq!(days[0].date, "2021-01-01");
+        assert_eq!(days[0].mean, 5.);
+        assert_eq!((days[0].heating, days[0].cooling), (13., 0.));
+        assert_eq!((days[1].heating, days[1].cooling), (0., 3.));
+    }
+
+    #[test]
+    fn trend_of_an_open_window() {
+        // a minute apart, losing 0.1°C and gaining 0.5% humidity every minute
+        let log = (0..10)
+            .map(|i| {
+                (
+                    Timestamp::from(1000 + i * 60),
+                    test_values(20_00 - i as i16 * 10, 50_00 + i as u16 * 50),
+                )
+            })
+            .collect::<Vec<_>>();
+        let trend = trend(&log).unwrap();
+        assert!((trend.temperature + 6.).abs() < 0.01);
+        assert!((trend.humidity - 30.).abs() < 0.01);
+        assert!(super::trend(&log[..3]).is_none());
+    }
+}
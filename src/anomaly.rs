@@ -0,0 +1,159 @@
+use crate::{
+    bluetooth::BluetoothAddress,
+    sensor::{Quantity, SensorValues},
+};
+use std::{collections::BTreeMap, sync::Mutex};
+
+const QUANTITIES: [Quantity; 3] = [
+    Quantity::Temperature,
+    Quantity::Humidity,
+    Quantity::Pressure,
+];
+
+/// Settings of the `[anomaly]` table of the config file, detection is off without it
+#[derive(serde::Deserialize, Clone, Debug)]
+pub(crate) struct AnomalyConfig {
+    /// number of minutely samples the baseline roughly averages over
+    #[serde(default = "default_window")]
+    window: u32,
+    /// standard deviations a value has to be away from the baseline to be unusual
+    #[serde(default = "default_threshold")]
+    threshold: f64,
+}
+
+fn default_window() -> u32 {
+    120
+}
+
+fn default_threshold() -> f64 {
+    4.
+}
+
+/// Deviations below sensor noise would make every tiny change look unusual on a steady signal
+fn min_deviation(quantity: Quantity) -> f64 {
+    match quantity {
+        Quantity::Temperature => 0.2,
+        Quantity::Humidity => 1.,
+        Quantity::Pressure => 50.,
+    }
+}
+
+/// Exponentially weighted mean and variance of one quantity of one sensor
+#[derive(Debug, Default)]
+struct Baseline {
+    mean: f64,
+    variance: f64,
+    samples: u32,
+    anomalous: bool,
+}
+
+impl Baseline {
+    fn observe(&mut self, value: f64, quantity: Quantity, config: &AnomalyConfig) {
+        // too few samples for the baseline to mean anything yet
+        if self.samples < config.window {
+            self.samples += 1;
+            let alpha = 1. / f64::from(self.samples);
+            let diff = value - self.mean;
+            self.mean += alpha * diff;
+            self.variance = (1. - alpha) * (self.variance + alpha * diff * diff);
+            return;
+        }
+
+        let deviation = self.variance.sqrt().max(min_deviation(quantity));
+        let diff = value - self.mean;
+        self.anomalous = diff.abs() / deviation > config.threshold;
+
+        let alpha = 2. / (f64::from(config.window) + 1.);
+        self.mean += alpha * diff;
+        self.variance = (1. - alpha) * (self.variance + alpha * diff * diff);
+    }
+}
+
+/// Flags values that stray far from the recent baseline of their sensor, the rolling z-score of
+/// an exponentially weighted average
+pub(crate) struct Detector {
+    config: AnomalyConfig,
+    baselines: Mutex<BTreeMap<(BluetoothAddress, Quantity), Baseline>>,
+}
+
+impl Detector {
+    pub(crate) fn new(config: AnomalyConfig) -> Self {
+        Self {
+            config,
+            baselines: Mutex::default(),
+        }
+    }
+
+    /// Should be called at a steady rate, the window is counted in calls
+    pub(crate) fn observe(&self, addr: BluetoothAddress, values: SensorValues) {
+        let mut baselines = self.baselines.lock().unwrap();
+        for quantity in &QUANTITIES {
+            baselines.entry((addr, *quantity)).or_default().observe(
+                quantity.of(values),
+                *quantity,
+                &self.config,
+            );
+        }
+    }
+
+    pub(crate) fn is_anomalous(&self, addr: BluetoothAddress, quantity: Quantity) -> bool {
+        self.baselines
+            .lock()
+            .unwrap()
+            .get(&(addr, quantity))
+            .map_or(false, |baseline| baseline.anomalous)
+    }
+
+    /// Quantities of `addr` that currently look unusual
+    pub(crate) fn anomalies(&self, addr: BluetoothAddress) -> Vec<Quantity> {
+        QUANTITIES
+            .iter()
+            .copied()
+            .filter(|quantity| self.is_anomalous(addr, *quantity))
+            .collect()
+    }
+
+    pub(crate) fn forget(&self, addr: BluetoothAddress) {
+        self.baselines
+            .lock()
+            .unwrap()
+            .retain(|(baseline_addr, _), _| *baseline_addr != addr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn flags_values_far_from_baseline() {
+        let config = AnomalyConfig {
+            window: 10,
+            threshold: 4.,
+        };
+        let mut baseline = Baseline::default();
+        for i in 0..30 {
+            // the fridge keeps cycling between 4 and 6 °C
+            baseline.observe(4. + f64::from(i % 3), Quantity::Temperature, &config);
+            assert!(!baseline.anomalous);
+        }
+        baseline.observe(12., Quantity::Temperature, &config);
+        assert!(baseline.anomalous);
+        baseline.observe(5., Quantity::Temperature, &config);
+        assert!(!baseline.anomalous);
+    }
+
+    #[test]
+    fn steady_signal_tolerates_noise() {
+        let config = AnomalyConfig {
+            window: 10,
+            threshold: 4.,
+        };
+        let mut baseline = Baseline::default();
+        for _ in 0..30 {
+            baseline.observe(50., Quantity::Humidity, &config);
+        }
+        baseline.observe(51., Quantity::Humidity, &config);
+        assert!(!baseline.anomalous);
+    }
+}
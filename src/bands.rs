@@ -0,0 +1,184 @@
+use crate::{
+    bluetooth::BluetoothAddress,
+    db::{self, Db},
+    sensor::{Quantity, SensorValues},
+    timestamp::Timestamp,
+};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Profiles older than this get recomputed, a few more log entries hardly change them
+const MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// The quantities of a [`Profile`] bucket, in this order
+const QUANTITIES: [Quantity; 3] = [
+    Quantity::Temperature,
+    Quantity::Humidity,
+    Quantity::Pressure,
+];
+
+/// The 10th, 50th and 90th percentile of some values
+#[derive(serde::Serialize, Copy, Clone, Debug, PartialEq)]
+pub(crate) struct Percentiles {
+    pub(crate) p10: f64,
+    pub(crate) p50: f64,
+    pub(crate) p90: f64,
+}
+
+impl Percentiles {
+    /// `None` for no values
+    fn of(mut values: Vec<f64>) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        Some(Self {
+            p10: percentile(&values, 0.1),
+            p50: percentile(&values, 0.5),
+            p90: percentile(&values, 0.9),
+        })
+    }
+}
+
+/// Linearly interpolated between the two closest ranks of the `sorted` values
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = p * (sorted.len() - 1) as f64;
+    let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+/// Seconds since local midnight
+fn time_of_day(time: Timestamp) -> u32 {
+    use chrono::{TimeZone, Timelike};
+    chrono::Local
+        .timestamp(i64::from(time.as_u32()), 0)
+        .num_seconds_from_midnight()
+}
+
+/// Typical values of a sensor by time of day
+#[derive(Debug)]
+pub(crate) struct Profile {
+    /// seconds per bucket
+    bucket: u32,
+    /// percentiles of every quantity, `None` for buckets without any log entries
+    buckets: Vec<Option<[Percentiles; 3]>>,
+}
+
+impl Profile {
+    /// Puts every entry of `log` into the bucket of its time of day
+    pub(crate) fn compute(log: &[(Timestamp, SensorValues)], bucket: u32) -> Self {
+        let count = (Timestamp::ONE_DAY.as_u32() + bucket - 1) / bucket;
+        let mut values = vec![[Vec::new(), Vec::new(), Vec::new()]; count as usize];
+        for (time, entry) in log {
+            let bucket_values = &mut values[(time_of_day(*time) / bucket) as usize];
+            for (quantity, values) in QUANTITIES.iter().zip(bucket_values.iter_mut()) {
+                values.push(quantity.of(*entry));
+            }
+        }
+        let buckets = values
+            .into_iter()
+            .map(|[temperature, humidity, pressure]| {
+                Some([
+                    Percentiles::of(temperature)?,
+                    Percentiles::of(humidity)?,
+                    Percentiles::of(pressure)?,
+                ])
+            })
+            .collect();
+        Self { bucket, buckets }
+    }
+
+    /// Percentiles of temperature, humidity and pressure around the time of day of `time`
+    pub(crate) fn at(&self, time: Timestamp) -> Option<[Percentiles; 3]> {
+        self.buckets[(time_of_day(time) / self.bucket) as usize]
+    }
+
+    pub(crate) fn bucket(&self) -> u32 {
+        self.bucket
+    }
+}
+
+/// Profiles by sensor, weeks of history and bucket size, computed when first asked for
+#[derive(Default)]
+pub(crate) struct Profiles {
+    cache: Mutex<BTreeMap<(BluetoothAddress, u32, u32), (Instant, Arc<Profile>)>>,
+}
+
+impl Profiles {
+    /// The profile of the last `weeks` of the log of `addr`, `None` for unknown sensors
+    pub(crate) fn get(
+        &self,
+        db: &Db,
+        addr: BluetoothAddress,
+        weeks: u32,
+        bucket: u32,
+    ) -> Result<Option<Arc<Profile>>, db::Error> {
+        let key = (addr, weeks, bucket);
+        if let Some((computed, profile)) = self.cache.lock().unwrap().get(&key) {
+            if computed.elapsed() < MAX_AGE {
+                return Ok(Some(profile.clone()));
+            }
+        }
+
+        let now = Timestamp::now();
+        let history = Timestamp::from(weeks * 7 * Timestamp::ONE_DAY.as_u32());
+        let log = {
+            let txn = db.read_txn()?;
+            match db.get_log(&txn, addr, now.bottoming_sub(history)..now, None)? {
+                Some(log) => log,
+                None => return Ok(None),
+            }
+        };
+        let profile = Arc::new(Profile::compute(&log, bucket));
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(key, (Instant::now(), profile.clone()));
+        Ok(Some(profile))
+    }
+
+    pub(crate) fn forget(&self, addr: BluetoothAddress) {
+        self.cache
+            .lock()
+            .unwrap()
+            .retain(|(cached, _, _), _| *cached != addr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sensor::RawSensorValues;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn percentiles_per_time_of_day() {
+        assert_eq!(percentile(&[1., 2., 3., 4., 5.], 0.5), 3.);
+        assert_eq!(percentile(&[10., 20.], 0.9), 19.);
+
+        let start = Timestamp::from(1_600_000_000);
+        // the same hour on ten days, one degree warmer every day
+        let log = (0..10)
+            .map(|day| {
+                let values = SensorValues::try_from(RawSensorValues {
+                    temperature: 10_00 + day as i16 * 1_00,
+                    humidity: 50_00,
+                    pressure: 1_000_000,
+                })
+                .unwrap();
+                let time = Timestamp::from(start.as_u32() + day * Timestamp::ONE_DAY.as_u32());
+                (time, values)
+            })
+            .collect::<Vec<_>>();
+        let profile = Profile::compute(&log, 60 * 60);
+        let [temperature, humidity, _] = profile.at(start).unwrap();
+        assert!((temperature.p50 - 14.5).abs() < 1e-9);
+        assert!((temperature.p10 - 10.9).abs() < 1e-9);
+        assert_eq!(humidity.p90, 50.);
+        let other_hour = Timestamp::from(start.as_u32() + 3 * 60 * 60);
+        assert!(profile.at(other_hour).is_none());
+    }
+}
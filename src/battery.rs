@@ -0,0 +1,202 @@
+//! Battery levels of stations with the battery service, which BlueZ reads on its own. They get
+//! logged every hour and a line fitted through the levels since the last battery change tells
+//! when a station runs empty.
+
+use crate::{bluetooth::BluetoothAddress, timestamp::Timestamp};
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+use tokio::task;
+
+/// Time between two logged levels of a station
+const LOG_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Levels further back don't matter for the prediction
+const FIT_WINDOW: u32 = 180 * 24 * 60 * 60;
+
+/// Percentage points the level has to rise by to count as fresh batteries
+const CHANGE_RISE: u8 = 10;
+
+/// Levels since the last change a prediction needs at least
+const MIN_LEVELS: usize = 6;
+
+/// Seconds they have to cover at least, levels of a single day are mostly noise
+const MIN_SPAN: f64 = 24. * 60. * 60.;
+
+const SECONDS_PER_DAY: f64 = 24. * 60. * 60.;
+
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Level {
+    pub(crate) percentage: u8,
+    pub(crate) time: Timestamp,
+}
+
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Prediction {
+    /// level the fit ends at, smoother than the latest reading
+    pub(crate) percentage: f64,
+    /// days until the fitted level reaches zero, counted from the latest level
+    pub(crate) empty_in_days: f64,
+}
+
+/// Latest levels as seen by the bluetooth thread and the predictions made from the logged ones
+#[derive(Default)]
+pub(crate) struct Batteries {
+    latest: Mutex<BTreeMap<BluetoothAddress, Level>>,
+    predictions: Mutex<BTreeMap<BluetoothAddress, Prediction>>,
+}
+
+impl Batteries {
+    /// Called by the bluetooth backends for every device that reports its level
+    pub(crate) fn observe(&self, addr: BluetoothAddress, percentage: u8, now: Timestamp) {
+        self.latest.lock().unwrap().insert(
+            addr,
+            Level {
+                percentage,
+                time: now,
+            },
+        );
+    }
+
+    pub(crate) fn latest(&self, addr: BluetoothAddress) -> Option<Level> {
+        self.latest.lock().unwrap().get(&addr).copied()
+    }
+
+    /// None while there are too few levels or the battery doesn't drain
+    pub(crate) fn prediction(&self, addr: BluetoothAddress) -> Option<Prediction> {
+        self.predictions.lock().unwrap().get(&addr).copied()
+    }
+}
+
+/// Logs the latest levels every hour and predicts from the log afterwards
+pub(crate) async fn run(ctx: super::Context) {
+    let mut interval = tokio::time::interval(LOG_INTERVAL);
+    loop {
+        interval.tick().await;
+        let addrs = ctx.sensors.read().await.keys().copied().collect::<Vec<_>>();
+        let ctx = ctx.clone();
+        let logged = task::spawn_blocking(move || log_and_predict(&ctx, &addrs))
+            .await
+            .expect("Predicting batteries panicked");
+        if let Err(e) = logged {
+            tracing::error!("Failed logging battery levels: {}", e);
+        }
+    }
+}
+
+fn log_and_predict(
+    ctx: &super::Context,
+    addrs: &[BluetoothAddress],
+) -> Result<(), crate::db::Error> {
+    // levels of forgotten or not yet approved sensors aren't logged, `addrs` are sorted
+    let latest = ctx
+        .batteries
+        .latest
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(addr, _)| addrs.binary_search(*addr).is_ok())
+        .filter(|(addr, _)| {
+            !ctx.pending
+                .as_ref()
+                .map_or(false, |pending| pending.contains(**addr))
+        })
+        .map(|(addr, level)| (*addr, *level))
+        .collect::<Vec<_>>();
+    if !latest.is_empty() {
+        let mut txn = ctx.db.write_txn()?;
+        for (addr, level) in &latest {
+            ctx.db
+                .put_battery(&mut txn, *addr, level.time, level.percentage)?;
+        }
+        txn.commit()?;
+    }
+
+    let now = Timestamp::now();
+    let txn = ctx.db.read_txn()?;
+    let mut predictions = BTreeMap::new();
+    for &addr in addrs {
+        let levels = ctx.db.get_battery(
+            &txn,
+            addr,
+            now.bottoming_sub(Timestamp::from(FIT_WINDOW))..Timestamp::from(u32::MAX),
+        )?;
+        if let Some(prediction) = predict(&levels) {
+            predictions.insert(addr, prediction);
+        }
+    }
+    *ctx.batteries.predictions.lock().unwrap() = predictions;
+    Ok(())
+}
+
+/// Least squares line through the levels since the last battery change, `levels` has to be
+/// sorted by time
+fn predict(levels: &[(Timestamp, u8)]) -> Option<Prediction> {
+    let changed = levels
+        .windows(2)
+        .rposition(|pair| pair[1].1 >= pair[0].1.saturating_add(CHANGE_RISE))
+        .map_or(0, |i| i + 1);
+    let levels = &levels[changed..];
+    let (first, last) = (levels.first()?.0, levels.last()?.0);
+    if levels.len() < MIN_LEVELS || f64::from(last.as_u32() - first.as_u32()) < MIN_SPAN {
+        return None;
+    }
+
+    let n = levels.len() as f64;
+    // relative to the first level, unix seconds squared lose too much precision
+    let points = levels
+        .iter()
+        .map(|(time, percentage)| {
+            (
+                f64::from(time.as_u32() - first.as_u32()),
+                f64::from(*percentage),
+            )
+        })
+        .collect::<Vec<_>>();
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let covariance = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum::<f64>();
+    let variance = points
+        .iter()
+        .map(|(x, _)| (x - mean_x).powi(2))
+        .sum::<f64>();
+    let slope = covariance / variance;
+    if slope >= 0. {
+        return None;
+    }
+
+    let end = f64::from(last.as_u32() - first.as_u32());
+    let percentage = (mean_y + slope * (end - mean_x)).max(0.);
+    Some(Prediction {
+        percentage,
+        empty_in_days: percentage / -slope / SECONDS_PER_DAY,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn predicts_from_the_levels_since_the_last_change() {
+        let day = 24 * 60 * 60;
+        // an old battery running low, then a fresh one losing a point a day
+        let mut levels = (0..10)
+            .map(|i| (Timestamp::from(i * day), 30 - i as u8 * 3))
+            .collect::<Vec<_>>();
+        levels.extend((10..20).map(|i| (Timestamp::from(i * day), 100 - (i - 10) as u8)));
+
+        let prediction = predict(&levels).unwrap();
+        assert!((prediction.percentage - 91.).abs() < 1e-6);
+        assert!((prediction.empty_in_days - 91.).abs() < 1e-6);
+
+        // too few levels of the new battery
+        assert_eq!(predict(&levels[..13]), None);
+        // a battery that doesn't drain never runs empty
+        let steady = (0..10)
+            .map(|i| (Timestamp::from(i * day), 80))
+            .collect::<Vec<_>>();
+        assert_eq!(predict(&steady), None);
+    }
+}
@@ -0,0 +1,89 @@
+//! Entry points for the criterion benches and the db stress binary, which can't reach the
+//! database otherwise. Nothing else should use them.
+
+use crate::{
+    bluetooth::BluetoothAddress,
+    db::{AddrDbEntry, Db, LogBatch},
+    opt::BenchDb,
+    sensor::{Quality, RawSensorValues, SensorValues},
+    timestamp::Timestamp,
+};
+use clap::Clap;
+use std::{convert::TryFrom, path::Path};
+
+/// Seconds between two simulated samples
+pub const SAMPLE_INTERVAL: u32 = 60;
+
+/// Fixed so runs are comparable
+const START: u32 = 1_600_000_000;
+
+/// A database with simulated sensors that get one sample per simulated minute
+pub struct LogBench {
+    db: Db,
+    addrs: Vec<BluetoothAddress>,
+    values: SensorValues,
+    minutes: u32,
+}
+
+impl LogBench {
+    /// Opens the database in `path` and memorizes `sensors` simulated sensors
+    pub fn open(path: &Path, sensors: u32) -> Result<Self, eyre::Error> {
+        let addrs = (0..sensors)
+            .map(|i| BluetoothAddress::from(u64::from(i)))
+            .collect::<Vec<_>>();
+        let db = Db::open(path)?;
+        {
+            let mut txn = db.write_txn()?;
+            for &addr in &addrs {
+                db.put_addr(&mut txn, addr, &AddrDbEntry::default())?;
+            }
+            txn.commit().map_err(crate::db::Error::from)?;
+        }
+        Ok(Self {
+            db,
+            addrs,
+            values: SensorValues::try_from(RawSensorValues {
+                temperature: 21_50,
+                humidity: 45_00,
+                pressure: 1_013_250,
+            })?,
+            minutes: 0,
+        })
+    }
+
+    /// Writes the next `minutes` samples of every sensor in one log txn
+    pub fn write(&mut self, minutes: u32) -> Result<(), eyre::Error> {
+        let mut batch = LogBatch::default();
+        for minute in self.minutes..self.minutes + minutes {
+            let time = Timestamp::from(START + minute * SAMPLE_INTERVAL);
+            for &addr in &self.addrs {
+                batch.push(addr, time, self.values, Quality::empty());
+            }
+        }
+        self.db.write_log(&batch)?;
+        self.minutes += minutes;
+        Ok(())
+    }
+
+    /// Seconds covered by the samples written so far
+    pub fn span(&self) -> u32 {
+        self.minutes * SAMPLE_INTERVAL
+    }
+
+    /// Number of entries in one day of the log of the `sensor`th sensor, starting `offset`
+    /// seconds after the first sample
+    pub fn query_day(&self, sensor: u32, offset: u32) -> Result<usize, eyre::Error> {
+        let from = Timestamp::from(START + offset);
+        let to = Timestamp::from(from.as_u32() + Timestamp::ONE_DAY.as_u32());
+        let txn = self.db.read_txn()?;
+        Ok(self
+            .db
+            .get_log(&txn, self.addrs[sensor as usize], from..to, None)?
+            .map_or(0, |log| log.len()))
+    }
+}
+
+/// Main of the stress binary, which takes the same arguments as the `bench-db` subcommand
+pub fn stress_db() -> Result<(), eyre::Error> {
+    crate::cmd::bench_db::run(BenchDb::parse())
+}
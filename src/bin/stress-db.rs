@@ -0,0 +1,6 @@
+//! Writes lots of samples across simulated sensors into a scratch database and reports log txn
+//! throughput and range query latency, see `stress-db --help`
+
+fn main() -> Result<(), eyre::Error> {
+    ble_weatherstation_central::bench::stress_db()
+}
@@ -1,19 +1,40 @@
+mod adapter;
 mod address;
-mod dbus_interfaces;
+#[cfg(unix)]
+mod bluez;
+#[cfg(feature = "btleplug")]
+mod btle;
+mod error;
+pub(crate) mod gatt;
+mod profile;
+mod settings;
+mod steering;
+#[cfg(feature = "alerts")]
+pub(crate) use adapter::watch as watch_adapter;
+pub(crate) use adapter::{AdapterConfig, AdapterState, AdapterStatus};
 pub use address::BluetoothAddress;
+pub(crate) use error::Error;
+pub(crate) use profile::{Profile, ProfileConfig, Profiles};
+pub(crate) use settings::{ConnectionParams, StationSettings};
+pub(crate) use steering::{Steering, SteeringConfig};
 use tokio::sync::oneshot;
 
-use crate::sensor::{Celsius, Pascal, RelativeHumidity, SensorState};
+use crate::{
+    battery::Batteries,
+    clock::DeviceClocks,
+    metrics::Metrics,
+    presence::Presence,
+    sensor::{Celsius, Pascal, RelativeHumidity, SensorState},
+    timestamp::Timestamp,
+};
 use byteorder::ByteOrder;
-use dbus_interfaces::{Adapter1Proxy, Device1Proxy, GattCharacteristic1Proxy};
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::BTreeMap,
     convert::TryFrom,
+    sync::Arc,
     thread,
     time::{Duration, Instant},
 };
-use zbus::fdo::ObjectManagerProxy;
-use zvariant::{Array, ObjectPath, OwnedObjectPath, OwnedValue};
 
 use crate::sensor::SensorValues;
 
@@ -21,160 +42,387 @@ const BLE_GATT_SERVICE_ENVIRONMENTAL_SENSING: &'static str = "0000180f-0000-1000
 
 const BLE_GATT_SERVICE_WEATHERSTATION: &'static str = "e7364bd3-a1c5-4924-847d-3a9cd6e343ef";
 
-struct Weatherstation {
-    device_path: OwnedObjectPath,
-    temperature_path: OwnedObjectPath,
-    humidity_path: OwnedObjectPath,
-    pressure_path: OwnedObjectPath,
+/// Unix time of the latest measurement by the clock of the station, only newer firmware has it
+const MEASUREMENT_TIMESTAMP_CHARACTERISTIC: &str = "e7364bd4-a1c5-4924-847d-3a9cd6e343ef";
+
+/// Writing [`HISTORY_START`] to this rewinds the history of a station to its oldest record
+const HISTORY_CONTROL_CHARACTERISTIC: &str = "e7364bd5-a1c5-4924-847d-3a9cd6e343ef";
+
+/// Every read returns the next few records of the history, nothing once all were read
+const HISTORY_RECORDS_CHARACTERISTIC: &str = "e7364bd6-a1c5-4924-847d-3a9cd6e343ef";
+
+const HISTORY_START: u8 = 1;
+
+/// Takes the current unix time, sets the clock the station logs and displays with
+const SET_CLOCK_CHARACTERISTIC: &str = "e7364bd7-a1c5-4924-847d-3a9cd6e343ef";
+
+/// Seconds between two measurements of the station as u16
+const MEASUREMENT_INTERVAL_CHARACTERISTIC: &str = "e7364bd8-a1c5-4924-847d-3a9cd6e343ef";
+
+/// Takes [`ConnectionParams::encode`], the station then asks the central for them
+const CONNECTION_PARAMETERS_CHARACTERISTIC: &str = "e7364bd9-a1c5-4924-847d-3a9cd6e343ef";
+
+/// Measurement timestamp, temperature, humidity and pressure, encoded like their characteristics
+const HISTORY_RECORD_SIZE: usize = 12;
+
+/// Stops syncing with firmware that never runs out of records, about a week of minutely ones
+const MAX_HISTORY_RECORDS: usize = 10_000;
+
+/// Time until the next poll after one that failed because of a single device
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Records a station buffered while it wasn't connected, already moved onto our clock
+pub(crate) type History = (BluetoothAddress, Vec<(Timestamp, SensorValues)>);
+
+/// Limits for blocking bluetooth calls, a device that exceeds them is treated as disconnected
+#[derive(Copy, Clone)]
+pub(crate) struct Timeouts {
+    pub(crate) connect: Duration,
+    pub(crate) read: Duration,
+    pub(crate) disconnect: Duration,
 }
 
-fn env_sensing_chr<'a>(device_path: &str, chr: &str) -> OwnedObjectPath {
-    ObjectPath::try_from(format!("{}/service000a/{}", device_path, chr).as_str())
-        .unwrap()
-        .into()
+/// Which bluetooth stack to talk to the weatherstations with
+#[derive(Copy, Clone, Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Backend {
+    /// BlueZ over dbus, what runs on the Raspberry Pi
+    Bluez,
+    /// btleplug, for development on macOS and Windows
+    Btleplug,
+    /// no bluetooth at all, for running only the dummy sensors of demo mode
+    Off,
 }
 
-impl Weatherstation {
-    fn from_device_path(device_path: String) -> Self {
-        Self {
-            pressure_path: env_sensing_chr(&device_path, "char000f"),
-            humidity_path: env_sensing_chr(&device_path, "char000d"),
-            temperature_path: env_sensing_chr(&device_path, "char000b"),
-            device_path: ObjectPath::try_from(device_path).unwrap().into(),
+impl Default for Backend {
+    fn default() -> Self {
+        if cfg!(target_os = "linux") {
+            Backend::Bluez
+        } else if cfg!(feature = "btleplug") {
+            Backend::Btleplug
+        } else {
+            Backend::Off
         }
     }
+}
 
-    fn read_values(&self, dbus: &zbus::Connection) -> Result<SensorValues, eyre::Error> {
-        let temperature = Self::read_with(
-            dbus,
-            &self.temperature_path,
-            byteorder::LittleEndian::read_i16,
-        )?;
+impl std::str::FromStr for Backend {
+    type Err = String;
 
-        let pressure =
-            Self::read_with(dbus, &self.pressure_path, byteorder::LittleEndian::read_u32)?;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bluez" => Ok(Self::Bluez),
+            "btleplug" => Ok(Self::Btleplug),
+            "off" => Ok(Self::Off),
+            _ => Err(String::from(
+                "Backend must be one of `bluez`, `btleplug` or `off`",
+            )),
+        }
+    }
+}
 
-        let humidity =
-            Self::read_with(dbus, &self.humidity_path, byteorder::LittleEndian::read_u16)?;
+/// What the backends share with the rest of the central, every adapter thread gets a copy
+#[derive(Clone)]
+pub(crate) struct BackendContext {
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) clocks: Arc<DeviceClocks>,
+    pub(crate) batteries: Arc<Batteries>,
+    pub(crate) history: flume::Sender<History>,
+    pub(crate) settings: Arc<StationSettings>,
+    pub(crate) adapter: Arc<AdapterStatus>,
+    pub(crate) presence: Option<Arc<Presence>>,
+    pub(crate) profiles: Arc<Profiles>,
+    pub(crate) steering: Arc<Steering>,
+    /// of the main runtime, for backends that hand sockets to its reactor
+    pub(crate) runtime: tokio::runtime::Handle,
+    pub(crate) timeouts: Timeouts,
+    pub(crate) low_memory: bool,
+}
 
-        Ok(SensorValues {
-            temperature: Celsius::try_from(temperature)?,
-            pressure: Pascal::from(pressure),
-            humidity: RelativeHumidity::try_from(humidity)?,
-        })
+impl Backend {
+    /// Checks that the bluetooth stack is there at all, without touching any adapter
+    pub(crate) fn probe(self) -> Result<(), Error> {
+        match self {
+            #[cfg(unix)]
+            Backend::Bluez => bluez::probe(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Names of the adapters that get a thread of their own, backends that can't tell them
+    /// apart use one thread for all of them
+    fn adapters(self) -> Result<Vec<String>, Error> {
+        match self {
+            #[cfg(unix)]
+            Backend::Bluez => {
+                let adapters = bluez::adapters()?;
+                if adapters.is_empty() {
+                    // waits for the adapter BlueZ names first to show up
+                    Ok(vec![String::from("hci0")])
+                } else {
+                    Ok(adapters)
+                }
+            }
+            _ => Ok(vec![String::from("default")]),
+        }
     }
 
-    fn disconnect(&self, dbus: &zbus::Connection) -> Result<(), zbus::Error> {
-        Device1Proxy::new_for(dbus, "org.bluez", self.device_path.as_str())?.disconnect()
+    fn open(
+        self,
+        adapter: String,
+        ctx: BackendContext,
+    ) -> Result<Box<dyn BluetoothBackend>, Error> {
+        match self {
+            #[cfg(unix)]
+            Backend::Bluez => Ok(Box::new(bluez::Bluez::new(adapter, ctx)?)),
+            #[cfg(not(unix))]
+            Backend::Bluez => Err(Error::Unsupported("BlueZ is only available on Linux")),
+            #[cfg(feature = "btleplug")]
+            Backend::Btleplug => {
+                let status = ctx.adapter.clone();
+                let backend = btle::Btleplug::new(ctx)?;
+                // btleplug can't power on adapters, one it found is as good as it gets
+                status.set_adapter(&adapter, AdapterState::Ready);
+                Ok(Box::new(backend))
+            }
+            #[cfg(not(feature = "btleplug"))]
+            Backend::Btleplug => Err(Error::Unsupported(
+                "The btleplug backend is selected but this build doesn't include the btleplug feature"
+            )),
+            Backend::Off => Err(Error::Unsupported("Bluetooth is turned off")),
+        }
     }
+}
+
+/// A bluetooth stack that finds, connects and reads weatherstations. It gets polled from a
+/// thread of its own so it's free to block.
+pub(crate) trait BluetoothBackend {
+    /// Connects new weatherstations and reads the connected ones, returns their state and how
+    /// long to wait until the next poll
+    fn poll(&mut self) -> Result<(BTreeMap<BluetoothAddress, SensorState>, Duration), Error>;
+
+    /// Disconnects all weatherstations before shutting down
+    fn shutdown(&mut self) -> Result<(), Error>;
+}
 
-    fn read_with<T, F>(
-        dbus: &zbus::Connection,
-        path: &OwnedObjectPath,
-        mut f: F,
-    ) -> Result<T, zbus::Error>
-    where
-        F: FnMut(&[u8]) -> T,
-    {
-        let value = GattCharacteristic1Proxy::new_for(dbus, "org.bluez", path)?
-            .read_value(HashMap::new())?;
-        Ok(f(&value))
+/// A BlueZ object as the central sees it, for telling why a station doesn't show up
+#[derive(serde::Serialize, Debug)]
+pub(crate) struct BluezDump {
+    path: String,
+    address: Option<String>,
+    name: Option<String>,
+    /// advertised or resolved service uuids of a device
+    services: Vec<String>,
+    connected: Option<bool>,
+    rssi: Option<i16>,
+    /// `adapter` or the profile of a weatherstation
+    kind: Option<String>,
+    /// why the central ignores it
+    skipped: Option<&'static str>,
+}
+
+/// Adapters and devices BlueZ currently knows
+#[cfg_attr(not(unix), allow(unused_variables))]
+pub(crate) fn dump_bluez(profiles: &Profiles) -> Result<Vec<BluezDump>, Error> {
+    #[cfg(unix)]
+    return bluez::dump(profiles);
+    #[cfg(not(unix))]
+    return Err(Error::Unsupported("BlueZ is only available on Linux"));
+}
+
+/// Measurement time from the raw value of the timestamp characteristic
+fn decode_timestamp(raw: &[u8]) -> Result<Timestamp, Error> {
+    if raw.len() != 4 {
+        return Err(Error::parse(format!(
+            "Measurement timestamp has {} bytes instead of 4",
+            raw.len()
+        )));
     }
+    Ok(Timestamp::from(byteorder::LittleEndian::read_u32(raw)))
 }
 
-pub(crate) fn bluetooth_thread(
-    stop: flume::Receiver<()>,
-) -> (
-    thread::JoinHandle<Result<(), eyre::Error>>,
-    oneshot::Receiver<()>,
-    flume::Receiver<BTreeMap<BluetoothAddress, SensorState>>,
+/// Sensor values from the raw values of the temperature, humidity and pressure characteristics
+fn decode_values(
+    temperature: &[u8],
+    humidity: &[u8],
+    pressure: &[u8],
+) -> Result<SensorValues, Error> {
+    Ok(SensorValues {
+        temperature: Celsius::try_from(byteorder::LittleEndian::read_i16(temperature))
+            .map_err(|e| Error::parse(e.to_string()))?,
+        pressure: Pascal::from(byteorder::LittleEndian::read_u32(pressure)),
+        humidity: RelativeHumidity::try_from(byteorder::LittleEndian::read_u16(humidity))
+            .map_err(|e| Error::parse(e.to_string()))?,
+    })
+}
+
+/// Records of one read of the history records characteristic
+fn decode_history(chunk: &[u8]) -> Result<Vec<(Timestamp, SensorValues)>, Error> {
+    if chunk.len() % HISTORY_RECORD_SIZE != 0 {
+        return Err(Error::parse(format!(
+            "History chunk of {} bytes doesn't consist of whole records",
+            chunk.len()
+        )));
+    }
+    chunk
+        .chunks_exact(HISTORY_RECORD_SIZE)
+        .map(|record| {
+            Ok((
+                decode_timestamp(&record[..4])?,
+                decode_values(&record[4..6], &record[6..8], &record[8..])?,
+            ))
+        })
+        .collect()
+}
+
+/// Moves the buffered records of `addr` onto our clock and hands them to the backfill task. Has
+/// to happen before the clock of the station gets set, that throws away its offsets.
+fn send_history(
+    addr: BluetoothAddress,
+    records: Vec<(Timestamp, SensorValues)>,
+    clocks: &DeviceClocks,
+    history: &flume::Sender<History>,
 ) {
-    let (tx, rx) = flume::bounded(1);
-    let poll_fn = move || -> Result<(), eyre::Error> {
-        let dbus = zbus::Connection::new_system()?;
-        let mut connected_devices = BTreeMap::new();
-        let bluez_object_proxy = ObjectManagerProxy::new_for(&dbus, "org.bluez", "/")?;
-        loop {
-            let poll_started = Instant::now();
-            let objs = bluez_object_proxy
-                .get_managed_objects()?
-                .into_iter()
-                .map(|(k, v)| (k.as_str().to_string(), v))
-                .collect::<BTreeMap<_, _>>();
-            let mut sleep_time = Duration::from_secs(31);
-            for (object_path, interfaces) in objs {
-                if let Some(obj) = interpret_object(&object_path, interfaces) {
-                    match obj {
-                        BluezObject::Interface {
-                            discovering: false,
-                            interface,
-                        } => {
-                            Adapter1Proxy::new_for(&dbus, "org.bluez", object_path.as_str())?
-                                .start_discovery()?;
-                            tracing::info!("Started discovery for interface {}", interface);
-                            sleep_time = Duration::from_secs(10);
-                        }
-                        BluezObject::WeatherstationDevice {
-                            connected: false,
-                            address,
-                            ..
-                        } => {
-                            match Device1Proxy::new_for(&dbus, "org.bluez", object_path.as_str())?
-                                .connect()
-                            {
-                                Ok(()) => {}
-                                Err(zbus::Error::MethodError(_, _, _)) => {
-                                    tracing::warn!("Could not connect to {}", address);
-                                }
-                                Err(e) => {
-                                    return Err(e.into());
-                                }
-                            };
-                        }
-                        BluezObject::WeatherstationDevice {
-                            services_resolved: true,
-                            address,
-                            ..
-                        } if !connected_devices.contains_key(&address) => {
-                            tracing::info!("Connected new device {}", address);
-                            let ws = Weatherstation::from_device_path(object_path);
-                            connected_devices.insert(address, ws);
-                        }
-                        _ => {}
-                    }
+    if records.is_empty() {
+        return;
+    }
+    tracing::info!("Read {} buffered records of {}", records.len(), addr);
+    let records = records
+        .into_iter()
+        .map(|(device, values)| (clocks.correct(addr, device), values))
+        .collect();
+    let _ = history.send((addr, records));
+}
+
+/// Polls the weatherstations of one adapter until `stop` fires or gets dropped
+fn poll_adapter(
+    backend: Backend,
+    adapter: String,
+    stop: flume::Receiver<()>,
+    ctx: BackendContext,
+    tx: flume::Sender<BTreeMap<BluetoothAddress, SensorState>>,
+    opened: flume::Sender<()>,
+) -> Result<(), Error> {
+    let (metrics, status) = (ctx.metrics.clone(), ctx.adapter.clone());
+    let kind = backend;
+    let opening = kind.open(adapter.clone(), ctx.clone());
+    // dropped after trying either way, a failure ends the thread below
+    drop(opened);
+    let mut backend = opening?;
+    let mut polled_since_open = false;
+    loop {
+        let poll_span = tracing::info_span!("poll", %adapter);
+        let poll_enter = poll_span.enter();
+        let poll_started = Instant::now();
+        let (state, sleep_time) = match backend.poll() {
+            Ok(polled) => {
+                polled_since_open = true;
+                polled
+            }
+            Err(e) if !e.fatal() => {
+                tracing::warn!("Poll of {} failed: {}", adapter, e);
+                status.failed(&adapter, &e);
+                (BTreeMap::new(), RETRY_INTERVAL)
+            }
+            // a backend that worked before gets opened again, one that fails right away doesn't
+            Err(e) if polled_since_open => {
+                tracing::error!("Restarting bluetooth backend of {}: {}", adapter, e);
+                status.failed(&adapter, &e);
+                status.restarted(&adapter);
+                metrics.bluetooth.backend_restarts.inc();
+                let _ = tx.send(BTreeMap::new());
+                if let Err(e) = backend.shutdown() {
+                    tracing::warn!("Shutting down the backend of {} failed: {}", adapter, e);
+                }
+                if !matches!(
+                    stop.recv_timeout(RETRY_INTERVAL),
+                    Err(flume::RecvTimeoutError::Timeout)
+                ) {
+                    return Ok(());
                 }
+                backend = kind.open(adapter.clone(), ctx.clone())?;
+                polled_since_open = false;
+                continue;
             }
+            Err(e) => return Err(e),
+        };
 
-            let mut state = BTreeMap::new();
-            for (addr, ws) in &connected_devices {
-                let sensor_values = ws.read_values(&dbus)?;
-                state.insert(*addr, SensorState::Connected(sensor_values));
-            }
+        let _ = tx.send(state);
+        metrics.bluetooth.poll.observe(poll_started.elapsed());
+        drop(poll_enter);
 
-            let _ = tx.send(state);
-
-            match stop.recv_timeout(
-                sleep_time
-                    .checked_sub(poll_started.elapsed())
-                    .unwrap_or(Duration::from_secs(0)),
-            ) {
-                Ok(()) | Err(flume::RecvTimeoutError::Disconnected) => {
-                    // TODO: parallelize this, takes about 2 seconds per device
-                    tracing::info!("Disconnecting devices");
-                    for (addr, ws) in connected_devices {
-                        tracing::info!("Disconnecting {}", addr);
-                        ws.disconnect(&dbus)?;
-                    }
-                    break Ok(());
-                }
-                _ => {}
+        match stop.recv_timeout(
+            sleep_time
+                .checked_sub(poll_started.elapsed())
+                .unwrap_or(Duration::from_secs(0)),
+        ) {
+            Ok(()) | Err(flume::RecvTimeoutError::Disconnected) => {
+                tracing::info!("Disconnecting devices of {}", adapter);
+                break backend.shutdown();
             }
+            _ => {}
         }
-    };
+    }
+}
 
+/// Runs a thread per adapter so one adapter with many stations doesn't hold up the others,
+/// their updates get merged by sharing one channel. The first one failing fails them all.
+/// `connected` gets sent once every adapter opened its backend and with it the connections to
+/// the bus it keeps.
+fn poll_adapters(
+    backend: Backend,
+    stop: flume::Receiver<()>,
+    ctx: BackendContext,
+    tx: flume::Sender<BTreeMap<BluetoothAddress, SensorState>>,
+    connected: oneshot::Sender<()>,
+) -> Result<(), Error> {
+    let (done_tx, done_rx) = flume::unbounded();
+    let (opened_tx, opened_rx) = flume::unbounded();
+    for adapter in backend.adapters()? {
+        tracing::info!("Polling weatherstations of adapter {}", adapter);
+        let (stop, ctx, tx, done_tx, opened_tx) = (
+            stop.clone(),
+            ctx.clone(),
+            tx.clone(),
+            done_tx.clone(),
+            opened_tx.clone(),
+        );
+        thread::Builder::new()
+            .name(format!("bluetooth-{}", adapter))
+            .spawn(move || {
+                let _ = done_tx.send(poll_adapter(backend, adapter, stop, ctx, tx, opened_tx));
+            })
+            .map_err(Error::Thread)?;
+    }
+    drop(done_tx);
+    drop(opened_tx);
+    // nothing gets sent, this returns once every adapter thread dropped its sender
+    let _ = opened_rx.recv();
+    let _ = connected.send(());
+    // ends once every adapter thread is done
+    for done in done_rx {
+        done?;
+    }
+    Ok(())
+}
+
+/// Also returns receivers for the thread failing and for it having connected to the bus, which
+/// is when root isn't needed anymore
+pub(crate) fn bluetooth_thread(
+    backend: Backend,
+    stop: flume::Receiver<()>,
+    ctx: BackendContext,
+) -> (
+    thread::JoinHandle<Result<(), Error>>,
+    oneshot::Receiver<()>,
+    oneshot::Receiver<()>,
+    flume::Receiver<BTreeMap<BluetoothAddress, SensorState>>,
+) {
+    let (tx, rx) = flume::bounded(1);
     let (error_tx, error_rx) = oneshot::channel();
-    let thread_handle = thread::spawn(move || -> Result<(), eyre::Error> {
-        match poll_fn() {
+    let (connected_tx, connected_rx) = oneshot::channel();
+    let thread_handle = thread::spawn(move || -> Result<(), Error> {
+        match poll_adapters(backend, stop, ctx, tx, connected_tx) {
             Err(e) => {
                 error_tx.send(()).unwrap();
                 Err(e)
@@ -183,74 +431,27 @@ pub(crate) fn bluetooth_thread(
         }
     });
 
-    (thread_handle, error_rx, rx)
+    (thread_handle, error_rx, connected_rx, rx)
 }
 
-#[derive(Debug)]
-enum BluezObject<'a> {
-    Interface {
-        discovering: bool,
-        interface: &'a str,
-    },
-
-    WeatherstationDevice {
-        address: BluetoothAddress,
-        connected: bool,
-        services_resolved: bool,
-    },
-}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sensor::Quantity;
 
-fn interpret_object(
-    object_path: &str,
-    interfaces: HashMap<String, HashMap<String, OwnedValue>>,
-) -> Option<BluezObject> {
-    let path = object_path
-        .strip_prefix("/org/bluez/")?
-        .split('/')
-        .collect::<Vec<_>>();
-
-    match path.as_slice() {
-        [_interface, _device] => {
-            let bluez_device = interfaces.get("org.bluez.Device1")?;
-            let uuid_array = bluez_device.get("UUIDs")?.downcast_ref::<Array>()?;
-
-            // FIXME: bad
-            let (mut environmental_sensing, mut weatherstation) = (false, false);
-            for uuid in uuid_array.get() {
-                if let zvariant::Value::Str(s) = uuid {
-                    if s.as_str() == BLE_GATT_SERVICE_ENVIRONMENTAL_SENSING {
-                        environmental_sensing = true;
-                    } else if s.as_str() == BLE_GATT_SERVICE_WEATHERSTATION {
-                        weatherstation = true;
-                    }
-                }
-            }
-            if !(environmental_sensing && weatherstation) {
-                return None;
-            }
-            let connected = *bluez_device.get("Connected")?.downcast_ref::<bool>()?;
-            let address = bluez_device
-                .get("Address")?
-                .downcast_ref::<zvariant::Str>()?;
-            let services_resolved = *bluez_device
-                .get("ServicesResolved")?
-                .downcast_ref::<bool>()?;
-
-            Some(BluezObject::WeatherstationDevice {
-                connected,
-                address: BluetoothAddress::parse_str(address.as_str()).ok()?,
-                services_resolved,
-            })
-        }
-
-        [interface] => {
-            let bluez_adapter = interfaces.get("org.bluez.Adapter1")?;
-            let discovering = *bluez_adapter.get("Discovering")?.downcast_ref::<bool>()?;
-            Some(BluezObject::Interface {
-                discovering,
-                interface,
-            })
+    #[test]
+    fn history_records() {
+        let mut chunk = Vec::new();
+        for (time, temperature) in &[(1000_u32, 2150_i16), (1060, -320)] {
+            chunk.extend_from_slice(&time.to_le_bytes());
+            chunk.extend_from_slice(&temperature.to_le_bytes());
+            chunk.extend_from_slice(&4500_u16.to_le_bytes());
+            chunk.extend_from_slice(&1_013_250_u32.to_le_bytes());
         }
-        _ => None,
+        let records = decode_history(&chunk).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].0, Timestamp::from(1060));
+        assert_eq!(Quantity::Temperature.of(records[1].1), -3.2);
+        assert!(decode_history(&chunk[..20]).is_err());
     }
 }
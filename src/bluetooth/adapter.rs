@@ -0,0 +1,301 @@
+use crate::timestamp::Timestamp;
+#[cfg(feature = "alerts")]
+use crate::{
+    alert::{self, ActionConfig, Actions, Event, EventKind},
+    bluetooth::BluetoothAddress,
+};
+use std::{collections::BTreeMap, fs, io, path::Path, sync::Mutex, time::Duration};
+
+const RFKILL_DIR: &str = "/sys/class/rfkill";
+
+/// Alerts about a bluetooth adapter that stays unusable, only settable in the config file
+#[derive(serde::Deserialize, Clone)]
+#[cfg_attr(not(feature = "alerts"), allow(dead_code))]
+pub(crate) struct AdapterConfig {
+    /// minutes the adapter has to be unavailable before the actions run
+    #[serde(default = "default_alert_after")]
+    alert_after: u64,
+    /// run when the adapter stays unavailable and once it works again
+    #[cfg(feature = "alerts")]
+    #[serde(rename = "action", default)]
+    actions: Vec<ActionConfig>,
+}
+
+fn default_alert_after() -> u64 {
+    5
+}
+
+/// What's up with the bluetooth adapter as far as the central can tell
+#[derive(Copy, Clone, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AdapterState {
+    /// the bluetooth thread didn't get to look at it yet
+    Starting,
+    /// powered and scanning for weatherstations
+    Ready,
+    /// turning it on didn't work
+    PoweredOff,
+    /// rfkill blocks it and unblocking didn't work
+    SoftBlocked,
+    /// a hardware switch blocks it, nothing to be done in software
+    HardBlocked,
+    /// BlueZ doesn't know any adapter
+    Missing,
+    /// bluetooth is turned off in the config or a scenario replaces it
+    Off,
+}
+
+impl AdapterState {
+    pub(crate) fn available(self) -> bool {
+        matches!(self, AdapterState::Ready | AdapterState::Off)
+    }
+
+    /// What somebody looking at the health of the central should know
+    pub(crate) fn describe(self) -> &'static str {
+        match self {
+            AdapterState::Starting => "waiting for the first bluetooth poll",
+            AdapterState::Ready => "scanning for weatherstations",
+            AdapterState::PoweredOff => "the adapter is powered off and could not be turned on",
+            AdapterState::SoftBlocked => {
+                "bluetooth is blocked by rfkill, try `rfkill unblock bluetooth`"
+            }
+            AdapterState::HardBlocked => "bluetooth is blocked by a hardware switch",
+            AdapterState::Missing => "no bluetooth adapter found",
+            AdapterState::Off => "bluetooth is turned off",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Current {
+    pub(crate) state: AdapterState,
+    /// since when the adapter has been available or unavailable, doesn't change between two
+    /// reasons for being unavailable
+    pub(crate) since: Timestamp,
+}
+
+/// One adapter as last seen by the thread polling it, to tell how the stations are spread
+#[derive(Clone, Debug, serde::Serialize)]
+pub(crate) struct AdapterStats {
+    pub(crate) state: AdapterState,
+    /// weatherstations connected through it
+    pub(crate) connected: usize,
+    /// duration of the latest poll in milliseconds
+    pub(crate) poll_ms: u64,
+    pub(crate) polls: u64,
+    /// times its backend got opened again after failing
+    pub(crate) restarts: u64,
+    /// of the devices and polls of the adapter by category
+    pub(crate) errors: BTreeMap<&'static str, u64>,
+    pub(crate) last_error: Option<String>,
+}
+
+/// The adapters as last seen by the bluetooth threads, for the health endpoint and alerts
+pub(crate) struct AdapterStatus {
+    /// of the best adapter, one working adapter is enough
+    current: Mutex<Current>,
+    adapters: Mutex<BTreeMap<String, AdapterStats>>,
+}
+
+impl AdapterStatus {
+    pub(crate) fn new(state: AdapterState) -> Self {
+        Self {
+            current: Mutex::new(Current {
+                state,
+                since: Timestamp::now(),
+            }),
+            adapters: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub(crate) fn current(&self) -> Current {
+        *self.current.lock().unwrap()
+    }
+
+    pub(crate) fn set(&self, state: AdapterState) {
+        self.set_at(state, Timestamp::now());
+    }
+
+    fn set_at(&self, state: AdapterState, now: Timestamp) {
+        let mut current = self.current.lock().unwrap();
+        if current.state == state {
+            return;
+        }
+        if state.available() {
+            tracing::info!("Bluetooth adapter is {:?}: {}", state, state.describe());
+        } else {
+            tracing::warn!("Bluetooth adapter is {:?}: {}", state, state.describe());
+        }
+        if current.state.available() != state.available() {
+            current.since = now;
+        }
+        current.state = state;
+    }
+
+    /// State of the adapter called `name`, like `hci0`
+    pub(crate) fn set_adapter(&self, name: &str, state: AdapterState) {
+        let best = {
+            let mut adapters = self.adapters.lock().unwrap();
+            adapters
+                .entry(name.to_owned())
+                .or_insert_with(|| AdapterStats {
+                    state,
+                    connected: 0,
+                    poll_ms: 0,
+                    polls: 0,
+                    restarts: 0,
+                    errors: BTreeMap::new(),
+                    last_error: None,
+                })
+                .state = state;
+            if adapters
+                .values()
+                .any(|adapter| adapter.state == AdapterState::Ready)
+            {
+                AdapterState::Ready
+            } else {
+                adapters
+                    .values()
+                    .next()
+                    .map_or(AdapterState::Missing, |adapter| adapter.state)
+            }
+        };
+        self.set(best);
+    }
+
+    /// Records a finished poll of the adapter called `name`, returns how many weatherstations
+    /// are connected through all adapters
+    pub(crate) fn polled(&self, name: &str, connected: usize, took: Duration) -> usize {
+        let mut adapters = self.adapters.lock().unwrap();
+        if let Some(adapter) = adapters.get_mut(name) {
+            adapter.connected = connected;
+            adapter.poll_ms = took.as_millis() as u64;
+            adapter.polls += 1;
+        }
+        adapters.values().map(|adapter| adapter.connected).sum()
+    }
+
+    /// Counts a restart of the backend of the adapter called `name`
+    pub(crate) fn restarted(&self, name: &str) {
+        if let Some(adapter) = self.adapters.lock().unwrap().get_mut(name) {
+            adapter.restarts += 1;
+        }
+    }
+
+    /// Counts an error the thread of the adapter called `name` got past
+    pub(crate) fn failed(&self, name: &str, error: &super::Error) {
+        let mut adapters = self.adapters.lock().unwrap();
+        if let Some(adapter) = adapters.get_mut(name) {
+            *adapter.errors.entry(error.category()).or_default() += 1;
+            adapter.last_error = Some(error.to_string());
+        }
+    }
+
+    pub(crate) fn adapters(&self) -> BTreeMap<String, AdapterStats> {
+        self.adapters.lock().unwrap().clone()
+    }
+}
+
+/// How rfkill blocks the bluetooth radios
+#[derive(Copy, Clone, Debug, Default)]
+pub(super) struct Rfkill {
+    pub(super) soft: bool,
+    pub(super) hard: bool,
+}
+
+/// Blocks of all bluetooth radios in `/sys/class/rfkill`, None without any or without sysfs
+pub(super) fn rfkill() -> Option<Rfkill> {
+    let mut blocked = None;
+    for dir in bluetooth_rfkills().ok()? {
+        let flag = |name| read_flag(&dir.join(name)).unwrap_or(false);
+        let found = blocked.get_or_insert_with(Rfkill::default);
+        found.soft |= flag("soft");
+        found.hard |= flag("hard");
+    }
+    blocked
+}
+
+/// Lifts the soft block of all bluetooth radios, needs root or a udev rule
+pub(super) fn unblock() -> Result<(), io::Error> {
+    for dir in bluetooth_rfkills()? {
+        fs::write(dir.join("soft"), "0")?;
+    }
+    Ok(())
+}
+
+fn bluetooth_rfkills() -> Result<Vec<std::path::PathBuf>, io::Error> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(RFKILL_DIR)? {
+        let dir = entry?.path();
+        if fs::read_to_string(dir.join("type"))?.trim() == "bluetooth" {
+            dirs.push(dir);
+        }
+    }
+    Ok(dirs)
+}
+
+fn read_flag(path: &Path) -> Result<bool, io::Error> {
+    Ok(fs::read_to_string(path)?.trim() == "1")
+}
+
+/// Runs the actions of `config` once the adapter was unavailable for `alert_after` minutes
+#[cfg(feature = "alerts")]
+pub(crate) async fn watch(
+    ctx: crate::Context,
+    config: AdapterConfig,
+    mqtt: Option<crate::MqttConnection>,
+) {
+    let mut actions = Actions::new("adapter", &config.actions, mqtt, &alert::http_client());
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
+    let mut fired = false;
+    loop {
+        interval.tick().await;
+        let current = ctx.adapter.current();
+        let minutes = Timestamp::now().bottoming_sub(current.since).as_u32() / 60;
+        let kind = match (current.state.available(), fired) {
+            (false, false) if u64::from(minutes) >= config.alert_after => EventKind::Fired,
+            (true, true) => EventKind::Cleared,
+            _ => continue,
+        };
+        fired = kind == EventKind::Fired;
+        actions
+            .perform(&Event {
+                rule: "adapter",
+                sensor: BluetoothAddress::from(0),
+                label: Some(current.state.describe().to_owned()),
+                value: f64::from(minutes),
+                kind,
+            })
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unavailable_since_first_failure() {
+        let status = AdapterStatus::new(AdapterState::Ready);
+        status.set_at(AdapterState::PoweredOff, Timestamp::from(100));
+        status.set_at(AdapterState::SoftBlocked, Timestamp::from(200));
+        assert_eq!(status.current().state, AdapterState::SoftBlocked);
+        assert_eq!(status.current().since, Timestamp::from(100));
+
+        status.set_at(AdapterState::Ready, Timestamp::from(300));
+        assert_eq!(status.current().since, Timestamp::from(300));
+    }
+
+    #[test]
+    fn one_ready_adapter_is_enough() {
+        let status = AdapterStatus::new(AdapterState::Starting);
+        status.set_adapter("hci0", AdapterState::PoweredOff);
+        assert_eq!(status.current().state, AdapterState::PoweredOff);
+        status.set_adapter("hci1", AdapterState::Ready);
+        assert_eq!(status.current().state, AdapterState::Ready);
+
+        status.polled("hci0", 3, Duration::from_millis(10));
+        assert_eq!(status.polled("hci1", 2, Duration::from_millis(20)), 5);
+        assert_eq!(status.adapters()["hci1"].poll_ms, 20);
+    }
+}
@@ -0,0 +1,1067 @@
+mod dbus_interfaces;
+mod notify;
+
+use super::{
+    adapter::{self, Rfkill},
+    profile::{Profile, Profiles, Reads},
+    AdapterState, AdapterStatus, BackendContext, BluetoothAddress, BluetoothBackend,
+    ConnectionParams, Error, History, StationSettings, Steering, Timeouts,
+    CONNECTION_PARAMETERS_CHARACTERISTIC, HISTORY_CONTROL_CHARACTERISTIC,
+    HISTORY_RECORDS_CHARACTERISTIC, HISTORY_START, MAX_HISTORY_RECORDS,
+    MEASUREMENT_INTERVAL_CHARACTERISTIC, SET_CLOCK_CHARACTERISTIC,
+};
+use crate::{
+    battery::Batteries,
+    clock::DeviceClocks,
+    metrics::Metrics,
+    presence::Presence,
+    sensor::{SensorState, SensorValues},
+    timestamp::Timestamp,
+};
+use dbus_interfaces::{Adapter1Proxy, Device1Proxy, GattCharacteristic1Proxy};
+use notify::Notifications;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    convert::TryFrom,
+    num::NonZeroU16,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use zbus::fdo::ObjectManagerProxy;
+use zvariant::{Array, ObjectPath, OwnedObjectPath, OwnedValue};
+
+/// Stack size of the threads running single dbus calls in low memory mode
+const SMALL_STACK_SIZE: usize = 256 * 1024;
+
+/// Longest the history, clock and settings writes after a read may take, a full history is
+/// about 10k records
+const AFTER_READ_TIMEOUT: Duration = Duration::from_secs(120);
+
+fn spawn_call<F>(low_memory: bool, f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut builder = thread::Builder::new();
+    if low_memory {
+        builder = builder.stack_size(SMALL_STACK_SIZE);
+    }
+    builder.spawn(f).expect("Could not spawn dbus call thread");
+}
+
+/// Runs a blocking dbus call on its own thread and gives up waiting for it after `timeout`.
+/// The call itself can't be cancelled so it keeps running in the background.
+fn call_with_timeout<T, F>(low_memory: bool, timeout: Duration, f: F) -> Option<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = flume::bounded(1);
+    spawn_call(low_memory, move || {
+        let _ = tx.send(f());
+    });
+    rx.recv_timeout(timeout).ok()
+}
+
+#[derive(Clone)]
+struct Weatherstation {
+    device_path: OwnedObjectPath,
+    profile: Arc<Profile>,
+    /// object paths of the gatt characteristics of the device by their uuid
+    characteristics: Arc<BTreeMap<String, OwnedObjectPath>>,
+    notifications: Arc<Notifications>,
+}
+
+impl Weatherstation {
+    fn path(&self, uuid: &str) -> Result<&OwnedObjectPath, Error> {
+        self.characteristics
+            .get(uuid)
+            .ok_or_else(|| Error::missing(uuid))
+    }
+
+    /// Values of the device at `addr`, notified ones don't need a dbus call
+    fn read_values(
+        &self,
+        dbus: &zbus::Connection,
+        addr: BluetoothAddress,
+    ) -> Result<(SensorValues, Option<Timestamp>), Error> {
+        let mut reads = Reads::new();
+        for uuid in self.profile.characteristics() {
+            let raw = match self.notifications.latest(addr, uuid) {
+                Some(raw) => raw,
+                None => Self::read(dbus, self.path(uuid)?)?,
+            };
+            reads.insert(uuid.to_owned(), raw);
+        }
+        // firmware without the measurement timestamp characteristic still gets read
+        if let Some(uuid) = self.profile.timestamp() {
+            if let Some(raw) = self
+                .path(uuid)
+                .ok()
+                .and_then(|path| Self::read(dbus, path).ok())
+            {
+                reads.insert(uuid.to_owned(), raw);
+            }
+        }
+        self.profile.decode(&reads)
+    }
+
+    /// Has the device at `addr` push the values of characteristics that can notify from now on
+    fn subscribe(&self, dbus: &zbus::Connection, addr: BluetoothAddress) {
+        for uuid in self.profile.characteristics() {
+            let subscribed = self
+                .path(uuid)
+                .and_then(|path| self.notifications.subscribe(dbus, addr, uuid, path));
+            match subscribed {
+                Ok(true) => tracing::debug!("Receiving notifications of {} from {}", uuid, addr),
+                Ok(false) => {}
+                Err(e) => tracing::warn!("Could not subscribe to {} of {}: {}", uuid, addr, e),
+            }
+        }
+    }
+
+    /// Everything the station buffered while it wasn't connected, nothing for firmware without a
+    /// history
+    fn read_history(
+        &self,
+        dbus: &zbus::Connection,
+    ) -> Result<Vec<(Timestamp, SensorValues)>, Error> {
+        let (control, records_path) = match (
+            self.characteristics.get(HISTORY_CONTROL_CHARACTERISTIC),
+            self.characteristics.get(HISTORY_RECORDS_CHARACTERISTIC),
+        ) {
+            (Some(control), Some(records)) => (control, records),
+            _ => return Ok(Vec::new()),
+        };
+        let control = GattCharacteristic1Proxy::new_for(dbus, "org.bluez", control)?;
+        match control.write_value(&[HISTORY_START], HashMap::new()) {
+            Ok(()) => {}
+            Err(zbus::Error::MethodError(_, _, _)) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut records = Vec::new();
+        while records.len() < MAX_HISTORY_RECORDS {
+            let chunk = Self::read(dbus, records_path)?;
+            if chunk.is_empty() {
+                break;
+            }
+            records.extend(super::decode_history(&chunk)?);
+        }
+        Ok(records)
+    }
+
+    /// Sets the clock of the station to `now`, false for firmware without a settable clock
+    fn set_clock(&self, dbus: &zbus::Connection, now: Timestamp) -> Result<bool, zbus::Error> {
+        self.write(dbus, SET_CLOCK_CHARACTERISTIC, &now.as_u32().to_le_bytes())
+    }
+
+    /// False for firmware without a measurement interval
+    fn set_measurement_interval(
+        &self,
+        dbus: &zbus::Connection,
+        interval: NonZeroU16,
+    ) -> Result<bool, zbus::Error> {
+        self.write(
+            dbus,
+            MEASUREMENT_INTERVAL_CHARACTERISTIC,
+            &interval.get().to_le_bytes(),
+        )
+    }
+
+    /// False for firmware without connection parameters
+    fn set_connection_parameters(
+        &self,
+        dbus: &zbus::Connection,
+        params: ConnectionParams,
+    ) -> Result<bool, zbus::Error> {
+        self.write(dbus, CONNECTION_PARAMETERS_CHARACTERISTIC, &params.encode())
+    }
+
+    fn disconnect(&self, dbus: &zbus::Connection) -> Result<(), zbus::Error> {
+        Device1Proxy::new_for(dbus, "org.bluez", self.device_path.as_str())?.disconnect()
+    }
+
+    fn disconnect_with_timeout(
+        self,
+        dbus: &zbus::Connection,
+        timeout: Duration,
+        low_memory: bool,
+    ) -> Option<Result<(), zbus::Error>> {
+        let dbus = dbus.clone();
+        call_with_timeout(low_memory, timeout, move || self.disconnect(&dbus))
+    }
+
+    fn read(dbus: &zbus::Connection, path: &OwnedObjectPath) -> Result<Vec<u8>, zbus::Error> {
+        GattCharacteristic1Proxy::new_for(dbus, "org.bluez", path)?.read_value(HashMap::new())
+    }
+
+    /// False if the characteristic doesn't exist
+    fn write(
+        &self,
+        dbus: &zbus::Connection,
+        uuid: &str,
+        value: &[u8],
+    ) -> Result<bool, zbus::Error> {
+        let path = match self.characteristics.get(uuid) {
+            Some(path) => path,
+            None => return Ok(false),
+        };
+        let characteristic = GattCharacteristic1Proxy::new_for(dbus, "org.bluez", path)?;
+        match characteristic.write_value(value, HashMap::new()) {
+            Ok(()) => Ok(true),
+            Err(zbus::Error::MethodError(_, _, _)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A finished read of the values of one device, tagged with the poll that started it
+type Read = (u64, BluetoothAddress, Result<SensorValues, Error>, Duration);
+
+/// Sent once everything after a read finished, with whether the history got read
+type Done = (BluetoothAddress, bool);
+
+/// Subscribes, reads the `history` of a new device and writes its clock and settings if they're
+/// due, returns whether the history got read
+fn after_read(
+    ws: &Weatherstation,
+    dbus: &zbus::Connection,
+    addr: BluetoothAddress,
+    history: Option<flume::Sender<History>>,
+    clocks: &DeviceClocks,
+    settings: &StationSettings,
+) -> bool {
+    let history_read = match history {
+        Some(history) => {
+            ws.subscribe(dbus, addr);
+            match ws.read_history(dbus) {
+                Ok(records) => {
+                    super::send_history(addr, records, clocks, &history);
+                    true
+                }
+                Err(e) => {
+                    tracing::warn!("Could not read history of {}: {}", addr, e);
+                    false
+                }
+            }
+        }
+        None => false,
+    };
+    if clocks.sync_due(addr) {
+        let now = Timestamp::now();
+        match ws.set_clock(dbus, now) {
+            Ok(true) => clocks.synced(addr, now),
+            Ok(false) => clocks.sync_attempted(addr),
+            Err(e) => tracing::warn!("Could not set clock of {}: {}", addr, e),
+        }
+    }
+    if let Some(interval) = settings.interval_due(addr) {
+        match ws.set_measurement_interval(dbus, interval) {
+            Ok(true) => settings.interval_written(addr, interval),
+            Ok(false) => settings.interval_unsupported(addr),
+            Err(e) => tracing::warn!("Could not set measurement interval of {}: {}", addr, e),
+        }
+    }
+    if let Some(params) = settings.connection_due(addr) {
+        match ws.set_connection_parameters(dbus, params) {
+            Ok(true) => settings.connection_written(addr),
+            Ok(false) => settings.connection_unsupported(addr),
+            Err(e) => tracing::warn!("Could not set connection parameters of {}: {}", addr, e),
+        }
+    }
+    history_read
+}
+
+/// Talks to BlueZ over the system bus, only available on Linux
+pub(super) struct Bluez {
+    dbus: zbus::Connection,
+    /// like `hci0`, only its devices get connected and read
+    name: String,
+    metrics: Arc<Metrics>,
+    clocks: Arc<DeviceClocks>,
+    batteries: Arc<Batteries>,
+    history: flume::Sender<History>,
+    settings: Arc<StationSettings>,
+    adapter: Arc<AdapterStatus>,
+    presence: Option<Arc<Presence>>,
+    profiles: Arc<Profiles>,
+    steering: Arc<Steering>,
+    notifications: Arc<Notifications>,
+    timeouts: Timeouts,
+    low_memory: bool,
+    connected_devices: BTreeMap<BluetoothAddress, Weatherstation>,
+    /// reads of earlier polls can still be running when a device hangs, devices stay in here
+    /// until the work after their read is done as well
+    reading: BTreeSet<BluetoothAddress>,
+    /// connected without their history read yet, it gets read after their next values
+    new_devices: BTreeSet<BluetoothAddress>,
+    read_tx: flume::Sender<Read>,
+    read_rx: flume::Receiver<Read>,
+    done_tx: flume::Sender<Done>,
+    done_rx: flume::Receiver<Done>,
+    poll_generation: u64,
+}
+
+impl Bluez {
+    pub(super) fn new(name: String, ctx: BackendContext) -> Result<Self, Error> {
+        let BackendContext {
+            metrics,
+            clocks,
+            batteries,
+            history,
+            settings,
+            adapter,
+            presence,
+            profiles,
+            steering,
+            runtime,
+            timeouts,
+            low_memory,
+        } = ctx;
+        let (read_tx, read_rx) = flume::unbounded();
+        let (done_tx, done_rx) = flume::unbounded();
+        Ok(Self {
+            dbus: zbus::Connection::new_system()?,
+            name,
+            metrics,
+            clocks,
+            batteries,
+            history,
+            settings,
+            adapter,
+            presence,
+            profiles,
+            steering,
+            notifications: Arc::new(Notifications::new(runtime)),
+            timeouts,
+            low_memory,
+            connected_devices: BTreeMap::new(),
+            reading: BTreeSet::new(),
+            new_devices: BTreeSet::new(),
+            read_tx,
+            read_rx,
+            done_tx,
+            done_rx,
+            poll_generation: 0,
+        })
+    }
+
+    /// Whether the object at `path` is the adapter of this thread or one of its devices
+    fn owns(&self, path: &str) -> bool {
+        path.strip_prefix("/org/bluez/")
+            .and_then(|rest| rest.strip_prefix(self.name.as_str()))
+            .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// Turns on an adapter that is powered off, lifting a soft block by rfkill first because
+    /// BlueZ refuses to power on blocked adapters
+    fn power_on(&self, object_path: &str, interface: &str) -> AdapterState {
+        match adapter::rfkill() {
+            Some(Rfkill { hard: true, .. }) => return AdapterState::HardBlocked,
+            Some(Rfkill { soft: true, .. }) => match adapter::unblock() {
+                Ok(()) => tracing::info!("Lifted the rfkill block of bluetooth"),
+                Err(e) => {
+                    tracing::warn!("Could not lift the rfkill block of bluetooth: {}", e);
+                    return AdapterState::SoftBlocked;
+                }
+            },
+            _ => {}
+        }
+
+        let powered = Adapter1Proxy::new_for(&self.dbus, "org.bluez", object_path)
+            .map_err(Error::from)
+            .and_then(|adapter| Ok(adapter.set_powered(true)?));
+        match powered {
+            Ok(()) => {
+                tracing::info!("Powered on interface {}", interface);
+                AdapterState::Ready
+            }
+            Err(e) => {
+                tracing::warn!("Could not power on interface {}: {}", interface, e);
+                AdapterState::PoweredOff
+            }
+        }
+    }
+}
+
+impl BluetoothBackend for Bluez {
+    fn poll(&mut self) -> Result<(BTreeMap<BluetoothAddress, SensorState>, Duration), Error> {
+        let (dbus, timeouts, low_memory) = (&self.dbus, &self.timeouts, self.low_memory);
+        let metrics = &self.metrics.bluetooth;
+        let started = Instant::now();
+        let objs = ObjectManagerProxy::new_for(dbus, "org.bluez", "/")?
+            .get_managed_objects()?
+            .into_iter()
+            .map(|(k, v)| (k.as_str().to_string(), v))
+            .filter(|(path, _)| self.owns(path))
+            .collect::<BTreeMap<_, _>>();
+        metrics.get_managed_objects.observe(started.elapsed());
+        let mut characteristics = characteristics(&objs);
+        let now = Timestamp::now();
+        if let Some(ref presence) = self.presence {
+            report_beacons(&objs, presence, now);
+        }
+        let load = self
+            .adapter
+            .adapters()
+            .into_iter()
+            .map(|(name, adapter)| (name, adapter.connected))
+            .collect::<BTreeMap<_, _>>();
+        let mut sleep_time = Duration::from_secs(31);
+        let mut adapters = Vec::new();
+        for (object_path, interfaces) in objs {
+            if let Ok(obj) = interpret_object(&object_path, interfaces, &self.profiles) {
+                if let BluezObject::WeatherstationDevice {
+                    address,
+                    connected,
+                    rssi,
+                    battery,
+                    ..
+                } = obj
+                {
+                    if connected || rssi.is_some() {
+                        self.steering.seen(address, &self.name, rssi, now);
+                    }
+                    if let (true, Some(percentage)) = (connected, battery) {
+                        self.batteries.observe(address, percentage, now);
+                    }
+                }
+                match obj {
+                    BluezObject::Interface {
+                        powered: false,
+                        interface,
+                        ..
+                    } => {
+                        let state = self.power_on(&object_path, interface);
+                        if state == AdapterState::Ready {
+                            // discovery starts on the next poll
+                            sleep_time = Duration::from_secs(10);
+                        }
+                        adapters.push(state);
+                    }
+                    BluezObject::Interface {
+                        discovering: false,
+                        interface,
+                        ..
+                    } => {
+                        Adapter1Proxy::new_for(dbus, "org.bluez", object_path.as_str())?
+                            .start_discovery()?;
+                        tracing::info!("Started discovery for interface {}", interface);
+                        sleep_time = Duration::from_secs(10);
+                        adapters.push(AdapterState::Ready);
+                    }
+                    BluezObject::Interface { .. } => adapters.push(AdapterState::Ready),
+                    BluezObject::WeatherstationDevice {
+                        connected: false,
+                        address,
+                        ..
+                    } if self.steering.assigned(address, &self.name, &load, now) => {
+                        let connect = {
+                            let (dbus, object_path) = (dbus.clone(), object_path.clone());
+                            move || {
+                                Device1Proxy::new_for(&dbus, "org.bluez", object_path.as_str())?
+                                    .connect()
+                            }
+                        };
+                        match call_with_timeout(low_memory, timeouts.connect, connect) {
+                            Some(Ok(())) => {}
+                            Some(Err(zbus::Error::MethodError(_, _, _))) => {
+                                metrics.connect_failures.inc();
+                                tracing::warn!("Could not connect to {}", address);
+                            }
+                            Some(Err(e)) => {
+                                return Err(e.into());
+                            }
+                            None => {
+                                metrics.connect_timeouts.inc();
+                                tracing::warn!("Timed out connecting to {}", address);
+                                self.adapter.failed(
+                                    &self.name,
+                                    &Error::Timeout {
+                                        addr: address,
+                                        action: "connecting",
+                                    },
+                                );
+                            }
+                        };
+                    }
+                    BluezObject::WeatherstationDevice {
+                        services_resolved: true,
+                        paired: false,
+                        address,
+                        ..
+                    } if self.settings.encryption_required(address) => {
+                        // stations refuse reads over unencrypted links, bonding encrypts it and
+                        // trusting lets it reconnect without pairing again
+                        let pair = {
+                            let (dbus, object_path) = (dbus.clone(), object_path.clone());
+                            move || -> Result<(), Error> {
+                                let device = Device1Proxy::new_for(
+                                    &dbus,
+                                    "org.bluez",
+                                    object_path.as_str(),
+                                )?;
+                                device.pair()?;
+                                device.set_trusted(true)?;
+                                Ok(())
+                            }
+                        };
+                        match call_with_timeout(low_memory, timeouts.connect, pair) {
+                            Some(Ok(())) => tracing::info!("Paired {}", address),
+                            Some(Err(e)) => tracing::warn!("Could not pair {}: {}", address, e),
+                            None => tracing::warn!("Timed out pairing {}", address),
+                        }
+                    }
+                    BluezObject::WeatherstationDevice {
+                        services_resolved: true,
+                        address,
+                        profile,
+                        ..
+                    } if !self.connected_devices.contains_key(&address) => {
+                        tracing::info!("Connected new {} device {}", profile.name, address);
+                        let ws = Weatherstation {
+                            characteristics: Arc::new(
+                                characteristics.remove(&object_path).unwrap_or_default(),
+                            ),
+                            device_path: ObjectPath::try_from(object_path).unwrap().into(),
+                            profile,
+                            notifications: self.notifications.clone(),
+                        };
+                        self.connected_devices.insert(address, ws);
+                        self.new_devices.insert(address);
+                        self.clocks.reconnected(address);
+                        self.settings.reconnected(address);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // stations steered to another adapter get let go so that one can connect them
+        let moved = self
+            .connected_devices
+            .keys()
+            .filter(|addr| !self.steering.assigned(**addr, &self.name, &load, now))
+            .copied()
+            .collect::<Vec<_>>();
+        for addr in moved {
+            let ws = self.connected_devices.remove(&addr).unwrap();
+            match ws.disconnect_with_timeout(dbus, timeouts.disconnect, low_memory) {
+                Some(Ok(())) => tracing::info!("Disconnected {} for another adapter", addr),
+                Some(Err(e)) => tracing::warn!("Could not disconnect {}: {}", addr, e),
+                None => tracing::warn!("Timed out disconnecting {}", addr),
+            }
+        }
+
+        self.adapter.set_adapter(
+            &self.name,
+            adapters.first().copied().unwrap_or(AdapterState::Missing),
+        );
+
+        for (addr, history_read) in self.done_rx.try_iter() {
+            self.reading.remove(&addr);
+            if history_read {
+                self.new_devices.remove(&addr);
+            }
+        }
+
+        self.poll_generation += 1;
+        let mut outstanding = 0;
+        for (addr, ws) in &self.connected_devices {
+            if !self.reading.insert(*addr) {
+                tracing::warn!("Still busy with the last read of {}, skipping it", addr);
+                continue;
+            }
+            outstanding += 1;
+
+            let (addr, ws, dbus, read_tx, done_tx, clocks, settings) = (
+                *addr,
+                ws.clone(),
+                dbus.clone(),
+                self.read_tx.clone(),
+                self.done_tx.clone(),
+                self.clocks.clone(),
+                self.settings.clone(),
+            );
+            // catches up on what happened while the central didn't listen, until that worked
+            let history = if self.new_devices.contains(&addr) {
+                Some(self.history.clone())
+            } else {
+                None
+            };
+            let read_span = tracing::debug_span!("read_values", %addr);
+            let generation = self.poll_generation;
+            spawn_call(low_memory, move || {
+                let _read_enter = read_span.enter();
+                let read_started = Instant::now();
+                let values = ws
+                    .read_values(&dbus, addr)
+                    .map(|(values, timestamp)| {
+                        if let Some(device) = timestamp {
+                            clocks.observe(addr, device, Timestamp::now());
+                        }
+                        values
+                    })
+                    .map_err(|e| e.at(addr));
+                let read = values.is_ok();
+                let _ = read_tx.send((generation, addr, values, read_started.elapsed()));
+                if !read {
+                    let _ = done_tx.send((addr, false));
+                    return;
+                }
+
+                // runs after sending the values so it doesn't count against the read timeout,
+                // the device stays busy until it's done
+                let sync = move || after_read(&ws, &dbus, addr, history, &clocks, &settings);
+                let history_read = match call_with_timeout(low_memory, AFTER_READ_TIMEOUT, sync) {
+                    Some(history_read) => history_read,
+                    None => {
+                        tracing::warn!("Timed out syncing {} after reading it", addr);
+                        false
+                    }
+                };
+                let _ = done_tx.send((addr, history_read));
+            });
+        }
+
+        let mut state = BTreeMap::new();
+        let read_deadline = Instant::now() + timeouts.read;
+        while outstanding > 0 {
+            let (generation, addr, values, elapsed) =
+                match self.read_rx.recv_deadline(read_deadline) {
+                    Ok(read) => read,
+                    Err(_) => break,
+                };
+            metrics.device_read.observe(elapsed);
+            // a read that outlived its poll, the device got marked as timed out back then
+            if generation != self.poll_generation {
+                tracing::debug!("Dropping late values of {}", addr);
+                continue;
+            }
+            outstanding -= 1;
+            match values {
+                Ok(values) => {
+                    state.insert(addr, SensorState::Connected(values));
+                }
+                // a station with odd values or a broken link doesn't stop the others from
+                // being read, it gets connected again
+                Err(e) if !e.fatal() => {
+                    tracing::warn!("Could not read {}: {}", addr, e);
+                    self.adapter.failed(&self.name, &e);
+                    self.connected_devices.remove(&addr);
+                    state.insert(addr, SensorState::Unconnected);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        // devices that didn't answer in time get reconnected once BlueZ resolves them again
+        let timed_out = self
+            .connected_devices
+            .keys()
+            .filter(|addr| self.reading.contains(*addr) && !state.contains_key(*addr))
+            .copied()
+            .collect::<Vec<_>>();
+        for addr in timed_out {
+            tracing::warn!("Timed out reading {}, marking it as unconnected", addr);
+            metrics.read_timeouts.inc();
+            self.adapter.failed(
+                &self.name,
+                &Error::Timeout {
+                    addr,
+                    action: "reading",
+                },
+            );
+            self.connected_devices.remove(&addr);
+            state.insert(addr, SensorState::Unconnected);
+        }
+        let connected =
+            self.adapter
+                .polled(&self.name, self.connected_devices.len(), started.elapsed());
+        metrics.connected_devices.set(connected as u64);
+
+        Ok((state, sleep_time))
+    }
+
+    fn shutdown(&mut self) -> Result<(), Error> {
+        // TODO: parallelize this, takes about 2 seconds per device
+        for (addr, ws) in std::mem::take(&mut self.connected_devices) {
+            tracing::info!("Disconnecting {}", addr);
+            let disconnected =
+                ws.disconnect_with_timeout(&self.dbus, self.timeouts.disconnect, self.low_memory);
+            match disconnected {
+                Some(res) => res?,
+                None => tracing::warn!("Timed out disconnecting {}", addr),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Objects of the connected device `addr` keyed by their path, the device itself first
+fn device_objects(
+    dbus: &zbus::Connection,
+    addr: BluetoothAddress,
+) -> Result<BTreeMap<String, Interfaces>, Error> {
+    let objs = ObjectManagerProxy::new_for(dbus, "org.bluez", "/")?
+        .get_managed_objects()?
+        .into_iter()
+        .map(|(k, v)| (k.as_str().to_string(), v))
+        .collect::<BTreeMap<_, _>>();
+    let device = objs
+        .iter()
+        .find(|(_, interfaces)| {
+            let device = match interfaces.get("org.bluez.Device1") {
+                Some(device) => device,
+                None => return false,
+            };
+            let address = device
+                .get("Address")
+                .and_then(|address| address.downcast_ref::<zvariant::Str>())
+                .and_then(|address| BluetoothAddress::parse_str(address.as_str()).ok());
+            let connected = device
+                .get("Connected")
+                .and_then(|connected| connected.downcast_ref::<bool>());
+            address == Some(addr) && connected == Some(&true)
+        })
+        .map(|(path, _)| path.clone())
+        .ok_or(Error::DeviceGone { addr })?;
+    let prefix = format!("{}/", device);
+    Ok(objs
+        .into_iter()
+        .filter(|(path, _)| *path == device || path.starts_with(&prefix))
+        .collect())
+}
+
+/// Object path of the characteristic `uuid` of the connected device `addr`
+fn gatt_path(
+    dbus: &zbus::Connection,
+    addr: BluetoothAddress,
+    uuid: &str,
+) -> Result<OwnedObjectPath, Error> {
+    let objs = device_objects(dbus, addr)?;
+    characteristics(&objs)
+        .into_iter()
+        .next()
+        .and_then(|(_, mut characteristics)| characteristics.remove(uuid))
+        .ok_or_else(|| Error::missing(uuid).at(addr))
+}
+
+pub(super) fn gatt_characteristics(
+    addr: BluetoothAddress,
+) -> Result<Vec<super::gatt::GattCharacteristic>, Error> {
+    let dbus = zbus::Connection::new_system()?;
+    let objs = device_objects(&dbus, addr)?;
+    let string = |interfaces: &HashMap<String, OwnedValue>, name| {
+        interfaces
+            .get(name)
+            .and_then(|value| value.downcast_ref::<zvariant::Str>())
+            .map(|value| value.as_str().to_owned())
+    };
+    let services = objs
+        .iter()
+        .filter_map(|(path, interfaces)| {
+            let service = interfaces.get("org.bluez.GattService1")?;
+            Some((path.as_str(), string(service, "UUID")?))
+        })
+        .collect::<BTreeMap<_, _>>();
+    Ok(objs
+        .iter()
+        .filter_map(|(path, interfaces)| {
+            let characteristic = interfaces.get("org.bluez.GattCharacteristic1")?;
+            let service = path.rsplitn(2, '/').nth(1)?;
+            let flags = characteristic
+                .get("Flags")
+                .and_then(|flags| flags.downcast_ref::<Array>())
+                .map(|flags| {
+                    flags
+                        .get()
+                        .iter()
+                        .filter_map(|flag| match flag {
+                            zvariant::Value::Str(flag) => Some(flag.as_str().to_owned()),
+                            _ => None,
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            Some(super::gatt::GattCharacteristic {
+                service: services.get(service)?.clone(),
+                uuid: string(characteristic, "UUID")?,
+                flags,
+            })
+        })
+        .collect())
+}
+
+pub(super) fn gatt_read(addr: BluetoothAddress, uuid: &str) -> Result<Vec<u8>, Error> {
+    let dbus = zbus::Connection::new_system()?;
+    let path = gatt_path(&dbus, addr, uuid)?;
+    Ok(Weatherstation::read(&dbus, &path)?)
+}
+
+pub(super) fn gatt_write(addr: BluetoothAddress, uuid: &str, value: &[u8]) -> Result<(), Error> {
+    let dbus = zbus::Connection::new_system()?;
+    let path = gatt_path(&dbus, addr, uuid)?;
+    GattCharacteristic1Proxy::new_for(&dbus, "org.bluez", &path)?
+        .write_value(value, HashMap::new())?;
+    Ok(())
+}
+
+/// Whether the system bus is reachable and BlueZ is on it
+pub(super) fn probe() -> Result<(), Error> {
+    let dbus = zbus::Connection::new_system().map_err(|e| Error::BusUnreachable {
+        address: crate::dbus::system_bus(),
+        source: Box::new(e),
+    })?;
+    if zbus::fdo::DBusProxy::new(&dbus)?.name_has_owner("org.bluez")? {
+        Ok(())
+    } else {
+        Err(Error::BluezMissing)
+    }
+}
+
+/// Names of the adapters BlueZ knows, like `hci0`
+pub(super) fn adapters() -> Result<Vec<String>, Error> {
+    let dbus = zbus::Connection::new_system()?;
+    let objs = ObjectManagerProxy::new_for(&dbus, "org.bluez", "/")?.get_managed_objects()?;
+    Ok(objs
+        .into_iter()
+        .filter(|(_, interfaces)| interfaces.contains_key("org.bluez.Adapter1"))
+        .filter_map(|(path, _)| path.as_str().strip_prefix("/org/bluez/").map(str::to_owned))
+        .collect())
+}
+
+#[derive(Debug)]
+enum BluezObject<'a> {
+    Interface {
+        discovering: bool,
+        powered: bool,
+        interface: &'a str,
+    },
+
+    WeatherstationDevice {
+        address: BluetoothAddress,
+        connected: bool,
+        services_resolved: bool,
+        paired: bool,
+        /// only while discovery sees it advertising
+        rssi: Option<i16>,
+        /// percentage BlueZ read from the battery service, if the station has one
+        battery: Option<u8>,
+        profile: Arc<Profile>,
+    },
+}
+
+type Interfaces = HashMap<String, HashMap<String, OwnedValue>>;
+
+/// Object paths of the gatt characteristics of every device by their uuid, BlueZ numbers them
+/// by their handles which differ between firmware versions
+fn characteristics(
+    objs: &BTreeMap<String, Interfaces>,
+) -> BTreeMap<String, BTreeMap<String, OwnedObjectPath>> {
+    let mut devices = BTreeMap::<_, BTreeMap<_, _>>::new();
+    for (object_path, interfaces) in objs {
+        let path = match object_path.strip_prefix("/org/bluez/") {
+            Some(path) => path.split('/').collect::<Vec<_>>(),
+            None => continue,
+        };
+        let (interface, device) = match path.as_slice() {
+            [interface, device, _service, _characteristic] => (interface, device),
+            _ => continue,
+        };
+        let uuid = interfaces
+            .get("org.bluez.GattCharacteristic1")
+            .and_then(|characteristic| characteristic.get("UUID"))
+            .and_then(|uuid| uuid.downcast_ref::<zvariant::Str>());
+        let path = ObjectPath::try_from(object_path.as_str()).map(OwnedObjectPath::from);
+        if let (Some(uuid), Ok(path)) = (uuid, path) {
+            devices
+                .entry(format!("/org/bluez/{}/{}", interface, device))
+                .or_default()
+                .insert(uuid.as_str().to_owned(), path);
+        }
+    }
+    devices
+}
+
+/// Tells `presence` about the beacons it tracks that BlueZ currently sees
+fn report_beacons(objs: &BTreeMap<String, Interfaces>, presence: &Presence, now: Timestamp) {
+    for interfaces in objs.values() {
+        let device = match interfaces.get("org.bluez.Device1") {
+            Some(device) => device,
+            None => continue,
+        };
+        let addr = device
+            .get("Address")
+            .and_then(|addr| addr.downcast_ref::<zvariant::Str>())
+            .and_then(|addr| BluetoothAddress::parse_str(addr.as_str()).ok());
+        let addr = match addr {
+            Some(addr) if presence.tracks(addr) => addr,
+            _ => continue,
+        };
+        // BlueZ only has an rssi for devices the running discovery saw advertising
+        match device
+            .get("RSSI")
+            .and_then(|rssi| rssi.downcast_ref::<i16>())
+        {
+            Some(rssi) => presence.seen(addr, Some(*rssi), now),
+            // connected devices like phones stop advertising
+            None if device
+                .get("Connected")
+                .and_then(|connected| connected.downcast_ref::<bool>())
+                == Some(&true) =>
+            {
+                presence.seen(addr, None, now)
+            }
+            None => {}
+        }
+    }
+}
+
+/// What the central makes of an object, or why it ignores it
+fn interpret_object<'a>(
+    object_path: &'a str,
+    interfaces: Interfaces,
+    profiles: &Profiles,
+) -> Result<BluezObject<'a>, &'static str> {
+    let path = object_path
+        .strip_prefix("/org/bluez/")
+        .ok_or("not a bluetooth object")?
+        .split('/')
+        .collect::<Vec<_>>();
+    let incomplete = "BlueZ didn't fill in all its properties yet";
+
+    match path.as_slice() {
+        [_interface, _device] => {
+            let bluez_device = interfaces.get("org.bluez.Device1").ok_or("not a device")?;
+            let uuid_array = bluez_device
+                .get("UUIDs")
+                .and_then(|uuids| uuids.downcast_ref::<Array>())
+                .ok_or("advertises no services")?;
+
+            let services = uuid_array
+                .get()
+                .iter()
+                .filter_map(|uuid| match uuid {
+                    zvariant::Value::Str(s) => Some(s.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            let profile = profiles
+                .detect(&services)
+                .ok_or("no profile matches its services")?;
+            let flag = |name| {
+                bluez_device
+                    .get(name)
+                    .and_then(|flag| flag.downcast_ref::<bool>())
+                    .copied()
+                    .ok_or(incomplete)
+            };
+            let connected = flag("Connected")?;
+            let address = bluez_device
+                .get("Address")
+                .and_then(|address| address.downcast_ref::<zvariant::Str>())
+                .ok_or(incomplete)?;
+            let services_resolved = flag("ServicesResolved")?;
+            let paired = flag("Paired")?;
+            let rssi = bluez_device
+                .get("RSSI")
+                .and_then(|rssi| rssi.downcast_ref::<i16>())
+                .copied();
+            let battery = interfaces
+                .get("org.bluez.Battery1")
+                .and_then(|battery| battery.get("Percentage"))
+                .and_then(|percentage| percentage.downcast_ref::<u8>())
+                .copied();
+
+            Ok(BluezObject::WeatherstationDevice {
+                connected,
+                address: BluetoothAddress::parse_str(address.as_str())
+                    .map_err(|_| "its address is malformed")?,
+                services_resolved,
+                paired,
+                rssi,
+                battery,
+                profile,
+            })
+        }
+
+        [interface] => {
+            let bluez_adapter = interfaces
+                .get("org.bluez.Adapter1")
+                .ok_or("not an adapter")?;
+            let flag = |name| {
+                bluez_adapter
+                    .get(name)
+                    .and_then(|flag| flag.downcast_ref::<bool>())
+                    .copied()
+                    .ok_or(incomplete)
+            };
+            Ok(BluezObject::Interface {
+                discovering: flag("Discovering")?,
+                powered: flag("Powered")?,
+                interface,
+            })
+        }
+        _ => Err("a gatt service or characteristic"),
+    }
+}
+
+/// Adapters and devices BlueZ knows and what the central makes of them
+pub(super) fn dump(profiles: &Profiles) -> Result<Vec<super::BluezDump>, Error> {
+    let dbus = zbus::Connection::new_system()?;
+    let objs = ObjectManagerProxy::new_for(&dbus, "org.bluez", "/")?.get_managed_objects()?;
+    Ok(objs
+        .into_iter()
+        // services and characteristics would drown out the devices
+        .filter(|(path, _)| path.as_str().matches('/').count() <= 4)
+        .map(|(path, interfaces)| {
+            let path = path.as_str().to_owned();
+            let device = interfaces.get("org.bluez.Device1");
+            let property = |name| device.and_then(|device| device.get(name));
+            let string = |name| {
+                property(name)
+                    .and_then(|value| value.downcast_ref::<zvariant::Str>())
+                    .map(|value| value.as_str().to_owned())
+            };
+            let services = property("UUIDs")
+                .and_then(|uuids| uuids.downcast_ref::<Array>())
+                .map(|uuids| {
+                    uuids
+                        .get()
+                        .iter()
+                        .filter_map(|uuid| match uuid {
+                            zvariant::Value::Str(uuid) => Some(uuid.as_str().to_owned()),
+                            _ => None,
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            let (address, name) = (string("Address"), string("Alias"));
+            let connected = property("Connected")
+                .and_then(|connected| connected.downcast_ref::<bool>())
+                .copied();
+            let rssi = property("RSSI")
+                .and_then(|rssi| rssi.downcast_ref::<i16>())
+                .copied();
+            let (kind, skipped) = match interpret_object(&path, interfaces, profiles) {
+                Ok(BluezObject::Interface { .. }) => (Some(String::from("adapter")), None),
+                Ok(BluezObject::WeatherstationDevice { profile, .. }) => {
+                    (Some(profile.name.clone()), None)
+                }
+                Err(reason) => (None, Some(reason)),
+            };
+            super::BluezDump {
+                path,
+                address,
+                name,
+                services,
+                connected,
+                rssi,
+                kind,
+                skipped,
+            }
+        })
+        .collect())
+}
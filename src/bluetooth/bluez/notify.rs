@@ -0,0 +1,102 @@
+use super::{dbus_interfaces::GattCharacteristic1Proxy, BluetoothAddress, Error};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Read},
+    os::unix::{io::FromRawFd, net::UnixStream},
+    sync::{Arc, Mutex},
+};
+use tokio::io::unix::AsyncFd;
+use zvariant::OwnedObjectPath;
+
+type Latest = Mutex<BTreeMap<(BluetoothAddress, String), Vec<u8>>>;
+
+/// Values characteristics pushed over the sockets BlueZ hands out with `AcquireNotify`, they
+/// replace a dbus call per characteristic and poll with one read of a socket per notification
+pub(super) struct Notifications {
+    runtime: tokio::runtime::Handle,
+    /// by device and characteristic uuid, entries go away with the socket
+    latest: Arc<Latest>,
+}
+
+impl Notifications {
+    pub(super) fn new(runtime: tokio::runtime::Handle) -> Self {
+        Self {
+            runtime,
+            latest: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Latest value of `uuid` the device `addr` notified about, None before the first one or
+    /// once it stopped notifying
+    pub(super) fn latest(&self, addr: BluetoothAddress, uuid: &str) -> Option<Vec<u8>> {
+        self.latest
+            .lock()
+            .unwrap()
+            .get(&(addr, uuid.to_owned()))
+            .cloned()
+    }
+
+    /// Starts receiving notifications of the characteristic at `path`, false if it doesn't
+    /// notify or somebody else already acquired it
+    pub(super) fn subscribe(
+        &self,
+        dbus: &zbus::Connection,
+        addr: BluetoothAddress,
+        uuid: &str,
+        path: &OwnedObjectPath,
+    ) -> Result<bool, Error> {
+        let characteristic = GattCharacteristic1Proxy::new_for(dbus, "org.bluez", path)?;
+        let (fd, mtu) = match characteristic.acquire_notify(HashMap::new()) {
+            Ok(acquired) => acquired,
+            Err(zbus::Error::MethodError(_, _, _)) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let failed = |e: io::Error| Error::Dbus {
+            addr: Some(addr),
+            source: Box::new(e),
+        };
+        // the fd belongs to the reply and gets closed with it
+        let fd =
+            nix::unistd::dup(fd).map_err(|e| failed(io::Error::new(io::ErrorKind::Other, e)))?;
+        // it's a seqpacket socket, every read is one notification
+        let socket = unsafe { UnixStream::from_raw_fd(fd) };
+        socket.set_nonblocking(true).map_err(failed)?;
+
+        let key = (addr, uuid.to_owned());
+        let latest = self.latest.clone();
+        let _enter = self.runtime.enter();
+        let socket = AsyncFd::new(socket).map_err(failed)?;
+        self.runtime.spawn(async move {
+            if let Err(e) = receive(&socket, usize::from(mtu), &key, &latest).await {
+                tracing::debug!("Notifications of {} {} stopped: {}", key.0, key.1, e);
+            }
+            latest.lock().unwrap().remove(&key);
+        });
+        Ok(true)
+    }
+}
+
+/// Stores every notification of `socket` as the latest value of `key` until BlueZ closes it
+async fn receive(
+    socket: &AsyncFd<UnixStream>,
+    mtu: usize,
+    key: &(BluetoothAddress, String),
+    latest: &Latest,
+) -> Result<(), io::Error> {
+    let mut buf = vec![0; mtu];
+    loop {
+        let mut guard = socket.readable().await?;
+        match guard.try_io(|socket| (&*socket.get_ref()).read(&mut buf)) {
+            // the device disconnected or stopped notifying
+            Ok(Ok(0)) => return Ok(()),
+            Ok(Ok(len)) => {
+                latest
+                    .lock()
+                    .unwrap()
+                    .insert(key.clone(), buf[..len].to_vec());
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(_would_block) => continue,
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use super::{
+    profile::{Profile, Profiles, Reads},
+    BackendContext, BluetoothAddress, BluetoothBackend, ConnectionParams, Error, History,
+    StationSettings, Timeouts, CONNECTION_PARAMETERS_CHARACTERISTIC,
+    HISTORY_CONTROL_CHARACTERISTIC, HISTORY_RECORDS_CHARACTERISTIC, HISTORY_START,
+    MAX_HISTORY_RECORDS, MEASUREMENT_INTERVAL_CHARACTERISTIC, SET_CLOCK_CHARACTERISTIC,
+};
+use crate::{
+    clock::DeviceClocks,
+    metrics::Metrics,
+    presence::Presence,
+    sensor::{SensorState, SensorValues},
+    timestamp::Timestamp,
+};
+use btleplug::{
+    api::{
+        Central, Characteristic, Manager as _, Peripheral as _, PeripheralProperties, WriteType,
+    },
+    platform::{Adapter, Manager, Peripheral},
+};
+use std::{
+    collections::BTreeMap,
+    num::NonZeroU16,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::time::timeout;
+
+/// Time between two polls, there's no discovery to wait for since scanning never stops
+const POLL_INTERVAL: Duration = Duration::from_secs(31);
+
+/// Time reading the whole history of a station may take
+const HISTORY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Talks to the native bluetooth stack of the platform through btleplug. Devices get read one
+/// after another so this is slower than BlueZ with many weatherstations, good enough for
+/// development.
+pub(super) struct Btleplug {
+    /// btleplug is async, this lets it run on the bluetooth thread
+    runtime: tokio::runtime::Runtime,
+    adapter: Adapter,
+    metrics: Arc<Metrics>,
+    clocks: Arc<DeviceClocks>,
+    history: flume::Sender<History>,
+    settings: Arc<StationSettings>,
+    presence: Option<Arc<Presence>>,
+    profiles: Arc<Profiles>,
+    timeouts: Timeouts,
+    connected: BTreeMap<BluetoothAddress, Peripheral>,
+}
+
+impl Btleplug {
+    pub(super) fn new(ctx: BackendContext) -> Result<Self, Error> {
+        let BackendContext {
+            metrics,
+            clocks,
+            history,
+            settings,
+            presence,
+            profiles,
+            timeouts,
+            ..
+        } = ctx;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(Error::Thread)?;
+        let adapter = runtime.block_on(async {
+            let adapter = Manager::new()
+                .await?
+                .adapters()
+                .await?
+                .into_iter()
+                .next()
+                .ok_or(Error::AdapterMissing)?;
+            adapter.start_scan().await?;
+            Ok::<_, Error>(adapter)
+        })?;
+        tracing::info!("Started scanning with btleplug");
+
+        Ok(Self {
+            runtime,
+            adapter,
+            metrics,
+            clocks,
+            history,
+            settings,
+            presence,
+            profiles,
+            timeouts,
+            connected: BTreeMap::new(),
+        })
+    }
+}
+
+/// Profile of a device advertising `services`, None if it's none of ours
+fn detect(profiles: &Profiles, services: &[btleplug::Uuid]) -> Option<Arc<Profile>> {
+    let services = services.iter().map(ToString::to_string).collect::<Vec<_>>();
+    profiles.detect(&services.iter().map(String::as_str).collect::<Vec<_>>())
+}
+
+async fn read(
+    peripheral: &Peripheral,
+    characteristics: &[Characteristic],
+    uuid: &str,
+) -> Result<Vec<u8>, Error> {
+    let characteristic = characteristics
+        .iter()
+        .find(|characteristic| characteristic.uuid.to_string() == uuid)
+        .ok_or_else(|| Error::missing(uuid))?;
+    Ok(peripheral.read(characteristic).await?)
+}
+
+/// The values and, if the firmware has it, the measurement timestamp of `peripheral`
+async fn read_values(
+    peripheral: &Peripheral,
+    profile: &Profile,
+) -> Result<(SensorValues, Option<Timestamp>), Error> {
+    let characteristics = peripheral.discover_characteristics().await?;
+    let mut reads = Reads::new();
+    for uuid in profile.characteristics() {
+        reads.insert(
+            uuid.to_owned(),
+            read(peripheral, &characteristics, uuid).await?,
+        );
+    }
+    if let Some(uuid) = profile.timestamp() {
+        if let Ok(raw) = read(peripheral, &characteristics, uuid).await {
+            reads.insert(uuid.to_owned(), raw);
+        }
+    }
+    profile.decode(&reads)
+}
+
+/// Everything the station buffered while it wasn't connected, nothing for firmware without a
+/// history
+async fn read_history(peripheral: &Peripheral) -> Result<Vec<(Timestamp, SensorValues)>, Error> {
+    let characteristics = peripheral.discover_characteristics().await?;
+    let control = match characteristics
+        .iter()
+        .find(|characteristic| characteristic.uuid.to_string() == HISTORY_CONTROL_CHARACTERISTIC)
+    {
+        Some(control) => control,
+        None => return Ok(Vec::new()),
+    };
+    peripheral
+        .write(control, &[HISTORY_START], WriteType::WithResponse)
+        .await?;
+
+    let mut records = Vec::new();
+    while records.len() < MAX_HISTORY_RECORDS {
+        let chunk = read(peripheral, &characteristics, HISTORY_RECORDS_CHARACTERISTIC).await?;
+        if chunk.is_empty() {
+            break;
+        }
+        records.extend(super::decode_history(&chunk)?);
+    }
+    Ok(records)
+}
+
+/// False if the characteristic doesn't exist
+async fn write(peripheral: &Peripheral, uuid: &str, value: &[u8]) -> Result<bool, Error> {
+    let characteristics = peripheral.discover_characteristics().await?;
+    match characteristics
+        .iter()
+        .find(|characteristic| characteristic.uuid.to_string() == uuid)
+    {
+        Some(characteristic) => {
+            peripheral
+                .write(characteristic, value, WriteType::WithResponse)
+                .await?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Sets the clock of the station to `now`, false for firmware without a settable clock
+async fn set_clock(peripheral: &Peripheral, now: Timestamp) -> Result<bool, Error> {
+    write(
+        peripheral,
+        SET_CLOCK_CHARACTERISTIC,
+        &now.as_u32().to_le_bytes(),
+    )
+    .await
+}
+
+/// False for firmware without a measurement interval
+async fn set_measurement_interval(
+    peripheral: &Peripheral,
+    interval: NonZeroU16,
+) -> Result<bool, Error> {
+    write(
+        peripheral,
+        MEASUREMENT_INTERVAL_CHARACTERISTIC,
+        &interval.get().to_le_bytes(),
+    )
+    .await
+}
+
+/// False for firmware without connection parameters
+async fn set_connection_parameters(
+    peripheral: &Peripheral,
+    params: ConnectionParams,
+) -> Result<bool, Error> {
+    write(
+        peripheral,
+        CONNECTION_PARAMETERS_CHARACTERISTIC,
+        &params.encode(),
+    )
+    .await
+}
+
+/// CoreBluetooth hides the MAC of devices, the address btleplug reports there is made up from
+/// the peripheral identifier macOS assigns instead, which stays the same for a device on one host
+fn device_addr(
+    peripheral: &Peripheral,
+    properties: &PeripheralProperties,
+) -> Result<BluetoothAddress, Error> {
+    let addr = if cfg!(target_os = "macos") {
+        peripheral.address()
+    } else {
+        properties.address
+    };
+    BluetoothAddress::parse_str(&addr.to_string()).map_err(|e| Error::parse(e.to_string()))
+}
+
+impl BluetoothBackend for Btleplug {
+    fn poll(&mut self) -> Result<(BTreeMap<BluetoothAddress, SensorState>, Duration), Error> {
+        let Self {
+            runtime,
+            adapter,
+            metrics,
+            clocks,
+            history,
+            settings,
+            presence,
+            profiles,
+            timeouts,
+            connected,
+        } = self;
+        let metrics = &metrics.bluetooth;
+        runtime.block_on(async {
+            let mut state = BTreeMap::new();
+            for peripheral in adapter.peripherals().await? {
+                // one broken device mustn't hide all the others
+                let properties = match peripheral.properties().await {
+                    Ok(Some(properties)) => properties,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        tracing::warn!("Could not get properties of a device: {}", e);
+                        continue;
+                    }
+                };
+                let addr = match device_addr(&peripheral, &properties) {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        tracing::warn!("Skipping device with unusable address: {}", e);
+                        continue;
+                    }
+                };
+                if let Some(ref presence) = presence {
+                    if presence.tracks(addr) && properties.rssi.is_some() {
+                        presence.seen(addr, properties.rssi, Timestamp::now());
+                    }
+                }
+                let profile = match detect(profiles, &properties.services) {
+                    Some(profile) => profile,
+                    None => continue,
+                };
+
+                if !peripheral.is_connected().await? {
+                    match timeout(timeouts.connect, peripheral.connect()).await {
+                        Ok(Ok(())) => {
+                            tracing::info!("Connected new {} device {}", profile.name, addr)
+                        }
+                        Ok(Err(e)) => {
+                            metrics.connect_failures.inc();
+                            tracing::warn!("Could not connect to {}: {}", addr, e);
+                            continue;
+                        }
+                        Err(_) => {
+                            metrics.connect_timeouts.inc();
+                            tracing::warn!("Timed out connecting to {}", addr);
+                            continue;
+                        }
+                    }
+                }
+
+                let read_started = Instant::now();
+                match timeout(timeouts.read, read_values(&peripheral, &profile)).await {
+                    Ok(Ok((values, timestamp))) => {
+                        metrics.device_read.observe(read_started.elapsed());
+                        if let Some(device) = timestamp {
+                            clocks.observe(addr, device, Timestamp::now());
+                        }
+                        state.insert(addr, SensorState::Connected(values));
+
+                        // catches up on what happened while the central didn't listen
+                        if !connected.contains_key(&addr) {
+                            clocks.reconnected(addr);
+                            settings.reconnected(addr);
+                            match timeout(HISTORY_TIMEOUT, read_history(&peripheral)).await {
+                                Ok(Ok(records)) => {
+                                    super::send_history(addr, records, clocks, history)
+                                }
+                                Ok(Err(e)) => {
+                                    tracing::warn!("Could not read history of {}: {}", addr, e)
+                                }
+                                Err(_) => tracing::warn!("Timed out reading history of {}", addr),
+                            }
+                        }
+                        if clocks.sync_due(addr) {
+                            let now = Timestamp::now();
+                            match timeout(timeouts.read, set_clock(&peripheral, now)).await {
+                                Ok(Ok(true)) => clocks.synced(addr, now),
+                                Ok(Ok(false)) => clocks.sync_attempted(addr),
+                                Ok(Err(e)) => {
+                                    tracing::warn!("Could not set clock of {}: {}", addr, e)
+                                }
+                                Err(_) => tracing::warn!("Timed out setting clock of {}", addr),
+                            }
+                        }
+                        if let Some(interval) = settings.interval_due(addr) {
+                            let set = set_measurement_interval(&peripheral, interval);
+                            match timeout(timeouts.read, set).await {
+                                Ok(Ok(true)) => settings.interval_written(addr, interval),
+                                Ok(Ok(false)) => settings.interval_unsupported(addr),
+                                Ok(Err(e)) => tracing::warn!(
+                                    "Could not set measurement interval of {}: {}",
+                                    addr,
+                                    e
+                                ),
+                                Err(_) => tracing::warn!(
+                                    "Timed out setting measurement interval of {}",
+                                    addr
+                                ),
+                            }
+                        }
+                        if let Some(params) = settings.connection_due(addr) {
+                            let set = set_connection_parameters(&peripheral, params);
+                            match timeout(timeouts.read, set).await {
+                                Ok(Ok(true)) => settings.connection_written(addr),
+                                Ok(Ok(false)) => settings.connection_unsupported(addr),
+                                Ok(Err(e)) => tracing::warn!(
+                                    "Could not set connection parameters of {}: {}",
+                                    addr,
+                                    e
+                                ),
+                                Err(_) => tracing::warn!(
+                                    "Timed out setting connection parameters of {}",
+                                    addr
+                                ),
+                            }
+                        }
+                        connected.insert(addr, peripheral);
+                    }
+                    Ok(Err(e)) => {
+                        tracing::warn!("Could not read {}: {}", addr, e);
+                        connected.remove(&addr);
+                        state.insert(addr, SensorState::Unconnected);
+                    }
+                    Err(_) => {
+                        tracing::warn!("Timed out reading {}, marking it as unconnected", addr);
+                        metrics.read_timeouts.inc();
+                        connected.remove(&addr);
+                        state.insert(addr, SensorState::Unconnected);
+                    }
+                }
+            }
+            metrics.connected_devices.set(connected.len() as u64);
+
+            Ok((state, POLL_INTERVAL))
+        })
+    }
+
+    fn shutdown(&mut self) -> Result<(), Error> {
+        let disconnect = self.timeouts.disconnect;
+        let connected = std::mem::take(&mut self.connected);
+        self.runtime.block_on(async {
+            for (addr, peripheral) in connected {
+                tracing::info!("Disconnecting {}", addr);
+                match timeout(disconnect, peripheral.disconnect()).await {
+                    Ok(res) => res?,
+                    Err(_) => tracing::warn!("Timed out disconnecting {}", addr),
+                }
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,191 @@
+use super::BluetoothAddress;
+
+/// `on AA:BB:..` for errors that belong to a device
+struct On(Option<BluetoothAddress>);
+
+impl std::fmt::Display for On {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.0 {
+            Some(addr) => write!(f, " on {}", addr),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum Error {
+    #[error("Dbus call failed{}", On(*addr))]
+    Dbus {
+        addr: Option<BluetoothAddress>,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[cfg(feature = "btleplug")]
+    #[error("btleplug call failed{}", On(*addr))]
+    Btleplug {
+        addr: Option<BluetoothAddress>,
+        #[source]
+        source: btleplug::Error,
+    },
+
+    #[error("Undecodable value{}: {reason}", On(*addr))]
+    Parse {
+        addr: Option<BluetoothAddress>,
+        reason: String,
+    },
+
+    #[error("Timed out {action} {addr}")]
+    Timeout {
+        addr: BluetoothAddress,
+        action: &'static str,
+    },
+
+    #[error("{addr} isn't connected anymore")]
+    DeviceGone { addr: BluetoothAddress },
+
+    #[error("Missing characteristic {uuid}{}", On(*addr))]
+    MissingCharacteristic {
+        addr: Option<BluetoothAddress>,
+        uuid: String,
+    },
+
+    #[error("No bluetooth adapter found")]
+    AdapterMissing,
+
+    #[error(
+        "Could not connect to the system bus at {address}, in a container mount /var/run/dbus \
+         or set dbus_system_bus"
+    )]
+    BusUnreachable {
+        address: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[error("BlueZ isn't on the system bus, is bluetoothd running on the host?")]
+    BluezMissing,
+
+    #[error("Could not start a bluetooth thread")]
+    Thread(#[source] std::io::Error),
+
+    #[error("{0}")]
+    Unsupported(&'static str),
+}
+
+impl Error {
+    pub(crate) fn parse(reason: impl Into<String>) -> Self {
+        Error::Parse {
+            addr: None,
+            reason: reason.into(),
+        }
+    }
+
+    pub(crate) fn missing(uuid: &str) -> Self {
+        Error::MissingCharacteristic {
+            addr: None,
+            uuid: uuid.to_owned(),
+        }
+    }
+
+    /// Attributes an error that happened while talking to `addr` to it
+    pub(crate) fn at(mut self, device: BluetoothAddress) -> Self {
+        match self {
+            Error::Dbus { ref mut addr, .. }
+            | Error::Parse { ref mut addr, .. }
+            | Error::MissingCharacteristic { ref mut addr, .. } => {
+                addr.get_or_insert(device);
+            }
+            #[cfg(feature = "btleplug")]
+            Error::Btleplug { ref mut addr, .. } => {
+                addr.get_or_insert(device);
+            }
+            _ => {}
+        }
+        self
+    }
+
+    /// Errors of the bluetooth stack or the adapter stop the bluetooth thread, those of a single
+    /// device only make it skip that device
+    pub(crate) fn fatal(&self) -> bool {
+        match self {
+            Error::Dbus { addr, .. } => addr.is_none(),
+            #[cfg(feature = "btleplug")]
+            Error::Btleplug { addr, .. } => addr.is_none(),
+            Error::AdapterMissing
+            | Error::BusUnreachable { .. }
+            | Error::BluezMissing
+            | Error::Thread(_)
+            | Error::Unsupported(_) => true,
+            Error::Parse { .. }
+            | Error::Timeout { .. }
+            | Error::DeviceGone { .. }
+            | Error::MissingCharacteristic { .. } => false,
+        }
+    }
+
+    /// For counting errors by what went wrong
+    pub(crate) fn category(&self) -> &'static str {
+        match self {
+            Error::Dbus { .. } => "dbus",
+            #[cfg(feature = "btleplug")]
+            Error::Btleplug { .. } => "btleplug",
+            Error::Parse { .. } => "parse",
+            Error::Timeout { .. } => "timeout",
+            Error::DeviceGone { .. } => "device_gone",
+            Error::MissingCharacteristic { .. } => "missing_characteristic",
+            Error::AdapterMissing => "adapter_missing",
+            Error::BusUnreachable { .. } => "bus_unreachable",
+            Error::BluezMissing => "bluez_missing",
+            Error::Thread(_) => "thread",
+            Error::Unsupported(_) => "unsupported",
+        }
+    }
+}
+
+#[cfg(unix)]
+impl From<zbus::Error> for Error {
+    fn from(e: zbus::Error) -> Self {
+        Error::Dbus {
+            addr: None,
+            source: Box::new(e),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl From<zbus::fdo::Error> for Error {
+    fn from(e: zbus::fdo::Error) -> Self {
+        Error::Dbus {
+            addr: None,
+            source: Box::new(e),
+        }
+    }
+}
+
+#[cfg(feature = "btleplug")]
+impl From<btleplug::Error> for Error {
+    fn from(e: btleplug::Error) -> Self {
+        Error::Btleplug {
+            addr: None,
+            source: e,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn device_errors_are_skipped() {
+        let addr = BluetoothAddress::from(1);
+        let parse = Error::parse("Empty measurement").at(addr);
+        assert!(!parse.fatal());
+        assert_eq!(
+            parse.to_string(),
+            "Undecodable value on 00:00:00:00:00:01: Empty measurement"
+        );
+        assert!(Error::AdapterMissing.fatal());
+    }
+}
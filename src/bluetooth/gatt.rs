@@ -0,0 +1,40 @@
+#[cfg(unix)]
+use super::bluez;
+use super::{BluetoothAddress, Error};
+
+/// A characteristic of a connected device as BlueZ resolved it
+#[derive(serde::Serialize, Debug)]
+pub(crate) struct GattCharacteristic {
+    pub(crate) service: String,
+    pub(crate) uuid: String,
+    /// like `read`, `write` or `notify`
+    pub(crate) flags: Vec<String>,
+}
+
+/// Characteristics of the connected device `addr`, for debugging new firmware
+#[cfg_attr(not(unix), allow(unused_variables))]
+pub(crate) fn characteristics(addr: BluetoothAddress) -> Result<Vec<GattCharacteristic>, Error> {
+    #[cfg(unix)]
+    return bluez::gatt_characteristics(addr);
+    #[cfg(not(unix))]
+    return Err(UNSUPPORTED);
+}
+
+#[cfg_attr(not(unix), allow(unused_variables))]
+pub(crate) fn read(addr: BluetoothAddress, uuid: &str) -> Result<Vec<u8>, Error> {
+    #[cfg(unix)]
+    return bluez::gatt_read(addr, uuid);
+    #[cfg(not(unix))]
+    return Err(UNSUPPORTED);
+}
+
+#[cfg_attr(not(unix), allow(unused_variables))]
+pub(crate) fn write(addr: BluetoothAddress, uuid: &str, value: &[u8]) -> Result<(), Error> {
+    #[cfg(unix)]
+    return bluez::gatt_write(addr, uuid, value);
+    #[cfg(not(unix))]
+    return Err(UNSUPPORTED);
+}
+
+#[cfg(not(unix))]
+const UNSUPPORTED: Error = Error::Unsupported("Accessing characteristics directly needs BlueZ");
@@ -0,0 +1,565 @@
+use super::{
+    Error, BLE_GATT_SERVICE_ENVIRONMENTAL_SENSING, BLE_GATT_SERVICE_WEATHERSTATION,
+    MEASUREMENT_TIMESTAMP_CHARACTERISTIC,
+};
+use crate::{
+    sensor::{Quantity, RawSensorValues, SensorValues},
+    timestamp::Timestamp,
+};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::{collections::BTreeMap, convert::TryFrom, sync::Arc};
+
+const TEMPERATURE_CHARACTERISTIC: &str = "00002a6e-0000-1000-8000-00805f9b34fb";
+const HUMIDITY_CHARACTERISTIC: &str = "00002a6f-0000-1000-8000-00805f9b34fb";
+const PRESSURE_CHARACTERISTIC: &str = "00002a6d-0000-1000-8000-00805f9b34fb";
+
+/// Environmental sensing service of the bluetooth spec, what off the shelf sensors advertise
+const BLE_GATT_SERVICE_ESS: &str = "0000181a-0000-1000-8000-00805f9b34fb";
+
+/// Service of the v2 firmware, it reads everything at once
+const BLE_GATT_SERVICE_WEATHERSTATION_V2: &str = "e7364be3-a1c5-4924-847d-3a9cd6e343ef";
+
+/// Measurement timestamp and values of the v2 firmware, encoded like a history record
+const MEASUREMENT_CHARACTERISTIC_V2: &str = "e7364be4-a1c5-4924-847d-3a9cd6e343ef";
+
+/// Temperature, humidity and pressure of a combined characteristic, a battery level byte may
+/// follow
+const COMBINED_SIZE: usize = 8;
+
+/// Short uuids get expanded with this, like `181a` to the environmental sensing service
+const BLUETOOTH_BASE_UUID: &str = "-0000-1000-8000-00805f9b34fb";
+
+/// A `[[profile]]` table, a device the central doesn't know and how to decode its values
+#[derive(serde::Deserialize, Clone)]
+pub(crate) struct ProfileConfig {
+    name: String,
+    /// uuids of the services the device advertises, it has to advertise all of them
+    services: Vec<String>,
+    /// one for each of temperature, humidity and pressure
+    #[serde(rename = "value", default)]
+    values: Vec<FieldConfig>,
+    /// characteristic with all values packed like the environmental sensing ones, in place of
+    /// `value` tables
+    combined: Option<String>,
+}
+
+/// A `[[profile.value]]` table
+#[derive(serde::Deserialize, Clone)]
+struct FieldConfig {
+    quantity: Quantity,
+    characteristic: String,
+    #[serde(rename = "type")]
+    data_type: DataType,
+    #[serde(default)]
+    endianness: Endianness,
+    /// raw values get multiplied with it to end up in °C, percent or Pa
+    #[serde(default = "default_scale")]
+    scale: f64,
+    /// byte the value starts at, for characteristics with more than one value
+    #[serde(default)]
+    offset: usize,
+}
+
+fn default_scale() -> f64 {
+    1.
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+enum DataType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl DataType {
+    fn size(self) -> usize {
+        match self {
+            DataType::U8 | DataType::I8 => 1,
+            DataType::U16 | DataType::I16 => 2,
+            DataType::U32 | DataType::I32 | DataType::F32 => 4,
+        }
+    }
+
+    fn read<B: ByteOrder>(self, bytes: &[u8]) -> f64 {
+        match self {
+            DataType::U8 => f64::from(bytes[0]),
+            DataType::I8 => f64::from(bytes[0] as i8),
+            DataType::U16 => f64::from(B::read_u16(bytes)),
+            DataType::I16 => f64::from(B::read_i16(bytes)),
+            DataType::U32 => f64::from(B::read_u32(bytes)),
+            DataType::I32 => f64::from(B::read_i32(bytes)),
+            DataType::F32 => f64::from(B::read_f32(bytes)),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Endianness {
+    Little,
+    Big,
+}
+
+impl Default for Endianness {
+    fn default() -> Self {
+        Endianness::Little
+    }
+}
+
+/// One value of a profile from the config file
+#[derive(Debug)]
+pub(crate) struct Field {
+    characteristic: String,
+    data_type: DataType,
+    endianness: Endianness,
+    scale: f64,
+    offset: usize,
+}
+
+impl Field {
+    /// The value in °C, percent or Pa
+    fn decode(&self, raw: &[u8]) -> Result<f64, Error> {
+        let bytes = raw
+            .get(self.offset..self.offset + self.data_type.size())
+            .ok_or_else(|| {
+                Error::parse(format!(
+                    "Characteristic {} has {} bytes, too few for a {:?} at {}",
+                    self.characteristic,
+                    raw.len(),
+                    self.data_type,
+                    self.offset
+                ))
+            })?;
+        let value = match self.endianness {
+            Endianness::Little => self.data_type.read::<LittleEndian>(bytes),
+            Endianness::Big => self.data_type.read::<BigEndian>(bytes),
+        };
+        Ok(value * self.scale)
+    }
+}
+
+/// `value` in units of `1 / per_unit` as stored in [`RawSensorValues`]
+fn to_raw<T: TryFrom<i64>>(value: f64, per_unit: f64, quantity: Quantity) -> Result<T, Error> {
+    let raw = (value * per_unit).round();
+    if raw.is_finite() {
+        if let Ok(raw) = T::try_from(raw as i64) {
+            return Ok(raw);
+        }
+    }
+    Err(Error::parse(format!(
+        "Decoded {:?} {} is out of range",
+        quantity, value
+    )))
+}
+
+/// Lowercase and with short uuids like `181a` expanded
+fn normalize_uuid(uuid: &str) -> Result<String, eyre::Error> {
+    let uuid = uuid.to_lowercase();
+    let hex = |s: &str| s.chars().all(|c| c.is_ascii_hexdigit());
+    let groups = uuid.split('-').map(str::len).collect::<Vec<_>>();
+    if uuid.len() == 4 && hex(&uuid) {
+        Ok(format!("0000{}{}", uuid, BLUETOOTH_BASE_UUID))
+    } else if groups == [8, 4, 4, 4, 12] && hex(&uuid.replace('-', "")) {
+        Ok(uuid)
+    } else {
+        Err(eyre::format_err!("Invalid uuid {}", uuid))
+    }
+}
+
+/// Raw values of one poll of a device by the uuids of their characteristics
+pub(crate) type Reads = BTreeMap<String, Vec<u8>>;
+
+/// Where the values of a device are and how they're encoded
+#[derive(Debug)]
+pub(crate) enum Values {
+    /// one characteristic per value, encoded like the ones of the environmental sensing service
+    Separate {
+        temperature: String,
+        humidity: String,
+        pressure: String,
+    },
+    /// measurement timestamp and values in one characteristic, encoded like a history record
+    Record(String),
+    /// temperature, humidity and pressure in one characteristic without a timestamp
+    Combined(String),
+    /// defined in the config file
+    Custom {
+        temperature: Field,
+        humidity: Field,
+        pressure: Field,
+    },
+}
+
+/// A kind of device the central can read
+#[derive(Debug)]
+pub(crate) struct Profile {
+    pub(crate) name: String,
+    /// a device has to advertise all of them
+    services: Vec<String>,
+    values: Values,
+    /// has the clock, history and settings characteristics of the weatherstation firmware
+    station: bool,
+}
+
+impl Profile {
+    pub(crate) fn from_config(config: ProfileConfig) -> Result<Self, eyre::Error> {
+        let services = config
+            .services
+            .iter()
+            .map(|uuid| normalize_uuid(uuid))
+            .collect::<Result<Vec<_>, _>>()?;
+        if services.is_empty() {
+            return Err(eyre::format_err!(
+                "Profile {} needs at least one service",
+                config.name
+            ));
+        }
+
+        if let Some(combined) = config.combined {
+            if !config.values.is_empty() {
+                return Err(eyre::format_err!(
+                    "Profile {} has both a combined characteristic and values",
+                    config.name
+                ));
+            }
+            return Ok(Self {
+                name: config.name,
+                services,
+                values: Values::Combined(normalize_uuid(&combined)?),
+                station: false,
+            });
+        }
+
+        let mut fields = BTreeMap::new();
+        for value in config.values {
+            let field = Field {
+                characteristic: normalize_uuid(&value.characteristic)?,
+                data_type: value.data_type,
+                endianness: value.endianness,
+                scale: value.scale,
+                offset: value.offset,
+            };
+            if fields.insert(value.quantity, field).is_some() {
+                return Err(eyre::format_err!(
+                    "Profile {} has more than one {:?} value",
+                    config.name,
+                    value.quantity
+                ));
+            }
+        }
+        let mut field = |quantity| {
+            fields.remove(&quantity).ok_or_else(|| {
+                eyre::format_err!("Profile {} has no {:?} value", config.name, quantity)
+            })
+        };
+        let values = Values::Custom {
+            temperature: field(Quantity::Temperature)?,
+            humidity: field(Quantity::Humidity)?,
+            pressure: field(Quantity::Pressure)?,
+        };
+
+        Ok(Self {
+            name: config.name,
+            services,
+            values,
+            station: false,
+        })
+    }
+
+    fn matches(&self, services: &[&str]) -> bool {
+        self.services
+            .iter()
+            .all(|service| services.contains(&service.as_str()))
+    }
+
+    /// Characteristics that all have to be read to get the values
+    pub(crate) fn characteristics(&self) -> Vec<&str> {
+        match self.values {
+            Values::Separate {
+                ref temperature,
+                ref humidity,
+                ref pressure,
+            } => vec![temperature, humidity, pressure],
+            Values::Record(ref uuid) | Values::Combined(ref uuid) => vec![uuid],
+            Values::Custom {
+                ref temperature,
+                ref humidity,
+                ref pressure,
+            } => {
+                let mut uuids = vec![
+                    temperature.characteristic.as_str(),
+                    humidity.characteristic.as_str(),
+                    pressure.characteristic.as_str(),
+                ];
+                uuids.sort_unstable();
+                uuids.dedup();
+                uuids
+            }
+        }
+    }
+
+    /// Characteristic with the measurement timestamp if it's read on its own, not every
+    /// firmware has it
+    pub(crate) fn timestamp(&self) -> Option<&str> {
+        match self.values {
+            Values::Separate { .. } if self.station => Some(MEASUREMENT_TIMESTAMP_CHARACTERISTIC),
+            _ => None,
+        }
+    }
+
+    /// Values and measurement timestamp, if there's one, out of `reads`
+    pub(crate) fn decode(&self, reads: &Reads) -> Result<(SensorValues, Option<Timestamp>), Error> {
+        let raw = |uuid: &str| reads.get(uuid).ok_or_else(|| Error::missing(uuid));
+        match self.values {
+            Values::Separate {
+                ref temperature,
+                ref humidity,
+                ref pressure,
+            } => {
+                let values = super::decode_values(
+                    checked(raw(temperature)?, 2)?,
+                    checked(raw(humidity)?, 2)?,
+                    checked(raw(pressure)?, 4)?,
+                )?;
+                let timestamp = match self.timestamp().and_then(|uuid| reads.get(uuid)) {
+                    Some(raw) => Some(super::decode_timestamp(raw)?),
+                    None => None,
+                };
+                Ok((values, timestamp))
+            }
+            Values::Record(ref uuid) => {
+                let (timestamp, values) = super::decode_history(raw(uuid)?)?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| Error::parse("Empty measurement"))?;
+                Ok((values, Some(timestamp)))
+            }
+            Values::Combined(ref uuid) => {
+                let raw = checked(raw(uuid)?, COMBINED_SIZE)?;
+                let values = super::decode_values(&raw[..2], &raw[2..4], &raw[4..8])?;
+                Ok((values, None))
+            }
+            Values::Custom {
+                ref temperature,
+                ref humidity,
+                ref pressure,
+            } => {
+                let decode = |field: &Field| field.decode(raw(&field.characteristic)?);
+                let values = SensorValues::try_from(RawSensorValues {
+                    temperature: to_raw(decode(temperature)?, 100., Quantity::Temperature)?,
+                    humidity: to_raw(decode(humidity)?, 100., Quantity::Humidity)?,
+                    pressure: to_raw(decode(pressure)?, 10., Quantity::Pressure)?,
+                })
+                .map_err(|e| Error::parse(e.to_string()))?;
+                Ok((values, None))
+            }
+        }
+    }
+}
+
+/// `raw` if it has at least `len` bytes, reading a shorter one would panic
+fn checked(raw: &[u8], len: usize) -> Result<&[u8], Error> {
+    if raw.len() < len {
+        return Err(Error::parse(format!(
+            "Value has {} bytes instead of {}",
+            raw.len(),
+            len
+        )));
+    }
+    Ok(raw)
+}
+
+/// All profiles the central knows, the first one matching the services of a device is used
+#[derive(Clone)]
+pub(crate) struct Profiles(Vec<Arc<Profile>>);
+
+impl Profiles {
+    /// The ones from the config file come first so they can take over devices a builtin profile
+    /// would match as well
+    pub(crate) fn new(custom: Vec<Profile>) -> Self {
+        let mut profiles = custom.into_iter().map(Arc::new).collect::<Vec<_>>();
+        profiles.extend(Self::builtin().0);
+        Self(profiles)
+    }
+
+    fn builtin() -> Self {
+        let separate = || Values::Separate {
+            temperature: TEMPERATURE_CHARACTERISTIC.to_owned(),
+            humidity: HUMIDITY_CHARACTERISTIC.to_owned(),
+            pressure: PRESSURE_CHARACTERISTIC.to_owned(),
+        };
+        Self(vec![
+            Arc::new(Profile {
+                name: "weatherstation-v2".to_owned(),
+                services: vec![BLE_GATT_SERVICE_WEATHERSTATION_V2.to_owned()],
+                values: Values::Record(MEASUREMENT_CHARACTERISTIC_V2.to_owned()),
+                station: true,
+            }),
+            Arc::new(Profile {
+                name: "weatherstation-v1".to_owned(),
+                services: vec![
+                    BLE_GATT_SERVICE_ENVIRONMENTAL_SENSING.to_owned(),
+                    BLE_GATT_SERVICE_WEATHERSTATION.to_owned(),
+                ],
+                values: separate(),
+                station: true,
+            }),
+            Arc::new(Profile {
+                name: "ess".to_owned(),
+                services: vec![BLE_GATT_SERVICE_ESS.to_owned()],
+                values: separate(),
+                station: false,
+            }),
+        ])
+    }
+
+    /// Profile of a device advertising `services`, None if it's none of ours
+    pub(crate) fn detect(&self, services: &[&str]) -> Option<Arc<Profile>> {
+        self.0
+            .iter()
+            .find(|profile| profile.matches(services))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sensor::Quantity;
+
+    #[test]
+    fn detects_and_decodes_every_firmware() {
+        let profiles = Profiles::new(Vec::new());
+        let v1 = profiles
+            .detect(&[
+                BLE_GATT_SERVICE_ENVIRONMENTAL_SENSING,
+                BLE_GATT_SERVICE_WEATHERSTATION,
+            ])
+            .unwrap();
+        assert_eq!(v1.name, "weatherstation-v1");
+        assert_eq!(
+            profiles.detect(&[BLE_GATT_SERVICE_ESS]).unwrap().name,
+            "ess"
+        );
+        assert!(profiles
+            .detect(&[BLE_GATT_SERVICE_ENVIRONMENTAL_SENSING])
+            .is_none());
+
+        let mut reads = Reads::new();
+        reads.insert(
+            TEMPERATURE_CHARACTERISTIC.to_owned(),
+            2150_i16.to_le_bytes().to_vec(),
+        );
+        reads.insert(
+            HUMIDITY_CHARACTERISTIC.to_owned(),
+            4500_u16.to_le_bytes().to_vec(),
+        );
+        reads.insert(
+            PRESSURE_CHARACTERISTIC.to_owned(),
+            1_013_250_u32.to_le_bytes().to_vec(),
+        );
+        let (values, timestamp) = v1.decode(&reads).unwrap();
+        assert_eq!(Quantity::Temperature.of(values), 21.5);
+        assert_eq!(timestamp, None);
+
+        let v2 = profiles
+            .detect(&[BLE_GATT_SERVICE_WEATHERSTATION_V2])
+            .unwrap();
+        let mut record = 1000_u32.to_le_bytes().to_vec();
+        record.extend_from_slice(&(-320_i16).to_le_bytes());
+        record.extend_from_slice(&4500_u16.to_le_bytes());
+        record.extend_from_slice(&1_013_250_u32.to_le_bytes());
+        reads.insert(MEASUREMENT_CHARACTERISTIC_V2.to_owned(), record);
+        let (values, timestamp) = v2.decode(&reads).unwrap();
+        assert_eq!(Quantity::Temperature.of(values), -3.2);
+        assert_eq!(timestamp, Some(Timestamp::from(1000)));
+    }
+
+    #[test]
+    fn decodes_profiles_from_the_config() {
+        let config: ProfileConfig = toml::from_str(
+            r#"
+            name = "thermometer"
+            services = ["FFE0"]
+
+            [[value]]
+            quantity = "temperature"
+            characteristic = "ffe1"
+            type = "i16"
+            endianness = "big"
+            scale = 0.1
+
+            [[value]]
+            quantity = "humidity"
+            characteristic = "ffe1"
+            type = "u8"
+            offset = 2
+
+            [[value]]
+            quantity = "pressure"
+            characteristic = "ffe2"
+            type = "f32"
+            scale = 100.0
+            "#,
+        )
+        .unwrap();
+        let profiles = Profiles::new(vec![Profile::from_config(config).unwrap()]);
+        let profile = profiles
+            .detect(&["0000ffe0-0000-1000-8000-00805f9b34fb"])
+            .unwrap();
+        assert_eq!(profile.characteristics().len(), 2);
+
+        let mut reads = Reads::new();
+        reads.insert(
+            "0000ffe1-0000-1000-8000-00805f9b34fb".to_owned(),
+            vec![0xff, 0xe0, 45],
+        );
+        reads.insert(
+            "0000ffe2-0000-1000-8000-00805f9b34fb".to_owned(),
+            1013.25_f32.to_le_bytes().to_vec(),
+        );
+        let (values, _) = profile.decode(&reads).unwrap();
+        assert_eq!(Quantity::Temperature.of(values), -3.2);
+        assert_eq!(Quantity::Humidity.of(values), 45.);
+        assert_eq!(Quantity::Pressure.of(values), 101_325.);
+    }
+
+    #[test]
+    fn reads_combined_characteristics_once() {
+        let config: ProfileConfig = toml::from_str(
+            r#"
+            name = "weatherstation-v3"
+            services = ["FFE0"]
+            combined = "ffe1"
+            "#,
+        )
+        .unwrap();
+        let profile = Profile::from_config(config).unwrap();
+        assert_eq!(
+            profile.characteristics(),
+            vec!["0000ffe1-0000-1000-8000-00805f9b34fb"]
+        );
+
+        let mut packed = 2150_i16.to_le_bytes().to_vec();
+        packed.extend_from_slice(&4500_u16.to_le_bytes());
+        packed.extend_from_slice(&1_013_250_u32.to_le_bytes());
+        // battery level
+        packed.push(87);
+        let mut reads = Reads::new();
+        reads.insert("0000ffe1-0000-1000-8000-00805f9b34fb".to_owned(), packed);
+        let (values, timestamp) = profile.decode(&reads).unwrap();
+        assert_eq!(Quantity::Humidity.of(values), 45.);
+        assert_eq!(timestamp, None);
+
+        reads.insert(
+            "0000ffe1-0000-1000-8000-00805f9b34fb".to_owned(),
+            vec![0; 6],
+        );
+        assert!(profile.decode(&reads).is_err());
+    }
+}
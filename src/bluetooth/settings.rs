@@ -0,0 +1,241 @@
+use super::BluetoothAddress;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    num::NonZeroU16,
+    sync::Mutex,
+};
+
+/// Radio a station asks to switch its connection to
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Phy {
+    #[serde(rename = "1m")]
+    Le1M,
+    /// twice the rate so the radio is on for half as long, needs a newer adapter
+    #[serde(rename = "2m")]
+    Le2M,
+    /// more range for stations far away, costs battery
+    #[serde(rename = "coded")]
+    Coded,
+}
+
+impl Default for Phy {
+    fn default() -> Self {
+        Phy::Le1M
+    }
+}
+
+/// The `[connection]` table, connection parameters the stations request from the central after
+/// connecting. BlueZ has no dbus api for them so they go through the firmware, longer intervals
+/// and more latency let the radio of a station sleep longer.
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ConnectionParams {
+    /// shortest connection interval in milliseconds
+    min_interval: f64,
+    /// longest connection interval in milliseconds
+    max_interval: f64,
+    /// connection events a station may skip when it has nothing to send
+    #[serde(default)]
+    latency: u16,
+    /// milliseconds without a packet until the connection counts as lost
+    supervision_timeout: u32,
+    #[serde(default)]
+    phy: Phy,
+}
+
+impl ConnectionParams {
+    /// Checks the limits of the bluetooth spec, stations reject anything outside of them
+    pub(crate) fn validate(&self) -> Result<(), eyre::Error> {
+        let interval = 7.5..=4000.0;
+        if !interval.contains(&self.min_interval) || !interval.contains(&self.max_interval) {
+            return Err(eyre::format_err!(
+                "Connection intervals have to be between 7.5 and 4000ms"
+            ));
+        }
+        if self.min_interval > self.max_interval {
+            return Err(eyre::format_err!(
+                "Minimum connection interval is larger than the maximum"
+            ));
+        }
+        if self.latency > 499 {
+            return Err(eyre::format_err!("Connection latency has to be below 500"));
+        }
+        if !(100..=32_000).contains(&self.supervision_timeout) {
+            return Err(eyre::format_err!(
+                "Supervision timeout has to be between 100 and 32000ms"
+            ));
+        }
+        // the connection would count as lost while the station is allowed to sleep
+        if f64::from(self.supervision_timeout)
+            <= (1.0 + f64::from(self.latency)) * self.max_interval * 2.0
+        {
+            return Err(eyre::format_err!(
+                "Supervision timeout has to be longer than twice the time a station may sleep"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Value of the connection parameters characteristic: intervals in units of 1.25ms,
+    /// latency, supervision timeout in units of 10ms as u16 and the phy as bitmask
+    pub(crate) fn encode(&self) -> [u8; 9] {
+        let mut raw = [0; 9];
+        let interval = |ms: f64| ((ms / 1.25).round() as u16).to_le_bytes();
+        raw[0..2].copy_from_slice(&interval(self.min_interval));
+        raw[2..4].copy_from_slice(&interval(self.max_interval));
+        raw[4..6].copy_from_slice(&self.latency.to_le_bytes());
+        raw[6..8].copy_from_slice(&((self.supervision_timeout / 10) as u16).to_le_bytes());
+        raw[8] = match self.phy {
+            Phy::Le1M => 1,
+            Phy::Le2M => 2,
+            Phy::Coded => 4,
+        };
+        raw
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    /// seconds between two measurements of stations that have one set
+    intervals: BTreeMap<BluetoothAddress, NonZeroU16>,
+    /// stations that got their interval written since they connected
+    written: BTreeSet<BluetoothAddress>,
+    /// same for every station, `None` leaves them as they are
+    connection: Option<ConnectionParams>,
+    /// stations that got the connection parameters written since they connected
+    connection_written: BTreeSet<BluetoothAddress>,
+    /// stations that only get read once they're bonded
+    encrypted: BTreeSet<BluetoothAddress>,
+}
+
+/// Settings of the addr db that live on the stations themselves. They get written on every
+/// connect and whenever they change.
+#[derive(Default)]
+pub(crate) struct StationSettings(Mutex<Inner>);
+
+impl StationSettings {
+    /// Follows the measurement interval setting of `addr`, `None` leaves the station as is
+    pub(crate) fn set_interval(&self, addr: BluetoothAddress, interval: Option<NonZeroU16>) {
+        let mut inner = self.0.lock().unwrap();
+        let changed = match interval {
+            Some(interval) => inner.intervals.insert(addr, interval) != Some(interval),
+            None => inner.intervals.remove(&addr).is_some(),
+        };
+        if changed {
+            inner.written.remove(&addr);
+        }
+    }
+
+    /// The measurement interval to write to `addr` if it doesn't have it yet
+    pub(crate) fn interval_due(&self, addr: BluetoothAddress) -> Option<NonZeroU16> {
+        let inner = self.0.lock().unwrap();
+        match inner.intervals.get(&addr) {
+            Some(interval) if !inner.written.contains(&addr) => Some(*interval),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn interval_written(&self, addr: BluetoothAddress, interval: NonZeroU16) {
+        let mut inner = self.0.lock().unwrap();
+        // changed again while it was being written
+        if inner.intervals.get(&addr) == Some(&interval) {
+            inner.written.insert(addr);
+            tracing::info!("Set measurement interval of {} to {}s", addr, interval);
+        }
+    }
+
+    /// Stops writing to `addr` until it reconnects, for firmware without a measurement interval
+    pub(crate) fn interval_unsupported(&self, addr: BluetoothAddress) {
+        self.0.lock().unwrap().written.insert(addr);
+        tracing::warn!("{} doesn't support setting its measurement interval", addr);
+    }
+
+    pub(crate) fn set_encryption(&self, addr: BluetoothAddress, required: bool) {
+        let mut inner = self.0.lock().unwrap();
+        if required {
+            inner.encrypted.insert(addr);
+        } else {
+            inner.encrypted.remove(&addr);
+        }
+    }
+
+    /// Whether `addr` has to be paired before its values can be read
+    pub(crate) fn encryption_required(&self, addr: BluetoothAddress) -> bool {
+        self.0.lock().unwrap().encrypted.contains(&addr)
+    }
+
+    pub(crate) fn set_connection(&self, params: Option<ConnectionParams>) {
+        let mut inner = self.0.lock().unwrap();
+        inner.connection = params;
+        inner.connection_written.clear();
+    }
+
+    /// The connection parameters to write to `addr` if it didn't get them since it connected
+    pub(crate) fn connection_due(&self, addr: BluetoothAddress) -> Option<ConnectionParams> {
+        let inner = self.0.lock().unwrap();
+        inner
+            .connection
+            .filter(|_| !inner.connection_written.contains(&addr))
+    }
+
+    pub(crate) fn connection_written(&self, addr: BluetoothAddress) {
+        self.0.lock().unwrap().connection_written.insert(addr);
+        tracing::info!("Requested connection parameters from {}", addr);
+    }
+
+    /// Stops writing to `addr` until it reconnects, for firmware without connection parameters
+    pub(crate) fn connection_unsupported(&self, addr: BluetoothAddress) {
+        self.0.lock().unwrap().connection_written.insert(addr);
+        tracing::warn!("{} doesn't support setting its connection parameters", addr);
+    }
+
+    pub(crate) fn reconnected(&self, addr: BluetoothAddress) {
+        let mut inner = self.0.lock().unwrap();
+        inner.written.remove(&addr);
+        inner.connection_written.remove(&addr);
+    }
+
+    pub(crate) fn forget(&self, addr: BluetoothAddress) {
+        let mut inner = self.0.lock().unwrap();
+        inner.intervals.remove(&addr);
+        inner.written.remove(&addr);
+        inner.connection_written.remove(&addr);
+        inner.encrypted.remove(&addr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn writes_changed_intervals_once() {
+        let addr = BluetoothAddress::from(1);
+        let settings = StationSettings::default();
+        assert_eq!(settings.interval_due(addr), None);
+        let interval = NonZeroU16::new(60).unwrap();
+        settings.set_interval(addr, Some(interval));
+        assert_eq!(settings.interval_due(addr), Some(interval));
+        settings.interval_written(addr, interval);
+        assert_eq!(settings.interval_due(addr), None);
+        settings.set_interval(addr, Some(interval));
+        assert_eq!(settings.interval_due(addr), None);
+        settings.reconnected(addr);
+        assert_eq!(settings.interval_due(addr), Some(interval));
+    }
+
+    #[test]
+    fn connection_params_in_ble_units() {
+        let params: ConnectionParams = toml::from_str(
+            "min_interval = 500.0\nmax_interval = 1000.0\nlatency = 4\nsupervision_timeout = 12000\nphy = \"2m\"",
+        )
+        .unwrap();
+        params.validate().unwrap();
+        assert_eq!(params.encode(), [144, 1, 32, 3, 4, 0, 176, 4, 2]);
+
+        let sleepy = ConnectionParams {
+            latency: 10,
+            ..params
+        };
+        assert!(sleepy.validate().is_err());
+    }
+}
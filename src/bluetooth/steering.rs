@@ -0,0 +1,193 @@
+use super::BluetoothAddress;
+use crate::timestamp::Timestamp;
+use std::{collections::BTreeMap, sync::Mutex};
+
+/// Seconds after which a sighting no longer counts, about two discovery rounds
+const SEEN_WITHIN: u32 = 60;
+
+/// What stations get moved towards
+#[derive(serde::Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Policy {
+    /// the adapter that hears the station the loudest
+    Rssi,
+    /// the adapter with the fewest connected stations
+    Load,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy::Rssi
+    }
+}
+
+/// The `[steering]` table, moves stations between adapters instead of leaving them with the
+/// one that happened to find them first
+#[derive(serde::Deserialize, Clone)]
+pub(crate) struct SteeringConfig {
+    #[serde(default)]
+    policy: Policy,
+    /// dBm another adapter has to hear a station better by before it moves there
+    #[serde(default = "default_margin")]
+    margin: i16,
+    /// seconds a station stays with an adapter before it can move again
+    #[serde(default = "default_hold")]
+    hold: u32,
+}
+
+fn default_margin() -> i16 {
+    10
+}
+
+fn default_hold() -> u32 {
+    600
+}
+
+#[derive(Default)]
+struct Station {
+    /// adapter that may connect it and since when
+    owner: Option<(String, Timestamp)>,
+    /// latest rssi and sighting by adapter, connected stations keep the rssi from before
+    sightings: BTreeMap<String, (Option<i16>, Timestamp)>,
+}
+
+impl Station {
+    fn fresh(&self, adapter: &str, now: Timestamp) -> bool {
+        self.sightings.get(adapter).map_or(false, |(_, seen)| {
+            now.bottoming_sub(*seen).as_u32() < SEEN_WITHIN
+        })
+    }
+
+    fn rssi(&self, adapter: &str) -> Option<i16> {
+        self.sightings.get(adapter).and_then(|(rssi, _)| *rssi)
+    }
+}
+
+/// Which adapter each weatherstation belongs to, shared by the adapter threads so only one of
+/// them connects a station
+pub(crate) struct Steering {
+    config: Option<SteeringConfig>,
+    stations: Mutex<BTreeMap<BluetoothAddress, Station>>,
+}
+
+impl Steering {
+    pub(crate) fn new(config: Option<SteeringConfig>) -> Self {
+        Self {
+            config,
+            stations: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Called for every weatherstation an adapter knows about, connected or advertising
+    pub(crate) fn seen(
+        &self,
+        addr: BluetoothAddress,
+        adapter: &str,
+        rssi: Option<i16>,
+        now: Timestamp,
+    ) {
+        let mut stations = self.stations.lock().unwrap();
+        let sighting = stations
+            .entry(addr)
+            .or_default()
+            .sightings
+            .entry(adapter.to_owned())
+            .or_insert((rssi, now));
+        *sighting = (rssi.or(sighting.0), now);
+    }
+
+    /// Whether `adapter` should be connected to `addr`. The first adapter to ask gets a station
+    /// nobody has, the owner loses it once it doesn't see it anymore or, with a `[steering]`
+    /// table, once a better adapter comes along. `load` are the connected stations by adapter.
+    pub(crate) fn assigned(
+        &self,
+        addr: BluetoothAddress,
+        adapter: &str,
+        load: &BTreeMap<String, usize>,
+        now: Timestamp,
+    ) -> bool {
+        let mut stations = self.stations.lock().unwrap();
+        let station = stations.entry(addr).or_default();
+        let (owner, since) = match station.owner {
+            Some((ref owner, since)) if owner == adapter => (owner.clone(), since),
+            Some((ref owner, _)) if station.fresh(owner, now) => return false,
+            _ => {
+                station.owner = Some((adapter.to_owned(), now));
+                return true;
+            }
+        };
+
+        let config = match self.config {
+            Some(ref config) if now.bottoming_sub(since).as_u32() >= config.hold => config,
+            _ => return true,
+        };
+        match better(config, station, &owner, load, now) {
+            Some(better) => {
+                tracing::info!("Moving {} from adapter {} to {}", addr, owner, better);
+                station.owner = Some((better, now));
+                false
+            }
+            None => true,
+        }
+    }
+}
+
+/// Adapter that sees the station of `owner` and suits it better by the policy of `config`
+fn better(
+    config: &SteeringConfig,
+    station: &Station,
+    owner: &str,
+    load: &BTreeMap<String, usize>,
+    now: Timestamp,
+) -> Option<String> {
+    let candidates = station
+        .sightings
+        .keys()
+        .filter(|adapter| *adapter != owner && station.fresh(adapter, now));
+    match config.policy {
+        Policy::Rssi => {
+            let current = station.rssi(owner)?;
+            let (adapter, rssi) = candidates
+                .filter_map(|adapter| Some((adapter, station.rssi(adapter)?)))
+                .max_by_key(|(_, rssi)| *rssi)?;
+            if rssi >= current.saturating_add(config.margin) {
+                Some(adapter.clone())
+            } else {
+                None
+            }
+        }
+        Policy::Load => {
+            let load_of = |adapter: &str| load.get(adapter).copied().unwrap_or(0);
+            let adapter = candidates.min_by_key(|adapter| load_of(adapter))?;
+            // moving a station between equally loaded adapters would only swap them around
+            if load_of(adapter) + 1 < load_of(owner) {
+                Some(adapter.clone())
+            } else {
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn moves_to_the_louder_adapter_after_holding() {
+        let config = toml::from_str("margin = 10\nhold = 600").unwrap();
+        let steering = Steering::new(Some(config));
+        let station = BluetoothAddress::from(1);
+        let load = BTreeMap::new();
+
+        steering.seen(station, "hci0", Some(-90), Timestamp::from(0));
+        assert!(steering.assigned(station, "hci0", &load, Timestamp::from(0)));
+        steering.seen(station, "hci1", Some(-60), Timestamp::from(10));
+        assert!(!steering.assigned(station, "hci1", &load, Timestamp::from(10)));
+        assert!(steering.assigned(station, "hci0", &load, Timestamp::from(10)));
+
+        steering.seen(station, "hci1", Some(-60), Timestamp::from(600));
+        assert!(!steering.assigned(station, "hci0", &load, Timestamp::from(600)));
+        assert!(steering.assigned(station, "hci1", &load, Timestamp::from(610)));
+    }
+}
@@ -0,0 +1,180 @@
+use crate::{sensor::Quantity, timestamp::Timestamp};
+use std::fmt::Write;
+
+const WIDTH: f64 = 600.;
+const HEIGHT: f64 = 300.;
+/// Space around the plot for the axis labels
+const MARGIN: f64 = 50.;
+
+/// Parses chart windows like `90m`, `24h` or `7d`, plain numbers are seconds
+pub(crate) fn parse_window(s: &str) -> Result<Timestamp, eyre::Error> {
+    let s = s.trim();
+    let (number, factor) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        Some((i, 'd')) => (&s[..i], 60 * 60 * 24),
+        _ => (s, 1),
+    };
+    let number = number
+        .parse::<u32>()
+        .map_err(|_| eyre::format_err!("Invalid chart window `{}`", s))?;
+    number
+        .checked_mul(factor)
+        .filter(|secs| *secs > 0)
+        .map(Timestamp::from)
+        .ok_or_else(|| eyre::format_err!("Chart window `{}` out of range", s))
+}
+
+fn unit(quantity: Quantity) -> &'static str {
+    match quantity {
+        Quantity::Temperature => "°C",
+        Quantity::Humidity => "%",
+        Quantity::Pressure => "Pa",
+    }
+}
+
+fn time_label(time: Timestamp) -> String {
+    use chrono::TimeZone;
+    chrono::Local
+        .timestamp(i64::from(time.as_u32()), 0)
+        .format("%d.%m. %H:%M")
+        .to_string()
+}
+
+/// Renders `points` of `quantity` in `range` as a standalone svg line chart
+pub(crate) fn render_svg(
+    title: &str,
+    quantity: Quantity,
+    range: std::ops::Range<Timestamp>,
+    points: &[(Timestamp, f64)],
+) -> String {
+    let mut svg = String::new();
+    // writing into a String can't fail
+    let _ = write!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif" font-size="12">"#,
+        w = WIDTH,
+        h = HEIGHT,
+    );
+    svg.push_str(r#"<rect width="100%" height="100%" fill="white"/>"#);
+    let _ = write!(
+        svg,
+        r#"<text x="{}" y="20" text-anchor="middle" font-size="14">{}</text>"#,
+        WIDTH / 2.,
+        escape(title)
+    );
+    let _ = write!(
+        svg,
+        r#"<rect x="{m}" y="{m}" width="{w}" height="{h}" fill="none" stroke="gray"/>"#,
+        m = MARGIN,
+        w = WIDTH - 2. * MARGIN,
+        h = HEIGHT - 2. * MARGIN,
+    );
+
+    if points.is_empty() {
+        let _ = write!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="middle">No data</text>"#,
+            WIDTH / 2.,
+            HEIGHT / 2.
+        );
+    } else {
+        let (min, max) = points
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), (_, y)| {
+                (min.min(*y), max.max(*y))
+            });
+        // a flat line still needs some height to be drawn in the middle
+        let (min, max) = if (max - min).abs() < f64::EPSILON {
+            (min - 1., max + 1.)
+        } else {
+            (min, max)
+        };
+        let start = f64::from(range.start.as_u32());
+        let span = f64::from(range.end.as_u32()) - start;
+
+        let x = |time: Timestamp| {
+            MARGIN + (f64::from(time.as_u32()) - start) / span.max(1.) * (WIDTH - 2. * MARGIN)
+        };
+        let y = |value: f64| HEIGHT - MARGIN - (value - min) / (max - min) * (HEIGHT - 2. * MARGIN);
+
+        svg.push_str(r#"<polyline fill="none" stroke="steelblue" stroke-width="2" points=""#);
+        for (time, value) in points {
+            let _ = write!(svg, "{:.1},{:.1} ", x(*time), y(*value));
+        }
+        svg.push_str(r#""/>"#);
+
+        let unit = unit(quantity);
+        let _ = write!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="end">{:.1}{}</text>"#,
+            MARGIN - 4.,
+            MARGIN + 4.,
+            max,
+            unit
+        );
+        let _ = write!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="end">{:.1}{}</text>"#,
+            MARGIN - 4.,
+            HEIGHT - MARGIN + 4.,
+            min,
+            unit
+        );
+    }
+
+    let _ = write!(
+        svg,
+        r#"<text x="{}" y="{}">{}</text>"#,
+        MARGIN,
+        HEIGHT - MARGIN + 18.,
+        time_label(range.start)
+    );
+    let _ = write!(
+        svg,
+        r#"<text x="{}" y="{}" text-anchor="end">{}</text>"#,
+        WIDTH - MARGIN,
+        HEIGHT - MARGIN + 18.,
+        time_label(range.end)
+    );
+    svg.push_str("</svg>");
+    svg
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chart_windows() {
+        assert_eq!(parse_window("24h").unwrap(), Timestamp::ONE_DAY);
+        assert_eq!(parse_window("90m").unwrap(), Timestamp::from(90 * 60));
+        assert_eq!(parse_window("3600").unwrap(), Timestamp::from(3600));
+        assert!(parse_window("0h").is_err());
+        assert!(parse_window("h").is_err());
+        assert!(parse_window("99999999d").is_err());
+    }
+
+    #[test]
+    fn svg_contains_every_point() {
+        let points = [(Timestamp::from(0), 20.), (Timestamp::from(50), 21.5)];
+        let svg = render_svg(
+            "<garden>",
+            Quantity::Temperature,
+            Timestamp::from(0)..Timestamp::from(100),
+            &points,
+        );
+        assert!(svg.starts_with("<svg") && svg.ends_with("</svg>"));
+        assert!(svg.contains("&lt;garden&gt;"));
+        assert!(svg.contains("21.5°C"));
+        assert!(svg.contains(&format!("{:.1},{:.1} ", MARGIN, HEIGHT - MARGIN)));
+    }
+}
@@ -0,0 +1,196 @@
+use crate::{bluetooth::BluetoothAddress, timestamp::Timestamp};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Offsets kept per station, the smallest one is the best guess since transfer delays only add up
+const MAX_SAMPLES: usize = 32;
+
+/// When the latest reading of a station was taken, by its own clock and by ours
+#[derive(Copy, Clone, Debug, serde::Serialize)]
+pub(crate) struct Measurement {
+    /// measurement timestamp of the station, as it reported it
+    pub(crate) device: Timestamp,
+    /// when the central read it
+    pub(crate) received: Timestamp,
+    /// `device` moved onto our clock
+    pub(crate) corrected: Timestamp,
+}
+
+#[derive(Default)]
+struct DeviceClock {
+    /// our time minus the time of the station of recent readings, in seconds
+    offsets: VecDeque<i64>,
+    latest: Option<Measurement>,
+}
+
+impl DeviceClock {
+    fn observe(&mut self, device: Timestamp, received: Timestamp) -> Measurement {
+        if self.offsets.len() == MAX_SAMPLES {
+            self.offsets.pop_front();
+        }
+        self.offsets
+            .push_back(i64::from(received.as_u32()) - i64::from(device.as_u32()));
+        // never later than `received` since the current offset is one of the candidates
+        let measurement = Measurement {
+            device,
+            received,
+            corrected: self.correct(device),
+        };
+        self.latest = Some(measurement);
+        measurement
+    }
+
+    fn correct(&self, device: Timestamp) -> Timestamp {
+        let offset = self.offsets.iter().copied().min().unwrap_or(0);
+        Timestamp::from((i64::from(device.as_u32()) + offset).max(0) as u32)
+    }
+}
+
+/// Which stations get their clock set and when that last happened
+struct ClockSync {
+    /// `None` turns setting clocks off
+    interval: Option<Duration>,
+    /// stations that opted out in their settings
+    disabled: BTreeSet<BluetoothAddress>,
+    /// only has connected stations, a reconnected one gets its clock set right away
+    last: BTreeMap<BluetoothAddress, Instant>,
+}
+
+impl ClockSync {
+    fn due(&self, addr: BluetoothAddress, now: Instant) -> bool {
+        match self.interval {
+            Some(interval) if !self.disabled.contains(&addr) => self
+                .last
+                .get(&addr)
+                .map_or(true, |last| now.duration_since(*last) >= interval),
+            _ => false,
+        }
+    }
+}
+
+/// Measurement timestamps of stations whose firmware reports them, with the skew of their clocks
+/// corrected. Also keeps track of setting the clocks of stations that can be set.
+pub(crate) struct DeviceClocks {
+    clocks: Mutex<BTreeMap<BluetoothAddress, DeviceClock>>,
+    sync: Mutex<ClockSync>,
+}
+
+impl DeviceClocks {
+    pub(crate) fn new(sync_interval: Option<Duration>) -> Self {
+        Self {
+            clocks: Mutex::default(),
+            sync: Mutex::new(ClockSync {
+                interval: sync_interval,
+                disabled: BTreeSet::new(),
+                last: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Called by the bluetooth backends for every reading that came with a timestamp
+    pub(crate) fn observe(
+        &self,
+        addr: BluetoothAddress,
+        device: Timestamp,
+        received: Timestamp,
+    ) -> Measurement {
+        self.clocks
+            .lock()
+            .unwrap()
+            .entry(addr)
+            .or_default()
+            .observe(device, received)
+    }
+
+    pub(crate) fn latest(&self, addr: BluetoothAddress) -> Option<Measurement> {
+        self.clocks
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .and_then(|clock| clock.latest)
+    }
+
+    /// `device` moved onto our clock, as is for stations that never reported a timestamp
+    pub(crate) fn correct(&self, addr: BluetoothAddress, device: Timestamp) -> Timestamp {
+        match self.clocks.lock().unwrap().get(&addr) {
+            Some(clock) => clock.correct(device),
+            None => device,
+        }
+    }
+
+    /// Whether the clock of `addr` should be set now, called by the bluetooth backends on
+    /// every read
+    pub(crate) fn sync_due(&self, addr: BluetoothAddress) -> bool {
+        self.sync.lock().unwrap().due(addr, Instant::now())
+    }
+
+    /// Records that the clock of `addr` was set to `time`. Offsets from before don't apply anymore.
+    pub(crate) fn synced(&self, addr: BluetoothAddress, time: Timestamp) {
+        self.sync_attempted(addr);
+        if let Some(clock) = self.clocks.lock().unwrap().get_mut(&addr) {
+            clock.offsets.clear();
+        }
+        tracing::info!("Set clock of {} to {}", addr, time.as_u32());
+    }
+
+    /// Records that setting the clock of `addr` was tried, for firmware without a settable clock
+    pub(crate) fn sync_attempted(&self, addr: BluetoothAddress) {
+        self.sync.lock().unwrap().last.insert(addr, Instant::now());
+    }
+
+    /// The next read of `addr` sets its clock again, for stations that just connected
+    pub(crate) fn reconnected(&self, addr: BluetoothAddress) {
+        self.sync.lock().unwrap().last.remove(&addr);
+    }
+
+    /// Follows the setting of `addr` that decides whether its clock gets set
+    pub(crate) fn set_sync(&self, addr: BluetoothAddress, enabled: bool) {
+        let mut sync = self.sync.lock().unwrap();
+        if enabled {
+            sync.disabled.remove(&addr);
+        } else {
+            sync.disabled.insert(addr);
+        }
+    }
+
+    pub(crate) fn forget(&self, addr: BluetoothAddress) {
+        self.clocks.lock().unwrap().remove(&addr);
+        let mut sync = self.sync.lock().unwrap();
+        sync.disabled.remove(&addr);
+        sync.last.remove(&addr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn corrects_skew_from_fastest_reading() {
+        let mut clock = DeviceClock::default();
+        // the station runs 100 seconds behind, reads take between 2 and 10 seconds
+        clock.observe(Timestamp::from(1000), Timestamp::from(1110));
+        clock.observe(Timestamp::from(1060), Timestamp::from(1162));
+        // a reading buffered for a while still lands at the moment it was taken
+        let buffered = clock.observe(Timestamp::from(1100), Timestamp::from(1500));
+        assert_eq!(buffered.corrected, Timestamp::from(1202));
+        assert_eq!(buffered.device, Timestamp::from(1100));
+    }
+
+    #[test]
+    fn sync_follows_interval_and_settings() {
+        let addr = BluetoothAddress::from(1);
+        let clocks = DeviceClocks::new(Some(Duration::from_secs(60)));
+        assert!(clocks.sync_due(addr));
+        clocks.synced(addr, Timestamp::from(1000));
+        assert!(!clocks.sync_due(addr));
+        clocks.reconnected(addr);
+        assert!(clocks.sync_due(addr));
+        clocks.set_sync(addr, false);
+        assert!(!clocks.sync_due(addr));
+        assert!(!DeviceClocks::new(None).sync_due(addr));
+    }
+}
@@ -0,0 +1,7 @@
+pub(crate) mod bench_db;
+pub(crate) mod bench_http;
+pub(crate) mod dump;
+pub(crate) mod export;
+pub(crate) mod fsck;
+pub(crate) mod import;
+pub(crate) mod restore;
@@ -0,0 +1,92 @@
+use crate::{bench::LogBench, opt::BenchDb, timestamp::Timestamp};
+use eyre::Context;
+use rand::Rng;
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
+
+pub(crate) fn run(args: BenchDb) -> Result<(), eyre::Error> {
+    let scratch = args.path.is_none();
+    let path = args.path.unwrap_or_else(|| {
+        std::env::temp_dir().join(format!("ble-weatherstation-bench-{}", std::process::id()))
+    });
+    if scratch && path.exists() {
+        return Err(eyre::format_err!("{} already exists", path.display()));
+    }
+
+    let ret = bench(&args, &path);
+
+    if scratch {
+        fs::remove_dir_all(&path)
+            .with_context(|| format!("Could not remove scratch database {}", path.display()))?;
+    }
+
+    ret
+}
+
+fn bench(args: &BenchDb, path: &std::path::Path) -> Result<(), eyre::Error> {
+    let mut bench = LogBench::open(path, args.sensors)?;
+
+    println!(
+        "Writing {} samples for {} sensors into {}",
+        args.samples,
+        args.sensors,
+        path.display()
+    );
+    let mut commit_times = Vec::with_capacity(args.samples as usize);
+    let write_started = Instant::now();
+    for _ in 0..args.samples {
+        let commit_started = Instant::now();
+        bench.write(1)?;
+        commit_times.push(commit_started.elapsed());
+    }
+    let write_elapsed = write_started.elapsed();
+    let total = u64::from(args.samples) * u64::from(args.sensors);
+    println!(
+        "Wrote {} samples in {:.2?} ({:.0} samples/s)",
+        total,
+        write_elapsed,
+        total as f64 / write_elapsed.as_secs_f64()
+    );
+    print_latencies("log txn", &mut commit_times);
+
+    let mut rng = rand::thread_rng();
+    let mut query_times = Vec::with_capacity((args.queries * args.sensors) as usize);
+    let mut returned = 0;
+    for sensor in 0..args.sensors {
+        for _ in 0..args.queries {
+            let offset = rng.gen_range(
+                0,
+                bench
+                    .span()
+                    .saturating_sub(Timestamp::ONE_DAY.as_u32())
+                    .max(1),
+            );
+
+            let query_started = Instant::now();
+            returned += bench.query_day(sensor, offset)?;
+            query_times.push(query_started.elapsed());
+        }
+    }
+    println!("Queried {} entries with one day range queries", returned);
+    print_latencies("range query", &mut query_times);
+
+    Ok(())
+}
+
+pub(super) fn print_latencies(name: &str, times: &mut [Duration]) {
+    if times.is_empty() {
+        return;
+    }
+    times.sort_unstable();
+    let percentile = |p: usize| times[(times.len() - 1) * p / 100];
+    println!(
+        "{} latency: min {:.2?} p50 {:.2?} p99 {:.2?} max {:.2?}",
+        name,
+        times[0],
+        percentile(50),
+        percentile(99),
+        times[times.len() - 1]
+    );
+}
@@ -0,0 +1,177 @@
+use super::bench_db::print_latencies;
+use crate::{config::Config, opt::BenchHttp};
+use rand::Rng;
+use reqwest::{Client, StatusCode, Url};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{Duration, Instant},
+};
+
+/// What a dashboard does after loading before it's looked at again, in milliseconds
+const THINK_TIME: (u64, u64) = (1000, 3000);
+
+/// Outcome of one request of a simulated visitor
+struct Request {
+    endpoint: &'static str,
+    latency: Duration,
+    status: Option<StatusCode>,
+}
+
+pub(crate) fn run(config: &Config, args: BenchHttp) -> Result<(), eyre::Error> {
+    let url = match args.url {
+        Some(url) => url,
+        None => {
+            let mut addr = *config
+                .listen
+                .first()
+                .ok_or_else(|| eyre::format_err!("No listen address configured"))?;
+            // a server listening everywhere is reachable on localhost
+            match addr.ip() {
+                IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+                IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+                _ => {}
+            }
+            Url::parse(&format!("http://{}{}/", addr, config.base_path))?
+        }
+    };
+    let url = if url.path().ends_with('/') {
+        url
+    } else {
+        Url::parse(&format!("{}/", url))?
+    };
+
+    println!(
+        "Simulating {} dashboard visitors of {} for {}s",
+        args.clients, url, args.duration
+    );
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let requests = runtime.block_on(async {
+        let deadline = Instant::now() + Duration::from_secs(args.duration);
+        let visitors = (0..args.clients)
+            .map(|_| tokio::spawn(visitor(url.clone(), deadline)))
+            .collect::<Vec<_>>();
+        let mut requests = Vec::new();
+        for visitor in visitors {
+            requests.extend(visitor.await?);
+        }
+        Ok::<_, eyre::Error>(requests)
+    })?;
+
+    report(&requests, args.duration);
+    Ok(())
+}
+
+/// Loads the home page and the state, then looks at the details of a random sensor, over and
+/// over like a browser tab somebody keeps clicking around in
+async fn visitor(base: Url, deadline: Instant) -> Vec<Request> {
+    // every visitor is its own browser with its own connections
+    let client = Client::new();
+    let mut requests = Vec::new();
+    while Instant::now() < deadline {
+        get(&client, &base, "", "home", &mut requests).await;
+        let sensors = match get(&client, &base, "api/state", "api_state", &mut requests).await {
+            Some(body) => sensor_addrs(&body),
+            None => Vec::new(),
+        };
+
+        if !sensors.is_empty() {
+            let addr = &sensors[rand::thread_rng().gen_range(0, sensors.len())];
+            let detail = [
+                (format!("detail/{}", addr), "detail"),
+                (format!("api/log/{}?window=86400", addr), "api_log"),
+                (format!("api/stats/{}", addr), "api_stats"),
+                (format!("api/gaps/{}", addr), "api_gaps"),
+                (
+                    format!("api/chart/{}.svg?metric=temperature&window=7d", addr),
+                    "api_chart",
+                ),
+            ];
+            for (path, endpoint) in &detail {
+                get(&client, &base, path, *endpoint, &mut requests).await;
+            }
+        }
+
+        let think = rand::thread_rng().gen_range(THINK_TIME.0, THINK_TIME.1);
+        tokio::time::sleep(Duration::from_millis(think)).await;
+    }
+    requests
+}
+
+/// Body of successful replies
+async fn get(
+    client: &Client,
+    base: &Url,
+    path: &str,
+    endpoint: &'static str,
+    requests: &mut Vec<Request>,
+) -> Option<String> {
+    let url = base.join(path).ok()?;
+    let started = Instant::now();
+    let reply = match client.get(url).send().await {
+        Ok(reply) => reply,
+        Err(_) => {
+            requests.push(Request {
+                endpoint,
+                latency: started.elapsed(),
+                status: None,
+            });
+            return None;
+        }
+    };
+    let status = reply.status();
+    let body = reply.text().await.ok();
+    requests.push(Request {
+        endpoint,
+        latency: started.elapsed(),
+        status: Some(status),
+    });
+    body.filter(|_| status.is_success())
+}
+
+/// Addresses in a reply of `/api/state`, a list of address and entry pairs
+fn sensor_addrs(body: &str) -> Vec<String> {
+    let sensors: Vec<(String, serde_json::Value)> = match serde_json::from_str(body) {
+        Ok(sensors) => sensors,
+        Err(_) => return Vec::new(),
+    };
+    sensors.into_iter().map(|(addr, _)| addr).collect()
+}
+
+fn report(requests: &[Request], duration: u64) {
+    let failed = requests
+        .iter()
+        .filter(|request| !request.status.map_or(false, |status| status.is_success()))
+        .count();
+    println!(
+        "{} requests ({:.1}/s), {} failed",
+        requests.len(),
+        requests.len() as f64 / duration.max(1) as f64,
+        failed
+    );
+
+    let mut statuses = BTreeMap::new();
+    for request in requests {
+        let status = request.status.map_or_else(
+            || "connection error".to_owned(),
+            |status| status.to_string(),
+        );
+        *statuses.entry(status).or_insert(0) += 1;
+    }
+    for (status, count) in statuses {
+        println!("  {}: {}", status, count);
+    }
+
+    let mut latencies = BTreeMap::<_, Vec<_>>::new();
+    for request in requests {
+        latencies
+            .entry(request.endpoint)
+            .or_default()
+            .push(request.latency);
+    }
+    for (endpoint, times) in &mut latencies {
+        print_latencies(endpoint, times);
+    }
+}
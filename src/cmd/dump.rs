@@ -0,0 +1,86 @@
+use crate::{
+    config::Config,
+    db::Db,
+    opt::{Dump, LogFormat},
+    sensor::{Quality, RawSensorValues},
+    timestamp::Timestamp,
+};
+use eyre::Context;
+use std::io::{self, Write};
+
+pub(crate) fn run(config: &Config, args: Dump) -> Result<(), eyre::Error> {
+    let db = Db::open_with(&config.db_path, config.db_key.as_ref(), config.max_dbs)
+        .with_context(|| format!("Opening database in {}", config.db_path.display()))?;
+    let txn = db.read_txn()?;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    match args.sensor {
+        Some(addr) => {
+            let log = db
+                .get_flagged_log(&txn, addr, Timestamp::UNIX_EPOCH..Timestamp::MAX, None)?
+                .ok_or_else(|| eyre::format_err!("Unknown sensor {}", addr))?;
+
+            #[derive(serde::Serialize)]
+            struct Entry<'a> {
+                time: Timestamp,
+                values: &'a crate::sensor::SensorValues,
+                quality: Quality,
+            }
+
+            if let LogFormat::Csv = args.format {
+                writeln!(out, "time,temperature,humidity,pressure,quality")?;
+            }
+
+            for (time, values, quality) in &log {
+                match args.format {
+                    LogFormat::Json => {
+                        serde_json::to_writer(
+                            &mut out,
+                            &Entry {
+                                time: *time,
+                                values,
+                                quality: *quality,
+                            },
+                        )?;
+                        writeln!(out)?;
+                    }
+                    LogFormat::Csv => {
+                        let raw = RawSensorValues::from(*values);
+                        writeln!(
+                            out,
+                            "{},{},{},{},{}",
+                            time.as_u32(),
+                            raw.temperature,
+                            raw.humidity,
+                            raw.pressure,
+                            quality.bits()
+                        )?;
+                    }
+                }
+            }
+        }
+        None => {
+            for addr in db.known_addrs(&txn)? {
+                let addr = addr?;
+                let label = db.get_addr(&txn, addr)?.and_then(|entry| entry.label);
+                write!(out, "{}", addr)?;
+                if let Some(label) = label {
+                    write!(out, " {:?}", label)?;
+                }
+                match db.log_stats(&txn, addr)? {
+                    Some(stats) => writeln!(
+                        out,
+                        " entries={} first={} last={}",
+                        stats.entries,
+                        stats.first.as_u32(),
+                        stats.last.as_u32()
+                    )?,
+                    None => writeln!(out, " entries=0")?,
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
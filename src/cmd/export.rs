@@ -0,0 +1,28 @@
+use crate::{config::Config, db::Db, opt::Export};
+use eyre::Context;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+pub(crate) fn run(config: &Config, args: Export) -> Result<(), eyre::Error> {
+    let db = Db::open_with(&config.db_path, config.db_key.as_ref(), config.max_dbs)
+        .with_context(|| format!("Opening database in {}", config.db_path.display()))?;
+    match args.file {
+        Some(path) => {
+            let file = File::create(&path)
+                .with_context(|| format!("Could not create {}", path.display()))?;
+            let mut out = BufWriter::new(file);
+            let readings = crate::export::export(&db, &mut out)?;
+            out.flush()?;
+            println!("Exported {} readings to {}", readings, path.display());
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut out = BufWriter::new(stdout.lock());
+            crate::export::export(&db, &mut out)?;
+            out.flush()?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,29 @@
+use crate::{config::Config, db::Db, opt::Fsck};
+use eyre::Context;
+
+pub(crate) fn run(config: &Config, args: Fsck) -> Result<(), eyre::Error> {
+    let db = Db::open_with(&config.db_path, config.db_key.as_ref(), config.max_dbs)
+        .with_context(|| format!("Opening database in {}", config.db_path.display()))?;
+    let report = db.check(args.repair)?;
+
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    println!(
+        "Checked {} log entries and {} sensors, found {} problems",
+        report.log_entries,
+        report.addr_entries,
+        report.problems.len()
+    );
+    if args.repair {
+        println!("Repaired {} entries", report.repaired);
+    } else if !report.problems.is_empty() {
+        println!("Run with --repair to delete or reset the broken entries");
+    }
+
+    if report.problems.is_empty() || args.repair {
+        Ok(())
+    } else {
+        Err(eyre::format_err!("Database has broken entries"))
+    }
+}
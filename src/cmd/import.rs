@@ -0,0 +1,47 @@
+use crate::{
+    config::Config,
+    db::{AddrDbEntry, Db},
+    import::{parse_log, prepare},
+    opt::Import,
+};
+use eyre::Context;
+use std::fs;
+
+pub(crate) fn run(config: &Config, args: Import) -> Result<(), eyre::Error> {
+    let content = fs::read_to_string(&args.file)
+        .with_context(|| format!("Could not read {}", args.file.display()))?;
+    let log = parse_log(&content, args.format)?;
+
+    let db = Db::open_with(&config.db_path, config.db_key.as_ref(), config.max_dbs)
+        .with_context(|| format!("Opening database in {}", config.db_path.display()))?;
+    let known = db.get_addr(&db.read_txn()?, args.sensor)?.is_some();
+    if !known {
+        let mut txn = db.write_txn()?;
+        db.put_addr(&mut txn, args.sensor, &AddrDbEntry::default())?;
+        txn.commit().map_err(crate::db::Error::from)?;
+        println!("Memorized new sensor {}", args.sensor);
+    }
+
+    let import = prepare(&db, args.sensor, log, args.overwrite)?;
+    db.write_log(&import.batch)?;
+    println!(
+        "Imported {} entries for {}",
+        import.batch.len(),
+        args.sensor
+    );
+    if !import.skipped.is_empty() {
+        let times = import
+            .skipped
+            .iter()
+            .map(|time| time.as_u32().to_string())
+            .collect::<Vec<_>>();
+        println!(
+            "Skipped {} entries that are logged already or repeated, pass --overwrite to replace \
+             logged ones: {}",
+            times.len(),
+            times.join(", ")
+        );
+    }
+
+    Ok(())
+}
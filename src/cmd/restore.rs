@@ -0,0 +1,17 @@
+use crate::{config::Config, db::Db, opt::Restore};
+use eyre::Context;
+use std::{fs::File, io::BufReader};
+
+pub(crate) fn run(config: &Config, args: Restore) -> Result<(), eyre::Error> {
+    let file = File::open(&args.file)
+        .with_context(|| format!("Could not read {}", args.file.display()))?;
+    let db = Db::open_with(&config.db_path, config.db_key.as_ref(), config.max_dbs)
+        .with_context(|| format!("Opening database in {}", config.db_path.display()))?;
+    let imported = crate::export::import(&db, BufReader::new(file))
+        .with_context(|| format!("Importing {}", args.file.display()))?;
+    println!(
+        "Restored {} sensors with {} readings",
+        imported.sensors, imported.readings
+    );
+    Ok(())
+}
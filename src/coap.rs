@@ -0,0 +1,368 @@
+use crate::{bluetooth::BluetoothAddress, db, sensor::SensorState};
+use coap_lite::{CoapOption, MessageClass, MessageType, Packet, RequestType, ResponseType};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    net::SocketAddr,
+};
+use tokio::{net::UdpSocket, sync::broadcast};
+
+/// Largest message size a coap endpoint has to expect without blockwise transfers
+const MAX_MESSAGE_SIZE: usize = 1152;
+/// Further registrations get served once without updates
+const MAX_OBSERVERS: usize = 64;
+
+const CONTENT_FORMAT_LINK: u32 = 40;
+const CONTENT_FORMAT_JSON: u32 = 50;
+
+/// Observe option values of a GET
+const OBSERVE_REGISTER: u32 = 0;
+const OBSERVE_DEREGISTER: u32 = 1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Resource {
+    /// `.well-known/core`
+    Discovery,
+    /// `sensors`, all sensors by address
+    Sensors,
+    /// `sensors/{addr}`
+    Sensor(BluetoothAddress),
+}
+
+impl Resource {
+    fn from_path(path: &[String]) -> Option<Self> {
+        match path {
+            [well_known, core] if well_known == ".well-known" && core == "core" => {
+                Some(Resource::Discovery)
+            }
+            [sensors] if sensors == "sensors" => Some(Resource::Sensors),
+            [sensors, addr] if sensors == "sensors" => {
+                BluetoothAddress::parse_str(addr).ok().map(Resource::Sensor)
+            }
+            _ => None,
+        }
+    }
+
+    fn changed_by(self, update: &BTreeMap<BluetoothAddress, SensorState>) -> bool {
+        match self {
+            Resource::Discovery => false,
+            Resource::Sensors => true,
+            Resource::Sensor(addr) => update.contains_key(&addr),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Reading {
+    label: Option<String>,
+    #[serde(flatten)]
+    state: SensorState,
+}
+
+struct Observer {
+    resource: Resource,
+    sequence: u32,
+    /// of the last notification, a reset answering it ends the observation
+    message_id: u16,
+}
+
+struct Server {
+    ctx: super::Context,
+    socket: UdpSocket,
+    /// keyed by client and request token
+    observers: HashMap<(SocketAddr, Vec<u8>), Observer>,
+    message_id: u16,
+}
+
+/// Serves sensor values as observable coap resources until the update channel closes
+pub(crate) async fn serve(ctx: super::Context, socket: UdpSocket) {
+    let mut updates = ctx.updates.subscribe();
+    let mut server = Server {
+        ctx,
+        socket,
+        observers: HashMap::new(),
+        message_id: rand::random(),
+    };
+    let mut buf = [0; MAX_MESSAGE_SIZE];
+
+    loop {
+        tokio::select! {
+            received = server.socket.recv_from(&mut buf) => {
+                let (len, peer) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        tracing::warn!("Receiving coap message failed: {}", e);
+                        continue;
+                    }
+                };
+                match Packet::from_bytes(&buf[..len]) {
+                    Ok(packet) => server.handle(packet, peer).await,
+                    Err(e) => tracing::debug!("Invalid coap message from {}: {:?}", peer, e),
+                }
+            }
+            update = updates.recv() => match update {
+                Ok(events) => server.notify(&crate::state::states(&events)).await,
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    // no idea what changed so everyone gets the current state
+                    let everything = server.ctx.sensors.read().await.clone();
+                    server.notify(&everything).await;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+impl Server {
+    fn next_message_id(&mut self) -> u16 {
+        self.message_id = self.message_id.wrapping_add(1);
+        self.message_id
+    }
+
+    async fn handle(&mut self, request: Packet, peer: SocketAddr) {
+        let message_type = request.header.get_type();
+        match message_type {
+            MessageType::Reset => {
+                let message_id = request.header.message_id;
+                self.observers.retain(|(client, _), observer| {
+                    *client != peer || observer.message_id != message_id
+                });
+                return;
+            }
+            MessageType::Acknowledgement => return,
+            MessageType::Confirmable | MessageType::NonConfirmable => (),
+        }
+
+        let mut response = Packet::new();
+        if message_type == MessageType::Confirmable {
+            response.header.set_type(MessageType::Acknowledgement);
+            response.header.message_id = request.header.message_id;
+        } else {
+            response.header.set_type(MessageType::NonConfirmable);
+            response.header.message_id = self.next_message_id();
+        }
+        let token = request.get_token().clone();
+        response.set_token(token.clone());
+
+        if request.header.code != MessageClass::Request(RequestType::Get) {
+            response.header.code = MessageClass::Response(ResponseType::MethodNotAllowed);
+            self.send(&response, peer).await;
+            return;
+        }
+
+        let path = request
+            .get_option(CoapOption::UriPath)
+            .map(|segments| {
+                segments
+                    .iter()
+                    .map(|segment| String::from_utf8_lossy(segment).into_owned())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let resource = match Resource::from_path(&path) {
+            Some(resource) => resource,
+            None => {
+                response.header.code = MessageClass::Response(ResponseType::NotFound);
+                self.send(&response, peer).await;
+                return;
+            }
+        };
+
+        let key = (peer, token);
+        let observe = request
+            .get_option(CoapOption::Observe)
+            .and_then(|values| values.front())
+            .map(|value| decode_uint(value));
+        match observe {
+            Some(OBSERVE_REGISTER) if resource != Resource::Discovery => {
+                if self.observers.contains_key(&key) || self.observers.len() < MAX_OBSERVERS {
+                    self.observers.insert(
+                        key.clone(),
+                        Observer {
+                            resource,
+                            sequence: 0,
+                            message_id: response.header.message_id,
+                        },
+                    );
+                }
+            }
+            Some(OBSERVE_DEREGISTER) => {
+                self.observers.remove(&key);
+            }
+            _ => (),
+        }
+
+        let sensors = self.ctx.sensors.read().await.clone();
+        let sequence = self.observers.get(&key).map(|observer| observer.sequence);
+        if !self.fill(&mut response, resource, &sensors, sequence) {
+            self.observers.remove(&key);
+        }
+        self.send(&response, peer).await;
+    }
+
+    /// Sends a notification to every observer of a resource in `update`
+    async fn notify(&mut self, update: &BTreeMap<BluetoothAddress, SensorState>) {
+        let sensors = self.ctx.sensors.read().await.clone();
+        let keys = self
+            .observers
+            .iter()
+            .filter(|(_, observer)| observer.resource.changed_by(update))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        for key in keys {
+            let message_id = self.next_message_id();
+            let observer = match self.observers.get_mut(&key) {
+                Some(observer) => observer,
+                None => continue,
+            };
+            // the option only has room for 24 bits
+            observer.sequence = (observer.sequence + 1) & 0xFF_FFFF;
+            observer.message_id = message_id;
+            let (resource, sequence) = (observer.resource, observer.sequence);
+
+            let mut notification = Packet::new();
+            notification.header.set_type(MessageType::NonConfirmable);
+            notification.header.message_id = message_id;
+            notification.set_token(key.1.clone());
+            if !self.fill(&mut notification, resource, &sensors, Some(sequence)) {
+                // sensor got forgotten, the 4.04 tells the client the observation is over
+                self.observers.remove(&key);
+            }
+            self.send(&notification, key.0).await;
+        }
+    }
+
+    /// Fills in code, options and payload of a reply with the representation of `resource`,
+    /// returns `false` if it doesn't exist
+    fn fill(
+        &self,
+        packet: &mut Packet,
+        resource: Resource,
+        sensors: &BTreeMap<BluetoothAddress, SensorState>,
+        sequence: Option<u32>,
+    ) -> bool {
+        let rendered = match resource {
+            Resource::Discovery => Ok(Some((CONTENT_FORMAT_LINK, discovery(sensors)))),
+            Resource::Sensors => self
+                .readings(sensors.iter().map(|(addr, state)| (*addr, *state)))
+                .map(|readings| {
+                    Some((CONTENT_FORMAT_JSON, serde_json::to_vec(&readings).unwrap()))
+                }),
+            Resource::Sensor(addr) => match sensors.get(&addr) {
+                Some(state) => self
+                    .readings(std::iter::once((addr, *state)))
+                    .map(|readings| {
+                        let reading = readings.values().next().unwrap();
+                        Some((CONTENT_FORMAT_JSON, serde_json::to_vec(reading).unwrap()))
+                    }),
+                None => Ok(None),
+            },
+        };
+
+        match rendered {
+            Ok(Some((content_format, payload))) => {
+                packet.header.code = MessageClass::Response(ResponseType::Content);
+                if let Some(sequence) = sequence {
+                    packet.add_option(CoapOption::Observe, encode_uint(sequence));
+                }
+                packet.add_option(CoapOption::ContentFormat, encode_uint(content_format));
+                packet.payload = payload;
+                true
+            }
+            Ok(None) => {
+                packet.header.code = MessageClass::Response(ResponseType::NotFound);
+                false
+            }
+            Err(e) => {
+                let e: &dyn std::error::Error = &e;
+                tracing::error!(e);
+                packet.header.code = MessageClass::Response(ResponseType::InternalServerError);
+                false
+            }
+        }
+    }
+
+    fn readings(
+        &self,
+        sensors: impl Iterator<Item = (BluetoothAddress, SensorState)>,
+    ) -> Result<BTreeMap<BluetoothAddress, Reading>, db::Error> {
+        let txn = self.ctx.db.read_txn()?;
+        sensors
+            .map(|(addr, state)| {
+                let label = self
+                    .ctx
+                    .db
+                    .get_addr(&txn, addr)?
+                    .and_then(|entry| entry.label);
+                Ok((addr, Reading { label, state }))
+            })
+            .collect()
+    }
+
+    async fn send(&self, packet: &Packet, peer: SocketAddr) {
+        let bytes = match packet.to_bytes() {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                tracing::error!("Can't encode coap message: {:?}", e);
+                return;
+            }
+        };
+        if let Err(e) = self.socket.send_to(&bytes, peer).await {
+            tracing::warn!("Sending coap message to {} failed: {}", peer, e);
+        }
+    }
+}
+
+/// CoRE link format listing of all resources
+fn discovery(sensors: &BTreeMap<BluetoothAddress, SensorState>) -> Vec<u8> {
+    let mut links = format!("</sensors>;ct={};obs", CONTENT_FORMAT_JSON);
+    for addr in sensors.keys() {
+        let _ = write!(links, ",</sensors/{}>;ct={};obs", addr, CONTENT_FORMAT_JSON);
+    }
+    links.into_bytes()
+}
+
+/// Shortest big endian encoding, zero is empty
+fn encode_uint(n: u32) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    let leading = bytes.iter().take_while(|b| **b == 0).count();
+    bytes[leading..].to_vec()
+}
+
+fn decode_uint(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .take(4)
+        .fold(0, |n, byte| (n << 8) | u32::from(*byte))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn uint_options() {
+        assert_eq!(encode_uint(0), Vec::<u8>::new());
+        assert_eq!(encode_uint(1), vec![1]);
+        assert_eq!(encode_uint(0x01_0000), vec![1, 0, 0]);
+        for n in &[0, 1, 255, 256, 0xFF_FFFF] {
+            assert_eq!(decode_uint(&encode_uint(*n)), *n);
+        }
+    }
+
+    #[test]
+    fn resource_paths() {
+        let path = |segments: &[&str]| {
+            Resource::from_path(&segments.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+        };
+        assert_eq!(path(&[".well-known", "core"]), Some(Resource::Discovery));
+        assert_eq!(path(&["sensors"]), Some(Resource::Sensors));
+        assert_eq!(
+            path(&["sensors", "00:11:22:33:FF:EE"]),
+            Some(Resource::Sensor(BluetoothAddress::from(0x0011_2233_FFEE)))
+        );
+        assert_eq!(path(&["sensors", "garden"]), None);
+        assert_eq!(path(&[]), None);
+    }
+}
@@ -1,24 +1,311 @@
+#[cfg(feature = "alerts")]
+use crate::alert::{Rule, RuleConfig};
+#[cfg(feature = "mqtt")]
+use crate::homeassistant::HomeAssistantConfig;
+use crate::{
+    anomaly::AnomalyConfig,
+    bluetooth, db, dbus,
+    dummy::{DemoConfig, DemoRanges},
+    forecast::ForecastConfig,
+    hook::HookConfig,
+    i18n::Language,
+    logging,
+    presence::PresenceConfig,
+    pws::PwsConfig,
+    script::ScriptConfig,
+    sink::SinkConfig,
+    snapshot::SnapshotConfig,
+    tenant::TenantConfig,
+};
+use clap::Clap;
 use directories_next::ProjectDirs;
 use eyre::Context;
 use std::{
     fs,
-    net::{IpAddr, Ipv4Addr},
-    num::NonZeroU8,
-    path::PathBuf,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    num::{NonZeroU32, NonZeroU8, NonZeroUsize},
+    path::{Path, PathBuf},
+    time::Duration,
 };
+#[cfg(feature = "mqtt")]
 use tokio_mqtt as mqtt;
 
-#[derive(serde::Deserialize)]
-struct EnvConfig {
+/// Configuration values that can be set on the command line, in the environment or in the config
+/// file, in that order of precedence
+#[derive(serde::Deserialize, Clap, Default)]
+pub(crate) struct ConfigSource {
+    /// url of the mqtt server sensor values get published to
+    #[clap(long)]
     mqtt_server_url: Option<url::Url>,
+    /// ca certificate used for mqtts urls
+    #[clap(long)]
     mqtt_cert_file: Option<PathBuf>,
-    #[serde(default = "default_host")]
-    pub host: IpAddr,
-    #[serde(default = "default_port")]
-    pub port: u16,
-    #[serde(default = "default_db_path")]
-    pub db_path: PathBuf,
-    pub demo: Option<NonZeroU8>,
+    /// topic like `sensors/weatherstation/snapshot` that gets the state of every sensor as one
+    /// retained json document whenever something changes
+    #[clap(long)]
+    mqtt_snapshot_topic: Option<String>,
+    /// client id sent to the mqtt server, every central on the same server needs its own
+    #[clap(long)]
+    mqtt_client_id: Option<String>,
+    /// seconds between pings to the mqtt server, 0 turns them off
+    #[clap(long)]
+    mqtt_keep_alive: Option<u16>,
+    /// whether the mqtt server forgets the session of the central when it disconnects
+    #[clap(long)]
+    mqtt_clean_session: Option<bool>,
+    /// seconds until reconnecting after losing the mqtt server, doubled after every failed
+    /// attempt
+    #[clap(long)]
+    mqtt_reconnect_delay: Option<u64>,
+    /// seconds between reconnect attempts at most
+    #[clap(long)]
+    mqtt_max_reconnect_delay: Option<u64>,
+    /// address the http server listens on
+    #[clap(long)]
+    host: Option<IpAddr>,
+    /// port the http server listens on
+    #[clap(long)]
+    port: Option<u16>,
+    /// addresses the http server listens on like `[::1]:8080`, replaces host and port
+    #[clap(long)]
+    listen: Option<Vec<SocketAddr>>,
+    /// path all pages and the api are served under like `/weather`, for reverse proxies
+    #[clap(long)]
+    base_path: Option<String>,
+    /// directory containing the database
+    #[clap(long)]
+    db_path: Option<PathBuf>,
+    /// hex encoded 32 byte key the database gets encrypted with, all of it apart from its
+    /// version and start counter
+    #[clap(skip)]
+    db_key: Option<db::Key>,
+    /// file with the database key, as is or hex encoded
+    #[clap(long)]
+    db_key_file: Option<PathBuf>,
+    /// number of dummy sensors to simulate
+    #[clap(long)]
+    demo: Option<NonZeroU8>,
+    /// seed for the dummy sensors, makes their values reproducible
+    #[clap(long)]
+    demo_seed: Option<u64>,
+    /// seconds between two values of a dummy sensor
+    #[clap(long)]
+    demo_interval: Option<u64>,
+    #[clap(skip)]
+    demo_ranges: Option<DemoRanges>,
+    /// append every bluetooth update to this file so it can be replayed later
+    #[clap(long)]
+    record: Option<PathBuf>,
+    /// bluetooth stack to use, bluez, btleplug or off, defaults to bluez on Linux
+    #[clap(long)]
+    bluetooth_backend: Option<bluetooth::Backend>,
+    /// run without bluetooth, same as the off backend
+    #[clap(long)]
+    no_bluetooth: Option<bool>,
+    /// seconds to wait for a weatherstation to connect
+    #[clap(long)]
+    connect_timeout: Option<u64>,
+    /// seconds to wait for the values of a weatherstation
+    #[clap(long)]
+    read_timeout: Option<u64>,
+    /// seconds to wait for a weatherstation to disconnect on shutdown
+    #[clap(long)]
+    disconnect_timeout: Option<u64>,
+    /// seconds between setting the clocks of connected weatherstations, 0 turns it off
+    #[clap(long)]
+    clock_sync_interval: Option<u64>,
+    /// trade speed for memory usage, meant for small boards like the Raspberry Pi Zero
+    #[clap(long)]
+    low_memory: Option<bool>,
+    /// maximum number of entries in a log reply, defaults to 500 in low memory mode
+    #[clap(long)]
+    max_log_entries: Option<usize>,
+    /// named databases the database can hold, at least the ones of the current layout. Only
+    /// migrating a database of the old layout with one database per sensor needs more. Each
+    /// costs memory in every transaction, defaults to 200
+    #[clap(long)]
+    max_dbs: Option<u32>,
+    /// which log messages to show like `info,ble_weatherstation_central::bluetooth=debug`,
+    /// changeable at runtime over `/api/log_filter`, defaults to info
+    #[clap(long)]
+    log_filter: Option<String>,
+    /// directory crash reports get written to, defaults to `crashes` in the data dir
+    #[clap(long)]
+    crash_dir: Option<PathBuf>,
+    /// seconds between two refreshes of the kiosk view
+    #[clap(long)]
+    kiosk_interval: Option<u64>,
+    /// language of the web ui if the browser doesn't ask for a supported one, en or de
+    #[clap(long)]
+    language: Option<Language>,
+    /// api requests allowed per minute and client ip, unlimited if unset
+    #[clap(long)]
+    rate_limit: Option<NonZeroU32>,
+    /// requests handled at once, further ones wait for a free slot, unlimited if unset
+    #[clap(long)]
+    max_concurrent_requests: Option<NonZeroUsize>,
+    /// largest request body in bytes, only imports come close to the default of 64 MiB
+    #[clap(long)]
+    max_body_size: Option<u64>,
+    /// allow raw reads and writes of gatt characteristics from the web ui for debugging
+    /// firmware, anybody who can reach it can then write to the stations
+    #[clap(long)]
+    gatt_console: Option<bool>,
+    /// port of the grpc server, needs a build with the grpc feature
+    #[clap(long)]
+    grpc_port: Option<u16>,
+    /// port of the coap server for constrained clients, disabled if unset
+    #[clap(long)]
+    coap_port: Option<u16>,
+    /// bus to offer the org.foldu.WeatherstationCentral service on, session or system
+    #[clap(long)]
+    dbus: Option<dbus::Bus>,
+    /// system bus BlueZ and the service are on, an address like `tcp:host=..,port=..` or the
+    /// path of its socket for containers with `/var/run/dbus` mounted
+    #[clap(long)]
+    dbus_system_bus: Option<String>,
+    /// url of a central to follow as a replica, with its base path. Replicas don't use
+    /// bluetooth and get every reading from there
+    #[clap(long)]
+    replicate_from: Option<url::Url>,
+    /// secret the replication stream is guarded by, a central only serves it with one set
+    #[clap(long)]
+    replication_token: Option<String>,
+    /// user to switch to once the database is open and the servers are bound when started as
+    /// root, it needs access to BlueZ over dbus
+    #[clap(long)]
+    run_as_user: Option<String>,
+    /// token that sees every sensor once `[[tenant]]` tables restrict the api
+    #[clap(long)]
+    admin_token: Option<String>,
+    /// new sensors wait in a pending list until they're approved before being logged and
+    /// published
+    #[clap(long)]
+    approve_new_sensors: Option<bool>,
+    #[clap(skip)]
+    pws: Option<PwsConfig>,
+    #[clap(skip)]
+    forecast: Option<ForecastConfig>,
+    #[clap(skip)]
+    anomaly: Option<AnomalyConfig>,
+    #[clap(skip)]
+    script: Option<ScriptConfig>,
+    #[clap(skip)]
+    snapshot: Option<SnapshotConfig>,
+    #[clap(skip)]
+    hook: Option<HookConfig>,
+    #[clap(skip)]
+    adapter: Option<bluetooth::AdapterConfig>,
+    #[clap(skip)]
+    connection: Option<bluetooth::ConnectionParams>,
+    #[clap(skip)]
+    presence: Option<PresenceConfig>,
+    #[clap(skip)]
+    #[cfg(feature = "mqtt")]
+    homeassistant: Option<HomeAssistantConfig>,
+    /// only there to complain about discovery a build without mqtt can't do
+    #[clap(skip)]
+    #[cfg(not(feature = "mqtt"))]
+    homeassistant: Option<serde::de::IgnoredAny>,
+    #[clap(skip)]
+    steering: Option<bluetooth::SteeringConfig>,
+    #[clap(skip)]
+    #[serde(rename = "profile")]
+    profiles: Option<Vec<bluetooth::ProfileConfig>>,
+    #[clap(skip)]
+    #[serde(rename = "sink")]
+    sinks: Option<Vec<SinkConfig>>,
+    #[clap(skip)]
+    #[serde(rename = "tenant")]
+    tenants: Option<Vec<TenantConfig>>,
+    #[clap(skip)]
+    #[serde(rename = "rule")]
+    #[cfg(feature = "alerts")]
+    rules: Option<Vec<RuleConfig>>,
+    /// only there to complain about rules a build without alerts can't check
+    #[clap(skip)]
+    #[serde(rename = "rule")]
+    #[cfg(not(feature = "alerts"))]
+    rules: Option<Vec<serde::de::IgnoredAny>>,
+}
+
+impl ConfigSource {
+    fn from_file(path: &Path) -> Result<Self, eyre::Error> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Could not read config file {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Fills every value that is unset in `self` from `fallback`
+    fn or(self, fallback: Self) -> Self {
+        Self {
+            mqtt_server_url: self.mqtt_server_url.or(fallback.mqtt_server_url),
+            mqtt_cert_file: self.mqtt_cert_file.or(fallback.mqtt_cert_file),
+            mqtt_snapshot_topic: self.mqtt_snapshot_topic.or(fallback.mqtt_snapshot_topic),
+            mqtt_client_id: self.mqtt_client_id.or(fallback.mqtt_client_id),
+            mqtt_keep_alive: self.mqtt_keep_alive.or(fallback.mqtt_keep_alive),
+            mqtt_clean_session: self.mqtt_clean_session.or(fallback.mqtt_clean_session),
+            mqtt_reconnect_delay: self.mqtt_reconnect_delay.or(fallback.mqtt_reconnect_delay),
+            mqtt_max_reconnect_delay: self
+                .mqtt_max_reconnect_delay
+                .or(fallback.mqtt_max_reconnect_delay),
+            host: self.host.or(fallback.host),
+            port: self.port.or(fallback.port),
+            listen: self.listen.or(fallback.listen),
+            base_path: self.base_path.or(fallback.base_path),
+            db_path: self.db_path.or(fallback.db_path),
+            db_key: self.db_key.or(fallback.db_key),
+            db_key_file: self.db_key_file.or(fallback.db_key_file),
+            demo: self.demo.or(fallback.demo),
+            demo_seed: self.demo_seed.or(fallback.demo_seed),
+            demo_interval: self.demo_interval.or(fallback.demo_interval),
+            demo_ranges: self.demo_ranges.or(fallback.demo_ranges),
+            record: self.record.or(fallback.record),
+            bluetooth_backend: self.bluetooth_backend.or(fallback.bluetooth_backend),
+            no_bluetooth: self.no_bluetooth.or(fallback.no_bluetooth),
+            connect_timeout: self.connect_timeout.or(fallback.connect_timeout),
+            read_timeout: self.read_timeout.or(fallback.read_timeout),
+            disconnect_timeout: self.disconnect_timeout.or(fallback.disconnect_timeout),
+            clock_sync_interval: self.clock_sync_interval.or(fallback.clock_sync_interval),
+            low_memory: self.low_memory.or(fallback.low_memory),
+            max_log_entries: self.max_log_entries.or(fallback.max_log_entries),
+            max_dbs: self.max_dbs.or(fallback.max_dbs),
+            log_filter: self.log_filter.or(fallback.log_filter),
+            crash_dir: self.crash_dir.or(fallback.crash_dir),
+            kiosk_interval: self.kiosk_interval.or(fallback.kiosk_interval),
+            language: self.language.or(fallback.language),
+            rate_limit: self.rate_limit.or(fallback.rate_limit),
+            max_concurrent_requests: self
+                .max_concurrent_requests
+                .or(fallback.max_concurrent_requests),
+            max_body_size: self.max_body_size.or(fallback.max_body_size),
+            gatt_console: self.gatt_console.or(fallback.gatt_console),
+            grpc_port: self.grpc_port.or(fallback.grpc_port),
+            coap_port: self.coap_port.or(fallback.coap_port),
+            dbus: self.dbus.or(fallback.dbus),
+            dbus_system_bus: self.dbus_system_bus.or(fallback.dbus_system_bus),
+            replicate_from: self.replicate_from.or(fallback.replicate_from),
+            replication_token: self.replication_token.or(fallback.replication_token),
+            run_as_user: self.run_as_user.or(fallback.run_as_user),
+            admin_token: self.admin_token.or(fallback.admin_token),
+            approve_new_sensors: self.approve_new_sensors.or(fallback.approve_new_sensors),
+            pws: self.pws.or(fallback.pws),
+            forecast: self.forecast.or(fallback.forecast),
+            anomaly: self.anomaly.or(fallback.anomaly),
+            script: self.script.or(fallback.script),
+            snapshot: self.snapshot.or(fallback.snapshot),
+            hook: self.hook.or(fallback.hook),
+            adapter: self.adapter.or(fallback.adapter),
+            connection: self.connection.or(fallback.connection),
+            presence: self.presence.or(fallback.presence),
+            homeassistant: self.homeassistant.or(fallback.homeassistant),
+            steering: self.steering.or(fallback.steering),
+            profiles: self.profiles.or(fallback.profiles),
+            sinks: self.sinks.or(fallback.sinks),
+            tenants: self.tenants.or(fallback.tenants),
+            rules: self.rules.or(fallback.rules),
+        }
+    }
 }
 
 fn default_host() -> IpAddr {
@@ -29,47 +316,463 @@ fn default_port() -> u16 {
     8080
 }
 
-fn default_db_path() -> PathBuf {
-    let dirs = ProjectDirs::from("org", "foldu", env!("CARGO_PKG_NAME"))
+/// `weather/` and `/weather` both become `/weather`, `/` becomes nothing
+fn normalize_base_path(path: &str) -> String {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", path)
+    }
+}
+
+fn project_dirs() -> ProjectDirs {
+    ProjectDirs::from("org", "foldu", env!("CARGO_PKG_NAME"))
         .ok_or_else(|| eyre::format_err!("Could not get project directories"))
-        .unwrap();
-    dirs.data_dir()
+        .unwrap()
+}
+
+fn default_db_path() -> PathBuf {
+    project_dirs()
+        .data_dir()
         .join(concat!(env!("CARGO_PKG_NAME"), ".mdb"))
 }
 
+fn default_config_file() -> PathBuf {
+    project_dirs().config_dir().join("config.toml")
+}
+
 pub(crate) struct Config {
+    #[cfg(feature = "mqtt")]
     pub mqtt_options: Option<mqtt::ConnectOptions>,
-    pub host: IpAddr,
-    pub port: u16,
+    #[cfg(feature = "mqtt")]
+    pub mqtt_session: mqtt::Session,
+    #[cfg(feature = "mqtt")]
+    pub mqtt_snapshot_topic: Option<String>,
+    /// every address the http server listens on, the grpc and coap servers use their ips
+    pub listen: Vec<SocketAddr>,
+    /// empty or with a leading and without a trailing slash
+    pub base_path: String,
     pub db_path: PathBuf,
-    pub demo: Option<NonZeroU8>,
+    pub db_key: Option<db::Key>,
+    pub max_dbs: u32,
+    pub demo: Option<DemoConfig>,
+    pub record: Option<PathBuf>,
+    pub bluetooth_backend: bluetooth::Backend,
+    pub bluetooth_timeouts: bluetooth::Timeouts,
+    pub clock_sync_interval: Option<Duration>,
+    pub low_memory: bool,
+    pub max_log_entries: Option<usize>,
+    pub log_filter: String,
+    pub crash_dir: PathBuf,
+    /// the config file that got read, if any
+    pub config_file: Option<PathBuf>,
+    pub kiosk_interval: Duration,
+    pub language: Language,
+    pub rate_limit: Option<NonZeroU32>,
+    pub max_concurrent_requests: Option<NonZeroUsize>,
+    pub max_body_size: u64,
+    pub gatt_console: bool,
+    pub grpc_port: Option<u16>,
+    pub coap_port: Option<u16>,
+    pub dbus: Option<dbus::Bus>,
+    /// address of the system bus if it isn't the default one
+    pub dbus_system_bus: Option<String>,
+    pub replicate_from: Option<url::Url>,
+    pub replication_token: Option<String>,
+    pub run_as_user: Option<String>,
+    pub admin_token: Option<String>,
+    pub approve_new_sensors: bool,
+    pub pws: Option<PwsConfig>,
+    pub forecast: Option<ForecastConfig>,
+    pub anomaly: Option<AnomalyConfig>,
+    pub script: Option<ScriptConfig>,
+    pub snapshot: Option<SnapshotConfig>,
+    pub hook: Option<HookConfig>,
+    pub adapter: Option<bluetooth::AdapterConfig>,
+    pub connection: Option<bluetooth::ConnectionParams>,
+    pub presence: Option<PresenceConfig>,
+    /// entities announced to Home Assistant over mqtt
+    #[cfg(feature = "mqtt")]
+    pub homeassistant: Option<HomeAssistantConfig>,
+    pub steering: Option<bluetooth::SteeringConfig>,
+    /// device profiles from the config file followed by the builtin ones
+    pub profiles: bluetooth::Profiles,
+    pub sinks: Vec<SinkConfig>,
+    /// sensors each api token may see, the api is open to everybody without them
+    pub tenants: Vec<TenantConfig>,
+    #[cfg(feature = "alerts")]
+    pub rules: Vec<Rule>,
 }
 
 impl Config {
-    pub fn from_env() -> Result<Self, eyre::Error> {
-        let env_config: EnvConfig =
+    /// Merges `cli` with the environment and the config file.
+    /// Without an explicit `config_file` the one in the project config dir is used if it exists.
+    pub fn load(cli: ConfigSource, config_file: Option<&Path>) -> Result<Self, eyre::Error> {
+        let env_config: ConfigSource =
             envy::from_env().context("Could not read config from environment")?;
-        let mqtt_options = env_config.mqtt_server_url.as_ref().map(|url| -> Result<_, eyre::Error> {
-            let ssl = if url.scheme() == "mqtts" {
-                let cert_path = env_config
-                    .mqtt_cert_file
-                    .as_ref()
-                    .ok_or_else(|| eyre::format_err!("Need a cert file for mqtts url but environment variable MQTT_CERT_FILE was not set"))?;
-                let pem = fs::read(&cert_path)
-                    .with_context(|| eyre::format_err!("Could not read cert pem from {}", cert_path.display()))?;
-                mqtt::Ssl::WithCert(pem)
-            } else {
-                mqtt::Ssl::None
+
+        let config_file = match config_file {
+            Some(path) => Some(path.to_owned()),
+            None => Some(default_config_file()).filter(|path| path.exists()),
+        };
+        let file_config = match config_file {
+            Some(ref path) => ConfigSource::from_file(path)?,
+            None => ConfigSource::default(),
+        };
+
+        Ok(Self {
+            config_file,
+            ..Self::from_source(cli.or(env_config).or(file_config))?
+        })
+    }
+
+    /// Ips of the listen addresses without duplicates
+    pub fn listen_ips(&self) -> Vec<IpAddr> {
+        let mut ips = Vec::new();
+        for addr in &self.listen {
+            if !ips.contains(&addr.ip()) {
+                ips.push(addr.ip());
+            }
+        }
+        ips
+    }
+
+    fn from_source(source: ConfigSource) -> Result<Self, eyre::Error> {
+        #[cfg(feature = "mqtt")]
+        let mqtt_options = source
+            .mqtt_server_url
+            .as_ref()
+            .map(|url| -> Result<_, eyre::Error> {
+                let ssl = if url.scheme() == "mqtts" {
+                    let cert_path = source.mqtt_cert_file.as_ref().ok_or_else(|| {
+                        eyre::format_err!(
+                            "Need a cert file for mqtts url but mqtt_cert_file was not set"
+                        )
+                    })?;
+                    let pem = fs::read(&cert_path).with_context(|| {
+                        eyre::format_err!("Could not read cert pem from {}", cert_path.display())
+                    })?;
+                    mqtt::Ssl::WithCert(pem)
+                } else {
+                    mqtt::Ssl::None
+                };
+                mqtt::ConnectOptions::new(&url, ssl).map_err(|e| e.into())
+            })
+            .transpose()?;
+        #[cfg(not(feature = "mqtt"))]
+        if source.mqtt_server_url.is_some() {
+            return Err(eyre::format_err!(
+                "mqtt_server_url is set but this build doesn't include the mqtt feature"
+            ));
+        }
+        #[cfg(not(feature = "mqtt"))]
+        if source.homeassistant.is_some() {
+            return Err(eyre::format_err!(
+                "A [homeassistant] table is configured but this build doesn't include the mqtt \
+                 feature"
+            ));
+        }
+        #[cfg(feature = "mqtt")]
+        if source.homeassistant.is_some() && mqtt_options.is_none() {
+            return Err(eyre::format_err!(
+                "Home Assistant discovery needs mqtt_server_url"
+            ));
+        }
+        if source.mqtt_snapshot_topic.is_some() && source.mqtt_server_url.is_none() {
+            return Err(eyre::format_err!(
+                "mqtt_snapshot_topic is set but mqtt_server_url isn't"
+            ));
+        }
+        #[cfg(feature = "mqtt")]
+        let mqtt_session = {
+            let client_id = source
+                .mqtt_client_id
+                .clone()
+                .unwrap_or_else(|| "ble-weatherstation-central".to_owned());
+            if client_id.is_empty() {
+                return Err(eyre::format_err!("mqtt_client_id can't be empty"));
+            }
+            let reconnect_delay = Duration::from_secs(source.mqtt_reconnect_delay.unwrap_or(1));
+            if reconnect_delay == Duration::from_secs(0) {
+                return Err(eyre::format_err!("mqtt_reconnect_delay can't be 0"));
+            }
+            mqtt::Session {
+                client_id,
+                keep_alive: source.mqtt_keep_alive.unwrap_or(60),
+                clean_session: source.mqtt_clean_session.unwrap_or(true),
+                reconnect_delay,
+                max_reconnect_delay: Duration::from_secs(
+                    source.mqtt_max_reconnect_delay.unwrap_or(60),
+                )
+                .max(reconnect_delay),
+            }
+        };
+
+        let db_key =
+            match (source.db_key, source.db_key_file) {
+                (Some(_), Some(_)) => {
+                    return Err(eyre::format_err!(
+                        "Only one of db_key and db_key_file can be set"
+                    ))
+                }
+                (Some(key), None) => Some(key),
+                (None, Some(path)) => Some(db::Key::read(&path).with_context(|| {
+                    format!("Could not read database key from {}", path.display())
+                })?),
+                (None, None) => None,
             };
-            mqtt::ConnectOptions::new(&url, ssl).map_err(|e| e.into())
-        }).transpose()?;
+
+        let listen = match source.listen {
+            Some(listen) if !listen.is_empty() => listen,
+            _ => vec![SocketAddr::from((
+                source.host.unwrap_or_else(default_host),
+                source.port.unwrap_or_else(default_port),
+            ))],
+        };
+
+        let (demo_seed, demo_interval, demo_ranges) =
+            (source.demo_seed, source.demo_interval, source.demo_ranges);
+        if let Some(ref ranges) = demo_ranges {
+            ranges.validate()?;
+        }
+        let demo = source.demo.map(|sensors| DemoConfig {
+            sensors,
+            seed: demo_seed,
+            interval: Duration::from_secs(demo_interval.unwrap_or(30)),
+            ranges: demo_ranges.unwrap_or_default(),
+        });
+
+        #[cfg(feature = "alerts")]
+        let rules = source
+            .rules
+            .unwrap_or_default()
+            .into_iter()
+            .map(Rule::from_config)
+            .collect::<Result<Vec<_>, _>>()?;
+        #[cfg(feature = "alerts")]
+        if source.anomaly.is_none() {
+            if let Some(rule) = rules.iter().find(|rule| rule.needs_anomalies()) {
+                return Err(eyre::format_err!(
+                    "Rule {} needs an [anomaly] table to detect anomalies",
+                    rule.name()
+                ));
+            }
+        }
+        #[cfg(not(feature = "alerts"))]
+        if source.rules.map_or(false, |rules| !rules.is_empty()) {
+            return Err(eyre::format_err!(
+                "Alert rules are configured but this build doesn't include the alerts feature"
+            ));
+        }
+
+        let profiles = source
+            .profiles
+            .unwrap_or_default()
+            .into_iter()
+            .map(bluetooth::Profile::from_config)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if let Some(ref connection) = source.connection {
+            connection
+                .validate()
+                .context("Invalid connection parameters")?;
+        }
+
+        let max_dbs = source.max_dbs.unwrap_or(db::DEFAULT_MAX_DBS);
+        if max_dbs < db::NAMED_DBS {
+            return Err(eyre::format_err!(
+                "max_dbs must be at least {}",
+                db::NAMED_DBS
+            ));
+        }
+
+        if source.replicate_from.is_some() && source.replication_token.is_none() {
+            return Err(eyre::format_err!(
+                "replicate_from needs the replication_token of the primary"
+            ));
+        }
+
+        // only the http server knows about tenants, the others show every sensor to anybody
+        if source
+            .tenants
+            .as_ref()
+            .map_or(false, |tenants| !tenants.is_empty())
+        {
+            let unscoped = [
+                ("gatt_console", source.gatt_console.unwrap_or(false)),
+                ("coap_port", source.coap_port.is_some()),
+                ("grpc_port", source.grpc_port.is_some()),
+                ("dbus", source.dbus.is_some()),
+            ];
+            if let Some((name, _)) = unscoped.iter().find(|(_, enabled)| *enabled) {
+                return Err(eyre::format_err!(
+                    "{} can't be used with tenants, it isn't restricted to their sensors",
+                    name
+                ));
+            }
+        }
+
+        let low_memory = source.low_memory.unwrap_or(false);
+        let max_log_entries = match source.max_log_entries {
+            None if low_memory => Some(500),
+            max_log_entries => max_log_entries,
+        };
 
         Ok(Self {
+            #[cfg(feature = "mqtt")]
             mqtt_options,
-            host: env_config.host,
-            port: env_config.port,
-            db_path: env_config.db_path,
-            demo: env_config.demo,
+            #[cfg(feature = "mqtt")]
+            mqtt_session,
+            #[cfg(feature = "mqtt")]
+            mqtt_snapshot_topic: source.mqtt_snapshot_topic,
+            listen,
+            base_path: source
+                .base_path
+                .as_deref()
+                .map(normalize_base_path)
+                .unwrap_or_default(),
+            db_path: source.db_path.unwrap_or_else(default_db_path),
+            db_key,
+            max_dbs,
+            demo,
+            record: source.record,
+            bluetooth_backend: if source.no_bluetooth.unwrap_or(false) {
+                bluetooth::Backend::Off
+            } else {
+                source.bluetooth_backend.unwrap_or_default()
+            },
+            bluetooth_timeouts: bluetooth::Timeouts {
+                connect: Duration::from_secs(source.connect_timeout.unwrap_or(30)),
+                read: Duration::from_secs(source.read_timeout.unwrap_or(10)),
+                disconnect: Duration::from_secs(source.disconnect_timeout.unwrap_or(10)),
+            },
+            clock_sync_interval: match source.clock_sync_interval.unwrap_or(6 * 60 * 60) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            low_memory,
+            max_log_entries,
+            log_filter: source
+                .log_filter
+                .unwrap_or_else(|| logging::DEFAULT_DIRECTIVES.to_owned()),
+            crash_dir: source
+                .crash_dir
+                .unwrap_or_else(|| project_dirs().data_dir().join("crashes")),
+            config_file: None,
+            kiosk_interval: Duration::from_secs(source.kiosk_interval.unwrap_or(60)),
+            language: source.language.unwrap_or_default(),
+            rate_limit: source.rate_limit,
+            max_concurrent_requests: source.max_concurrent_requests,
+            max_body_size: source.max_body_size.unwrap_or(64 * 1024 * 1024),
+            gatt_console: source.gatt_console.unwrap_or(false),
+            grpc_port: source.grpc_port,
+            coap_port: source.coap_port,
+            dbus: source.dbus,
+            dbus_system_bus: source
+                .dbus_system_bus
+                .as_deref()
+                .map(dbus::parse_address)
+                .transpose()
+                .map_err(|e| eyre::format_err!("Invalid dbus_system_bus: {}", e))?,
+            replicate_from: source.replicate_from,
+            replication_token: source.replication_token,
+            run_as_user: source.run_as_user,
+            admin_token: source.admin_token,
+            approve_new_sensors: source.approve_new_sensors.unwrap_or(false),
+            pws: source.pws,
+            forecast: source.forecast,
+            anomaly: source.anomaly,
+            script: source.script,
+            snapshot: source.snapshot,
+            hook: source.hook,
+            adapter: source.adapter,
+            connection: source.connection,
+            presence: source.presence,
+            #[cfg(feature = "mqtt")]
+            homeassistant: source.homeassistant,
+            steering: source.steering,
+            profiles: bluetooth::Profiles::new(profiles),
+            sinks: source.sinks.unwrap_or_default(),
+            tenants: source.tenants.unwrap_or_default(),
+            #[cfg(feature = "alerts")]
+            rules,
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn config_source_precedence() {
+        let cli = ConfigSource {
+            port: Some(9090),
+            ..ConfigSource::default()
+        };
+        let file: ConfigSource = toml::from_str("port = 1234\nhost = \"::1\"").unwrap();
+
+        let merged = cli.or(ConfigSource::default()).or(file);
+        assert_eq!(merged.port, Some(9090));
+        assert_eq!(merged.host, Some("::1".parse().unwrap()));
+        assert!(merged.db_path.is_none());
+    }
+
+    #[test]
+    fn listens_on_every_address() {
+        let source: ConfigSource =
+            toml::from_str(r#"listen = ["[::1]:8080", "192.168.1.10:8080", "[::1]:8081"]"#)
+                .unwrap();
+        let config = Config::from_source(source).unwrap();
+        assert_eq!(config.listen.len(), 3);
+        assert_eq!(
+            config.listen_ips(),
+            vec![
+                "::1".parse::<IpAddr>().unwrap(),
+                "192.168.1.10".parse().unwrap()
+            ]
+        );
+
+        let config = Config::from_source(ConfigSource::default()).unwrap();
+        assert_eq!(config.listen, vec!["127.0.0.1:8080".parse().unwrap()]);
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn mqtt_session_defaults() {
+        let config = Config::from_source(ConfigSource::default()).unwrap();
+        assert_eq!(config.mqtt_session.client_id, "ble-weatherstation-central");
+        assert_eq!(config.mqtt_session.keep_alive, 60);
+        assert!(config.mqtt_session.clean_session);
+
+        let source: ConfigSource = toml::from_str(
+            "mqtt_client_id = \"attic\"\nmqtt_reconnect_delay = 120\nmqtt_max_reconnect_delay = 30",
+        )
+        .unwrap();
+        let session = Config::from_source(source).unwrap().mqtt_session;
+        assert_eq!(session.client_id, "attic");
+        // never waits less than the first delay
+        assert_eq!(session.max_reconnect_delay, Duration::from_secs(120));
+
+        let source: ConfigSource = toml::from_str("mqtt_client_id = \"\"").unwrap();
+        assert!(Config::from_source(source).is_err());
+    }
+
+    #[test]
+    fn tenants_refuse_unscoped_servers() {
+        let tenant = "[[tenant]]\nname = \"1a\"\ntoken = \"one\"\nsensors = []\n";
+        let source: ConfigSource = toml::from_str(tenant).unwrap();
+        assert!(Config::from_source(source).is_ok());
+        let source: ConfigSource =
+            toml::from_str(&format!("coap_port = 5683\n{}", tenant)).unwrap();
+        assert!(Config::from_source(source).is_err());
+    }
+
+    #[test]
+    fn base_path_gets_normalized() {
+        assert_eq!(normalize_base_path("weather/"), "/weather");
+        assert_eq!(normalize_base_path("/home/weather"), "/home/weather");
+        assert_eq!(normalize_base_path("/"), "");
+    }
+}
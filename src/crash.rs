@@ -0,0 +1,220 @@
+//! Crash reports, a file with what helps telling what went wrong written on panics and fatal
+//! errors so a bug report can carry more than the last screen of the journal
+
+use crate::{bluetooth, timestamp::Timestamp};
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    panic,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Lines of the log a report includes
+const RECENT_LINES: usize = 200;
+
+/// Values of config keys containing one of these don't end up in reports
+const SECRET_KEYS: &[&str] = &["key", "token", "password", "secret"];
+
+/// The latest lines of the log, which goes to stdout as well
+#[derive(Clone, Default)]
+pub(crate) struct RecentLogs(Arc<Mutex<VecDeque<String>>>);
+
+impl RecentLogs {
+    fn lines(&self) -> Vec<String> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl MakeWriter for RecentLogs {
+    type Writer = Tee;
+
+    fn make_writer(&self) -> Tee {
+        Tee {
+            logs: self.clone(),
+            event: Vec::new(),
+        }
+    }
+}
+
+/// Writes one event to stdout and keeps it once it's complete
+pub(crate) struct Tee {
+    logs: RecentLogs,
+    event: Vec<u8>,
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stdout().write_all(buf)?;
+        self.event.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+impl Drop for Tee {
+    fn drop(&mut self) {
+        let line = strip_ansi(&String::from_utf8_lossy(&self.event));
+        let mut lines = self.logs.0.lock().unwrap();
+        lines.push_back(line.trim_end().to_owned());
+        if lines.len() > RECENT_LINES {
+            lines.pop_front();
+        }
+    }
+}
+
+/// `text` without the color codes of a terminal
+fn strip_ansi(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // the codes are `ESC [ params letter`
+            for c in &mut chars {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+/// Replaces the values of keys that look like they hold secrets
+fn redact(config: &str) -> String {
+    config
+        .lines()
+        .map(|line| match line.find('=') {
+            Some(i)
+                if SECRET_KEYS
+                    .iter()
+                    .any(|secret| line[..i].to_lowercase().contains(secret)) =>
+            {
+                format!("{}= <redacted>", &line[..i])
+            }
+            _ => line.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Writes crash reports into `dir`, with the state of the central once it's attached
+#[derive(Clone)]
+pub(crate) struct CrashReporter {
+    dir: PathBuf,
+    config_file: Option<PathBuf>,
+    logs: RecentLogs,
+    ctx: Arc<Mutex<Option<super::Context>>>,
+}
+
+impl CrashReporter {
+    pub(crate) fn new(dir: PathBuf, config_file: Option<PathBuf>, logs: RecentLogs) -> Self {
+        Self {
+            dir,
+            config_file,
+            logs,
+            ctx: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Writes a report on every panic, after the message of the previous hook
+    pub(crate) fn install(&self) {
+        let reporter = self.clone();
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            previous(info);
+            reporter.report(&info.to_string());
+        }));
+    }
+
+    /// Reports from now on include the sensors and BlueZ objects of `ctx`
+    pub(crate) fn attach(&self, ctx: super::Context) {
+        *self.ctx.lock().unwrap() = Some(ctx);
+    }
+
+    /// Writes a report about `reason`, failing to only ends up on stderr since the log might be
+    /// what's broken
+    pub(crate) fn report(&self, reason: &str) {
+        match self.write(reason) {
+            Ok(path) => eprintln!("Wrote crash report to {}", path.display()),
+            Err(e) => eprintln!("Could not write crash report: {:?}", e),
+        }
+    }
+
+    fn write(&self, reason: &str) -> Result<PathBuf, eyre::Error> {
+        let now = Timestamp::now();
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{} {} crashed at {}\n\n## Reason\n{}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            now.as_u32(),
+            reason
+        )?;
+
+        // a panic while holding the lock leaves it poisoned
+        let ctx = self
+            .ctx
+            .lock()
+            .map(|ctx| ctx.clone())
+            .unwrap_or_else(|poisoned| poisoned.into_inner().clone());
+        if let Some(ctx) = ctx {
+            writeln!(out, "\n## Sensors")?;
+            match ctx.sensors.try_read() {
+                Ok(sensors) => writeln!(out, "{}", serde_json::to_string_pretty(&*sensors)?)?,
+                Err(_) => writeln!(out, "locked")?,
+            }
+            writeln!(out, "\n## BlueZ")?;
+            match bluetooth::dump_bluez(&ctx.profiles) {
+                Ok(objects) => writeln!(out, "{}", serde_json::to_string_pretty(&objects)?)?,
+                Err(e) => writeln!(out, "{}", e)?,
+            }
+        }
+
+        writeln!(out, "\n## Config")?;
+        match self.config_file {
+            Some(ref path) => match fs::read_to_string(path) {
+                Ok(config) => writeln!(out, "{}\n{}", path.display(), redact(&config))?,
+                Err(e) => writeln!(out, "{}: {}", path.display(), e)?,
+            },
+            None => writeln!(out, "no config file")?,
+        }
+
+        writeln!(out, "\n## Log")?;
+        for line in self.logs.lines() {
+            writeln!(out, "{}", line)?;
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!("crash-{}.txt", now.as_u32()));
+        fs::write(&path, out)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn secrets_and_colors_stay_out_of_reports() {
+        let config = "port = 8080\nreplication_token = \"hunter2\"\n[pws]\npassword=\"x\"";
+        assert_eq!(
+            redact(config),
+            "port = 8080\nreplication_token = <redacted>\n[pws]\npassword= <redacted>"
+        );
+        assert_eq!(
+            strip_ansi("\u{1b}[2m2021\u{1b}[0m \u{1b}[32m INFO\u{1b}[0m started"),
+            "2021  INFO started"
+        );
+    }
+}
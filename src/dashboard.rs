@@ -0,0 +1,105 @@
+use crate::{bluetooth::BluetoothAddress, sensor::Quantity, timestamp::Timestamp};
+
+/// Name of the layout used when none is asked for
+pub(crate) const DEFAULT_NAME: &str = "default";
+
+/// Layout names end up in urls so they're kept to characters that don't need escaping
+pub(crate) fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Customization of the home page, stored server side under a name so it survives across browsers
+#[derive(serde::Serialize, serde::Deserialize, Default, Clone, Debug, PartialEq)]
+pub(crate) struct Layout {
+    /// sensors shown before all others, in this order
+    #[serde(default)]
+    pub(crate) pinned: Vec<BluetoothAddress>,
+    /// sensors not shown at all
+    #[serde(default)]
+    pub(crate) hidden: Vec<BluetoothAddress>,
+    /// values shown per sensor, all of them if empty
+    #[serde(default)]
+    pub(crate) metrics: Vec<Quantity>,
+    /// seconds of history shown in charts, one day if unset
+    #[serde(default)]
+    pub(crate) chart_window: Option<u32>,
+}
+
+#[cfg_attr(not(feature = "web-ui"), allow(dead_code))]
+impl Layout {
+    /// Drops hidden sensors and moves pinned ones to the front
+    pub(crate) fn arrange<T>(
+        &self,
+        sensors: Vec<(BluetoothAddress, T)>,
+    ) -> Vec<(BluetoothAddress, T)> {
+        let mut ret = sensors
+            .into_iter()
+            .filter(|(addr, _)| !self.hidden.contains(addr))
+            .collect::<Vec<_>>();
+        // stable so unpinned sensors keep their order
+        ret.sort_by_key(|(addr, _)| {
+            self.pinned
+                .iter()
+                .position(|pinned| pinned == addr)
+                .unwrap_or(usize::MAX)
+        });
+        ret
+    }
+
+    pub(crate) fn is_pinned(&self, addr: &BluetoothAddress) -> bool {
+        self.pinned.contains(addr)
+    }
+
+    pub(crate) fn shows(&self, quantity: Quantity) -> bool {
+        self.metrics.is_empty() || self.metrics.contains(&quantity)
+    }
+
+    pub(crate) fn chart_window(&self) -> Timestamp {
+        self.chart_window
+            .map_or(Timestamp::ONE_DAY, Timestamp::from)
+    }
+}
+
+impl Layout {
+    /// The layout without any pinned or hidden sensor `keep` returns false for
+    pub(crate) fn only(mut self, keep: impl Fn(BluetoothAddress) -> bool) -> Self {
+        self.pinned.retain(|addr| keep(*addr));
+        self.hidden.retain(|addr| keep(*addr));
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn arrange_pins_and_hides() {
+        let addrs = (1..=4).map(BluetoothAddress::from).collect::<Vec<_>>();
+        let layout = Layout {
+            pinned: vec![addrs[3], addrs[2]],
+            hidden: vec![addrs[0]],
+            ..Layout::default()
+        };
+
+        let arranged = layout
+            .arrange(addrs.iter().map(|addr| (*addr, ())).collect())
+            .into_iter()
+            .map(|(addr, ())| addr)
+            .collect::<Vec<_>>();
+        assert_eq!(arranged, vec![addrs[3], addrs[2], addrs[1]]);
+    }
+
+    #[test]
+    fn layout_names() {
+        assert!(valid_name(DEFAULT_NAME));
+        assert!(valid_name("living-room_2"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("a b"));
+        assert!(!valid_name("../meta"));
+    }
+}
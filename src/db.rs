@@ -1,95 +1,722 @@
+mod block;
+mod check;
+mod crypt;
+mod rollup;
+
+pub(crate) use crypt::Key;
+
 use crate::{
     bluetooth::BluetoothAddress,
-    sensor::{RawSensorValues, SensorValues},
+    dashboard::Layout,
+    sensor::{Calibration, Quality, RawSensorValues, SensorValues},
     timestamp::Timestamp,
 };
 use heed::{
     byteorder::BigEndian,
-    types::{integer::U32, OwnedType, SerdeBincode},
+    types::{integer::U32, ByteSlice, DecodeIgnore, OwnedType, SerdeBincode, Str},
     RoTxn,
 };
+use rollup::Resolution;
 use std::{
-    collections::BTreeMap,
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
     convert::TryFrom,
     fs,
-    ops::Range,
+    iter::Peekable,
+    num::NonZeroU16,
+    ops::{Bound, Range, RangeInclusive},
     path::{Path, PathBuf},
-    sync::{RwLock, RwLockReadGuard},
 };
+use tokio::sync::broadcast;
 
 type BEU32 = U32<BigEndian>;
 
-type LogDb =
-    BTreeMap<BluetoothAddress, heed::Database<OwnedType<BEU32>, OwnedType<RawSensorValues>>>;
+/// Per sensor log database of the old layout, one per address named after it
+type LegacyLogDb = heed::Database<OwnedType<BEU32>, OwnedType<RawSensorValues>>;
+
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+const STARTS_KEY: &str = "starts";
+
+/// Version 1 keeps the logs of all sensors in a single database with [`LogKey`]s,
+/// version 2 stores addr entries as json, version 3 stores [`LogValues`] with quality flags,
+/// version 4 has hourly and daily [`rollup::Rollup`]s of the log
+const SCHEMA_VERSION: u32 = 4;
+
+/// Log entries converted per round when adding quality flags, keeps memory usage flat
+const MIGRATION_CHUNK: usize = 10_000;
+
+/// Leading byte of addr entries in the current encoding, json after it. Entries from before
+/// the version byte are plain json objects and start with `{`.
+const ADDR_ENTRY_VERSION: u8 = 1;
+
+/// Named databases of the current layout
+pub(crate) const NAMED_DBS: u32 = 9;
+
+/// Enough to migrate the old layout with a database per sensor for most installations
+pub(crate) const DEFAULT_MAX_DBS: u32 = 200;
+
+/// Value of the log database
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct LogValues {
+    values: RawSensorValues,
+    /// bits of [`Quality`]
+    quality: u8,
+    _reserved: [u8; 3],
+}
+
+impl LogValues {
+    fn new(values: RawSensorValues, quality: Quality) -> Self {
+        Self {
+            values,
+            quality: quality.bits(),
+            _reserved: [0; 3],
+        }
+    }
+
+    fn quality(&self) -> Quality {
+        Quality::from_bits_truncate(self.quality)
+    }
+}
+
+/// Key of the log database, big endian so entries are ordered by sensor and then by time
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct LogKey {
+    addr: [u8; 8],
+    time: [u8; 4],
+}
+
+impl LogKey {
+    fn new(addr: BluetoothAddress, time: Timestamp) -> Self {
+        Self {
+            addr: addr.as_u64().to_be_bytes(),
+            time: time.as_u32().to_be_bytes(),
+        }
+    }
+
+    fn addr(&self) -> BluetoothAddress {
+        BluetoothAddress::from(u64::from_be_bytes(self.addr))
+    }
+
+    fn time(&self) -> Timestamp {
+        Timestamp::from(u32::from_be_bytes(self.time))
+    }
 
+    /// All keys of `addr` in `range`
+    fn range(addr: BluetoothAddress, range: Range<Timestamp>) -> Range<Self> {
+        Self::new(addr, range.start)..Self::new(addr, range.end)
+    }
+
+    /// All keys of `addr`
+    fn sensor(addr: BluetoothAddress) -> RangeInclusive<Self> {
+        Self::new(addr, Timestamp::UNIX_EPOCH)..=Self::new(addr, Timestamp::MAX)
+    }
+}
+
+/// Addr entries are json and log entries [`LogValues`], everything but the meta entries is
+/// encrypted if the database has a key
 pub(crate) struct Db {
     env: heed::Env,
-    addr_db: heed::Database<OwnedType<BluetoothAddress>, SerdeBincode<AddrDbEntry>>,
-    sensor_log: RwLock<LogDb>,
+    addr_db: heed::Database<OwnedType<BluetoothAddress>, ByteSlice>,
+    log_db: heed::Database<OwnedType<LogKey>, ByteSlice>,
+    /// whole days of the log compressed by [`Db::seal_blocks`], keyed by sensor and UTC day
+    block_db: heed::Database<OwnedType<LogKey>, ByteSlice>,
+    /// [`rollup::Rollup`]s keyed by sensor and start of their hour, kept up to date by [`Db::write_log`]
+    hour_db: heed::Database<OwnedType<LogKey>, ByteSlice>,
+    /// like `hour_db` by UTC day
+    day_db: heed::Database<OwnedType<LogKey>, ByteSlice>,
+    meta_db: heed::Database<Str, OwnedType<u32>>,
+    /// json [`Layout`]s by name
+    dashboard_db: heed::Database<Str, ByteSlice>,
+    /// when sensors connected or got lost as json [`Connection`]s, apart from the log so gaps
+    /// can be told apart
+    connection_db: heed::Database<OwnedType<LogKey>, ByteSlice>,
+    /// battery percentages of stations that report them as single bytes, about one per hour
+    battery_db: heed::Database<OwnedType<LogKey>, ByteSlice>,
+    /// every committed [`Db::write_log`]
+    commits: broadcast::Sender<Committed>,
+    max_dbs: u32,
+    cipher: Option<crypt::Cipher>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Default)]
+/// Value of the connection database, what happened to the connection of a sensor
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Connection {
+    Connected,
+    Lost,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub(crate) struct AddrDbEntry {
     pub(crate) label: Option<String>,
+    #[serde(default)]
+    pub(crate) placement: Placement,
+    #[serde(default)]
+    pub(crate) room: Option<String>,
+    #[serde(default)]
+    pub(crate) calibration: Calibration,
+    /// whether values of the sensor get written to the log
+    #[serde(default = "log_by_default")]
+    pub(crate) log: bool,
+    /// whether the central sets the clock of the sensor, if its firmware has one
+    #[serde(default = "sync_clock_by_default")]
+    pub(crate) sync_clock: bool,
+    /// seconds between two measurements, pushed to the station, firmware default if unset
+    #[serde(default)]
+    pub(crate) measurement_interval: Option<NonZeroU16>,
+    /// values only get read over an encrypted link, the station gets paired for it
+    #[serde(default)]
+    pub(crate) require_encryption: bool,
+    /// shown next to the label
+    #[serde(default)]
+    pub(crate) icon: Option<Icon>,
+    /// sensors with a lower one come first, sensors without one come last
+    #[serde(default)]
+    pub(crate) sort_order: Option<i32>,
+    /// anything worth remembering about the station, like where it came from
+    #[serde(default)]
+    pub(crate) notes: Option<String>,
+    #[serde(default)]
+    pub(crate) purchased: Option<Date>,
+    /// like `2xAA NiMH` or `CR2032`
+    #[serde(default)]
+    pub(crate) battery_type: Option<String>,
+    #[serde(default)]
+    pub(crate) battery_changed: Option<Date>,
+}
+
+fn log_by_default() -> bool {
+    true
+}
+
+fn sync_clock_by_default() -> bool {
+    true
+}
+
+impl Default for AddrDbEntry {
+    fn default() -> Self {
+        Self {
+            label: None,
+            placement: Placement::default(),
+            room: None,
+            calibration: Calibration::default(),
+            log: log_by_default(),
+            sync_clock: sync_clock_by_default(),
+            measurement_interval: None,
+            require_encryption: false,
+            icon: None,
+            sort_order: None,
+            notes: None,
+            purchased: None,
+            battery_type: None,
+            battery_changed: None,
+        }
+    }
+}
+
+/// Changes to an [`AddrDbEntry`], fields that are missing stay as they are and `null` clears
+/// optional ones. Clients from before a field existed don't reset it this way.
+#[derive(serde::Deserialize, Default, Debug)]
+pub(crate) struct AddrDbPatch {
+    #[serde(default, deserialize_with = "present")]
+    label: Option<Option<String>>,
+    placement: Option<Placement>,
+    #[serde(default, deserialize_with = "present")]
+    room: Option<Option<String>>,
+    calibration: Option<Calibration>,
+    log: Option<bool>,
+    sync_clock: Option<bool>,
+    #[serde(default, deserialize_with = "present")]
+    measurement_interval: Option<Option<NonZeroU16>>,
+    require_encryption: Option<bool>,
+    #[serde(default, deserialize_with = "present")]
+    icon: Option<Option<Icon>>,
+    #[serde(default, deserialize_with = "present")]
+    sort_order: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "present")]
+    notes: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    purchased: Option<Option<Date>>,
+    #[serde(default, deserialize_with = "present")]
+    battery_type: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    battery_changed: Option<Option<Date>>,
+}
+
+/// Tells a `null` field apart from a missing one, which stays `None` through `default`
+fn present<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: serde::Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+impl AddrDbPatch {
+    pub(crate) fn apply(self, entry: &mut AddrDbEntry) {
+        fn set<T>(field: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+        set(&mut entry.label, self.label);
+        set(&mut entry.placement, self.placement);
+        set(&mut entry.room, self.room);
+        set(&mut entry.calibration, self.calibration);
+        set(&mut entry.log, self.log);
+        set(&mut entry.sync_clock, self.sync_clock);
+        set(&mut entry.measurement_interval, self.measurement_interval);
+        set(&mut entry.require_encryption, self.require_encryption);
+        set(&mut entry.icon, self.icon);
+        set(&mut entry.sort_order, self.sort_order);
+        set(&mut entry.notes, self.notes);
+        set(&mut entry.purchased, self.purchased);
+        set(&mut entry.battery_type, self.battery_type);
+        set(&mut entry.battery_changed, self.battery_changed);
+    }
+}
+
+/// Replaces every field
+impl From<AddrDbEntry> for AddrDbPatch {
+    fn from(entry: AddrDbEntry) -> Self {
+        Self {
+            label: Some(entry.label),
+            placement: Some(entry.placement),
+            room: Some(entry.room),
+            calibration: Some(entry.calibration),
+            log: Some(entry.log),
+            sync_clock: Some(entry.sync_clock),
+            measurement_interval: Some(entry.measurement_interval),
+            require_encryption: Some(entry.require_encryption),
+            icon: Some(entry.icon),
+            sort_order: Some(entry.sort_order),
+            notes: Some(entry.notes),
+            purchased: Some(entry.purchased),
+            battery_type: Some(entry.battery_type),
+            battery_changed: Some(entry.battery_changed),
+        }
+    }
+}
+
+impl AddrDbEntry {
+    /// Json behind [`ADDR_ENTRY_VERSION`], new fields need a serde default so older entries
+    /// keep decoding
+    fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut encoded = vec![ADDR_ENTRY_VERSION];
+        serde_json::to_writer(&mut encoded, self).map_err(|e| Error::Heed(e.into()))?;
+        Ok(encoded)
+    }
+
+    /// Decodes entries of every version up to the current one
+    pub(crate) fn decode(plain: &[u8]) -> Result<Self, Error> {
+        let json = match plain.first() {
+            Some(b'{') => plain,
+            Some(&ADDR_ENTRY_VERSION) => &plain[1..],
+            Some(&version) => {
+                return Err(Error::NewerEncoding {
+                    db: "addr",
+                    version,
+                })
+            }
+            None => return Err(Error::Corrupt("addr")),
+        };
+        serde_json::from_slice(json).map_err(|e| Error::Heed(e.into()))
+    }
+}
+
+/// Where a sensor is, decides which comfort indicators make sense for it
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Placement {
+    Indoor,
+    Outdoor,
+}
+
+impl Default for Placement {
+    fn default() -> Self {
+        Placement::Indoor
+    }
+}
+
+/// Symbol of a sensor in the web ui
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Icon {
+    Couch,
+    Bed,
+    Kitchen,
+    Bath,
+    Desk,
+    Child,
+    Plant,
+    Garden,
+    Garage,
+    Cellar,
+}
+
+#[cfg_attr(not(feature = "web-ui"), allow(dead_code))]
+impl Icon {
+    pub(crate) const ALL: &'static [Icon] = &[
+        Icon::Couch,
+        Icon::Bed,
+        Icon::Kitchen,
+        Icon::Bath,
+        Icon::Desk,
+        Icon::Child,
+        Icon::Plant,
+        Icon::Garden,
+        Icon::Garage,
+        Icon::Cellar,
+    ];
+
+    /// Same as the serialized name
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Icon::Couch => "couch",
+            Icon::Bed => "bed",
+            Icon::Kitchen => "kitchen",
+            Icon::Bath => "bath",
+            Icon::Desk => "desk",
+            Icon::Child => "child",
+            Icon::Plant => "plant",
+            Icon::Garden => "garden",
+            Icon::Garage => "garage",
+            Icon::Cellar => "cellar",
+        }
+    }
+
+    pub(crate) fn glyph(self) -> &'static str {
+        match self {
+            Icon::Couch => "🛋️",
+            Icon::Bed => "🛏️",
+            Icon::Kitchen => "🍳",
+            Icon::Bath => "🛁",
+            Icon::Desk => "🖥️",
+            Icon::Child => "🧸",
+            Icon::Plant => "🪴",
+            Icon::Garden => "🌳",
+            Icon::Garage => "🚗",
+            Icon::Cellar => "📦",
+        }
+    }
+}
+
+/// A day without a time like the ones in the metadata of sensors, `YYYY-MM-DD` in json
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct Date(chrono::NaiveDate);
+
+impl Date {
+    const FORMAT: &'static str = "%Y-%m-%d";
+
+    /// Whole days from this date until the local day of `now`, negative for dates after it
+    #[cfg_attr(not(feature = "web-ui"), allow(dead_code))]
+    pub(crate) fn days_until(self, now: Timestamp) -> i64 {
+        use chrono::TimeZone;
+        let today = chrono::Local
+            .timestamp(i64::from(now.as_u32()), 0)
+            .naive_local()
+            .date();
+        today.signed_duration_since(self.0).num_days()
+    }
+}
+
+impl TryFrom<String> for Date {
+    type Error = chrono::ParseError;
+
+    fn try_from(date: String) -> Result<Self, Self::Error> {
+        chrono::NaiveDate::parse_from_str(&date, Self::FORMAT).map(Date)
+    }
+}
+
+impl From<Date> for String {
+    fn from(date: Date) -> Self {
+        date.to_string()
+    }
 }
 
-pub(crate) struct LogTransaction<'a> {
-    sensor_values: RwLockReadGuard<'a, LogDb>,
-    txn: heed::RwTxn<'a, 'a>,
+impl std::fmt::Display for Date {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.format(Self::FORMAT))
+    }
 }
 
-impl<'a> LogTransaction<'a> {
-    pub(crate) fn log(
+pub(crate) struct LogStats {
+    pub(crate) entries: u64,
+    pub(crate) first: Timestamp,
+    pub(crate) last: Timestamp,
+}
+
+/// What a log write added, sent to everyone subscribed with [`Db::subscribe_commits`] once it's
+/// committed
+#[derive(Clone, Debug, serde::Serialize)]
+pub(crate) struct Committed {
+    pub(crate) entries: usize,
+    /// newest entry written of every sensor in the write
+    pub(crate) newest: BTreeMap<BluetoothAddress, Timestamp>,
+}
+
+/// Log entries collected without holding any database lock, written with [`Db::write_log`]
+#[derive(Default)]
+pub(crate) struct LogBatch(Vec<(BluetoothAddress, Timestamp, LogValues)>);
+
+impl LogBatch {
+    pub(crate) fn push(
         &mut self,
         addr: BluetoothAddress,
         timestamp: Timestamp,
         values: SensorValues,
-    ) -> Result<(), heed::Error> {
-        if let Some(db) = self.sensor_values.get(&addr) {
-            db.append(
-                &mut self.txn,
-                &BEU32::new(timestamp.as_u32()),
-                &values.into(),
-            )?;
-        }
-        Ok(())
+        quality: Quality,
+    ) {
+        self.0
+            .push((addr, timestamp, LogValues::new(values.into(), quality)));
+    }
+
+    /// Puts the entries of `older` in front of the ones in `self`, keeping at most `max` of the
+    /// newest entries
+    pub(crate) fn prepend(&mut self, mut older: LogBatch, max: usize) {
+        older.0.append(&mut self.0);
+        let excess = older.0.len().saturating_sub(max);
+        older.0.drain(..excess);
+        self.0 = older.0;
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Entries with values a sensor can measure, in the order they were pushed
+    pub(crate) fn entries(
+        &self,
+    ) -> impl Iterator<Item = (BluetoothAddress, Timestamp, SensorValues, Quality)> + '_ {
+        self.0.iter().filter_map(|(addr, time, logged)| {
+            let values = SensorValues::try_from(logged.values).ok()?;
+            Some((*addr, *time, values, logged.quality()))
+        })
+    }
+
+    /// Every sensor with entries in the batch
+    pub(crate) fn addrs(&self) -> BTreeSet<BluetoothAddress> {
+        self.0.iter().map(|(addr, _, _)| *addr).collect()
     }
 
-    pub(crate) fn commit(self) -> Result<(), Error> {
-        self.txn.commit().map_err(heed_err)
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
     }
 }
 
 impl Db {
     pub fn open(db_path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::open_with_key(db_path, None)
+    }
+
+    /// Opens a database whose entries are encrypted with `key`. A plaintext
+    /// database gets encrypted, an encrypted one can't be opened without its key.
+    pub fn open_with_key(db_path: impl AsRef<Path>, key: Option<&Key>) -> Result<Self, Error> {
+        Self::open_with(db_path, key, DEFAULT_MAX_DBS)
+    }
+
+    /// Like [`Db::open_with_key`] with room for `max_dbs` named databases. Every slot costs a
+    /// bit of memory in each transaction, only migrating the old layout needs more than
+    /// [`NAMED_DBS`].
+    pub fn open_with(
+        db_path: impl AsRef<Path>,
+        key: Option<&Key>,
+        max_dbs: u32,
+    ) -> Result<Self, Error> {
         let db_path = db_path.as_ref();
         fs::create_dir_all(&db_path).map_err(|source| Error::Create {
             path: db_path.to_owned(),
             source,
         })?;
 
-        let env = heed::EnvOpenOptions::new().max_dbs(200).open(db_path)?;
+        // the old layout needs one database per sensor so this must stay high enough to migrate
+        let env = heed::EnvOpenOptions::new().max_dbs(max_dbs).open(db_path)?;
         let addr_db = env.create_database(Some("addr"))?;
-        let ret = Self {
+        let log_db = env.create_database(Some("log"))?;
+        let block_db = env.create_database(Some("log_block"))?;
+        let hour_db = env.create_database(Some("rollup_hour"))?;
+        let day_db = env.create_database(Some("rollup_day"))?;
+        let meta_db = env.create_database(Some("meta"))?;
+        let dashboard_db = env.create_database(Some("dashboard"))?;
+        let connection_db = env.create_database(Some("connection"))?;
+        let battery_db = env.create_database(Some("battery"))?;
+        let mut ret = Self {
             env,
             addr_db,
-            sensor_log: RwLock::new(BTreeMap::new()),
+            log_db,
+            block_db,
+            hour_db,
+            day_db,
+            meta_db,
+            dashboard_db,
+            connection_db,
+            battery_db,
+            commits: broadcast::channel(16).0,
+            max_dbs,
+            cipher: None,
         };
 
-        let known_addrs = {
+        let key_check = {
             let txn = ret.read_txn()?;
-            let it = ret.known_addrs(&txn)?;
+            ret.meta_db
+                .remap_data_type::<ByteSlice>()
+                .get(&txn, crypt::KEY_CHECK_KEY)?
+                .map(<[u8]>::to_vec)
+        };
+        match (key, key_check) {
+            (Some(key), Some(check)) => {
+                ret.cipher = Some(crypt::Cipher::new(key));
+                if !ret.key_matches(&check) {
+                    return Err(Error::WrongKey);
+                }
+                ret.migrate()?;
+            }
+            // migrations only know plaintext entries
+            (Some(key), None) => {
+                ret.migrate()?;
+                ret.cipher = Some(crypt::Cipher::new(key));
+                ret.encrypt_existing()?;
+            }
+            (None, Some(_)) => return Err(Error::MissingKey),
+            (None, None) => ret.migrate()?,
+        }
+
+        Ok(ret)
+    }
+
+    fn migrate(&self) -> Result<(), Error> {
+        let version = {
+            let txn = self.read_txn()?;
+            self.meta_db.get(&txn, SCHEMA_VERSION_KEY)?.unwrap_or(0)
+        };
+        if version >= SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        // databases can't be opened while a write transaction is running
+        let legacy_dbs = if version < 1 {
+            self.legacy_log_dbs()?
+        } else {
+            Vec::new()
+        };
+
+        let mut txn = self.write_txn()?;
+        if version < 1 {
+            let moved = self.merge_legacy_logs(&mut txn, legacy_dbs)?;
+            tracing::info!("Moved {} log entries into the shared log database", moved);
+        }
+        if version < 2 {
+            self.reencode_addr_entries(&mut txn)?;
+        }
+        if version < 3 {
+            let converted = self.add_quality_flags(&mut txn)?;
+            tracing::info!("Added quality flags to {} log entries", converted);
+        }
+        if version < 4 {
+            let hours = self.rebuild_rollups(&mut txn)?;
+            tracing::info!("Rolled up {} hours of logs", hours);
+        }
+        self.meta_db
+            .put(&mut txn, SCHEMA_VERSION_KEY, &SCHEMA_VERSION)?;
+        txn.commit()?;
+
+        tracing::info!(
+            "Migrated database from schema version {} to {}",
+            version,
+            SCHEMA_VERSION
+        );
+
+        Ok(())
+    }
+
+    fn legacy_log_dbs(&self) -> Result<Vec<(BluetoothAddress, LegacyLogDb)>, Error> {
+        let known_addrs = {
+            let txn = self.read_txn()?;
+            let it = self.known_addrs(&txn)?;
             it.collect::<Result<Vec<_>, _>>()?
         };
+        let needed = NAMED_DBS + known_addrs.len() as u32;
+        if needed > self.max_dbs {
+            return Err(Error::TooFewDbs {
+                sensors: known_addrs.len(),
+                needed,
+                max_dbs: self.max_dbs,
+            });
+        }
+        let mut ret = Vec::new();
+        for addr in known_addrs {
+            let legacy_db: Option<LegacyLogDb> = self.env.open_database(Some(&addr.to_string()))?;
+            if let Some(legacy_db) = legacy_db {
+                ret.push((addr, legacy_db));
+            }
+        }
+        Ok(ret)
+    }
 
-        {
-            let mut sensor_log = ret.sensor_log.write().unwrap();
-            for addr in known_addrs {
-                sensor_log.insert(addr, ret.env.create_database(Some(&addr.to_string()))?);
+    fn merge_legacy_logs(
+        &self,
+        txn: &mut heed::RwTxn,
+        legacy_dbs: Vec<(BluetoothAddress, LegacyLogDb)>,
+    ) -> Result<usize, Error> {
+        let mut moved = 0;
+        for (addr, legacy_db) in legacy_dbs {
+            let entries = legacy_db.iter(txn)?.collect::<Result<Vec<_>, _>>()?;
+            for (time, values) in entries {
+                let key = LogKey::new(addr, Timestamp::from(time.get()));
+                self.put_log(txn, &key, &LogValues::new(values, Quality::empty()))?;
+                moved += 1;
             }
+            legacy_db.clear(txn)?;
         }
+        Ok(moved)
+    }
 
-        Ok(ret)
+    /// Addr entries used to be bincode which can't deal with new fields
+    fn reencode_addr_entries(&self, txn: &mut heed::RwTxn) -> Result<(), Error> {
+        #[derive(serde::Deserialize)]
+        struct LegacyAddrDbEntry {
+            label: Option<String>,
+        }
+
+        let legacy_db = self
+            .addr_db
+            .remap_data_type::<SerdeBincode<LegacyAddrDbEntry>>();
+        let entries = legacy_db.iter(txn)?.collect::<Result<Vec<_>, _>>()?;
+        for (addr, entry) in entries {
+            let entry = AddrDbEntry {
+                label: entry.label,
+                ..AddrDbEntry::default()
+            };
+            self.put_addr(txn, addr, &entry)?;
+        }
+        Ok(())
+    }
+
+    /// Log values used to be stored without quality flags, they all count as raw readings
+    fn add_quality_flags(&self, txn: &mut heed::RwTxn) -> Result<usize, Error> {
+        let legacy_db = self.log_db.remap_data_type::<OwnedType<RawSensorValues>>();
+        let mut converted = 0;
+        let mut after = Bound::Unbounded;
+        loop {
+            let chunk = legacy_db
+                .range(txn, &(after, Bound::Unbounded))?
+                .take(MIGRATION_CHUNK)
+                .collect::<Result<Vec<_>, _>>()?;
+            let last = match chunk.last() {
+                Some((key, _)) => *key,
+                None => break,
+            };
+            for (key, values) in chunk {
+                self.put_log(txn, &key, &LogValues::new(values, Quality::empty()))?;
+                converted += 1;
+            }
+            after = Bound::Excluded(last);
+        }
+        Ok(converted)
     }
 
     pub fn read_txn(&self) -> Result<heed::RoTxn, Error> {
@@ -100,11 +727,65 @@ impl Db {
         self.env.write_txn().map_err(heed_err)
     }
 
-    pub fn log_txn(&self) -> Result<LogTransaction, Error> {
-        Ok(LogTransaction {
-            sensor_values: self.sensor_log.read().unwrap(),
-            txn: self.write_txn()?,
-        })
+    /// Counts a start of the central, returns the starts so far including this one
+    pub fn count_start(&self) -> Result<u32, Error> {
+        let mut txn = self.write_txn()?;
+        let starts = self
+            .meta_db
+            .get(&txn, STARTS_KEY)?
+            .unwrap_or(0)
+            .saturating_add(1);
+        self.meta_db.put(&mut txn, STARTS_KEY, &starts)?;
+        txn.commit()?;
+        Ok(starts)
+    }
+
+    /// Consistent copy of the whole database at `path` while it stays in use, free pages get
+    /// left out
+    pub fn snapshot(&self, path: &Path) -> Result<(), Error> {
+        self.env
+            .copy_to_path(path, heed::CompactionOption::Enabled)
+            .map_err(heed_err)?;
+        Ok(())
+    }
+
+    /// Writes all entries of `batch` and updates the rollups they fall into in a single write
+    /// transaction
+    pub fn write_log(&self, batch: &LogBatch) -> Result<(), Error> {
+        let mut txn = self.write_txn()?;
+        // sealed days become rows again so replaced entries and stale hours are found as usual
+        let days = batch
+            .0
+            .iter()
+            .map(|(addr, timestamp, _)| (*addr, Resolution::Day.bucket(*timestamp)))
+            .collect();
+        self.unseal_days(&mut txn, &days)?;
+        let mut rollups = rollup::RollupUpdate::default();
+        for (addr, timestamp, values) in &batch.0 {
+            let key = LogKey::new(*addr, *timestamp);
+            let replaced = self.log_db.get(&txn, &key)?.is_some();
+            self.put_log(&mut txn, &key, values)?;
+            rollups.logged(*addr, *timestamp, values, replaced);
+        }
+        self.apply_rollups(&mut txn, rollups)?;
+        txn.commit().map_err(heed_err)?;
+
+        let mut newest = BTreeMap::new();
+        for (addr, timestamp, _) in &batch.0 {
+            let time = newest.entry(*addr).or_insert(*timestamp);
+            *time = (*time).max(*timestamp);
+        }
+        // nobody might be listening
+        let _ = self.commits.send(Committed {
+            entries: batch.len(),
+            newest,
+        });
+        Ok(())
+    }
+
+    /// Receives what every following log write added
+    pub(crate) fn subscribe_commits(&self) -> broadcast::Receiver<Committed> {
+        self.commits.subscribe()
     }
 
     pub fn get_addr<'txn, T>(
@@ -112,7 +793,13 @@ impl Db {
         txn: &'txn RoTxn<'_, T>,
         addr: BluetoothAddress,
     ) -> Result<Option<AddrDbEntry>, Error> {
-        self.addr_db.get(txn, &addr).map_err(heed_err)
+        match self.addr_db.get(txn, &addr)? {
+            Some(stored) => {
+                let plain = self.unseal("addr", bytemuck::bytes_of(&addr), stored)?;
+                AddrDbEntry::decode(&plain).map(Some)
+            }
+            None => Ok(None),
+        }
     }
 
     pub fn put_addr(
@@ -121,7 +808,53 @@ impl Db {
         addr: BluetoothAddress,
         data: &AddrDbEntry,
     ) -> Result<(), Error> {
-        self.addr_db.put(txn, &addr, data).map_err(heed_err)
+        let encoded = data.encode()?;
+        let stored = self.seal(bytemuck::bytes_of(&addr), &encoded);
+        self.addr_db.put(txn, &addr, &stored).map_err(heed_err)
+    }
+
+    fn put_log(
+        &self,
+        txn: &mut heed::RwTxn<'_, '_>,
+        key: &LogKey,
+        values: &LogValues,
+    ) -> Result<(), Error> {
+        let stored = self.seal(crypt::log_aad(key), bytemuck::bytes_of(values));
+        self.log_db.put(txn, key, &stored).map_err(heed_err)
+    }
+
+    fn get_log_values(&self, key: &LogKey, stored: &[u8]) -> Result<LogValues, Error> {
+        let plain = self.unseal("log", crypt::log_aad(key), stored)?;
+        if plain.len() != std::mem::size_of::<LogValues>() {
+            return Err(Error::Corrupt("log"));
+        }
+        let mut values: LogValues = bytemuck::Zeroable::zeroed();
+        bytemuck::bytes_of_mut(&mut values).copy_from_slice(&plain);
+        Ok(values)
+    }
+
+    /// Encrypts `plain` if the database has a key
+    fn seal<'a>(&self, aad: &[u8], plain: &'a [u8]) -> Cow<'a, [u8]> {
+        match self.cipher {
+            Some(ref cipher) => Cow::Owned(cipher.seal(aad, plain)),
+            None => Cow::Borrowed(plain),
+        }
+    }
+
+    /// Decrypts `stored` if the database has a key
+    fn unseal<'a>(
+        &self,
+        db: &'static str,
+        aad: &[u8],
+        stored: &'a [u8],
+    ) -> Result<Cow<'a, [u8]>, Error> {
+        match self.cipher {
+            Some(ref cipher) => cipher
+                .open(aad, stored)
+                .map(Cow::Owned)
+                .ok_or(Error::Corrupt(db)),
+            None => Ok(Cow::Borrowed(stored)),
+        }
     }
 
     pub fn known_addrs<'txn, T>(
@@ -129,8 +862,9 @@ impl Db {
         txn: &'txn RoTxn<'_, T>,
     ) -> Result<impl Iterator<Item = Result<BluetoothAddress, Error>> + 'txn, Error> {
         self.addr_db
+            .remap_data_type::<DecodeIgnore>()
             .iter(txn)
-            .map(|it| it.map(|res| res.map(|(addr, _)| addr).map_err(heed_err)))
+            .map(|it| it.map(|res| res.map(|(addr, ())| addr).map_err(heed_err)))
             .map_err(heed_err)
     }
 
@@ -142,29 +876,354 @@ impl Db {
         self.addr_db.delete(txn, &addr).map_err(heed_err)
     }
 
+    pub fn get_dashboard<T>(
+        &self,
+        txn: &RoTxn<'_, T>,
+        name: &str,
+    ) -> Result<Option<Layout>, Error> {
+        match self.dashboard_db.get(txn, name)? {
+            Some(stored) => {
+                let aad = crypt::entry_aad("dashboard", name.as_bytes());
+                let plain = self.unseal("dashboard", &aad, stored)?;
+                serde_json::from_slice(&plain)
+                    .map(Some)
+                    .map_err(|e| Error::Heed(e.into()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn put_dashboard(
+        &self,
+        txn: &mut heed::RwTxn<'_, '_>,
+        name: &str,
+        layout: &Layout,
+    ) -> Result<(), Error> {
+        let encoded = serde_json::to_vec(layout).map_err(|e| Error::Heed(e.into()))?;
+        let stored = self.seal(&crypt::entry_aad("dashboard", name.as_bytes()), &encoded);
+        self.dashboard_db.put(txn, name, &stored).map_err(heed_err)
+    }
+
+    pub fn delete_dashboard(
+        &self,
+        txn: &mut heed::RwTxn<'_, '_>,
+        name: &str,
+    ) -> Result<bool, Error> {
+        self.dashboard_db.delete(txn, name).map_err(heed_err)
+    }
+
+    pub fn put_connection(
+        &self,
+        txn: &mut heed::RwTxn<'_, '_>,
+        addr: BluetoothAddress,
+        time: Timestamp,
+        connection: Connection,
+    ) -> Result<(), Error> {
+        let key = LogKey::new(addr, time);
+        let encoded = serde_json::to_vec(&connection).map_err(|e| Error::Heed(e.into()))?;
+        let aad = crypt::entry_aad("connection", bytemuck::bytes_of(&key));
+        let stored = self.seal(&aad, &encoded);
+        self.connection_db.put(txn, &key, &stored).map_err(heed_err)
+    }
+
+    /// Connects and losses of `addr` in `range`, oldest first. Returns `None` for unknown
+    /// sensors.
+    pub fn get_connections<T>(
+        &self,
+        txn: &RoTxn<'_, T>,
+        addr: BluetoothAddress,
+        range: Range<Timestamp>,
+    ) -> Result<Option<Vec<(Timestamp, Connection)>>, Error> {
+        if self.addr_db.get(txn, &addr)?.is_none() {
+            return Ok(None);
+        }
+
+        self.connection_db
+            .range(txn, &LogKey::range(addr, range))?
+            .map(|entry| {
+                let (key, stored) = entry?;
+                let aad = crypt::entry_aad("connection", bytemuck::bytes_of(&key));
+                let plain = self.unseal("connection", &aad, stored)?;
+                let connection: Connection =
+                    serde_json::from_slice(&plain).map_err(|e| Error::Heed(e.into()))?;
+                Ok((key.time(), connection))
+            })
+            .collect::<Result<Vec<_>, Error>>()
+            .map(Some)
+    }
+
+    pub fn put_battery(
+        &self,
+        txn: &mut heed::RwTxn<'_, '_>,
+        addr: BluetoothAddress,
+        time: Timestamp,
+        percentage: u8,
+    ) -> Result<(), Error> {
+        let key = LogKey::new(addr, time);
+        let stored = self.seal(
+            &crypt::entry_aad("battery", bytemuck::bytes_of(&key)),
+            &[percentage],
+        );
+        self.battery_db.put(txn, &key, &stored).map_err(heed_err)
+    }
+
+    /// Battery percentages of `addr` in `range`, oldest first
+    pub fn get_battery<T>(
+        &self,
+        txn: &RoTxn<'_, T>,
+        addr: BluetoothAddress,
+        range: Range<Timestamp>,
+    ) -> Result<Vec<(Timestamp, u8)>, Error> {
+        self.battery_db
+            .range(txn, &LogKey::range(addr, range))?
+            .map(|entry| {
+                let (key, stored) = entry?;
+                let aad = crypt::entry_aad("battery", bytemuck::bytes_of(&key));
+                match *self.unseal("battery", &aad, stored)? {
+                    [percentage] => Ok((key.time(), percentage)),
+                    _ => Err(Error::Corrupt("battery")),
+                }
+            })
+            .collect()
+    }
+
+    /// Log entries of `addr` in `range`, at most `limit` of them. Ranges long enough get
+    /// averaged by hour or day from the rollups, shorter ones are evenly thinned out. Returns
+    /// `None` for unknown sensors.
     pub fn get_log<T>(
         &self,
         txn: &RoTxn<'_, T>,
         addr: BluetoothAddress,
         range: Range<Timestamp>,
+        limit: Option<usize>,
     ) -> Result<Option<Vec<(Timestamp, SensorValues)>>, Error> {
-        let sensor_log = self.sensor_log.read().unwrap();
-        let db = match sensor_log.get(&addr) {
-            Some(db) => db,
-            _ => return Ok(None),
+        Ok(self.get_flagged_log(txn, addr, range, limit)?.map(|log| {
+            log.into_iter()
+                .map(|(time, values, _)| (time, values))
+                .collect()
+        }))
+    }
+
+    /// Like [`Db::get_log`] with the quality flags of every entry
+    pub fn get_flagged_log<T>(
+        &self,
+        txn: &RoTxn<'_, T>,
+        addr: BluetoothAddress,
+        range: Range<Timestamp>,
+        limit: Option<usize>,
+    ) -> Result<Option<Vec<(Timestamp, SensorValues, Quality)>>, Error> {
+        if self.addr_db.get(txn, &addr)?.is_none() {
+            return Ok(None);
+        }
+
+        if let Some(resolution) = limit.and_then(|limit| Resolution::for_limit(&range, limit)) {
+            let half = resolution.seconds() / 2;
+            let buckets = resolution.bucket(range.start)..range.end;
+            return Ok(Some(
+                self.get_rollups(txn, addr, resolution, buckets)?
+                    .into_iter()
+                    .filter_map(|(start, rollup)| {
+                        let time = Timestamp::from(start.as_u32() + half).min(range.end);
+                        Some((time, rollup.mean()?, rollup.quality()))
+                    })
+                    .collect(),
+            ));
+        }
+
+        let step = match limit {
+            Some(limit) => {
+                let count = self.log_count(txn, addr, range.clone())?;
+                ((count + limit.max(1) - 1) / limit.max(1)).max(1)
+            }
+            None => 1,
         };
 
-        let range = BEU32::new(range.start.as_u32())..BEU32::new(range.end.as_u32());
+        let mut log = Vec::new();
+        for entry in self.log_entries(txn, addr, range)?.step_by(step) {
+            let (time, logged) = entry?;
+            if let Ok(values) = SensorValues::try_from(logged.values) {
+                log.push((time, values, logged.quality()));
+            }
+        }
+        Ok(Some(log))
+    }
 
-        let mut ret = Vec::new();
-        for val in db.range(txn, &range)? {
-            let (time, values) = val?;
-            if let Ok(values) = SensorValues::try_from(values) {
-                ret.push((Timestamp::from(time.get()), values));
+    /// Entries of `addr` in `range` out of both rows and sealed days, oldest first and decoded
+    /// only as they're reached
+    fn log_entries<'txn, T>(
+        &'txn self,
+        txn: &'txn RoTxn<'_, T>,
+        addr: BluetoothAddress,
+        range: Range<Timestamp>,
+    ) -> Result<impl Iterator<Item = Result<(Timestamp, LogValues), Error>> + 'txn, Error> {
+        let rows = self
+            .log_db
+            .range(txn, &LogKey::range(addr, range.clone()))?
+            .map(move |entry| {
+                let (key, stored) = entry?;
+                Ok((key.time(), self.get_log_values(&key, stored)?))
+            });
+        Ok(ByTime::new(self.block_entries(txn, addr, range)?, rows))
+    }
+
+    /// Like [`Db::log_entries`] with rows left undecoded
+    fn log_entry_times<'txn, T>(
+        &'txn self,
+        txn: &'txn RoTxn<'_, T>,
+        addr: BluetoothAddress,
+        range: Range<Timestamp>,
+    ) -> Result<impl Iterator<Item = Result<(Timestamp, ()), Error>> + 'txn, Error> {
+        let blocks = self
+            .block_entries(txn, addr, range.clone())?
+            .map(|entry| entry.map(|(time, _)| (time, ())));
+        let rows = self
+            .log_db
+            .remap_data_type::<DecodeIgnore>()
+            .range(txn, &LogKey::range(addr, range))?
+            .map(|entry| Ok((entry?.0.time(), ())));
+        Ok(ByTime::new(blocks, rows))
+    }
+
+    /// Times of the log entries of `addr` in `range` without decoding their values. Returns
+    /// `None` for unknown sensors.
+    pub fn log_times<T>(
+        &self,
+        txn: &RoTxn<'_, T>,
+        addr: BluetoothAddress,
+        range: Range<Timestamp>,
+    ) -> Result<Option<Vec<Timestamp>>, Error> {
+        if self.addr_db.get(txn, &addr)?.is_none() {
+            return Ok(None);
+        }
+
+        self.log_entry_times(txn, addr, range)?
+            .map(|entry| entry.map(|(time, ())| time))
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+
+    /// Number of log entries of `addr` in `range`, whole hours get counted from their rollups
+    /// and the rest from block headers and undecoded rows
+    pub fn log_count<T>(
+        &self,
+        txn: &RoTxn<'_, T>,
+        addr: BluetoothAddress,
+        range: Range<Timestamp>,
+    ) -> Result<usize, Error> {
+        let count_entries = |range: Range<Timestamp>| -> Result<usize, Error> {
+            let mut rows = 0;
+            for entry in self
+                .log_db
+                .remap_data_type::<DecodeIgnore>()
+                .range(txn, &LogKey::range(addr, range.clone()))?
+            {
+                entry?;
+                rows += 1;
             }
+            Ok(rows + self.block_count(txn, addr, range)?)
+        };
+
+        let hour = Resolution::Hour.seconds();
+        let first_hour = Resolution::Hour.bucket(Timestamp::from(
+            range.start.as_u32().saturating_add(hour - 1),
+        ));
+        let last_hour = Resolution::Hour.bucket(range.end);
+        if first_hour >= last_hour {
+            return count_entries(range);
+        }
+        let whole_hours = self
+            .get_rollups(txn, addr, Resolution::Hour, first_hour..last_hour)?
+            .iter()
+            .map(|(_, rollup)| rollup.count() as usize)
+            .sum::<usize>();
+        Ok(count_entries(range.start..first_hour)?
+            + whole_hours
+            + count_entries(last_hour..range.end)?)
+    }
+
+    pub fn log_stats<T>(
+        &self,
+        txn: &RoTxn<'_, T>,
+        addr: BluetoothAddress,
+    ) -> Result<Option<LogStats>, Error> {
+        let range = LogKey::sensor(addr);
+        let first = self.log_db.range(txn, &range)?.next().transpose()?;
+        let last = self.log_db.rev_range(txn, &range)?.next().transpose()?;
+        let rows = first
+            .zip(last)
+            .map(|((first, _), (last, _))| (first.time(), last.time()));
+        let bounds = match (rows, self.block_bounds(txn, addr)?) {
+            (Some((first, last)), Some((block_first, block_last))) => {
+                Some((first.min(block_first), last.max(block_last)))
+            }
+            (rows, blocks) => rows.or(blocks),
+        };
+
+        match bounds {
+            Some((first, last)) => Ok(Some(LogStats {
+                entries: self
+                    .get_rollups(
+                        txn,
+                        addr,
+                        Resolution::Day,
+                        Timestamp::UNIX_EPOCH..Timestamp::MAX,
+                    )?
+                    .iter()
+                    .map(|(_, rollup)| u64::from(rollup.count()))
+                    .sum(),
+                first,
+                last,
+            })),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Sealed and unsealed entries of a sensor merged by time. Writes unseal a day before adding to
+/// it so both shouldn't have the same time, rows win like when sealing if they do.
+struct ByTime<B: Iterator, R: Iterator> {
+    blocks: Peekable<B>,
+    rows: Peekable<R>,
+}
+
+impl<B: Iterator, R: Iterator> ByTime<B, R> {
+    fn new(blocks: B, rows: R) -> Self {
+        Self {
+            blocks: blocks.peekable(),
+            rows: rows.peekable(),
         }
+    }
+}
 
-        Ok(Some(ret))
+impl<V, B, R> Iterator for ByTime<B, R>
+where
+    B: Iterator<Item = Result<(Timestamp, V), Error>>,
+    R: Iterator<Item = Result<(Timestamp, V), Error>>,
+{
+    type Item = Result<(Timestamp, V), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let block = match self.blocks.peek() {
+            Some(Ok((time, _))) => Some(*time),
+            Some(Err(_)) => return self.blocks.next(),
+            None => None,
+        };
+        let row = match self.rows.peek() {
+            Some(Ok((time, _))) => Some(*time),
+            Some(Err(_)) => return self.rows.next(),
+            None => None,
+        };
+        match (block, row) {
+            (Some(block), Some(row)) if block < row => self.blocks.next(),
+            (Some(block), Some(row)) => {
+                if block == row {
+                    self.blocks.next();
+                }
+                self.rows.next()
+            }
+            (Some(_), None) => self.blocks.next(),
+            (None, _) => self.rows.next(),
+        }
     }
 }
 
@@ -178,6 +1237,30 @@ pub(crate) enum Error {
 
     #[error("Error in database backend")]
     Heed(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("Database is encrypted but no key is configured")]
+    MissingKey,
+
+    #[error("Database was encrypted with another key")]
+    WrongKey,
+
+    #[error("Undecodable entry in the {0} database")]
+    Corrupt(&'static str),
+
+    #[error(
+        "Entry in the {db} database has encoding version {version}, written by a newer central"
+    )]
+    NewerEncoding { db: &'static str, version: u8 },
+
+    #[error(
+        "Migrating the {sensors} sensors of the old database layout needs max_dbs of at least \
+         {needed} instead of {max_dbs}"
+    )]
+    TooFewDbs {
+        sensors: usize,
+        needed: u32,
+        max_dbs: u32,
+    },
 }
 
 fn heed_err(e: heed::Error) -> Error {
@@ -191,3 +1274,326 @@ impl From<heed::Error> for Error {
 }
 
 impl warp::reject::Reject for Error {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sensor::test_values;
+
+    #[test]
+    fn patch_keeps_missing_fields() {
+        let mut entry = AddrDbEntry {
+            notes: Some("attic".to_owned()),
+            sort_order: Some(3),
+            ..AddrDbEntry::default()
+        };
+        let patch: AddrDbPatch =
+            serde_json::from_str(r#"{"label": "kitchen", "sort_order": null}"#).unwrap();
+        patch.apply(&mut entry);
+        assert_eq!(entry.label.as_deref(), Some("kitchen"));
+        assert_eq!(entry.notes.as_deref(), Some("attic"));
+        assert_eq!(entry.sort_order, None);
+    }
+
+    #[test]
+    fn new_sensor_log_is_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let known = BluetoothAddress::from(1);
+        let new = BluetoothAddress::from(2);
+
+        let db = Db::open(dir.path()).unwrap();
+        let mut txn = db.write_txn().unwrap();
+        db.put_addr(&mut txn, known, &AddrDbEntry::default())
+            .unwrap();
+        txn.commit().unwrap();
+        drop(db);
+
+        // the second sensor only gets memorized after the database was opened
+        let db = Db::open(dir.path()).unwrap();
+        let mut txn = db.write_txn().unwrap();
+        db.put_addr(&mut txn, new, &AddrDbEntry::default()).unwrap();
+        txn.commit().unwrap();
+
+        let mut batch = LogBatch::default();
+        batch.push(
+            known,
+            Timestamp::from(10),
+            test_values(10_00, 50_00),
+            Quality::empty(),
+        );
+        batch.push(
+            new,
+            Timestamp::from(10),
+            test_values(20_00, 50_00),
+            Quality::empty(),
+        );
+        batch.push(
+            new,
+            Timestamp::from(20),
+            test_values(21_00, 50_00),
+            Quality::empty(),
+        );
+        db.write_log(&batch).unwrap();
+        drop(db);
+
+        let db = Db::open(dir.path()).unwrap();
+        let txn = db.read_txn().unwrap();
+        let log = db
+            .get_log(&txn, new, Timestamp::UNIX_EPOCH..Timestamp::MAX, None)
+            .unwrap()
+            .unwrap();
+        let temperatures = log
+            .into_iter()
+            .map(|(time, values)| (time, RawSensorValues::from(values).temperature))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            temperatures,
+            vec![(Timestamp::from(10), 20_00), (Timestamp::from(20), 21_00)]
+        );
+        assert_eq!(db.log_stats(&txn, known).unwrap().unwrap().entries, 1);
+    }
+
+    #[test]
+    fn legacy_addr_entries_get_reencoded() {
+        #[derive(serde::Serialize)]
+        struct LegacyAddrDbEntry {
+            label: Option<String>,
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let addr = BluetoothAddress::from(1);
+        {
+            let db = Db::open(dir.path()).unwrap();
+            let mut txn = db.write_txn().unwrap();
+            let legacy = LegacyAddrDbEntry {
+                label: Some("garden".to_owned()),
+            };
+            db.addr_db
+                .remap_data_type::<SerdeBincode<LegacyAddrDbEntry>>()
+                .put(&mut txn, &addr, &legacy)
+                .unwrap();
+            db.meta_db.put(&mut txn, SCHEMA_VERSION_KEY, &1).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let db = Db::open(dir.path()).unwrap();
+        let txn = db.read_txn().unwrap();
+        let entry = db.get_addr(&txn, addr).unwrap().unwrap();
+        assert_eq!(entry.label.as_deref(), Some("garden"));
+        assert_eq!(entry.placement, Placement::Indoor);
+        assert!(entry.log);
+        assert!(entry.sync_clock);
+        assert!(!entry.require_encryption);
+    }
+
+    #[test]
+    fn addr_entries_of_every_version_decode() {
+        let entry = AddrDbEntry {
+            label: Some("attic".to_owned()),
+            ..AddrDbEntry::default()
+        };
+        let encoded = entry.encode().unwrap();
+        assert_eq!(encoded[0], ADDR_ENTRY_VERSION);
+        let decoded = AddrDbEntry::decode(&encoded).unwrap();
+        assert_eq!(decoded.label.as_deref(), Some("attic"));
+
+        let unversioned = AddrDbEntry::decode(br#"{"label":"cellar"}"#).unwrap();
+        assert_eq!(unversioned.label.as_deref(), Some("cellar"));
+        assert!(unversioned.sync_clock);
+        assert!(unversioned.battery_changed.is_none());
+
+        let with_metadata =
+            AddrDbEntry::decode(br#"{"label":null,"battery_changed":"2021-03-04"}"#).unwrap();
+        let changed = with_metadata.battery_changed.unwrap();
+        assert_eq!(changed.to_string(), "2021-03-04");
+        assert!(AddrDbEntry::decode(br#"{"label":null,"purchased":"04.03.2021"}"#).is_err());
+        assert!(matches!(
+            AddrDbEntry::decode(b"\x07{}"),
+            Err(Error::NewerEncoding { version: 7, .. })
+        ));
+    }
+
+    #[test]
+    fn migrating_needs_a_database_per_sensor() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = Db::open(dir.path()).unwrap();
+            let mut txn = db.write_txn().unwrap();
+            db.put_addr(&mut txn, BluetoothAddress::from(1), &AddrDbEntry::default())
+                .unwrap();
+            db.meta_db.put(&mut txn, SCHEMA_VERSION_KEY, &0).unwrap();
+            txn.commit().unwrap();
+        }
+
+        assert!(matches!(
+            Db::open_with(dir.path(), None, NAMED_DBS),
+            Err(Error::TooFewDbs { needed: 10, .. })
+        ));
+        assert!(Db::open_with(dir.path(), None, NAMED_DBS + 1).is_ok());
+    }
+
+    #[test]
+    fn sealed_days_and_rows_merge_by_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let addr = BluetoothAddress::from(1);
+        let db = Db::open(dir.path()).unwrap();
+        let mut txn = db.write_txn().unwrap();
+        db.put_addr(&mut txn, addr, &AddrDbEntry::default())
+            .unwrap();
+        txn.commit().unwrap();
+        let day = Timestamp::ONE_DAY.as_u32();
+        let mut batch = LogBatch::default();
+        for i in 0..432 {
+            let time = Timestamp::from(day + i * 600);
+            batch.push(addr, time, test_values(20_00, 50_00), Quality::empty());
+        }
+        db.write_log(&batch).unwrap();
+        assert_eq!(db.seal_blocks(Timestamp::from(3 * day)).unwrap(), 2);
+
+        let range = Timestamp::from(day + day / 2)..Timestamp::from(3 * day + day / 2);
+        let txn = db.read_txn().unwrap();
+        assert_eq!(db.log_count(&txn, addr, range.clone()).unwrap(), 288);
+        let log = db
+            .get_log(&txn, addr, range.clone(), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(log.len(), 288);
+        assert_eq!(log[0].0, range.start);
+        assert!(log
+            .windows(2)
+            .all(|pair| pair[1].0.as_u32() - pair[0].0.as_u32() == 600));
+        let thinned = db.get_log(&txn, addr, range, Some(200)).unwrap().unwrap();
+        assert_eq!(thinned.len(), 144);
+    }
+
+    #[test]
+    fn log_entries_get_quality_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let addr = BluetoothAddress::from(1);
+        {
+            let db = Db::open(dir.path()).unwrap();
+            let mut txn = db.write_txn().unwrap();
+            db.put_addr(&mut txn, addr, &AddrDbEntry::default())
+                .unwrap();
+            let legacy_db = db.log_db.remap_data_type::<OwnedType<RawSensorValues>>();
+            for time in 0..3 {
+                let key = LogKey::new(addr, Timestamp::from(time));
+                legacy_db
+                    .put(
+                        &mut txn,
+                        &key,
+                        &RawSensorValues::from(test_values(20_00, 50_00)),
+                    )
+                    .unwrap();
+            }
+            db.meta_db.put(&mut txn, SCHEMA_VERSION_KEY, &2).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let db = Db::open(dir.path()).unwrap();
+        let mut batch = LogBatch::default();
+        batch.push(
+            addr,
+            Timestamp::from(3),
+            test_values(21_00, 50_00),
+            Quality::IMPORTED,
+        );
+        db.write_log(&batch).unwrap();
+        let txn = db.read_txn().unwrap();
+        let qualities = db
+            .get_flagged_log(&txn, addr, Timestamp::UNIX_EPOCH..Timestamp::MAX, None)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|(_, _, quality)| quality)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            qualities,
+            vec![
+                Quality::empty(),
+                Quality::empty(),
+                Quality::empty(),
+                Quality::IMPORTED
+            ]
+        );
+    }
+
+    #[test]
+    fn connections_stay_apart_from_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let addr = BluetoothAddress::from(1);
+        let db = Db::open(dir.path()).unwrap();
+        let mut txn = db.write_txn().unwrap();
+        db.put_addr(&mut txn, addr, &AddrDbEntry::default())
+            .unwrap();
+        db.put_connection(&mut txn, addr, Timestamp::from(10), Connection::Connected)
+            .unwrap();
+        db.put_connection(&mut txn, addr, Timestamp::from(50), Connection::Lost)
+            .unwrap();
+        txn.commit().unwrap();
+
+        let txn = db.read_txn().unwrap();
+        assert_eq!(
+            db.get_connections(&txn, addr, Timestamp::from(20)..Timestamp::MAX)
+                .unwrap(),
+            Some(vec![(Timestamp::from(50), Connection::Lost)])
+        );
+        assert_eq!(
+            db.log_stats(&txn, addr).unwrap().map(|log| log.entries),
+            None
+        );
+        assert!(db
+            .get_connections(
+                &txn,
+                BluetoothAddress::from(2),
+                Timestamp::UNIX_EPOCH..Timestamp::MAX
+            )
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn starts_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(Db::open(dir.path()).unwrap().count_start().unwrap(), 1);
+        assert_eq!(Db::open(dir.path()).unwrap().count_start().unwrap(), 2);
+    }
+
+    #[test]
+    fn commits_announce_the_newest_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let mut commits = db.subscribe_commits();
+        let mut batch = LogBatch::default();
+        batch.push(
+            BluetoothAddress::from(1),
+            Timestamp::from(120),
+            test_values(20_00, 50_00),
+            Quality::empty(),
+        );
+        batch.push(
+            BluetoothAddress::from(1),
+            Timestamp::from(60),
+            test_values(20_00, 50_00),
+            Quality::empty(),
+        );
+        batch.push(
+            BluetoothAddress::from(2),
+            Timestamp::from(60),
+            test_values(20_00, 50_00),
+            Quality::empty(),
+        );
+        db.write_log(&batch).unwrap();
+
+        let committed = commits.try_recv().unwrap();
+        assert_eq!(committed.entries, 3);
+        assert_eq!(
+            committed.newest[&BluetoothAddress::from(1)],
+            Timestamp::from(120)
+        );
+        assert_eq!(
+            committed.newest[&BluetoothAddress::from(2)],
+            Timestamp::from(60)
+        );
+    }
+}
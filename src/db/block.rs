@@ -0,0 +1,521 @@
+use super::{heed_err, rollup::Resolution, Db, Error, LogKey, LogValues};
+use crate::{
+    bluetooth::BluetoothAddress,
+    sensor::{Quality, RawSensorValues},
+    timestamp::Timestamp,
+};
+use heed::types::DecodeIgnore;
+use std::{borrow::Cow, collections::BTreeSet, ops::Range};
+
+/// Leading byte of a block, for telling encodings apart once there's more than one
+const BLOCK_VERSION: u8 = 1;
+
+/// Days sealed per write transaction, keeps the first run over years of rows from holding one
+/// huge transaction
+const DAYS_PER_TXN: usize = 64;
+
+/// Appends single bits to a byte vector, most significant bit first
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// bits of the last byte in use, 0 if it's full
+    used: u8,
+}
+
+impl BitWriter {
+    fn push(&mut self, value: u64, bits: u8) {
+        for i in (0..bits).rev() {
+            if self.used == 0 {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().unwrap() |= bit << (7 - self.used);
+            self.used = (self.used + 1) % 8;
+        }
+    }
+
+    /// Gorilla style buckets, small changes are far more common than big ones
+    fn push_signed(&mut self, value: i64) {
+        match value {
+            0 => self.push(0b0, 1),
+            -64..=63 => {
+                self.push(0b10, 2);
+                self.push(value as u64, 7);
+            }
+            -256..=255 => {
+                self.push(0b110, 3);
+                self.push(value as u64, 9);
+            }
+            -2048..=2047 => {
+                self.push(0b1110, 4);
+                self.push(value as u64, 12);
+            }
+            _ => {
+                self.push(0b1111, 4);
+                self.push(value as u64, 64);
+            }
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: Cow<'a, [u8]>,
+    /// in bits
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn read(&mut self, bits: u8) -> Result<u64, Error> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = self
+                .bytes
+                .get(self.pos / 8)
+                .ok_or(Error::Corrupt("log_block"))?;
+            value = (value << 1) | u64::from((byte >> (7 - self.pos % 8)) & 1);
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    fn read_signed(&mut self) -> Result<i64, Error> {
+        let bits = if self.read(1)? == 0 {
+            return Ok(0);
+        } else if self.read(1)? == 0 {
+            7
+        } else if self.read(1)? == 0 {
+            9
+        } else if self.read(1)? == 0 {
+            12
+        } else {
+            64
+        };
+        // sign extends the two's complement value
+        let shift = 64 - bits;
+        Ok(((self.read(bits)? << shift) as i64) >> shift)
+    }
+}
+
+fn raw_fields(values: &RawSensorValues) -> [i64; 3] {
+    [
+        i64::from(values.temperature),
+        i64::from(values.humidity),
+        i64::from(values.pressure),
+    ]
+}
+
+/// Timestamps as delta of deltas and values as deltas to the previous entry, a day of entries a
+/// minute apart takes about 4 bytes each instead of a key and value per entry
+fn encode(entries: &[(Timestamp, LogValues)]) -> Vec<u8> {
+    let mut bits = BitWriter::default();
+    bits.push(entries.len() as u64, 32);
+    let mut previous: Option<(Timestamp, i64, [i64; 3], u8)> = None;
+    for (time, logged) in entries {
+        let fields = raw_fields(&logged.values);
+        match previous {
+            None => {
+                bits.push(u64::from(time.as_u32()), 32);
+                bits.push(fields[0] as u64, 16);
+                bits.push(fields[1] as u64, 16);
+                bits.push(fields[2] as u64, 32);
+                bits.push(u64::from(logged.quality), 8);
+                previous = Some((*time, 0, fields, logged.quality));
+            }
+            Some((previous_time, previous_delta, previous_fields, previous_quality)) => {
+                let delta = i64::from(time.as_u32()) - i64::from(previous_time.as_u32());
+                bits.push_signed(delta - previous_delta);
+                for (field, previous_field) in fields.iter().zip(&previous_fields) {
+                    bits.push_signed(field - previous_field);
+                }
+                if logged.quality == previous_quality {
+                    bits.push(0, 1);
+                } else {
+                    bits.push(1, 1);
+                    bits.push(u64::from(logged.quality), 8);
+                }
+                previous = Some((*time, delta, fields, logged.quality));
+            }
+        }
+    }
+
+    let mut block = vec![BLOCK_VERSION];
+    block.extend(bits.bytes);
+    block
+}
+
+fn decode(block: &[u8]) -> Result<Vec<(Timestamp, LogValues)>, Error> {
+    BlockEntries::new(Cow::Borrowed(block))?.collect()
+}
+
+/// Entries of a block decoded one after another, oldest first, so readers can stop early
+pub(super) struct BlockEntries<'a> {
+    bits: BitReader<'a>,
+    remaining: usize,
+    /// time, delta to the time before, fields and quality of the entry before
+    previous: Option<(i64, i64, [i64; 3], u8)>,
+}
+
+impl<'a> BlockEntries<'a> {
+    fn new(block: Cow<'a, [u8]>) -> Result<Self, Error> {
+        if block.first() != Some(&BLOCK_VERSION) {
+            return Err(Error::Corrupt("log_block"));
+        }
+        let mut bits = BitReader {
+            bytes: block,
+            pos: 8,
+        };
+        let remaining = bits.read(32)? as usize;
+        Ok(Self {
+            bits,
+            remaining,
+            previous: None,
+        })
+    }
+
+    /// Entries not read yet, straight from the header of the block
+    pub(super) fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    fn read_entry(&mut self) -> Result<(Timestamp, LogValues), Error> {
+        let bits = &mut self.bits;
+        let (time, delta, fields, quality) = match self.previous {
+            None => {
+                let time = i64::from(bits.read(32)? as u32);
+                let fields = [
+                    i64::from(bits.read(16)? as u16 as i16),
+                    i64::from(bits.read(16)? as u16),
+                    i64::from(bits.read(32)? as u32),
+                ];
+                (time, 0, fields, bits.read(8)? as u8)
+            }
+            Some((time, delta, mut fields, mut quality)) => {
+                let delta = delta + bits.read_signed()?;
+                for field in &mut fields {
+                    *field += bits.read_signed()?;
+                }
+                if bits.read(1)? == 1 {
+                    quality = bits.read(8)? as u8;
+                }
+                (time + delta, delta, fields, quality)
+            }
+        };
+        self.previous = Some((time, delta, fields, quality));
+
+        let values = RawSensorValues {
+            temperature: fields[0] as i16,
+            humidity: fields[1] as u16,
+            pressure: fields[2] as u32,
+        };
+        Ok((
+            Timestamp::from(time as u32),
+            LogValues::new(values, Quality::from_bits_truncate(quality)),
+        ))
+    }
+}
+
+impl Iterator for BlockEntries<'_> {
+    type Item = Result<(Timestamp, LogValues), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let entry = self.read_entry();
+        // nothing after a broken entry can be trusted
+        self.remaining = if entry.is_ok() { self.remaining - 1 } else { 0 };
+        Some(entry)
+    }
+}
+
+/// Additional data of a block, ties it to its database, sensor and day
+fn block_aad(key: &LogKey) -> Vec<u8> {
+    let mut aad = b"log_block".to_vec();
+    aad.extend_from_slice(bytemuck::bytes_of(key));
+    aad
+}
+
+impl Db {
+    fn read_block<'a>(&self, key: &LogKey, stored: &'a [u8]) -> Result<BlockEntries<'a>, Error> {
+        BlockEntries::new(self.unseal("log_block", &block_aad(key), stored)?)
+    }
+
+    fn decode_block(
+        &self,
+        key: &LogKey,
+        stored: &[u8],
+    ) -> Result<Vec<(Timestamp, LogValues)>, Error> {
+        self.read_block(key, stored)?.collect()
+    }
+
+    /// Entries of `addr` in `range` out of the sealed days, oldest first. Blocks get decoded
+    /// one at a time as the iterator gets there and not past the end of `range`.
+    pub(super) fn block_entries<'txn, T>(
+        &'txn self,
+        txn: &'txn heed::RoTxn<'_, T>,
+        addr: BluetoothAddress,
+        range: Range<Timestamp>,
+    ) -> Result<impl Iterator<Item = Result<(Timestamp, LogValues), Error>> + 'txn, Error> {
+        let days = Resolution::Day.bucket(range.start)..range.end;
+        let Range { start, end } = range;
+        Ok(self
+            .block_db
+            .range(txn, &LogKey::range(addr, days))?
+            .flat_map(
+                move |block| -> Box<dyn Iterator<Item = Result<_, Error>> + 'txn> {
+                    match block
+                        .map_err(Error::from)
+                        .and_then(|(key, stored)| self.read_block(&key, stored))
+                    {
+                        Ok(entries) => Box::new(entries),
+                        Err(e) => Box::new(std::iter::once(Err(e))),
+                    }
+                },
+            )
+            .skip_while(move |entry| matches!(entry, Ok((time, _)) if *time < start))
+            .take_while(move |entry| !matches!(entry, Ok((time, _)) if *time >= end)))
+    }
+
+    /// Number of sealed entries of `addr` in `range`, days within it are counted from the
+    /// headers of their blocks
+    pub(super) fn block_count<T>(
+        &self,
+        txn: &heed::RoTxn<'_, T>,
+        addr: BluetoothAddress,
+        range: Range<Timestamp>,
+    ) -> Result<usize, Error> {
+        let days = Resolution::Day.bucket(range.start)..range.end;
+        let mut count = 0;
+        for block in self.block_db.range(txn, &LogKey::range(addr, days))? {
+            let (key, stored) = block?;
+            let entries = self.read_block(&key, stored)?;
+            let day_end = key
+                .time()
+                .as_u32()
+                .saturating_add(Resolution::Day.seconds());
+            if key.time() >= range.start && day_end <= range.end.as_u32() {
+                count += entries.remaining();
+                continue;
+            }
+            for entry in entries {
+                let (time, _) = entry?;
+                if time >= range.end {
+                    break;
+                }
+                if time >= range.start {
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// Times of the first and last sealed entry of `addr`
+    pub(super) fn block_bounds<T>(
+        &self,
+        txn: &heed::RoTxn<'_, T>,
+        addr: BluetoothAddress,
+    ) -> Result<Option<(Timestamp, Timestamp)>, Error> {
+        let range = LogKey::sensor(addr);
+        let first = self.block_db.range(txn, &range)?.next().transpose()?;
+        let last = self.block_db.rev_range(txn, &range)?.next().transpose()?;
+        match (first, last) {
+            (Some((first_key, first)), Some((last_key, last))) => {
+                let first = self.read_block(&first_key, first)?.next().transpose()?;
+                let last = self.read_block(&last_key, last)?.last().transpose()?;
+                Ok(first.zip(last).map(|((first, _), (last, _))| (first, last)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Moves the entries of the sealed `days` back into rows so they can be written to, they
+    /// get sealed again later
+    pub(super) fn unseal_days(
+        &self,
+        txn: &mut heed::RwTxn<'_, '_>,
+        days: &BTreeSet<(BluetoothAddress, Timestamp)>,
+    ) -> Result<(), Error> {
+        for &(addr, day) in days {
+            let key = LogKey::new(addr, day);
+            let entries = match self.block_db.get(txn, &key)? {
+                Some(stored) => self.decode_block(&key, stored)?,
+                None => continue,
+            };
+            for (time, values) in entries {
+                self.put_log(txn, &LogKey::new(addr, time), &values)?;
+            }
+            self.block_db.delete(txn, &key)?;
+        }
+        Ok(())
+    }
+
+    /// Moves every block back into rows, the rollups get rebuilt from rows only
+    pub(super) fn unseal_all(&self, txn: &mut heed::RwTxn<'_, '_>) -> Result<(), Error> {
+        let days = self
+            .block_db
+            .remap_data_type::<DecodeIgnore>()
+            .iter(txn)?
+            .map(|block| block.map(|(key, ())| (key.addr(), key.time())))
+            .collect::<Result<BTreeSet<_>, _>>()?;
+        self.unseal_days(txn, &days)
+    }
+
+    /// Moves every block of a plaintext database back into rows, done before encrypting it
+    pub(super) fn unseal_plain_blocks(
+        &self,
+        txn: &mut heed::RwTxn<'_, '_>,
+    ) -> Result<usize, Error> {
+        let blocks = self
+            .block_db
+            .iter(txn)?
+            .map(|block| block.map(|(key, stored)| (key, stored.to_vec())))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (key, stored) in &blocks {
+            for (time, values) in decode(stored)? {
+                // plaintext like the rows the caller encrypts next
+                self.log_db.put(
+                    txn,
+                    &LogKey::new(key.addr(), time),
+                    bytemuck::bytes_of(&values),
+                )?;
+            }
+        }
+        self.block_db.clear(txn)?;
+        Ok(blocks.len())
+    }
+
+    /// Compresses the rows of every day that ended before the one `before` falls into into a
+    /// block per sensor and day, returns the number of blocks. Later writes to a sealed day
+    /// unseal it again.
+    pub fn seal_blocks(&self, before: Timestamp) -> Result<usize, Error> {
+        let before = Resolution::Day.bucket(before);
+        let days = {
+            let txn = self.read_txn()?;
+            self.log_db
+                .remap_data_type::<DecodeIgnore>()
+                .iter(&txn)?
+                .filter_map(|entry| match entry {
+                    Ok((key, ())) if key.time() < before => {
+                        Some(Ok((key.addr(), Resolution::Day.bucket(key.time()))))
+                    }
+                    Ok(_) => None,
+                    Err(e) => Some(Err(heed_err(e))),
+                })
+                .collect::<Result<BTreeSet<_>, _>>()?
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+        // rows written in between get sealed along with the rest of their day
+        for chunk in days.chunks(DAYS_PER_TXN) {
+            let mut txn = self.write_txn()?;
+            for &(addr, day) in chunk {
+                self.seal_day(&mut txn, addr, day)?;
+            }
+            txn.commit()?;
+        }
+        Ok(days.len())
+    }
+
+    fn seal_day(
+        &self,
+        txn: &mut heed::RwTxn<'_, '_>,
+        addr: BluetoothAddress,
+        day: Timestamp,
+    ) -> Result<(), Error> {
+        let end = Timestamp::from(day.as_u32() + Resolution::Day.seconds());
+        let rows = LogKey::range(addr, day..end);
+        let mut entries = self
+            .log_db
+            .range(txn, &rows)?
+            .map(|entry| {
+                let (key, stored) = entry?;
+                Ok((key.time(), self.get_log_values(&key, stored)?))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+        // writes unseal a day before adding to it, a block next to rows would be older than them
+        let key = LogKey::new(addr, day);
+        if let Some(stored) = self.block_db.get(txn, &key)? {
+            let times = entries
+                .iter()
+                .map(|(time, _)| *time)
+                .collect::<BTreeSet<_>>();
+            entries.extend(
+                self.decode_block(&key, stored)?
+                    .into_iter()
+                    .filter(|(time, _)| !times.contains(time)),
+            );
+            entries.sort_by_key(|(time, _)| *time);
+        }
+
+        let block = encode(&entries);
+        let stored = self.seal(&block_aad(&key), &block);
+        self.block_db.put(txn, &key, &stored)?;
+        self.log_db.delete_range(txn, &rows)?;
+        Ok(())
+    }
+
+    /// Blocks that can't be decrypted or decoded, by key
+    pub(super) fn broken_blocks<T>(&self, txn: &heed::RoTxn<'_, T>) -> Result<Vec<LogKey>, Error> {
+        let mut broken = Vec::new();
+        for block in self.block_db.iter(txn)? {
+            let (key, stored) = block?;
+            if self.decode_block(&key, stored).is_err() {
+                broken.push(key);
+            }
+        }
+        Ok(broken)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn logged(temperature: i16, humidity: u16, pressure: u32, quality: Quality) -> LogValues {
+        LogValues::new(
+            RawSensorValues {
+                temperature,
+                humidity,
+                pressure,
+            },
+            quality,
+        )
+    }
+
+    #[test]
+    fn blocks_round_trip_and_stay_small() {
+        let entries = (0..1440_u32)
+            .map(|i| {
+                // a missed reading now and then and a jump in the pressure
+                let time = Timestamp::from(86_400 + i * 60 + if i % 100 == 0 { 7 } else { 0 });
+                let quality = if i == 500 {
+                    Quality::INTERPOLATED
+                } else {
+                    Quality::empty()
+                };
+                let pressure = if i < 700 { 1_013_250 } else { 990_000 } + i % 3;
+                (
+                    time,
+                    logged(-5_00 + i as i16, 40_00 + (i % 7) as u16, pressure, quality),
+                )
+            })
+            .collect::<Vec<_>>();
+        let block = encode(&entries);
+        assert!(block.len() < entries.len() * 5);
+
+        let decoded = decode(&block).unwrap();
+        assert_eq!(decoded.len(), entries.len());
+        for ((time, values), (decoded_time, decoded_values)) in entries.iter().zip(&decoded) {
+            assert_eq!(time, decoded_time);
+            assert_eq!(
+                bytemuck::bytes_of(values),
+                bytemuck::bytes_of(decoded_values)
+            );
+        }
+        assert!(decode(&block[..block.len() / 2]).is_err());
+    }
+}
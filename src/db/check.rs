@@ -0,0 +1,280 @@
+use super::{AddrDbEntry, Db, Error, LogKey, LogValues};
+use crate::{
+    bluetooth::BluetoothAddress,
+    sensor::{Quality, SensorValues},
+    timestamp::Timestamp,
+};
+use heed::types::ByteSlice;
+use std::{convert::TryFrom, fmt};
+
+/// Something wrong with an entry of the database, found by [`Db::check`]
+#[derive(Debug)]
+pub(crate) enum Problem {
+    /// key or value doesn't have the size of its type
+    Malformed { db: &'static str, key: Vec<u8> },
+    /// value that can't be decrypted with the key of the database
+    Undecryptable { db: &'static str, key: Vec<u8> },
+    /// addr entry that isn't valid json of an entry
+    UndecodableAddr {
+        addr: BluetoothAddress,
+        error: String,
+    },
+    /// keys are kept sorted, anything else means a broken btree
+    OutOfOrder { key: Vec<u8> },
+    /// values no sensor can measure or unknown quality flags
+    Impossible {
+        addr: BluetoothAddress,
+        time: Timestamp,
+        reason: String,
+    },
+    /// entry from after the check started
+    Future {
+        addr: BluetoothAddress,
+        time: Timestamp,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Malformed { db, key } => write!(f, "Malformed entry in {} db: {:x?}", db, key),
+            Problem::Undecryptable { db, key } => {
+                write!(f, "Undecryptable entry in {} db: {:x?}", db, key)
+            }
+            Problem::UndecodableAddr { addr, error } => {
+                write!(f, "Undecodable addr entry of {}: {}", addr, error)
+            }
+            Problem::OutOfOrder { key } => write!(f, "Log key out of order: {:x?}", key),
+            Problem::Impossible { addr, time, reason } => write!(
+                f,
+                "Impossible log entry of {} at {}: {}",
+                addr,
+                time.as_u32(),
+                reason
+            ),
+            Problem::Future { addr, time } => write!(
+                f,
+                "Log entry of {} from the future at {}",
+                addr,
+                time.as_u32()
+            ),
+        }
+    }
+}
+
+/// What [`Db::check`] found and did about it
+#[derive(Default)]
+pub(crate) struct Report {
+    pub(crate) log_entries: usize,
+    pub(crate) addr_entries: usize,
+    pub(crate) problems: Vec<Problem>,
+    /// log entries deleted and addr entries reset to the defaults, only when repairing
+    pub(crate) repaired: usize,
+}
+
+impl Db {
+    /// Scans the log, its sealed days and the addr database for entries that can't be decoded,
+    /// keys out of order and values that can't have been measured. With `repair` broken log
+    /// entries and days get deleted, broken addr entries reset and the rollups rebuilt without
+    /// the deleted entries.
+    pub fn check(&self, repair: bool) -> Result<Report, Error> {
+        let now = Timestamp::now();
+        let raw_log = self.log_db.remap_types::<ByteSlice, ByteSlice>();
+        let raw_addr = self.addr_db.remap_types::<ByteSlice, ByteSlice>();
+        let mut report = Report::default();
+        let mut broken_log = Vec::new();
+        let mut broken_addr = Vec::new();
+        let mut broken_blocks = Vec::new();
+
+        {
+            let txn = self.read_txn()?;
+            let mut previous: Option<Vec<u8>> = None;
+            for entry in raw_log.iter(&txn)? {
+                let (key, value) = entry?;
+                report.log_entries += 1;
+                let out_of_order = previous
+                    .as_deref()
+                    .map_or(false, |previous| previous >= key);
+                previous = Some(key.to_vec());
+                let problem = if out_of_order {
+                    Some(Problem::OutOfOrder { key: key.to_vec() })
+                } else {
+                    match self.unseal("log", key, value) {
+                        Ok(value) => check_log_entry(key, &value, now),
+                        Err(_) => Some(Problem::Undecryptable {
+                            db: "log",
+                            key: key.to_vec(),
+                        }),
+                    }
+                };
+                if let Some(problem) = problem {
+                    report.problems.push(problem);
+                    broken_log.push(key.to_vec());
+                }
+            }
+
+            for key in self.broken_blocks(&txn)? {
+                report.problems.push(Problem::Malformed {
+                    db: "log_block",
+                    key: bytemuck::bytes_of(&key).to_vec(),
+                });
+                broken_blocks.push(key);
+            }
+
+            for entry in raw_addr.iter(&txn)? {
+                let (key, value) = entry?;
+                report.addr_entries += 1;
+                let addr = match <[u8; 8]>::try_from(key) {
+                    Ok(bytes) => BluetoothAddress::from(u64::from_ne_bytes(bytes)),
+                    Err(_) => {
+                        report.problems.push(Problem::Malformed {
+                            db: "addr",
+                            key: key.to_vec(),
+                        });
+                        broken_addr.push((key.to_vec(), None));
+                        continue;
+                    }
+                };
+                let value = match self.unseal("addr", key, value) {
+                    Ok(value) => value,
+                    Err(_) => {
+                        report.problems.push(Problem::Undecryptable {
+                            db: "addr",
+                            key: key.to_vec(),
+                        });
+                        broken_addr.push((key.to_vec(), Some(addr)));
+                        continue;
+                    }
+                };
+                match AddrDbEntry::decode(&value) {
+                    Ok(_) => {}
+                    // resetting it would throw away settings a newer central can read
+                    Err(e @ Error::NewerEncoding { .. }) => {
+                        report.problems.push(Problem::UndecodableAddr {
+                            addr,
+                            error: e.to_string(),
+                        });
+                    }
+                    Err(e) => {
+                        let error = match e {
+                            Error::Heed(source) => source.to_string(),
+                            e => e.to_string(),
+                        };
+                        report
+                            .problems
+                            .push(Problem::UndecodableAddr { addr, error });
+                        broken_addr.push((key.to_vec(), Some(addr)));
+                    }
+                }
+            }
+        }
+
+        if repair && !(broken_log.is_empty() && broken_addr.is_empty() && broken_blocks.is_empty())
+        {
+            let mut txn = self.write_txn()?;
+            for key in broken_blocks {
+                if self.block_db.delete(&mut txn, &key)? {
+                    report.repaired += 1;
+                }
+            }
+            for key in broken_log {
+                if raw_log.delete(&mut txn, &key)? {
+                    report.repaired += 1;
+                }
+            }
+            for (key, addr) in broken_addr {
+                match addr {
+                    Some(addr) => self.put_addr(&mut txn, addr, &AddrDbEntry::default())?,
+                    None => {
+                        raw_addr.delete(&mut txn, &key)?;
+                    }
+                }
+                report.repaired += 1;
+            }
+            self.rebuild_rollups(&mut txn)?;
+            txn.commit()?;
+        }
+
+        Ok(report)
+    }
+}
+
+fn check_log_entry(key: &[u8], value: &[u8], now: Timestamp) -> Option<Problem> {
+    let mut parsed_key = LogKey::new(BluetoothAddress::from(0), Timestamp::UNIX_EPOCH);
+    let mut parsed_value: LogValues = bytemuck::Zeroable::zeroed();
+    if key.len() != std::mem::size_of::<LogKey>() || value.len() != std::mem::size_of::<LogValues>()
+    {
+        return Some(Problem::Malformed {
+            db: "log",
+            key: key.to_vec(),
+        });
+    }
+    bytemuck::bytes_of_mut(&mut parsed_key).copy_from_slice(key);
+    bytemuck::bytes_of_mut(&mut parsed_value).copy_from_slice(value);
+
+    let (addr, time) = (parsed_key.addr(), parsed_key.time());
+    if time > now {
+        return Some(Problem::Future { addr, time });
+    }
+    if let Err(e) = SensorValues::try_from(parsed_value.values) {
+        return Some(Problem::Impossible {
+            addr,
+            time,
+            reason: e.to_string(),
+        });
+    }
+    if Quality::from_bits(parsed_value.quality).is_none() {
+        return Some(Problem::Impossible {
+            addr,
+            time,
+            reason: format!("unknown quality flags {:#b}", parsed_value.quality),
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{db::LogBatch, sensor::RawSensorValues};
+
+    #[test]
+    fn finds_and_deletes_impossible_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let addr = BluetoothAddress::from(1);
+        let db = Db::open(dir.path()).unwrap();
+        let mut txn = db.write_txn().unwrap();
+        db.put_addr(&mut txn, addr, &AddrDbEntry::default())
+            .unwrap();
+        let values = RawSensorValues {
+            temperature: 20_00,
+            humidity: 50_00,
+            pressure: 1_000_000,
+        };
+        let broken = RawSensorValues {
+            humidity: 150_00,
+            ..values
+        };
+        db.put_log(
+            &mut txn,
+            &LogKey::new(addr, Timestamp::from(20)),
+            &LogValues::new(broken, Quality::empty()),
+        )
+        .unwrap();
+        txn.commit().unwrap();
+        let mut batch = LogBatch::default();
+        batch.push(
+            addr,
+            Timestamp::from(10),
+            SensorValues::try_from(values).unwrap(),
+            Quality::empty(),
+        );
+        db.write_log(&batch).unwrap();
+
+        let report = db.check(true).unwrap();
+        assert_eq!(report.log_entries, 2);
+        assert_eq!(report.problems.len(), 1);
+        assert_eq!(report.repaired, 1);
+        assert!(db.check(false).unwrap().problems.is_empty());
+    }
+}
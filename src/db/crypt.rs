@@ -0,0 +1,264 @@
+use super::{Db, Error, LogKey, LogValues};
+use aes_gcm::{
+    aead::{generic_array::GenericArray, Aead, NewAead, Payload},
+    Aes256Gcm,
+};
+use heed::types::{ByteSlice, OwnedType};
+use std::{convert::TryFrom, fmt, fs, ops::Bound, path::Path};
+
+const NONCE_SIZE: usize = 12;
+
+/// Meta entry holding [`KEY_CHECK`] encrypted with the key of the database, only exists in
+/// encrypted databases
+pub(super) const KEY_CHECK_KEY: &str = "key_check";
+
+const KEY_CHECK: &[u8] = b"weatherstation";
+
+/// Key the entries of the database get encrypted with, 32 bytes for AES-256-GCM
+#[derive(Clone, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct Key([u8; 32]);
+
+impl Key {
+    /// Key files have the key as is, like from `head -c 32 /dev/urandom`, or hex encoded
+    pub(crate) fn read(path: &Path) -> Result<Self, eyre::Error> {
+        let content = fs::read(path)?;
+        match <[u8; 32]>::try_from(content.as_slice()) {
+            Ok(key) => Ok(Self(key)),
+            Err(_) => Self::from_hex(String::from_utf8_lossy(&content).trim())
+                .map_err(|e| eyre::format_err!(e)),
+        }
+    }
+
+    fn from_hex(s: &str) -> Result<Self, String> {
+        let bytes = hex::decode(s).map_err(|e| format!("Database key isn't hex: {}", e))?;
+        <[u8; 32]>::try_from(bytes.as_slice())
+            .map(Self)
+            .map_err(|_| format!("Database key has {} bytes instead of 32", bytes.len()))
+    }
+}
+
+impl TryFrom<String> for Key {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::from_hex(s.trim())
+    }
+}
+
+/// Never shows the key itself
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// Encrypts single entries, every one gets a random nonce and is bound to its database key so
+/// entries can't be swapped around
+pub(super) struct Cipher(Aes256Gcm);
+
+impl Cipher {
+    pub(super) fn new(key: &Key) -> Self {
+        Self(Aes256Gcm::new(GenericArray::from_slice(&key.0)))
+    }
+
+    /// Nonce followed by the encrypted `plain` and its tag
+    pub(super) fn seal(&self, aad: &[u8], plain: &[u8]) -> Vec<u8> {
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.0
+                .encrypt(
+                    GenericArray::from_slice(&nonce),
+                    Payload { msg: plain, aad },
+                )
+                .expect("Entries are far below the size limit of AES-GCM"),
+        );
+        sealed
+    }
+
+    /// `None` for entries that were tampered with or encrypted with another key
+    pub(super) fn open(&self, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        self.0
+            .decrypt(
+                GenericArray::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .ok()
+    }
+}
+
+impl Db {
+    /// Encrypts all entries of a database that was plaintext until now, the rollups get built
+    /// again from the encrypted log
+    pub(super) fn encrypt_existing(&self) -> Result<(), Error> {
+        let cipher = self
+            .cipher
+            .as_ref()
+            .expect("Only called once the cipher is set");
+        let mut txn = self.write_txn()?;
+
+        let addr_entries = self
+            .addr_db
+            .remap_key_type::<ByteSlice>()
+            .iter(&txn)?
+            .map(|entry| entry.map(|(key, value)| (key.to_vec(), value.to_vec())))
+            .collect::<Result<Vec<_>, _>>()?;
+        let raw_addr = self.addr_db.remap_key_type::<ByteSlice>();
+        for (key, value) in &addr_entries {
+            raw_addr.put(&mut txn, key, &cipher.seal(key, value))?;
+        }
+
+        let others = [
+            ("dashboard", self.dashboard_db.remap_key_type::<ByteSlice>()),
+            (
+                "connection",
+                self.connection_db.remap_key_type::<ByteSlice>(),
+            ),
+            ("battery", self.battery_db.remap_key_type::<ByteSlice>()),
+        ];
+        for (name, db) in &others {
+            let entries = db
+                .iter(&txn)?
+                .map(|entry| entry.map(|(key, value)| (key.to_vec(), value.to_vec())))
+                .collect::<Result<Vec<_>, _>>()?;
+            for (key, value) in &entries {
+                db.put(&mut txn, key, &cipher.seal(&entry_aad(name, key), value))?;
+            }
+        }
+
+        // the blocks are plaintext and become rows that get encrypted below
+        let blocks = self.unseal_plain_blocks(&mut txn)?;
+        let plain_log = self.log_db.remap_data_type::<OwnedType<LogValues>>();
+        let mut encrypted = 0;
+        let mut after = Bound::Unbounded;
+        loop {
+            let chunk = plain_log
+                .range(&txn, &(after, Bound::Unbounded))?
+                .take(super::MIGRATION_CHUNK)
+                .collect::<Result<Vec<_>, _>>()?;
+            let last = match chunk.last() {
+                Some((key, _)) => *key,
+                None => break,
+            };
+            for (key, values) in chunk {
+                self.put_log(&mut txn, &key, &values)?;
+                encrypted += 1;
+            }
+            after = Bound::Excluded(last);
+        }
+        self.rebuild_rollups(&mut txn)?;
+
+        self.meta_db.remap_data_type::<ByteSlice>().put(
+            &mut txn,
+            KEY_CHECK_KEY,
+            &cipher.seal(KEY_CHECK_KEY.as_bytes(), KEY_CHECK),
+        )?;
+        txn.commit()?;
+        tracing::info!(
+            "Encrypted {} addr and {} log entries, {} sealed days became entries again",
+            addr_entries.len(),
+            encrypted,
+            blocks
+        );
+        Ok(())
+    }
+
+    /// Whether the database was encrypted with the key of the cipher
+    pub(super) fn key_matches(&self, check: &[u8]) -> bool {
+        self.cipher.as_ref().map_or(false, |cipher| {
+            cipher.open(KEY_CHECK_KEY.as_bytes(), check).as_deref() == Some(KEY_CHECK)
+        })
+    }
+}
+
+/// Additional data of a log entry, ties its values to its sensor and time
+pub(super) fn log_aad(key: &LogKey) -> &[u8] {
+    bytemuck::bytes_of(key)
+}
+
+/// Additional data of an entry of the `db` database, ties it to the database and its `key`
+pub(super) fn entry_aad(db: &str, key: &[u8]) -> Vec<u8> {
+    let mut aad = db.as_bytes().to_vec();
+    aad.extend_from_slice(key);
+    aad
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        bluetooth::BluetoothAddress,
+        db::{AddrDbEntry, LogBatch},
+        sensor::{Quality, RawSensorValues, SensorValues},
+        timestamp::Timestamp,
+    };
+
+    #[test]
+    fn plaintext_database_gets_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let addr = BluetoothAddress::from(1);
+        let values = SensorValues::try_from(RawSensorValues {
+            temperature: 20_00,
+            humidity: 50_00,
+            pressure: 1_000_000,
+        })
+        .unwrap();
+        {
+            let db = Db::open(dir.path()).unwrap();
+            let mut txn = db.write_txn().unwrap();
+            let entry = AddrDbEntry {
+                label: Some("bedroom".to_owned()),
+                ..AddrDbEntry::default()
+            };
+            db.put_addr(&mut txn, addr, &entry).unwrap();
+            db.put_battery(&mut txn, addr, Timestamp::from(10), 80)
+                .unwrap();
+            txn.commit().unwrap();
+            let mut batch = LogBatch::default();
+            batch.push(addr, Timestamp::from(10), values, Quality::empty());
+            db.write_log(&batch).unwrap();
+        }
+
+        let key = Key([7; 32]);
+        let db = Db::open_with_key(dir.path(), Some(&key)).unwrap();
+        let txn = db.read_txn().unwrap();
+        let raw = db
+            .addr_db
+            .get(&txn, &addr)
+            .unwrap()
+            .unwrap()
+            .windows(7)
+            .any(|window| window == b"bedroom");
+        assert!(!raw);
+        let entry = db.get_addr(&txn, addr).unwrap().unwrap();
+        assert_eq!(entry.label.as_deref(), Some("bedroom"));
+        let log = db
+            .get_log(&txn, addr, Timestamp::UNIX_EPOCH..Timestamp::MAX, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(log.len(), 1);
+        let battery = db
+            .get_battery(&txn, addr, Timestamp::UNIX_EPOCH..Timestamp::MAX)
+            .unwrap();
+        assert_eq!(battery, vec![(Timestamp::from(10), 80)]);
+        drop(txn);
+        drop(db);
+
+        assert!(matches!(
+            Db::open(dir.path()).err(),
+            Some(Error::MissingKey)
+        ));
+        assert!(matches!(
+            Db::open_with_key(dir.path(), Some(&Key([8; 32]))).err(),
+            Some(Error::WrongKey)
+        ));
+    }
+}
@@ -0,0 +1,442 @@
+use super::{heed_err, Db, Error, LogKey, LogValues, MIGRATION_CHUNK};
+use crate::{
+    bluetooth::BluetoothAddress,
+    sensor::{Quality, RawSensorValues, SensorValues},
+    timestamp::Timestamp,
+};
+use heed::types::{ByteSlice, OwnedType};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    ops::{Bound, Range},
+};
+
+/// Bucket length of one of the rollup databases
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Resolution {
+    Hour,
+    Day,
+}
+
+impl Resolution {
+    pub(crate) fn seconds(self) -> u32 {
+        match self {
+            Resolution::Hour => 60 * 60,
+            Resolution::Day => Timestamp::ONE_DAY.as_u32(),
+        }
+    }
+
+    /// Start of the bucket `time` falls into, days are UTC days
+    pub(crate) fn bucket(self, time: Timestamp) -> Timestamp {
+        Timestamp::from(time.as_u32() / self.seconds() * self.seconds())
+    }
+
+    /// Coarsest resolution with at most `limit` buckets in `range`, `None` if even hours would
+    /// be too coarse
+    pub(crate) fn for_limit(range: &Range<Timestamp>, limit: usize) -> Option<Self> {
+        let per_point = range.end.bottoming_sub(range.start).as_u32() / limit.max(1) as u32;
+        [Resolution::Day, Resolution::Hour]
+            .iter()
+            .copied()
+            .find(|resolution| per_point >= resolution.seconds())
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Resolution::Hour => "rollup_hour",
+            Resolution::Day => "rollup_day",
+        }
+    }
+}
+
+/// Aggregate of the log entries of one sensor in one bucket, value of the rollup databases
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct Rollup {
+    count: u32,
+    /// bits of every [`Quality`] of the entries
+    quality: u8,
+    _reserved: [u8; 3],
+    /// sums of the raw temperature, humidity and pressure
+    sums: [i64; 3],
+    min: RawSensorValues,
+    max: RawSensorValues,
+}
+
+/// Empty, merging anything into it gives that
+impl Default for Rollup {
+    fn default() -> Self {
+        bytemuck::Zeroable::zeroed()
+    }
+}
+
+impl Rollup {
+    fn add(&mut self, logged: &LogValues) {
+        let values = logged.values;
+        self.merge(&Rollup {
+            count: 1,
+            quality: logged.quality,
+            _reserved: [0; 3],
+            sums: [
+                i64::from(values.temperature),
+                i64::from(values.humidity),
+                i64::from(values.pressure),
+            ],
+            min: values,
+            max: values,
+        });
+    }
+
+    fn merge(&mut self, other: &Rollup) {
+        if other.count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            *self = *other;
+            return;
+        }
+        self.count += other.count;
+        self.quality |= other.quality;
+        for (sum, other) in self.sums.iter_mut().zip(&other.sums) {
+            *sum += other;
+        }
+        self.min = RawSensorValues {
+            temperature: self.min.temperature.min(other.min.temperature),
+            humidity: self.min.humidity.min(other.min.humidity),
+            pressure: self.min.pressure.min(other.min.pressure),
+        };
+        self.max = RawSensorValues {
+            temperature: self.max.temperature.max(other.max.temperature),
+            humidity: self.max.humidity.max(other.max.humidity),
+            pressure: self.max.pressure.max(other.max.pressure),
+        };
+    }
+
+    pub(crate) fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub(crate) fn quality(&self) -> Quality {
+        Quality::from_bits_truncate(self.quality)
+    }
+
+    /// Mean of all entries, `None` for an empty rollup
+    pub(crate) fn mean(&self) -> Option<SensorValues> {
+        if self.count == 0 {
+            return None;
+        }
+        let count = i64::from(self.count);
+        SensorValues::try_from(RawSensorValues {
+            temperature: (self.sums[0] / count) as i16,
+            humidity: (self.sums[1] / count) as u16,
+            pressure: (self.sums[2] / count) as u32,
+        })
+        .ok()
+    }
+}
+
+/// Buckets touched by a write, collected while the log entries get put
+#[derive(Default)]
+pub(super) struct RollupUpdate {
+    /// hours that only got new entries
+    added: BTreeMap<(BluetoothAddress, Timestamp), Rollup>,
+    /// hours where existing entries got replaced, they're recomputed from the log
+    stale: BTreeSet<(BluetoothAddress, Timestamp)>,
+}
+
+impl RollupUpdate {
+    pub(super) fn logged(
+        &mut self,
+        addr: BluetoothAddress,
+        time: Timestamp,
+        values: &LogValues,
+        replaced: bool,
+    ) {
+        let hour = (addr, Resolution::Hour.bucket(time));
+        if replaced {
+            self.stale.insert(hour);
+        } else {
+            self.added.entry(hour).or_default().add(values);
+        }
+    }
+}
+
+/// Additional data of a rollup, ties it to its database, sensor and bucket
+fn rollup_aad(resolution: Resolution, key: &LogKey) -> Vec<u8> {
+    let mut aad = resolution.name().as_bytes().to_vec();
+    aad.extend_from_slice(bytemuck::bytes_of(key));
+    aad
+}
+
+impl Db {
+    fn rollup_db(&self, resolution: Resolution) -> heed::Database<OwnedType<LogKey>, ByteSlice> {
+        match resolution {
+            Resolution::Hour => self.hour_db,
+            Resolution::Day => self.day_db,
+        }
+    }
+
+    fn put_rollup(
+        &self,
+        txn: &mut heed::RwTxn<'_, '_>,
+        resolution: Resolution,
+        key: &LogKey,
+        rollup: &Rollup,
+    ) -> Result<(), Error> {
+        let stored = self.seal(&rollup_aad(resolution, key), bytemuck::bytes_of(rollup));
+        self.rollup_db(resolution)
+            .put(txn, key, &stored)
+            .map_err(heed_err)
+    }
+
+    fn decode_rollup(
+        &self,
+        resolution: Resolution,
+        key: &LogKey,
+        stored: &[u8],
+    ) -> Result<Rollup, Error> {
+        let plain = self.unseal(resolution.name(), &rollup_aad(resolution, key), stored)?;
+        if plain.len() != std::mem::size_of::<Rollup>() {
+            return Err(Error::Corrupt(resolution.name()));
+        }
+        let mut rollup: Rollup = bytemuck::Zeroable::zeroed();
+        bytemuck::bytes_of_mut(&mut rollup).copy_from_slice(&plain);
+        Ok(rollup)
+    }
+
+    /// Rollups of `addr` whose buckets start in `range`
+    pub fn get_rollups<T>(
+        &self,
+        txn: &heed::RoTxn<'_, T>,
+        addr: BluetoothAddress,
+        resolution: Resolution,
+        range: Range<Timestamp>,
+    ) -> Result<Vec<(Timestamp, Rollup)>, Error> {
+        self.rollup_db(resolution)
+            .range(txn, &LogKey::range(addr, range))?
+            .map(|entry| {
+                let (key, stored) = entry?;
+                Ok((key.time(), self.decode_rollup(resolution, &key, stored)?))
+            })
+            .collect()
+    }
+
+    /// Hour of the log of `addr` from `start` aggregated from scratch
+    fn compute_hour<T>(
+        &self,
+        txn: &heed::RoTxn<'_, T>,
+        addr: BluetoothAddress,
+        start: Timestamp,
+    ) -> Result<Rollup, Error> {
+        let end = Timestamp::from(start.as_u32() + Resolution::Hour.seconds());
+        let mut rollup = Rollup::default();
+        for entry in self.log_db.range(txn, &LogKey::range(addr, start..end))? {
+            let (key, stored) = entry?;
+            rollup.add(&self.get_log_values(&key, stored)?);
+        }
+        Ok(rollup)
+    }
+
+    /// Folds the buckets touched by a write into the rollup databases. Days get recomputed from
+    /// their hours so they never drift from them.
+    pub(super) fn apply_rollups(
+        &self,
+        txn: &mut heed::RwTxn<'_, '_>,
+        update: RollupUpdate,
+    ) -> Result<(), Error> {
+        let mut days = BTreeSet::new();
+        for (addr, hour) in &update.stale {
+            let rollup = self.compute_hour(txn, *addr, *hour)?;
+            self.put_rollup(txn, Resolution::Hour, &LogKey::new(*addr, *hour), &rollup)?;
+            days.insert((*addr, Resolution::Day.bucket(*hour)));
+        }
+        for ((addr, hour), added) in update.added {
+            if update.stale.contains(&(addr, hour)) {
+                continue;
+            }
+            let key = LogKey::new(addr, hour);
+            let mut rollup = match self.hour_db.get(txn, &key)? {
+                Some(stored) => self.decode_rollup(Resolution::Hour, &key, stored)?,
+                None => Rollup::default(),
+            };
+            rollup.merge(&added);
+            self.put_rollup(txn, Resolution::Hour, &key, &rollup)?;
+            days.insert((addr, Resolution::Day.bucket(hour)));
+        }
+
+        for (addr, day) in days {
+            let end = Timestamp::from(day.as_u32() + Resolution::Day.seconds());
+            let mut rollup = Rollup::default();
+            for (_, hour) in self.get_rollups(txn, addr, Resolution::Hour, day..end)? {
+                rollup.merge(&hour);
+            }
+            self.put_rollup(txn, Resolution::Day, &LogKey::new(addr, day), &rollup)?;
+        }
+        Ok(())
+    }
+
+    /// Throws away both rollup databases and builds them again from the whole log, returns the
+    /// number of hourly rollups
+    pub(super) fn rebuild_rollups(&self, txn: &mut heed::RwTxn<'_, '_>) -> Result<usize, Error> {
+        self.unseal_all(txn)?;
+        self.hour_db.clear(txn)?;
+        self.day_db.clear(txn)?;
+
+        let mut hours = 0;
+        let mut current: Option<((BluetoothAddress, Timestamp), Rollup)> = None;
+        let mut after = Bound::Unbounded;
+        loop {
+            let chunk = self
+                .log_db
+                .range(txn, &(after, Bound::Unbounded))?
+                .take(MIGRATION_CHUNK)
+                .map(|entry| {
+                    let (key, stored) = entry?;
+                    Ok((key, self.get_log_values(&key, stored)?))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            let last = match chunk.last() {
+                Some((key, _)) => *key,
+                None => break,
+            };
+            for (key, values) in chunk {
+                let bucket = (key.addr(), Resolution::Hour.bucket(key.time()));
+                match current {
+                    Some((current_bucket, ref mut rollup)) if current_bucket == bucket => {
+                        rollup.add(&values)
+                    }
+                    _ => {
+                        if let Some(((addr, hour), rollup)) = current.take() {
+                            self.put_rollup(
+                                txn,
+                                Resolution::Hour,
+                                &LogKey::new(addr, hour),
+                                &rollup,
+                            )?;
+                            hours += 1;
+                        }
+                        let mut rollup = Rollup::default();
+                        rollup.add(&values);
+                        current = Some((bucket, rollup));
+                    }
+                }
+            }
+            after = Bound::Excluded(last);
+        }
+        if let Some(((addr, hour), rollup)) = current {
+            self.put_rollup(txn, Resolution::Hour, &LogKey::new(addr, hour), &rollup)?;
+            hours += 1;
+        }
+
+        // hours are far fewer than log entries, a day at a time is plenty
+        let mut days = BTreeMap::<(BluetoothAddress, Timestamp), Rollup>::new();
+        let mut after = Bound::Unbounded;
+        loop {
+            let chunk = self
+                .hour_db
+                .range(txn, &(after, Bound::Unbounded))?
+                .take(MIGRATION_CHUNK)
+                .map(|entry| {
+                    let (key, stored) = entry?;
+                    Ok((key, self.decode_rollup(Resolution::Hour, &key, stored)?))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            let last = match chunk.last() {
+                Some((key, _)) => *key,
+                None => break,
+            };
+            for (key, rollup) in chunk {
+                days.entry((key.addr(), Resolution::Day.bucket(key.time())))
+                    .or_default()
+                    .merge(&rollup);
+            }
+            after = Bound::Excluded(last);
+        }
+        for ((addr, day), rollup) in days {
+            self.put_rollup(txn, Resolution::Day, &LogKey::new(addr, day), &rollup)?;
+        }
+
+        Ok(hours)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        db::{AddrDbEntry, LogBatch},
+        sensor::test_values,
+    };
+
+    fn mean_temperature(rollup: &Rollup) -> i16 {
+        RawSensorValues::from(rollup.mean().unwrap()).temperature
+    }
+
+    #[test]
+    fn rollups_follow_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let addr = BluetoothAddress::from(1);
+        let mut txn = db.write_txn().unwrap();
+        db.put_addr(&mut txn, addr, &AddrDbEntry::default())
+            .unwrap();
+        txn.commit().unwrap();
+
+        let mut batch = LogBatch::default();
+        batch.push(
+            addr,
+            Timestamp::from(0),
+            test_values(10_00, 50_00),
+            Quality::empty(),
+        );
+        batch.push(
+            addr,
+            Timestamp::from(60),
+            test_values(20_00, 50_00),
+            Quality::empty(),
+        );
+        batch.push(
+            addr,
+            Timestamp::from(3600),
+            test_values(30_00, 50_00),
+            Quality::empty(),
+        );
+        db.write_log(&batch).unwrap();
+        // replacing an entry can't be done incrementally
+        let mut batch = LogBatch::default();
+        batch.push(
+            addr,
+            Timestamp::from(60),
+            test_values(12_00, 50_00),
+            Quality::IMPORTED,
+        );
+        db.write_log(&batch).unwrap();
+
+        let everything = Timestamp::UNIX_EPOCH..Timestamp::MAX;
+        let txn = db.read_txn().unwrap();
+        let hours = db
+            .get_rollups(&txn, addr, Resolution::Hour, everything.clone())
+            .unwrap();
+        assert_eq!(hours.len(), 2);
+        assert_eq!(hours[0].1.count(), 2);
+        assert_eq!(hours[0].1.quality(), Quality::IMPORTED);
+        assert_eq!(mean_temperature(&hours[0].1), 11_00);
+        let days = db
+            .get_rollups(&txn, addr, Resolution::Day, everything.clone())
+            .unwrap();
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].1.count(), 3);
+        assert_eq!(days[0].1.min.temperature, 10_00);
+        assert_eq!(days[0].1.max.temperature, 30_00);
+        assert_eq!(mean_temperature(&days[0].1), 17_33);
+        drop(txn);
+
+        let mut txn = db.write_txn().unwrap();
+        assert_eq!(db.rebuild_rollups(&mut txn).unwrap(), 2);
+        let rebuilt = db
+            .get_rollups(&txn, addr, Resolution::Day, everything)
+            .unwrap();
+        assert_eq!(rebuilt[0].1.count(), 3);
+        assert_eq!(mean_temperature(&rebuilt[0].1), 17_33);
+    }
+}
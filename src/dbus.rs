@@ -0,0 +1,81 @@
+#[cfg(unix)]
+mod service;
+
+#[cfg(unix)]
+pub(crate) use service::serve;
+
+/// Message bus the service gets offered on
+#[derive(Copy, Clone, Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Bus {
+    Session,
+    System,
+}
+
+impl std::str::FromStr for Bus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "session" => Ok(Self::Session),
+            "system" => Ok(Self::System),
+            _ => Err(String::from("Bus must be either `session` or `system`")),
+        }
+    }
+}
+
+/// Where zbus looks for the system bus
+const SYSTEM_BUS_VAR: &str = "DBUS_SYSTEM_BUS_ADDRESS";
+
+const DEFAULT_SYSTEM_BUS: &str = "unix:path=/var/run/dbus/system_bus_socket";
+
+/// A dbus address like `unix:path=/run/dbus/system_bus_socket` or `tcp:host=..,port=..` from
+/// either an address or the path of a socket, like one mounted into a container
+pub(crate) fn parse_address(address: &str) -> Result<String, String> {
+    if address.starts_with('/') {
+        Ok(format!("unix:path={}", address))
+    } else if address.contains(':') {
+        Ok(address.to_owned())
+    } else {
+        Err(format!(
+            "{} is neither a dbus address like `unix:path=..` nor an absolute socket path",
+            address
+        ))
+    }
+}
+
+/// Connects everything that talks to the system bus, BlueZ and the service, to `address`.
+/// Has to happen before any other thread is started.
+pub(crate) fn use_system_bus(address: &str) {
+    std::env::set_var(SYSTEM_BUS_VAR, address);
+}
+
+/// Address of the system bus connections go to
+pub(crate) fn system_bus() -> String {
+    std::env::var(SYSTEM_BUS_VAR).unwrap_or_else(|_| DEFAULT_SYSTEM_BUS.to_owned())
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn serve(_ctx: super::Context, _bus: Bus) -> Result<(), eyre::Error> {
+    Err(eyre::format_err!(
+        "The dbus service is only available on unix"
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn socket_paths_become_addresses() {
+        assert_eq!(
+            parse_address("/var/run/dbus/system_bus_socket").unwrap(),
+            DEFAULT_SYSTEM_BUS
+        );
+        assert_eq!(
+            parse_address("tcp:host=10.0.0.1,port=1234").unwrap(),
+            "tcp:host=10.0.0.1,port=1234"
+        );
+        assert!(parse_address("run/dbus").is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use super::Bus;
+use crate::{
+    bluetooth::BluetoothAddress,
+    sensor::{Quantity, SensorState},
+};
+use eyre::Context as _;
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    sync::{Arc, Mutex},
+    thread,
+};
+use tokio::sync::broadcast;
+use zbus::{dbus_interface, fdo};
+
+const NAME: &str = "org.foldu.WeatherstationCentral";
+const PATH: &str = "/org/foldu/WeatherstationCentral";
+const INTERFACE: &str = "org.foldu.WeatherstationCentral1";
+
+type Snapshot = Arc<Mutex<BTreeMap<BluetoothAddress, SensorState>>>;
+
+struct Service {
+    ctx: crate::Context,
+    /// zbus is blocking so method calls can't wait for the lock on `ctx.sensors`
+    sensors: Snapshot,
+}
+
+fn parse(addr: &str) -> fdo::Result<BluetoothAddress> {
+    BluetoothAddress::parse_str(addr).map_err(|e| fdo::Error::InvalidArgs(e.to_string()))
+}
+
+impl Service {
+    fn state(&self, addr: BluetoothAddress) -> fdo::Result<SensorState> {
+        self.sensors
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .copied()
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("Unknown sensor {}", addr)))
+    }
+}
+
+#[dbus_interface(name = "org.foldu.WeatherstationCentral1")]
+impl Service {
+    /// Addresses of all known sensors
+    fn sensors(&self) -> Vec<String> {
+        self.sensors
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|addr| addr.to_string())
+            .collect()
+    }
+
+    /// Temperature in °C, relative humidity in % and pressure in Pa
+    fn get_values(&self, addr: &str) -> fdo::Result<(f64, f64, f64)> {
+        let addr = parse(addr)?;
+        match self.state(addr)? {
+            SensorState::Connected(values) => Ok((
+                Quantity::Temperature.of(values),
+                Quantity::Humidity.of(values),
+                Quantity::Pressure.of(values),
+            )),
+            SensorState::Unconnected => {
+                Err(fdo::Error::Failed(format!("Sensor {} not connected", addr)))
+            }
+        }
+    }
+
+    /// Empty for sensors without label
+    fn get_label(&self, addr: &str) -> fdo::Result<String> {
+        let addr = parse(addr)?;
+        self.state(addr)?;
+        let db_err = |e: crate::db::Error| fdo::Error::Failed(e.to_string());
+        let txn = self.ctx.db.read_txn().map_err(db_err)?;
+        let entry = self.ctx.db.get_addr(&txn, addr).map_err(db_err)?;
+        Ok(entry.and_then(|entry| entry.label).unwrap_or_default())
+    }
+}
+
+/// Offers the service on `bus` and emits an `Updated(addr, temperature, humidity, pressure)`
+/// signal for every new value of a connected sensor
+pub(crate) async fn serve(ctx: crate::Context, bus: Bus) -> Result<(), eyre::Error> {
+    let connection = match bus {
+        Bus::Session => zbus::Connection::new_session()?,
+        Bus::System => zbus::Connection::new_system().with_context(|| {
+            format!(
+                "Could not connect to the system bus at {}",
+                super::system_bus()
+            )
+        })?,
+    };
+    fdo::DBusProxy::new(&connection)?
+        .request_name(NAME, fdo::RequestNameFlags::ReplaceExisting.into())?;
+
+    // subscribing before copying the current state so no update gets lost in between
+    let (sensors, mut updates) = {
+        let sensors = ctx.sensors.read().await;
+        (sensors.clone(), ctx.updates.subscribe())
+    };
+    let sensors = Arc::new(Mutex::new(sensors));
+
+    let mut object_server = zbus::ObjectServer::new(&connection);
+    object_server.at(
+        &PATH.try_into()?,
+        Service {
+            ctx: ctx.clone(),
+            sensors: sensors.clone(),
+        },
+    )?;
+    thread::spawn(move || loop {
+        if let Err(e) = object_server.try_handle_next() {
+            tracing::warn!("Handling dbus message failed: {}", e);
+        }
+    });
+
+    tokio::spawn(async move {
+        loop {
+            let events = match updates.recv().await {
+                Ok(events) => events,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("dbus service lagged behind by {} updates", skipped);
+                    let current = ctx.sensors.read().await.clone();
+                    *sensors.lock().unwrap() = current;
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            {
+                let mut sensors = sensors.lock().unwrap();
+                for event in &events {
+                    match event.state() {
+                        Some(state) => sensors.insert(event.addr(), state),
+                        None => sensors.remove(&event.addr()),
+                    };
+                }
+            }
+
+            let connection = connection.clone();
+            let emitted = tokio::task::spawn_blocking(move || {
+                for (addr, state) in crate::state::states(&events) {
+                    if let SensorState::Connected(values) = state {
+                        let body = (
+                            addr.to_string(),
+                            Quantity::Temperature.of(values),
+                            Quantity::Humidity.of(values),
+                            Quantity::Pressure.of(values),
+                        );
+                        connection.emit_signal(None, PATH, INTERFACE, "Updated", &body)?;
+                    }
+                }
+                Ok::<_, zbus::Error>(())
+            })
+            .await;
+            if let Ok(Err(e)) = emitted {
+                tracing::warn!("Emitting dbus signal failed: {}", e);
+            }
+        }
+    });
+
+    Ok(())
+}
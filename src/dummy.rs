@@ -1,92 +1,423 @@
 use crate::{
     bluetooth::BluetoothAddress,
-    sensor::{Celsius, Pascal, RelativeHumidity, SensorState, SensorValues},
+    sensor::{RawSensorValues, SensorState, SensorValues},
+    timestamp::Timestamp,
 };
+use eyre::Context;
 use futures_util::stream::Stream;
-use rand::Rng;
-use std::{collections::BTreeMap, convert::TryFrom, future::Future, time::Duration};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    f64::consts::PI,
+    fs,
+    future::Future,
+    num::{NonZeroU64, NonZeroU8},
+    ops::Range,
+    path::Path,
+    time::Duration,
+};
 use tokio::sync::mpsc;
 
-struct FluctuatingSensor {
-    humidity: u16,
-    temperature: i16,
-    pressure: u32,
+/// Settings for the dummy sensors of demo mode
+pub(crate) struct DemoConfig {
+    pub(crate) sensors: NonZeroU8,
+    /// seed for the sensors' rngs, random when unset
+    pub(crate) seed: Option<u64>,
+    pub(crate) interval: Duration,
+    pub(crate) ranges: DemoRanges,
+}
+
+/// Bounds of the simulated values as (min, max)
+#[derive(serde::Deserialize, Clone, Copy)]
+pub(crate) struct DemoRanges {
+    /// temperature in °C
+    #[serde(default = "default_temperature_range")]
+    pub(crate) temperature: (f64, f64),
+    /// relative humidity in percent
+    #[serde(default = "default_humidity_range")]
+    pub(crate) humidity: (f64, f64),
+    /// pressure in Pa
+    #[serde(default = "default_pressure_range")]
+    pub(crate) pressure: (f64, f64),
+}
+
+fn default_temperature_range() -> (f64, f64) {
+    (0., 30.)
+}
+
+fn default_humidity_range() -> (f64, f64) {
+    (20., 90.)
+}
+
+fn default_pressure_range() -> (f64, f64) {
+    (90_000., 110_000.)
+}
+
+impl DemoRanges {
+    /// Bounds have to be in order and within what a sensor can report
+    pub(crate) fn validate(&self) -> Result<(), eyre::Error> {
+        let check = |name, (lo, hi): (f64, f64), (min, max): (f64, f64)| {
+            if (min..=max).contains(&lo) && (lo..=max).contains(&hi) {
+                Ok(())
+            } else {
+                Err(eyre::format_err!(
+                    "Demo {} range ({}, {}) has to be ascending and within ({}, {})",
+                    name,
+                    lo,
+                    hi,
+                    min,
+                    max
+                ))
+            }
+        };
+        check(
+            "temperature",
+            self.temperature,
+            (-273.15, f64::from(i16::MAX) / 100.),
+        )?;
+        check("humidity", self.humidity, (0., 100.))?;
+        check("pressure", self.pressure, (0., f64::from(u32::MAX) / 10.))
+    }
 }
 
-impl Default for FluctuatingSensor {
+impl Default for DemoRanges {
     fn default() -> Self {
         Self {
-            humidity: 50_00,
-            pressure: 1_000_000_0,
-            temperature: 20_00,
+            temperature: default_temperature_range(),
+            humidity: default_humidity_range(),
+            pressure: default_pressure_range(),
         }
     }
 }
 
-fn clamp<T>(n: T, lo: T, hi: T) -> T
-where
-    T: Ord + Copy,
-{
-    if n < lo {
-        lo
-    } else if n > hi {
-        hi
-    } else {
-        n
+/// Dummy sensor following a day/night temperature curve with humidity falling as the temperature
+/// rises and pressure drifting with multi-day weather systems
+struct FluctuatingSensor {
+    rng: StdRng,
+    ranges: DemoRanges,
+    /// per sensor offset of the pressure systems in seconds
+    pressure_phase: f64,
+    /// per sensor temperature offset in °C, some rooms are just warmer
+    temperature_offset: f64,
+}
+
+const DAY: f64 = 24. * 60. * 60.;
+
+impl FluctuatingSensor {
+    fn new(mut rng: StdRng, ranges: &DemoRanges) -> Self {
+        let temperature_spread = ranges.temperature.1 - ranges.temperature.0;
+        Self {
+            pressure_phase: rng.gen_range(0., 10. * DAY),
+            temperature_offset: rng.gen_range(-0.1, 0.1) * temperature_spread,
+            rng,
+            ranges: *ranges,
+        }
+    }
+
+    fn values_at(&mut self, time: Timestamp) -> SensorValues {
+        let time = f64::from(time.as_u32());
+        let (t_lo, t_hi) = self.ranges.temperature;
+        let (h_lo, h_hi) = self.ranges.humidity;
+        let (p_lo, p_hi) = self.ranges.pressure;
+
+        // coldest at 03:00, warmest at 15:00
+        let day_progress = (time % DAY) / DAY;
+        let daily = (2. * PI * (day_progress - 0.375)).sin();
+
+        let temperature = mid(t_lo, t_hi)
+            + self.temperature_offset
+            + daily * (t_hi - t_lo) * 0.3
+            + self.rng.gen_range(-0.1, 0.1);
+
+        let humidity =
+            mid(h_lo, h_hi) - daily * (h_hi - h_lo) * 0.3 + self.rng.gen_range(-0.5, 0.5);
+
+        let time = time + self.pressure_phase;
+        let systems =
+            0.6 * (2. * PI * time / (4.3 * DAY)).sin() + 0.4 * (2. * PI * time / (9.1 * DAY)).sin();
+        let pressure =
+            mid(p_lo, p_hi) + systems * (p_hi - p_lo) * 0.4 + self.rng.gen_range(-5., 5.);
+
+        let raw = RawSensorValues {
+            temperature: (clamp(temperature, t_lo, t_hi) * 100.).round() as i16,
+            humidity: (clamp(humidity, h_lo, h_hi) * 100.).round() as u16,
+            pressure: (clamp(pressure, p_lo, p_hi) * 10.).round() as u32,
+        };
+        SensorValues::try_from(raw).unwrap()
     }
 }
 
+fn mid(lo: f64, hi: f64) -> f64 {
+    (lo + hi) / 2.
+}
+
+fn clamp(n: f64, lo: f64, hi: f64) -> f64 {
+    n.max(lo).min(hi)
+}
+
 impl Iterator for FluctuatingSensor {
     type Item = SensorValues;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut rng = rand::thread_rng();
-        self.temperature = clamp(self.temperature + rng.gen_range(-1_00, 1_00), 0_00, 30_00);
-        self.pressure = clamp(
-            // TODO: better range for pressure
-            if rng.gen::<bool>() {
-                self.pressure + rng.gen_range(100, 1000)
-            } else {
-                self.pressure - rng.gen_range(100, 1000)
-            },
-            900_000_0,
-            1_100_000_0,
-        );
-        self.humidity = clamp(
-            if rng.gen::<bool>() {
-                self.humidity + rng.gen_range(0_20, 0_90)
-            } else {
-                self.humidity - rng.gen_range(0_20, 0_90)
-            },
-            20_00,
-            90_00,
-        );
-        Some(SensorValues {
-            humidity: RelativeHumidity::try_from(self.humidity).unwrap(),
-            pressure: Pascal::from(self.pressure),
-            temperature: Celsius::try_from(self.temperature).unwrap(),
-        })
+        Some(self.values_at(Timestamp::now()))
     }
 }
 
+/// Creates the `index`th dummy sensor of `config`
 pub(crate) fn dummy_sensor(
     addr: BluetoothAddress,
+    config: &DemoConfig,
+    index: u8,
 ) -> (
     impl Future<Output = ()>,
     impl Stream<Item = BTreeMap<BluetoothAddress, SensorState>> + Sync + Send,
 ) {
+    let rng = match config.seed {
+        Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(u64::from(index))),
+        None => StdRng::from_entropy(),
+    };
+    let sensor = FluctuatingSensor::new(rng, &config.ranges);
+    let interval = config.interval;
+
     let (tx, rx) = mpsc::channel(1);
     let dummy_task = async move {
-        let mut map = BTreeMap::new();
-
-        for value in FluctuatingSensor::default() {
+        for value in sensor {
+            let mut map = BTreeMap::new();
             map.insert(addr, SensorState::Connected(value));
-            if let Err(_) = tx.send(map.clone()).await {
+            if tx.send(map).await.is_err() {
                 break;
             }
-            tokio::time::sleep(Duration::from_secs(30)).await;
+            tokio::time::sleep(interval).await;
         }
     };
 
     (dummy_task, tokio_stream::wrappers::ReceiverStream::new(rx))
 }
+
+/// A scripted sequence of sensor states, read from a toml file
+#[derive(serde::Deserialize)]
+pub(crate) struct Scenario {
+    /// seconds between two steps
+    #[serde(default = "default_step_secs")]
+    step_secs: NonZeroU64,
+    /// number of steps after which the scenario ends
+    steps: u32,
+    /// start again from the first step after the last one
+    #[serde(default)]
+    repeat: bool,
+    #[serde(rename = "sensor", default)]
+    sensors: Vec<ScenarioSensor>,
+}
+
+fn default_step_secs() -> NonZeroU64 {
+    NonZeroU64::new(30).unwrap()
+}
+
+#[derive(serde::Deserialize)]
+struct ScenarioSensor {
+    addr: BluetoothAddress,
+    /// step in which the sensor shows up for the first time
+    #[serde(default)]
+    appear: u32,
+    /// step from which on the sensor stays unconnected
+    disappear: Option<u32>,
+    /// steps in which the sensor is unconnected
+    #[serde(default)]
+    dropouts: Vec<Range<u32>>,
+    /// steps in which the sensor sends an invalid humidity
+    #[serde(default)]
+    invalid: Vec<u32>,
+    /// temperature in °C
+    temperature: Ramp,
+    /// relative humidity in percent
+    humidity: Ramp,
+    /// pressure in Pa
+    pressure: Ramp,
+}
+
+/// A value that either stays constant or changes linearly over the whole scenario
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Ramp {
+    Constant(f64),
+    Linear { from: f64, to: f64 },
+}
+
+impl Ramp {
+    fn at(&self, step: u32, steps: u32) -> f64 {
+        match *self {
+            Ramp::Constant(value) => value,
+            Ramp::Linear { from, to } => {
+                let progress = if steps > 1 {
+                    f64::from(step) / f64::from(steps - 1)
+                } else {
+                    0.
+                };
+                from + (to - from) * progress
+            }
+        }
+    }
+}
+
+impl Scenario {
+    pub fn from_file(path: &Path) -> Result<Self, eyre::Error> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Could not read scenario file {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("Invalid scenario file {}", path.display()))
+    }
+
+    /// States of all sensors that have appeared in `step`
+    fn state_at(&self, step: u32) -> BTreeMap<BluetoothAddress, SensorState> {
+        let mut ret = BTreeMap::new();
+        for sensor in &self.sensors {
+            if step < sensor.appear {
+                continue;
+            }
+
+            let gone = sensor
+                .disappear
+                .map_or(false, |disappear| step >= disappear)
+                || sensor
+                    .dropouts
+                    .iter()
+                    .any(|dropout| dropout.contains(&step));
+            if gone {
+                ret.insert(sensor.addr, SensorState::Unconnected);
+                continue;
+            }
+
+            let raw = RawSensorValues {
+                temperature: (sensor.temperature.at(step, self.steps) * 100.).round() as i16,
+                humidity: if sensor.invalid.contains(&step) {
+                    u16::MAX
+                } else {
+                    (sensor.humidity.at(step, self.steps) * 100.).round() as u16
+                },
+                pressure: (sensor.pressure.at(step, self.steps) * 10.).round() as u32,
+            };
+
+            // invalid values get dropped just like the bluetooth source would
+            match SensorValues::try_from(raw) {
+                Ok(values) => {
+                    ret.insert(sensor.addr, SensorState::Connected(values));
+                }
+                Err(e) => {
+                    tracing::warn!("Scenario sensor {} sent invalid values: {}", sensor.addr, e);
+                }
+            }
+        }
+
+        ret
+    }
+}
+
+pub(crate) fn scenario_source(
+    scenario: Scenario,
+) -> (
+    impl Future<Output = ()>,
+    impl Stream<Item = BTreeMap<BluetoothAddress, SensorState>> + Sync + Send,
+) {
+    let (tx, rx) = mpsc::channel(1);
+    let scenario_task = async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(scenario.step_secs.get()));
+        loop {
+            for step in 0..scenario.steps {
+                interval.tick().await;
+                if tx.send(scenario.state_at(step)).await.is_err() {
+                    return;
+                }
+            }
+
+            if !scenario.repeat {
+                tracing::info!("Scenario finished");
+                break;
+            }
+        }
+    };
+
+    (
+        scenario_task,
+        tokio_stream::wrappers::ReceiverStream::new(rx),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn raw_series(seed: u64) -> Vec<(i16, u16, u32)> {
+        let mut sensor =
+            FluctuatingSensor::new(StdRng::seed_from_u64(seed), &DemoRanges::default());
+        (0..20)
+            .map(|i| RawSensorValues::from(sensor.values_at(Timestamp::from(i * 60 * 60))))
+            .map(|raw| (raw.temperature, raw.humidity, raw.pressure))
+            .collect()
+    }
+
+    #[test]
+    fn seeded_dummy_sensors_repeat() {
+        assert_eq!(raw_series(42), raw_series(42));
+        assert_ne!(raw_series(42), raw_series(43));
+    }
+
+    #[test]
+    fn demo_ranges_stay_within_sensor_bounds() {
+        assert!(DemoRanges::default().validate().is_ok());
+        let ranges = |humidity| DemoRanges {
+            humidity,
+            ..DemoRanges::default()
+        };
+        assert!(ranges((0., 100.)).validate().is_ok());
+        assert!(ranges((20., 120.)).validate().is_err());
+        assert!(ranges((90., 20.)).validate().is_err());
+    }
+
+    #[test]
+    fn dummy_sensor_day_night() {
+        let series = raw_series(42);
+        let (night, afternoon) = (series[3], series[15]);
+        assert!(afternoon.0 > night.0);
+        assert!(afternoon.1 < night.1);
+    }
+
+    const SCENARIO: &str = r#"
+        steps = 11
+
+        [[sensor]]
+        addr = "00:00:00:00:00:01"
+        appear = 2
+        disappear = 9
+        dropouts = [{ start = 4, end = 6 }]
+        invalid = [7]
+        temperature = { from = 10.0, to = 20.0 }
+        humidity = 50.0
+        pressure = 100000.0
+    "#;
+
+    #[test]
+    fn scenario_steps() {
+        let scenario: Scenario = toml::from_str(SCENARIO).unwrap();
+        let addr = BluetoothAddress::from(1);
+
+        assert!(scenario.state_at(0).is_empty());
+        match scenario.state_at(3)[&addr] {
+            SensorState::Connected(values) => {
+                assert_eq!(RawSensorValues::from(values).temperature, 13_00)
+            }
+            _ => panic!("Sensor should be connected"),
+        }
+        assert!(matches!(
+            scenario.state_at(5)[&addr],
+            SensorState::Unconnected
+        ));
+        assert!(scenario.state_at(7).is_empty());
+        assert!(matches!(
+            scenario.state_at(9)[&addr],
+            SensorState::Unconnected
+        ));
+
+        assert!(toml::from_str::<Scenario>("steps = 1\nstep_secs = 0").is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! The export format, json lines meant to outlive the database layout and to be read by other
+//! tools:
+//!
+//! - one `{"type":"header","format":"ble-weatherstation-export","version":1,"created":...}`
+//! - then for each sensor a `{"type":"sensor","addr":...,"settings":{...}}` with the settings
+//!   as in the database, followed by its readings
+//! - `{"type":"reading","addr":...,"time":...,"values":{...},"quality":[...]}` oldest first,
+//!   like the lines written by dump
+//!
+//! Times are unix timestamps, temperatures in hundredths of °C, humidities in hundredths of a
+//! percent and pressures in tenths of Pa. Readers skip record types they don't know, new
+//! fields get added without a new version, anything else bumps it.
+
+use crate::{
+    bluetooth::BluetoothAddress,
+    db::{self, AddrDbEntry, Db, LogBatch},
+    sensor::{Quality, SensorValues},
+    timestamp::Timestamp,
+};
+use eyre::Context;
+use std::io::{BufRead, Write};
+
+const FORMAT: &str = "ble-weatherstation-export";
+
+/// Bumped on changes older readers would get wrong
+pub(crate) const VERSION: u32 = 1;
+
+/// Readings written per transaction on import
+const IMPORT_BATCH: usize = 10_000;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Record {
+    Header {
+        format: String,
+        version: u32,
+        created: Timestamp,
+    },
+    Sensor {
+        addr: BluetoothAddress,
+        settings: AddrDbEntry,
+    },
+    Reading {
+        addr: BluetoothAddress,
+        time: Timestamp,
+        values: SensorValues,
+        #[serde(default)]
+        quality: Quality,
+    },
+    /// of a newer version that only added record types
+    #[serde(other)]
+    Unknown,
+}
+
+pub(crate) fn write_record(out: &mut impl Write, record: &Record) -> Result<(), eyre::Error> {
+    serde_json::to_writer(&mut *out, record)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Writes every sensor with its settings and log to `out`, returns the number of readings
+pub(crate) fn export(db: &Db, out: &mut impl Write) -> Result<usize, eyre::Error> {
+    export_since(db, |_| Timestamp::UNIX_EPOCH, out)
+}
+
+/// Like [`export`] with only the readings of each sensor from `since` of it on
+pub(crate) fn export_since(
+    db: &Db,
+    since: impl Fn(BluetoothAddress) -> Timestamp,
+    out: &mut impl Write,
+) -> Result<usize, eyre::Error> {
+    let txn = db.read_txn()?;
+    write_record(
+        out,
+        &Record::Header {
+            format: FORMAT.to_owned(),
+            version: VERSION,
+            created: Timestamp::now(),
+        },
+    )?;
+
+    let mut readings = 0;
+    for addr in db.known_addrs(&txn)? {
+        let addr = addr?;
+        let settings = db.get_addr(&txn, addr)?.unwrap_or_default();
+        write_record(out, &Record::Sensor { addr, settings })?;
+        let log = db
+            .get_flagged_log(&txn, addr, since(addr)..Timestamp::MAX, None)?
+            .unwrap_or_default();
+        for (time, values, quality) in log {
+            write_record(
+                out,
+                &Record::Reading {
+                    addr,
+                    time,
+                    values,
+                    quality,
+                },
+            )?;
+            readings += 1;
+        }
+    }
+    Ok(readings)
+}
+
+/// Whether a header with `format` and `version` starts something this central can read
+pub(crate) fn check_header(format: &str, version: u32) -> Result<(), eyre::Error> {
+    if format != FORMAT {
+        return Err(eyre::format_err!("Not an export but {}", format));
+    }
+    if version > VERSION {
+        return Err(eyre::format_err!(
+            "Export has version {}, this central only reads up to {}",
+            version,
+            VERSION
+        ));
+    }
+    Ok(())
+}
+
+/// What an import added
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Imported {
+    pub(crate) sensors: usize,
+    pub(crate) readings: usize,
+}
+
+/// Adds the sensors and readings of an export to `db`, settings of known sensors get replaced
+pub(crate) fn import(db: &Db, input: impl BufRead) -> Result<Imported, eyre::Error> {
+    let mut imported = Imported::default();
+    let mut batch = LogBatch::default();
+    let mut header = false;
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid record in line {}", i + 1))?;
+        match record {
+            Record::Header {
+                format, version, ..
+            } => {
+                check_header(&format, version)?;
+                header = true;
+            }
+            _ if !header => return Err(eyre::format_err!("Export doesn't start with a header")),
+            Record::Sensor { addr, settings } => {
+                let mut txn = db.write_txn()?;
+                db.put_addr(&mut txn, addr, &settings)?;
+                txn.commit().map_err(db::Error::from)?;
+                imported.sensors += 1;
+            }
+            Record::Reading {
+                addr,
+                time,
+                values,
+                quality,
+            } => {
+                batch.push(addr, time, values, quality);
+                imported.readings += 1;
+                if batch.len() >= IMPORT_BATCH {
+                    db.write_log(&std::mem::take(&mut batch))?;
+                }
+            }
+            Record::Unknown => {}
+        }
+    }
+    if !header {
+        return Err(eyre::format_err!("Export is empty"));
+    }
+    db.write_log(&batch)?;
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sensor::RawSensorValues;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn exports_import_into_an_empty_database() {
+        let addr = BluetoothAddress::from(1);
+        let values = SensorValues::try_from(RawSensorValues {
+            temperature: 21_50,
+            humidity: 45_00,
+            pressure: 1_013_250,
+        })
+        .unwrap();
+
+        let source_dir = tempfile::tempdir().unwrap();
+        let source = Db::open(source_dir.path()).unwrap();
+        let mut txn = source.write_txn().unwrap();
+        let settings = AddrDbEntry {
+            label: Some("kitchen".to_owned()),
+            ..AddrDbEntry::default()
+        };
+        source.put_addr(&mut txn, addr, &settings).unwrap();
+        txn.commit().unwrap();
+        let mut batch = LogBatch::default();
+        batch.push(addr, Timestamp::from(60), values, Quality::CALIBRATED);
+        batch.push(addr, Timestamp::from(120), values, Quality::empty());
+        source.write_log(&batch).unwrap();
+
+        let mut exported = Vec::new();
+        assert_eq!(export(&source, &mut exported).unwrap(), 2);
+        // a newer export with a record type this one doesn't know
+        exported.extend_from_slice(b"{\"type\":\"annotation\",\"text\":\"moved\"}\n");
+
+        let target_dir = tempfile::tempdir().unwrap();
+        let target = Db::open(target_dir.path()).unwrap();
+        assert_eq!(
+            import(&target, &exported[..]).unwrap(),
+            Imported {
+                sensors: 1,
+                readings: 2
+            }
+        );
+        let txn = target.read_txn().unwrap();
+        let entry = target.get_addr(&txn, addr).unwrap().unwrap();
+        assert_eq!(entry.label.as_deref(), Some("kitchen"));
+        let log = target
+            .get_flagged_log(&txn, addr, Timestamp::UNIX_EPOCH..Timestamp::MAX, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].2, Quality::CALIBRATED);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::timestamp::Timestamp;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+const OPEN_METEO_URL: &str = "https://api.open-meteo.com/v1/forecast";
+
+/// Refreshes can't be closer together than this, failed ones get retried after it
+const MIN_REFRESH: Duration = Duration::from_secs(10 * 60);
+
+/// Hours shown on the home page
+#[cfg(feature = "web-ui")]
+pub(crate) const HOME_HOURS: usize = 6;
+
+/// Location of the Open-Meteo forecast, only settable in the config file
+#[derive(serde::Deserialize, Clone)]
+pub(crate) struct ForecastConfig {
+    latitude: f64,
+    longitude: f64,
+    /// seconds a fetched forecast stays valid
+    refresh: Option<u64>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub(crate) struct Forecast {
+    pub(crate) fetched: Timestamp,
+    pub(crate) hours: Vec<ForecastHour>,
+}
+
+impl Forecast {
+    /// Hours of the forecast starting with the current one
+    pub(crate) fn upcoming(&self, now: Timestamp) -> impl Iterator<Item = &ForecastHour> {
+        let start = now.bottoming_sub(Timestamp::from(60 * 60));
+        self.hours.iter().skip_while(move |hour| hour.time <= start)
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
+pub(crate) struct ForecastHour {
+    pub(crate) time: Timestamp,
+    /// temperature in °C
+    pub(crate) temperature: f64,
+    /// relative humidity in percent
+    pub(crate) humidity: f64,
+    /// surface pressure in hPa
+    pub(crate) pressure: f64,
+}
+
+#[cfg(feature = "web-ui")]
+impl ForecastHour {
+    /// Local time of day of this hour
+    pub(crate) fn label(&self) -> String {
+        use chrono::TimeZone;
+        chrono::Local
+            .timestamp(i64::from(self.time.as_u32()), 0)
+            .format("%H:%M")
+            .to_string()
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct OpenMeteoReply {
+    hourly: OpenMeteoHourly,
+}
+
+#[derive(serde::Deserialize)]
+struct OpenMeteoHourly {
+    time: Vec<u32>,
+    temperature_2m: Vec<f64>,
+    relativehumidity_2m: Vec<f64>,
+    surface_pressure: Vec<f64>,
+}
+
+impl OpenMeteoHourly {
+    fn into_hours(self) -> Vec<ForecastHour> {
+        self.time
+            .into_iter()
+            .zip(self.temperature_2m)
+            .zip(self.relativehumidity_2m)
+            .zip(self.surface_pressure)
+            .map(|(((time, temperature), humidity), pressure)| ForecastHour {
+                time: Timestamp::from(time),
+                temperature,
+                humidity,
+                pressure,
+            })
+            .collect()
+    }
+}
+
+/// Keeps the latest forecast around, fetched by [`run`] every `refresh`
+pub(crate) struct Forecaster {
+    client: reqwest::Client,
+    config: ForecastConfig,
+    refresh: Duration,
+    latest: Mutex<Option<Arc<Forecast>>>,
+}
+
+impl Forecaster {
+    pub fn new(config: ForecastConfig) -> Self {
+        let refresh = config
+            .refresh
+            .map_or(Duration::from_secs(60 * 60), Duration::from_secs)
+            .max(MIN_REFRESH);
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("Could not create http client"),
+            config,
+            refresh,
+            latest: Mutex::new(None),
+        }
+    }
+
+    /// The latest forecast without waiting for Open-Meteo, none until the first fetch worked
+    pub fn get(&self) -> Option<Arc<Forecast>> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    async fn fetch(&self) -> Result<Forecast, reqwest::Error> {
+        let reply = self
+            .client
+            .get(OPEN_METEO_URL)
+            .query(&[
+                ("latitude", self.config.latitude.to_string()),
+                ("longitude", self.config.longitude.to_string()),
+                (
+                    "hourly",
+                    "temperature_2m,relativehumidity_2m,surface_pressure".to_owned(),
+                ),
+                ("timeformat", "unixtime".to_owned()),
+                ("forecast_days", "2".to_owned()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<OpenMeteoReply>()
+            .await?;
+
+        Ok(Forecast {
+            fetched: Timestamp::now(),
+            hours: reply.hourly.into_hours(),
+        })
+    }
+}
+
+/// Fetches the forecast every `refresh`, failed fetches keep the last one and get retried sooner
+pub(crate) async fn run(ctx: super::Context) {
+    let forecaster = match ctx.forecast {
+        Some(ref forecaster) => forecaster,
+        None => return,
+    };
+    loop {
+        let wait = match forecaster.fetch().await {
+            Ok(forecast) => {
+                *forecaster.latest.lock().unwrap() = Some(Arc::new(forecast));
+                forecaster.refresh
+            }
+            Err(e) => {
+                tracing::error!("Failed fetching forecast: {}", e);
+                MIN_REFRESH
+            }
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{
+    bluetooth::BluetoothAddress,
+    db::{self, Db},
+    tasks::LOG_INTERVAL,
+    timestamp::Timestamp,
+};
+use heed::RoTxn;
+
+/// A stretch of time without log entries
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub(crate) struct Gap {
+    /// time of the last entry before the gap
+    pub(crate) start: Timestamp,
+    /// time of the first entry after the gap, the end of the scanned range if it's ongoing
+    pub(crate) end: Timestamp,
+    /// no entry came after the gap yet
+    pub(crate) ongoing: bool,
+}
+
+impl Gap {
+    pub(crate) fn seconds(&self) -> u32 {
+        self.end.as_u32() - self.start.as_u32()
+    }
+}
+
+/// Gaps longer than `max_interval` between the sorted log entry `times` and from the last one
+/// to `end`. Nothing is known before the first entry so that doesn't count.
+pub(crate) fn find_gaps(times: &[Timestamp], end: Timestamp, max_interval: u32) -> Vec<Gap> {
+    let too_long = |start: Timestamp, end: Timestamp| end.as_u32() - start.as_u32() > max_interval;
+    let mut gaps = times
+        .windows(2)
+        .filter(|pair| too_long(pair[0], pair[1]))
+        .map(|pair| Gap {
+            start: pair[0],
+            end: pair[1],
+            ongoing: false,
+        })
+        .collect::<Vec<_>>();
+    if let Some(&last) = times.last() {
+        if last < end && too_long(last, end) {
+            gaps.push(Gap {
+                start: last,
+                end,
+                ongoing: true,
+            });
+        }
+    }
+    gaps
+}
+
+/// Fraction of the expected log entries of a sensor that are there, one every log interval.
+/// Windows start at the first entry of sensors that are newer than them.
+#[derive(serde::Serialize, Debug, Clone, Copy)]
+pub(crate) struct Availability {
+    /// `None` if there's nothing to expect yet
+    pub(crate) day: Option<f64>,
+    pub(crate) week: Option<f64>,
+    pub(crate) month: Option<f64>,
+}
+
+impl Availability {
+    pub(crate) fn of<T>(
+        db: &Db,
+        txn: &RoTxn<'_, T>,
+        addr: BluetoothAddress,
+        now: Timestamp,
+    ) -> Result<Self, db::Error> {
+        let first = match db.log_stats(txn, addr)? {
+            Some(stats) => stats.first,
+            None => {
+                return Ok(Self {
+                    day: None,
+                    week: None,
+                    month: None,
+                })
+            }
+        };
+        let last_days = |days: u32| -> Result<Option<f64>, db::Error> {
+            let start = now
+                .bottoming_sub(Timestamp::from(days * Timestamp::ONE_DAY.as_u32()))
+                .max(first);
+            let entries = db.log_count(txn, addr, start..now)?;
+            Ok(fraction(entries, start, now))
+        };
+        Ok(Self {
+            day: last_days(1)?,
+            week: last_days(7)?,
+            month: last_days(30)?,
+        })
+    }
+
+    /// Percentages of the day, week and month
+    #[cfg(feature = "web-ui")]
+    pub(crate) fn summary(&self) -> String {
+        let percent = |fraction: Option<f64>| match fraction {
+            Some(fraction) => format!("{:.0}%", fraction * 100.),
+            None => "–".to_owned(),
+        };
+        format!(
+            "{} / {} / {}",
+            percent(self.day),
+            percent(self.week),
+            percent(self.month)
+        )
+    }
+}
+
+fn fraction(entries: usize, start: Timestamp, end: Timestamp) -> Option<f64> {
+    let expected = end.bottoming_sub(start).as_u32() / LOG_INTERVAL;
+    if expected == 0 {
+        None
+    } else {
+        Some((entries as f64 / f64::from(expected)).min(1.))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_gaps_and_ongoing_outage() {
+        let times = [0, 60, 120, 600, 660, 721]
+            .iter()
+            .copied()
+            .map(Timestamp::from)
+            .collect::<Vec<_>>();
+        let gaps = find_gaps(&times, Timestamp::from(1000), 120);
+        assert_eq!(
+            gaps,
+            vec![
+                Gap {
+                    start: Timestamp::from(120),
+                    end: Timestamp::from(600),
+                    ongoing: false,
+                },
+                Gap {
+                    start: Timestamp::from(721),
+                    end: Timestamp::from(1000),
+                    ongoing: true,
+                },
+            ]
+        );
+        assert!(find_gaps(&[], Timestamp::from(1000), 120).is_empty());
+    }
+
+    #[test]
+    fn availability_fraction() {
+        let day = Timestamp::ONE_DAY;
+        assert_eq!(fraction(720, Timestamp::from(0), day), Some(0.5));
+        // an extra entry from a write that got delayed doesn't make it more than everything
+        assert_eq!(fraction(1441, Timestamp::from(0), day), Some(1.));
+        assert_eq!(fraction(0, day, day), None);
+    }
+}
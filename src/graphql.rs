@@ -0,0 +1,249 @@
+//! GraphQL endpoint on `/api/graphql` with the sensors, their current values, logs, stats and
+//! alert rules in one schema, for frontends that pick the fields they need instead of stitching
+//! together rest calls. Tenants only see their own sensors here as well.
+
+use crate::{
+    alert::RuleStatus,
+    bluetooth::BluetoothAddress,
+    db::{self, AddrDbEntry},
+    sensor::{Quantity, SensorState, SensorValues},
+    tenant::Access,
+    timestamp::Timestamp,
+};
+use async_graphql::{Context, EmptyMutation, EmptySubscription, Object, Result, SimpleObject};
+
+/// Deepest nesting of a query
+const MAX_DEPTH: usize = 8;
+
+/// Fields a query may ask for at most, summed up over every sensor
+const MAX_COMPLEXITY: usize = 1000;
+
+pub(crate) type Schema = async_graphql::Schema<Query, EmptyMutation, EmptySubscription>;
+
+pub(crate) fn schema(ctx: super::Context) -> Schema {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .data(ctx)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+pub(crate) struct Query;
+
+#[Object]
+impl Query {
+    /// Sensors in their sort order and by address otherwise
+    async fn sensors(&self, gql: &Context<'_>) -> Result<Vec<Sensor>> {
+        let ctx = gql.data_unchecked::<super::Context>();
+        let access = gql.data_unchecked::<Access>();
+        let sensors = ctx.sensors.read().await;
+        let txn = ctx.db.read_txn()?;
+        let mut found = sensors
+            .iter()
+            .filter(|(addr, _)| access.allows(**addr))
+            .map(|(addr, state)| {
+                let entry = ctx.db.get_addr(&txn, *addr)?.unwrap_or_default();
+                Ok(Sensor {
+                    addr: *addr,
+                    state: *state,
+                    entry,
+                })
+            })
+            .collect::<Result<Vec<_>, db::Error>>()?;
+        found.sort_by_key(|sensor| (sensor.entry.sort_order.is_none(), sensor.entry.sort_order));
+        Ok(found)
+    }
+
+    /// The sensor with an address like `AA:BB:CC:DD:EE:FF`
+    async fn sensor(&self, gql: &Context<'_>, addr: String) -> Result<Option<Sensor>> {
+        let ctx = gql.data_unchecked::<super::Context>();
+        let addr = addr.parse::<BluetoothAddress>()?;
+        if !gql.data_unchecked::<Access>().allows(addr) {
+            return Ok(None);
+        }
+        let state = match ctx.sensors.read().await.get(&addr) {
+            Some(state) => *state,
+            None => return Ok(None),
+        };
+        let txn = ctx.db.read_txn()?;
+        let entry = ctx.db.get_addr(&txn, addr)?.unwrap_or_default();
+        Ok(Some(Sensor { addr, state, entry }))
+    }
+
+    /// Alert rules of the config and whether they fire
+    async fn alerts(&self, gql: &Context<'_>) -> Vec<Alert> {
+        alerts(gql, |_| true)
+    }
+}
+
+/// Rules on sensors the request may see that `select` picks
+fn alerts(gql: &Context<'_>, select: impl Fn(&RuleStatus) -> bool) -> Vec<Alert> {
+    let access = gql.data_unchecked::<Access>();
+    gql.data_unchecked::<super::Context>()
+        .alerts
+        .rules()
+        .into_iter()
+        .filter(|status| access.allows(status.sensor) && select(status))
+        .map(Alert::from)
+        .collect()
+}
+
+pub(crate) struct Sensor {
+    addr: BluetoothAddress,
+    state: SensorState,
+    entry: AddrDbEntry,
+}
+
+#[Object]
+impl Sensor {
+    async fn addr(&self) -> String {
+        self.addr.to_string()
+    }
+
+    async fn label(&self) -> Option<&str> {
+        self.entry.label.as_deref()
+    }
+
+    async fn connected(&self) -> bool {
+        matches!(self.state, SensorState::Connected(_))
+    }
+
+    /// Latest values, none while the sensor isn't connected
+    async fn values(&self) -> Option<Values> {
+        match self.state {
+            SensorState::Connected(values) => Some(Values::from(values)),
+            SensorState::Unconnected => None,
+        }
+    }
+
+    /// Log entries from `start` until `end` in unix seconds, the last day by default. Long
+    /// ranges get thinned out like in the rest api.
+    async fn log(
+        &self,
+        gql: &Context<'_>,
+        start: Option<u32>,
+        end: Option<u32>,
+    ) -> Result<Vec<LogEntry>> {
+        let ctx = gql.data_unchecked::<super::Context>();
+        let end = end.map_or_else(Timestamp::now, Timestamp::from);
+        let start = start.map_or_else(|| end.bottoming_sub(Timestamp::ONE_DAY), Timestamp::from);
+        let txn = ctx.db.read_txn()?;
+        let log = ctx
+            .db
+            .get_log(&txn, self.addr, start..end, ctx.max_log_entries)?
+            .unwrap_or_default();
+        Ok(log
+            .into_iter()
+            .map(|(time, values)| LogEntry {
+                time: time.as_u32(),
+                values: Values::from(values),
+            })
+            .collect())
+    }
+
+    /// Size of the whole log, none without any entries
+    async fn stats(&self, gql: &Context<'_>) -> Result<Option<Stats>> {
+        let ctx = gql.data_unchecked::<super::Context>();
+        let txn = ctx.db.read_txn()?;
+        Ok(ctx.db.log_stats(&txn, self.addr)?.map(|stats| Stats {
+            entries: stats.entries,
+            first: stats.first.as_u32(),
+            last: stats.last.as_u32(),
+        }))
+    }
+
+    /// Alert rules on this sensor
+    async fn alerts(&self, gql: &Context<'_>) -> Vec<Alert> {
+        alerts(gql, |status| status.sensor == self.addr)
+    }
+}
+
+#[derive(SimpleObject)]
+struct Values {
+    /// °C
+    temperature: f64,
+    /// relative humidity in percent
+    humidity: f64,
+    /// Pa
+    pressure: f64,
+}
+
+impl From<SensorValues> for Values {
+    fn from(values: SensorValues) -> Self {
+        Self {
+            temperature: Quantity::Temperature.of(values),
+            humidity: Quantity::Humidity.of(values),
+            pressure: Quantity::Pressure.of(values),
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+struct LogEntry {
+    /// unix seconds
+    time: u32,
+    values: Values,
+}
+
+#[derive(SimpleObject)]
+struct Stats {
+    entries: u64,
+    /// unix seconds of the oldest entry
+    first: u32,
+    /// unix seconds of the newest entry
+    last: u32,
+}
+
+#[derive(SimpleObject)]
+struct Alert {
+    rule: String,
+    sensor: String,
+    /// temperature, humidity or pressure, battery for rules on the predicted battery life
+    quantity: String,
+    /// unix seconds since when the rule fires, none while it doesn't
+    firing_since: Option<u32>,
+}
+
+impl From<RuleStatus> for Alert {
+    fn from(status: RuleStatus) -> Self {
+        Self {
+            rule: status.rule,
+            sensor: status.sensor.to_string(),
+            quantity: status.quantity.map_or_else(
+                || "battery".to_owned(),
+                |quantity| format!("{:?}", quantity).to_lowercase(),
+            ),
+            firing_since: status.firing_since.map(Timestamp::as_u32),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sensor::RawSensorValues;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn fields_use_the_units_of_the_rest_api() {
+        let values = SensorValues::try_from(RawSensorValues {
+            temperature: 21_50,
+            humidity: 45_00,
+            pressure: 1_013_250,
+        })
+        .unwrap();
+        let values = Values::from(values);
+        assert_eq!(values.temperature, 21.5);
+        assert_eq!(values.pressure, 101_325.);
+
+        let alert = Alert::from(RuleStatus {
+            rule: "damp".to_owned(),
+            sensor: BluetoothAddress::from(1),
+            quantity: Some(Quantity::Humidity),
+            firing_since: Some(Timestamp::from(60)),
+        });
+        assert_eq!(alert.quantity, "humidity");
+        assert_eq!(alert.sensor, "00:00:00:00:00:01");
+        assert_eq!(alert.firing_since, Some(60));
+    }
+}
@@ -0,0 +1,177 @@
+use crate::{
+    bluetooth::BluetoothAddress,
+    sensor::{Quantity, SensorState, SensorValues},
+    timestamp::Timestamp,
+};
+use std::net::SocketAddr;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+mod proto {
+    tonic::include_proto!("weatherstation");
+}
+
+use proto::state_service_server::{StateService, StateServiceServer};
+
+impl From<SensorValues> for proto::Values {
+    fn from(values: SensorValues) -> Self {
+        Self {
+            temperature: Quantity::Temperature.of(values),
+            humidity: Quantity::Humidity.of(values),
+            pressure: Quantity::Pressure.of(values),
+        }
+    }
+}
+
+fn sensor(addr: BluetoothAddress, label: Option<String>, state: SensorState) -> proto::Sensor {
+    proto::Sensor {
+        addr: addr.to_string(),
+        label: label.unwrap_or_default(),
+        values: match state {
+            SensorState::Connected(values) => Some(values.into()),
+            SensorState::Unconnected => None,
+        },
+    }
+}
+
+fn internal(e: crate::db::Error) -> Status {
+    let e: &dyn std::error::Error = &e;
+    tracing::error!(e);
+    Status::internal("Database error")
+}
+
+struct Service {
+    ctx: super::Context,
+}
+
+impl Service {
+    fn label(&self, addr: BluetoothAddress) -> Result<Option<String>, Status> {
+        let txn = self.ctx.db.read_txn().map_err(internal)?;
+        let entry = self.ctx.db.get_addr(&txn, addr).map_err(internal)?;
+        Ok(entry.and_then(|entry| entry.label))
+    }
+
+    fn list(
+        &self,
+        sensors: impl IntoIterator<Item = (BluetoothAddress, SensorState)>,
+    ) -> Result<Vec<proto::Sensor>, Status> {
+        sensors
+            .into_iter()
+            .map(|(addr, state)| Ok(sensor(addr, self.label(addr)?, state)))
+            .collect()
+    }
+}
+
+#[tonic::async_trait]
+impl StateService for Service {
+    async fn list_sensors(
+        &self,
+        _request: Request<proto::ListSensorsRequest>,
+    ) -> Result<Response<proto::ListSensorsReply>, Status> {
+        let sensors = self.ctx.sensors.read().await.clone();
+        Ok(Response::new(proto::ListSensorsReply {
+            sensors: self.list(sensors)?,
+        }))
+    }
+
+    type StreamUpdatesStream = ReceiverStream<Result<proto::Sensor, Status>>;
+
+    async fn stream_updates(
+        &self,
+        _request: Request<proto::StreamUpdatesRequest>,
+    ) -> Result<Response<Self::StreamUpdatesStream>, Status> {
+        // subscribing before copying the current state so no update gets lost in between
+        let (current, mut updates) = {
+            let sensors = self.ctx.sensors.read().await;
+            (sensors.clone(), self.ctx.updates.subscribe())
+        };
+        let current = self.list(current)?;
+
+        let (tx, rx) = mpsc::channel(16);
+        let ctx = self.ctx.clone();
+        tokio::spawn(async move {
+            for sensor in current {
+                if tx.send(Ok(sensor)).await.is_err() {
+                    return;
+                }
+            }
+            let service = Service { ctx };
+            loop {
+                let events = match updates.recv().await {
+                    Ok(events) => events,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("grpc client lagged behind by {} updates", skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let sensors = match service.list(crate::state::states(&events)) {
+                    Ok(sensors) => sensors.into_iter().map(Ok).collect(),
+                    Err(status) => vec![Err(status)],
+                };
+                for sensor in sensors {
+                    if tx.send(sensor).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type QueryLogStream = ReceiverStream<Result<proto::LogEntry, Status>>;
+
+    async fn query_log(
+        &self,
+        request: Request<proto::QueryLogRequest>,
+    ) -> Result<Response<Self::QueryLogStream>, Status> {
+        let request = request.into_inner();
+        let addr = BluetoothAddress::parse_str(&request.addr)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let end = match request.end {
+            0 => Timestamp::now(),
+            end => Timestamp::from(end),
+        };
+        let limit = match request.limit {
+            0 => self.ctx.max_log_entries,
+            limit => Some(limit as usize),
+        };
+
+        let log = {
+            let txn = self.ctx.db.read_txn().map_err(internal)?;
+            self.ctx
+                .db
+                .get_log(&txn, addr, Timestamp::from(request.start)..end, limit)
+                .map_err(internal)?
+                .ok_or_else(|| Status::not_found(format!("Unknown sensor {}", addr)))?
+        };
+
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            for (time, values) in log {
+                let entry = proto::LogEntry {
+                    time: time.as_u32(),
+                    values: Some(values.into()),
+                };
+                if tx.send(Ok(entry)).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+pub(crate) async fn serve(ctx: super::Context, addr: SocketAddr) {
+    let service = StateServiceServer::new(Service { ctx });
+    if let Err(e) = tonic::transport::Server::builder()
+        .add_service(service)
+        .serve(addr)
+        .await
+    {
+        tracing::error!("grpc server failed: {}", e);
+    }
+}
@@ -0,0 +1,119 @@
+use crate::{
+    bluetooth::{BluetoothAddress, History},
+    db,
+    sensor::SensorValues,
+    timestamp::Timestamp,
+};
+use tokio::task;
+
+/// Seconds a buffered record may be away from a logged entry and still be the same reading
+const SAME_READING: u32 = 30;
+
+/// Logs the records stations buffered while they weren't connected
+pub(crate) async fn backfill(ctx: crate::Context, rx: flume::Receiver<History>) {
+    while let Ok((addr, records)) = rx.recv_async().await {
+        match store(&ctx, addr, records).await {
+            Ok(n) => tracing::info!("Backfilled {} log entries of {}", n, addr),
+            Err(e) => tracing::error!("Failed backfilling log of {}: {}", addr, e),
+        }
+    }
+}
+
+async fn store(
+    ctx: &crate::Context,
+    addr: BluetoothAddress,
+    mut records: Vec<(Timestamp, SensorValues)>,
+) -> Result<usize, eyre::Error> {
+    if ctx.pending.is_some() && !ctx.sensors.read().await.contains_key(&addr) {
+        tracing::debug!("Not backfilling {} before it's approved", addr);
+        return Ok(0);
+    }
+    // a station can reconnect with its history before its first poll memorized it
+    ctx.state.memorize(addr).await?;
+
+    records.sort_by_key(|(time, _)| *time);
+    let (first, last) = match (records.first(), records.last()) {
+        (Some((first, _)), Some((last, _))) => (*first, *last),
+        _ => return Ok(0),
+    };
+
+    let (logged, calibration) = {
+        let txn = ctx.db.read_txn()?;
+        let entry = ctx.db.get_addr(&txn, addr)?.unwrap_or_default();
+        if !entry.log {
+            return Ok(0);
+        }
+        let range = first.bottoming_sub(Timestamp::from(SAME_READING))
+            ..Timestamp::from(last.as_u32().saturating_add(SAME_READING + 1));
+        let logged = ctx
+            .db
+            .get_log(&txn, addr, range, None)?
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(time, _)| time)
+            .collect::<Vec<_>>();
+        (logged, entry.calibration)
+    };
+
+    let mut batch = db::LogBatch::default();
+    for (time, values) in new_records(&logged, records) {
+        let (values, quality) = calibration.apply(values);
+        batch.push(addr, time, values, quality);
+    }
+    let n = batch.len();
+    let writer = ctx.clone();
+    let batch = task::spawn_blocking(move || writer.db.write_log(&batch).map(|()| batch)).await??;
+    ctx.queries.invalidate(Some(addr));
+    crate::state::bump_generation(ctx);
+    crate::replication::publish(ctx, batch);
+    Ok(n)
+}
+
+/// The records that weren't logged already, `logged` has to be sorted
+fn new_records(
+    logged: &[Timestamp],
+    records: Vec<(Timestamp, SensorValues)>,
+) -> impl Iterator<Item = (Timestamp, SensorValues)> + '_ {
+    records.into_iter().filter(move |(time, _)| {
+        let i = match logged.binary_search(time) {
+            Ok(_) => return false,
+            Err(i) => i,
+        };
+        let close = |other: &Timestamp| {
+            time.as_u32().max(other.as_u32()) - time.as_u32().min(other.as_u32()) <= SAME_READING
+        };
+        !(i.checked_sub(1)
+            .and_then(|i| logged.get(i))
+            .map_or(false, close)
+            || logged.get(i).map_or(false, close))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sensor::{Celsius, Pascal, RelativeHumidity};
+    use std::convert::TryFrom;
+
+    #[test]
+    fn skips_logged_readings() {
+        let values = SensorValues {
+            temperature: Celsius::try_from(2000).unwrap(),
+            humidity: RelativeHumidity::try_from(5000).unwrap(),
+            pressure: Pascal::from(100_000),
+        };
+        let logged = [1000, 1060, 1300]
+            .iter()
+            .copied()
+            .map(Timestamp::from)
+            .collect::<Vec<_>>();
+        let records = [1010, 1120, 1180, 1240, 1290]
+            .iter()
+            .map(|time| (Timestamp::from(*time), values))
+            .collect();
+        let new = new_records(&logged, records)
+            .map(|(time, _)| time.as_u32())
+            .collect::<Vec<_>>();
+        assert_eq!(new, vec![1120, 1180, 1240]);
+    }
+}
@@ -0,0 +1,335 @@
+//! Home Assistant mqtt discovery. Every sensor becomes a device with its values, whether it's
+//! connected and its battery level, alert rules become problem sensors, all without writing
+//! any templates in Home Assistant.
+
+#[cfg(feature = "alerts")]
+use crate::alert::RuleStatus;
+use crate::{
+    bluetooth::BluetoothAddress,
+    sensor::{Quantity, SensorState},
+};
+use serde_json::json;
+use std::{collections::BTreeMap, time::Duration};
+
+/// Time between two publishes of all states
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The `[homeassistant]` table
+#[derive(serde::Deserialize, Clone)]
+pub(crate) struct HomeAssistantConfig {
+    /// what Home Assistant subscribes to for discovery
+    #[serde(default = "default_discovery_prefix")]
+    discovery_prefix: String,
+    /// states get published below it
+    #[serde(default = "default_topic")]
+    topic: String,
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_owned()
+}
+
+fn default_topic() -> String {
+    "sensors/homeassistant".to_owned()
+}
+
+/// One discoverable entity, published as `{prefix}/{component}/{node}/{object}/config`
+struct Entity {
+    component: &'static str,
+    node: String,
+    object: String,
+    config: serde_json::Value,
+}
+
+impl Entity {
+    fn config_topic(&self, config: &HomeAssistantConfig) -> String {
+        format!(
+            "{}/{}/{}/{}/config",
+            config.discovery_prefix, self.component, self.node, self.object
+        )
+    }
+}
+
+/// `addr` the way it can be part of a topic and an id
+fn node_id(addr: BluetoothAddress) -> String {
+    addr.to_string().replace(':', "").to_lowercase()
+}
+
+/// Only ascii letters, digits and underscores are allowed in object ids
+#[cfg_attr(not(feature = "alerts"), allow(dead_code))]
+fn object_id(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn state_topic(config: &HomeAssistantConfig, addr: BluetoothAddress) -> String {
+    format!("{}/{}", config.topic, node_id(addr))
+}
+
+/// Values, connectivity and battery of a sensor, which share its state topic
+fn sensor_entities(
+    config: &HomeAssistantConfig,
+    addr: BluetoothAddress,
+    label: Option<&str>,
+) -> Vec<Entity> {
+    let node = node_id(addr);
+    let name = label.map_or_else(|| addr.to_string(), str::to_owned);
+    let device = json!({
+        "identifiers": [format!("ble_weatherstation_{}", node)],
+        "connections": [["mac", addr.to_string()]],
+        "name": name,
+        "model": "weatherstation",
+    });
+    let state_topic = state_topic(config, addr);
+    let entity = |component, object: &str, title: &str, extra: serde_json::Value| {
+        let mut config = json!({
+            "name": format!("{} {}", name, title),
+            "unique_id": format!("ble_weatherstation_{}_{}", node, object),
+            "state_topic": state_topic,
+            "device": device,
+        });
+        if let (Some(config), serde_json::Value::Object(extra)) = (config.as_object_mut(), extra) {
+            config.extend(extra);
+        }
+        Entity {
+            component,
+            node: node.clone(),
+            object: object.to_owned(),
+            config,
+        }
+    };
+
+    let mut entities = [
+        (Quantity::Temperature, "temperature", "°C"),
+        (Quantity::Humidity, "humidity", "%"),
+        (Quantity::Pressure, "pressure", "Pa"),
+    ]
+    .iter()
+    .map(|(quantity, class, unit)| {
+        entity(
+            "sensor",
+            class,
+            &format!("{:?}", quantity),
+            json!({
+                "device_class": class,
+                "unit_of_measurement": unit,
+                "state_class": "measurement",
+                "value_template": format!("{{{{ value_json.{} }}}}", class),
+                // values are only there while the sensor is connected
+                "availability_topic": state_topic,
+                "availability_template": "{{ 'online' if value_json.connected == 'ON' else 'offline' }}",
+            }),
+        )
+    })
+    .collect::<Vec<_>>();
+    entities.push(entity(
+        "binary_sensor",
+        "connectivity",
+        "Connectivity",
+        json!({
+            "device_class": "connectivity",
+            "value_template": "{{ value_json.connected }}",
+        }),
+    ));
+    entities.push(entity(
+        "sensor",
+        "battery",
+        "Battery",
+        json!({
+            "device_class": "battery",
+            "unit_of_measurement": "%",
+            "state_class": "measurement",
+            "value_template": "{{ value_json.battery }}",
+            "availability_topic": state_topic,
+            "availability_template": "{{ 'online' if value_json.battery is not none else 'offline' }}",
+        }),
+    ));
+    entities
+}
+
+fn sensor_state(state: SensorState, battery: Option<u8>) -> serde_json::Value {
+    let switch = |on| if on { "ON" } else { "OFF" };
+    match state {
+        SensorState::Connected(values) => json!({
+            "connected": switch(true),
+            "temperature": Quantity::Temperature.of(values),
+            "humidity": Quantity::Humidity.of(values),
+            "pressure": Quantity::Pressure.of(values),
+            "battery": battery,
+        }),
+        SensorState::Unconnected => json!({
+            "connected": switch(false),
+            "battery": battery,
+        }),
+    }
+}
+
+#[cfg(feature = "alerts")]
+fn alert_topic(config: &HomeAssistantConfig, rule: &str) -> String {
+    format!("{}/alert/{}", config.topic, object_id(rule))
+}
+
+/// A problem sensor that is on while the rule fires, attached to the device of its sensor
+#[cfg(feature = "alerts")]
+fn alert_entity(config: &HomeAssistantConfig, status: &RuleStatus) -> Entity {
+    let object = object_id(&status.rule);
+    Entity {
+        component: "binary_sensor",
+        node: node_id(status.sensor),
+        object: format!("alert_{}", object),
+        config: json!({
+            "name": format!("Alert {}", status.rule),
+            "unique_id": format!("ble_weatherstation_alert_{}", object),
+            "device_class": "problem",
+            "state_topic": alert_topic(config, &status.rule),
+            "device": {
+                "identifiers": [format!("ble_weatherstation_{}", node_id(status.sensor))],
+            },
+        }),
+    }
+}
+
+/// Everything is retained so Home Assistant finds it after restarting as well
+async fn publish(
+    cxn: &mut crate::MqttConnection,
+    topic: String,
+    payload: &serde_json::Value,
+) -> Result<(), eyre::Error> {
+    let topic = tokio_mqtt::TopicName::new(topic)?;
+    let payload = match payload {
+        // plain states like `ON` go out without quotes
+        serde_json::Value::String(payload) => payload.clone().into_bytes(),
+        payload => serde_json::to_vec(payload).unwrap(),
+    };
+    cxn.publish_retained(topic, payload).await?;
+    Ok(())
+}
+
+/// Announces every sensor and rule to Home Assistant and keeps publishing their states, again
+/// whenever sensors come, go or get renamed
+pub(crate) async fn run(
+    ctx: crate::Context,
+    config: HomeAssistantConfig,
+    mut cxn: crate::MqttConnection,
+) {
+    let mut announced = BTreeMap::<BluetoothAddress, Option<String>>::new();
+    #[cfg(feature = "alerts")]
+    let mut rules_announced = false;
+    let mut interval = tokio::time::interval(PUBLISH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let sensors = ctx.sensors.read().await.clone();
+        let labels = match ctx.db.read_txn().and_then(|txn| {
+            sensors
+                .keys()
+                .map(|addr| {
+                    let entry = ctx.db.get_addr(&txn, *addr)?;
+                    Ok((*addr, entry.and_then(|entry| entry.label)))
+                })
+                .collect::<Result<BTreeMap<_, _>, _>>()
+        }) {
+            Ok(labels) => labels,
+            Err(e) => {
+                tracing::error!("Could not look up labels for Home Assistant: {}", e);
+                continue;
+            }
+        };
+
+        let published = async {
+            for (addr, label) in &labels {
+                if announced.get(addr) != Some(label) {
+                    for entity in sensor_entities(&config, *addr, label.as_deref()) {
+                        publish(&mut cxn, entity.config_topic(&config), &entity.config).await?;
+                    }
+                    tracing::info!("Announced {} to Home Assistant", addr);
+                }
+            }
+            // sensors that were forgotten disappear from Home Assistant as well
+            for addr in announced.keys().filter(|addr| !labels.contains_key(addr)) {
+                for entity in sensor_entities(&config, *addr, None) {
+                    publish(&mut cxn, entity.config_topic(&config), &json!("")).await?;
+                }
+            }
+            announced = labels;
+
+            for (addr, state) in &sensors {
+                let battery = ctx.batteries.latest(*addr).map(|level| level.percentage);
+                publish(
+                    &mut cxn,
+                    state_topic(&config, *addr),
+                    &sensor_state(*state, battery),
+                )
+                .await?;
+            }
+
+            #[cfg(feature = "alerts")]
+            for status in ctx.alerts.rules() {
+                if !rules_announced {
+                    let entity = alert_entity(&config, &status);
+                    publish(&mut cxn, entity.config_topic(&config), &entity.config).await?;
+                }
+                let firing = if status.firing_since.is_some() {
+                    "ON"
+                } else {
+                    "OFF"
+                };
+                publish(&mut cxn, alert_topic(&config, &status.rule), &json!(firing)).await?;
+            }
+            #[cfg(feature = "alerts")]
+            {
+                rules_announced = true;
+            }
+            Ok::<_, eyre::Error>(())
+        };
+        if let Err(e) = published.await {
+            tracing::error!("Could not publish to Home Assistant: {}", e);
+            // announces everything again once the server is back
+            announced.clear();
+            #[cfg(feature = "alerts")]
+            {
+                rules_announced = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn entities_share_the_state_topic_of_their_sensor() {
+        let config: HomeAssistantConfig = toml::from_str("").unwrap();
+        let addr = BluetoothAddress::parse_str("AA:BB:CC:DD:EE:FF").unwrap();
+        let entities = sensor_entities(&config, addr, Some("Greenhouse"));
+
+        let connectivity = entities
+            .iter()
+            .find(|entity| entity.object == "connectivity")
+            .unwrap();
+        assert_eq!(connectivity.component, "binary_sensor");
+        assert_eq!(connectivity.node, "aabbccddeeff");
+        assert_eq!(
+            connectivity.config["state_topic"],
+            "sensors/homeassistant/aabbccddeeff"
+        );
+        assert_eq!(connectivity.config["name"], "Greenhouse Connectivity");
+        assert!(entities.iter().all(|entity| entity.config["unique_id"]
+            .as_str()
+            .unwrap()
+            .starts_with("ble_weatherstation_aabbccddeeff_")));
+
+        assert_eq!(
+            sensor_state(SensorState::Unconnected, Some(40)),
+            json!({"connected": "OFF", "battery": 40})
+        );
+        assert_eq!(object_id("Greenhouse too hot!"), "greenhouse_too_hot_");
+    }
+}
@@ -0,0 +1,125 @@
+use crate::db::Committed;
+use eyre::Context as _;
+use std::{num::NonZeroUsize, path::PathBuf, process::Stdio, sync::Arc, time::Duration};
+use tokio::{
+    io::AsyncWriteExt,
+    process::Command,
+    sync::{broadcast::error::RecvError, Semaphore},
+    task, time,
+};
+
+/// The `[hook]` table of the config file, a program run after every log write with a json
+/// summary of the write on stdin, like `{"entries":3,"newest":{"AA:BB:CC:DD:EE:FF":1612345678}}`
+#[derive(serde::Deserialize, Clone, Debug)]
+pub(crate) struct HookConfig {
+    command: PathBuf,
+    #[serde(default)]
+    args: Vec<String>,
+    /// seconds a run may take before it gets killed
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    /// runs at once, writes that happen while all of them are busy get no run
+    #[serde(default = "default_max_running")]
+    max_running: NonZeroUsize,
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+fn default_max_running() -> NonZeroUsize {
+    NonZeroUsize::new(1).unwrap()
+}
+
+/// Runs the hook of `config` after every committed log write
+pub(crate) async fn run(ctx: super::Context, config: HookConfig) {
+    let config = Arc::new(config);
+    let slots = Arc::new(Semaphore::new(config.max_running.get()));
+    let mut commits = ctx.db.subscribe_commits();
+    loop {
+        let committed = match commits.recv().await {
+            Ok(committed) => committed,
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!("Hook missed {} log writes", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let permit = match slots.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                tracing::warn!(
+                    "Skipped hook {} for a log write, all {} runs are busy",
+                    config.command.display(),
+                    config.max_running
+                );
+                continue;
+            }
+        };
+        let config = config.clone();
+        task::spawn(async move {
+            if let Err(e) = run_once(&config, &committed).await {
+                tracing::warn!("Hook {} failed: {:?}", config.command.display(), e);
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn run_once(config: &HookConfig, committed: &Committed) -> Result<(), eyre::Error> {
+    let input = serde_json::to_vec(committed)?;
+    let mut child = Command::new(&config.command)
+        .args(&config.args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Could not start it")?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let finished = async {
+        // a hook that doesn't care about the summary may exit without reading it
+        let _ = stdin.write_all(&input).await;
+        drop(stdin);
+        child.wait().await
+    };
+    let status = time::timeout(Duration::from_secs(config.timeout), finished)
+        .await
+        .map_err(|_| eyre::format_err!("Killed after {} seconds", config.timeout))??;
+    if !status.success() {
+        return Err(eyre::format_err!("Exited with {}", status));
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use crate::{bluetooth::BluetoothAddress, timestamp::Timestamp};
+    use std::collections::BTreeMap;
+
+    #[tokio::test]
+    async fn hooks_get_the_summary_and_a_deadline() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("summary.json");
+        let mut newest = BTreeMap::new();
+        newest.insert(BluetoothAddress::from(1), Timestamp::from(60));
+        let committed = Committed { entries: 1, newest };
+        let hook = |script: String| HookConfig {
+            command: PathBuf::from("/bin/sh"),
+            args: vec!["-c".to_owned(), script],
+            timeout: 1,
+            max_running: default_max_running(),
+        };
+
+        run_once(&hook(format!("cat > {}", out.display())), &committed)
+            .await
+            .unwrap();
+        let summary: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&out).unwrap()).unwrap();
+        assert_eq!(summary["entries"], 1);
+
+        assert!(run_once(&hook("sleep 5".to_owned()), &committed)
+            .await
+            .is_err());
+    }
+}
@@ -1,146 +1,687 @@
+mod cache;
+mod error;
+mod filter;
+#[cfg(feature = "web-ui")]
+mod pages;
+mod rate_limit;
+#[cfg(feature = "web-ui")]
 mod templates;
 
 use crate::{
-    bluetooth::BluetoothAddress,
-    db::{self, AddrDbEntry},
-    sensor::SensorValues,
+    analytics::{self, Comfort, Trend},
+    bands, battery,
+    bluetooth::{self, gatt, AdapterState, AdapterStats, BluetoothAddress},
+    chart,
+    clock::Measurement,
+    dashboard::{self, Layout},
+    db::{self, AddrDbPatch, Icon, Placement},
+    gaps::{self, Availability},
+    import::{self, parse_log},
+    opt::LogFormat,
+    replication,
+    sensor::{Quality, Quantity, SensorState, SensorValues},
+    state, tasks,
+    tenant::Access,
     timestamp::Timestamp,
 };
-use std::{future::Future, net::SocketAddr};
-use warp::{http::StatusCode, reject, Filter};
-
-// TODO: add better error handling after warp 0.3
-
-#[macro_use]
-macro_rules! static_file {
-    ($content_type:expr, $path:literal) => {{
-        const BIN: &[u8] = include_bytes!(concat!(
-            env!("CARGO_MANIFEST_DIR"),
-            "/frontend/dist/",
-            $path
-        ));
-        warp::http::Response::builder()
-            .header("Content-Type", $content_type)
-            .status(warp::http::StatusCode::OK)
-            .body(BIN)
-    }};
-}
+use cache::Cached;
+pub(crate) use cache::QueryCache;
+use error::Error;
+use filter::{SensorFilter, SensorQuery};
+use futures_util::{future, FutureExt};
+use rate_limit::RateLimiter;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    future::Future,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task, time,
+};
+use warp::{
+    http::{Method, StatusCode},
+    reject, Filter, Reply,
+};
+
+/// Json bodies are small, this is plenty even for large dashboards
+const MAX_JSON_BODY_SIZE: u64 = 1024 * 1024;
+
+/// Requests waiting longer than this for a free slot get turned away
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Charts get thinned out to at most this many points
+const MAX_CHART_POINTS: usize = 500;
 
+/// Seconds a watch request waits for new log entries at most
+const MAX_WATCH_WAIT: u64 = 60;
+
+/// Tokens taken by log queries and changes, which are the heavy ones on a small board
+const EXPENSIVE_REQUEST_COST: u32 = 5;
+
+/// Binds the server to every address of `addrs` and serves on `listeners`, all of them stop on
+/// `shutdown`. Requests on `listeners` have no remote address so they aren't rate limited.
 pub(crate) fn serve(
     ctx: super::Context,
-    addr: SocketAddr,
+    addrs: &[SocketAddr],
+    listeners: Vec<tokio::net::TcpListener>,
     shutdown: impl Future<Output = ()> + Send + 'static,
-) -> (std::net::SocketAddr, impl warp::Future) {
+) -> (Vec<SocketAddr>, impl warp::Future) {
+    let limiter = ctx
+        .rate_limit
+        .map(|limit| Arc::new(RateLimiter::new(limit)));
+    let base_path = ctx.base_path.clone();
+    // everything after this only sees api requests, paths are peeked at since the base path is
+    // already consumed
+    let api_prefix = warp::path::peek()
+        .and_then(|path: warp::path::Peek| async move {
+            if path.as_str().starts_with("api/") {
+                Ok(())
+            } else {
+                Err(reject::not_found())
+            }
+        })
+        .untuple_one();
+    let rate_limit = warp::method()
+        .and(warp::path::peek())
+        .and(warp::addr::remote())
+        .and_then(
+            move |method: Method, path: warp::path::Peek, remote: Option<SocketAddr>| {
+                let limiter = limiter.clone();
+                async move {
+                    match (limiter, remote) {
+                        (Some(limiter), Some(remote)) => limiter
+                            .check(
+                                remote.ip(),
+                                request_cost(&method, &format!("/{}", path.as_str())),
+                            )
+                            .map_err(|wait| reject::custom(Error::RateLimited(wait))),
+                        _ => Ok(()),
+                    }
+                }
+            },
+        )
+        .untuple_one();
+
+    let log = {
+        let metrics = ctx.metrics.clone();
+        let base_path = base_path.clone();
+        warp::log::custom(move |info| {
+            let status = info.status().as_u16();
+            let span = tracing::Span::current();
+            span.record("status", &status);
+            span.record("latency_ms", &(info.elapsed().as_secs_f64() * 1000.));
+            tracing::debug!("Finished request");
+            metrics.http.observe(
+                route_name(strip_base(&base_path, info.path())),
+                status,
+                info.elapsed(),
+            );
+        })
+    };
+
+    let json_body = warp::body::content_length_limit(MAX_JSON_BODY_SIZE.min(ctx.max_body_size));
+    let max_body_size = ctx.max_body_size;
+    // the permit of a request is held until its reply is ready
+    let slots = ctx
+        .max_concurrent_requests
+        .map(|max| Arc::new(Semaphore::new(max.get())));
+    let admit = warp::any().and_then(move || {
+        let slots = slots.clone();
+        async move {
+            match slots {
+                Some(slots) => match time::timeout(QUEUE_TIMEOUT, slots.acquire_owned()).await {
+                    Ok(Ok(permit)) => Ok(Some(permit)),
+                    _ => Err(reject::custom(Error::Busy)),
+                },
+                None => Ok(None),
+            }
+        }
+    });
+
+    #[cfg(feature = "graphql")]
+    let schema = crate::graphql::schema(ctx.clone());
     let ctx = warp::any().map({
         let ctx = ctx.clone();
         move || ctx.clone()
     });
-
-    let home = warp::get()
-        .and(warp::path::end())
+    let access = warp::header::optional::<String>("authorization")
         .and(ctx.clone())
-        .and_then(show_sensors);
+        .and_then(
+            |authorization: Option<String>, ctx: super::Context| async move {
+                ctx.tenants
+                    .access(authorization.as_deref())
+                    .ok_or_else(|| reject::custom(Error::Unauthorized))
+            },
+        )
+        .boxed();
+    // settings of the central and sensors named in request bodies aren't for tenants
+    let admin = access
+        .clone()
+        .and_then(|access: Access| async move {
+            match access {
+                Access::All => Ok(()),
+                Access::Tenant(_) => Err(reject::custom(Error::Forbidden)),
+            }
+        })
+        .untuple_one()
+        .boxed();
+
+    #[cfg(feature = "web-ui")]
+    let ui = pages::routes(ctx.clone().boxed(), access.clone(), admin.clone());
 
     let change_label = warp::put()
         .and(warp::path!("api" / "change_label"))
+        .and(admin.clone())
         .and(ctx.clone())
+        .and(json_body)
         .and(warp::filters::body::json())
         .and_then(change_label);
 
+    let change_placement = warp::put()
+        .and(warp::path!("api" / "change_placement"))
+        .and(admin.clone())
+        .and(ctx.clone())
+        .and(json_body)
+        .and(warp::filters::body::json())
+        .and_then(change_placement);
+
+    let get_sensor = warp::get()
+        .and(ctx.clone())
+        .and(sensor_path(
+            warp::path!("api" / "sensor" / BluetoothAddress),
+            access.clone(),
+        ))
+        .and_then(get_sensor);
+
+    let change_settings = warp::put()
+        .and(ctx.clone())
+        .and(sensor_path(
+            warp::path!("api" / "sensor" / BluetoothAddress),
+            access.clone(),
+        ))
+        .and(json_body)
+        .and(warp::filters::body::json())
+        .and_then(change_settings);
+
     let forget = warp::delete()
         .and(warp::path!("api" / "forget"))
+        .and(admin.clone())
         .and(ctx.clone())
+        .and(json_body)
         .and(warp::filters::body::json())
         .and_then(forget);
 
+    let get_pending = warp::get()
+        .and(warp::path!("api" / "pending"))
+        .and(admin.clone())
+        .and(ctx.clone())
+        .and_then(get_pending);
+
+    let approve_pending = warp::post()
+        .and(warp::path!("api" / "pending" / "approve"))
+        .and(admin.clone())
+        .and(ctx.clone())
+        .and(json_body)
+        .and(warp::filters::body::json())
+        .and_then(approve_pending);
+
+    let dismiss_pending = warp::delete()
+        .and(warp::path!("api" / "pending"))
+        .and(admin.clone())
+        .and(ctx.clone())
+        .and(json_body)
+        .and(warp::filters::body::json())
+        .and_then(dismiss_pending);
+
     let get_state = warp::get()
         .and(warp::path!("api" / "state"))
         .and(ctx.clone())
+        .and(access.clone())
+        .and(warp::query())
+        .and(warp::header::optional::<String>("if-none-match"))
         .and_then(get_state);
 
     let api_log = warp::get()
         .and(ctx.clone())
-        .and(warp::path!("api" / "log" / BluetoothAddress))
+        .and(sensor_path(
+            warp::path!("api" / "log" / BluetoothAddress),
+            access.clone(),
+        ))
+        .and(warp::query())
         .and_then(get_log);
 
-    let detail = warp::get()
+    let get_log_filter = warp::get()
+        .and(warp::path!("api" / "log_filter"))
+        .and(admin.clone())
+        .and(ctx.clone())
+        .map(|ctx: super::Context| {
+            warp::reply::json(&LogFilter {
+                directives: ctx.log_filter.directives(),
+            })
+        });
+
+    let put_log_filter = warp::put()
+        .and(warp::path!("api" / "log_filter"))
+        .and(admin.clone())
+        .and(ctx.clone())
+        .and(json_body)
+        .and(warp::filters::body::json())
+        .and_then(put_log_filter);
+
+    let api_watch = warp::get()
+        .and(ctx.clone())
+        .and(sensor_path(
+            warp::path!("api" / "watch" / BluetoothAddress),
+            access.clone(),
+        ))
+        .and(warp::query())
+        .and_then(watch_log);
+
+    let api_events = warp::get()
+        .and(ctx.clone())
+        .and(sensor_path(
+            warp::path!("api" / "events" / BluetoothAddress),
+            access.clone(),
+        ))
+        .and(warp::query())
+        .and_then(get_events);
+
+    let api_replication = warp::get()
         .and(ctx.clone())
-        .and(warp::path!("detail" / BluetoothAddress))
-        .and_then(detail);
+        .and(warp::path!("api" / "replication"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query())
+        .and_then(get_replication);
 
-    let script = warp::get()
-        .and(warp::path!("static" / "script.js"))
-        .map(|| static_file!("application/javascript", "main.bundle.js"));
+    let api_chart = warp::get()
+        .and(ctx.clone())
+        .and(
+            sensor_path(
+                warp::path!("api" / "chart" / SvgFile).map(|SvgFile(addr)| addr),
+                access.clone(),
+            )
+            .map(SvgFile),
+        )
+        .and(warp::query())
+        .and_then(get_chart);
+
+    let api_stats = warp::get()
+        .and(ctx.clone())
+        .and(sensor_path(
+            warp::path!("api" / "stats" / BluetoothAddress),
+            access.clone(),
+        ))
+        .and_then(get_stats);
+
+    let api_battery = warp::get()
+        .and(ctx.clone())
+        .and(sensor_path(
+            warp::path!("api" / "battery" / BluetoothAddress),
+            access.clone(),
+        ))
+        .and_then(get_battery);
+
+    let api_gaps = warp::get()
+        .and(ctx.clone())
+        .and(sensor_path(
+            warp::path!("api" / "gaps" / BluetoothAddress),
+            access.clone(),
+        ))
+        .and(warp::query())
+        .and_then(get_gaps);
+
+    let api_bands = warp::get()
+        .and(ctx.clone())
+        .and(sensor_path(
+            warp::path!("api" / "bands" / BluetoothAddress),
+            access.clone(),
+        ))
+        .and(warp::query())
+        .and_then(get_bands);
+
+    let api_degree_days = warp::get()
+        .and(ctx.clone())
+        .and(sensor_path(
+            warp::path!("api" / "degree-days" / BluetoothAddress),
+            access.clone(),
+        ))
+        .and(warp::query())
+        .and_then(get_degree_days);
+
+    let import = warp::post()
+        .and(ctx.clone())
+        .and(sensor_path(
+            warp::path!("api" / "import" / BluetoothAddress),
+            access.clone(),
+        ))
+        .and(warp::query())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::content_length_limit(max_body_size))
+        .and(warp::body::bytes())
+        .and_then(import);
+
+    // the forecast isn't about any sensor but still only for known tokens
+    let api_forecast = warp::get()
+        .and(warp::path!("api" / "forecast"))
+        .and(access.clone().map(|_: Access| ()).untuple_one())
+        .and(ctx.clone())
+        .and_then(get_forecast);
+
+    let api_presence = warp::get()
+        .and(warp::path!("api" / "presence"))
+        .and(admin.clone())
+        .and(ctx.clone())
+        .and_then(get_presence);
+
+    let api_bluez = warp::get()
+        .and(warp::path!("api" / "debug" / "bluez"))
+        .and(admin.clone())
+        .and(ctx.clone())
+        .and_then(get_bluez);
+
+    let api_gatt = warp::get()
+        .and(ctx.clone())
+        .and(warp::path!("api" / "debug" / "gatt" / BluetoothAddress))
+        .and(admin.clone())
+        .and_then(get_gatt);
+
+    let api_gatt_read = warp::get()
+        .and(ctx.clone())
+        .and(warp::path!(
+            "api" / "debug" / "gatt" / BluetoothAddress / String
+        ))
+        .and(admin.clone())
+        .and_then(read_gatt);
+
+    let api_gatt_write = warp::put()
+        .and(ctx.clone())
+        .and(warp::path!(
+            "api" / "debug" / "gatt" / BluetoothAddress / String
+        ))
+        .and(admin.clone())
+        .and(json_body)
+        .and(warp::filters::body::json())
+        .and_then(write_gatt);
+
+    let api_health = warp::get()
+        .and(warp::path!("api" / "health"))
+        .and(ctx.clone())
+        .map(get_health);
+
+    let get_dashboard = warp::get()
+        .and(ctx.clone())
+        .and(warp::path!("api" / "dashboard" / String))
+        .and(access.clone())
+        .and_then(get_dashboard);
+
+    let put_dashboard = warp::put()
+        .and(ctx.clone())
+        .and(warp::path!("api" / "dashboard" / String))
+        .and(admin.clone())
+        .and(json_body)
+        .and(warp::filters::body::json())
+        .and_then(put_dashboard);
+
+    let delete_dashboard = warp::delete()
+        .and(ctx.clone())
+        .and(warp::path!("api" / "dashboard" / String))
+        .and(admin.clone())
+        .and_then(delete_dashboard);
 
-    let css = warp::get()
-        .and(warp::path!("static" / "style.css"))
-        .map(|| static_file!("text/css", "main.css"));
+    // the schema checks the sensors against the access itself
+    #[cfg(feature = "graphql")]
+    let api_graphql = warp::post()
+        .and(warp::path!("api" / "graphql"))
+        .and(json_body)
+        .and(access.clone())
+        .and(async_graphql_warp::graphql(schema))
+        .and_then(
+            |access: Access,
+             (schema, request): (crate::graphql::Schema, async_graphql::Request)| async move {
+                let response = schema.execute(request.data(access)).await;
+                Ok::<_, std::convert::Infallible>(async_graphql_warp::Response::from(response))
+            },
+        );
+
+    #[cfg(feature = "metrics")]
+    let metrics = warp::get()
+        .and(warp::path!("metrics"))
+        .and(admin.clone())
+        .and(ctx.clone())
+        .map(|ctx: super::Context| {
+            warp::reply::with_header(
+                ctx.metrics.render(),
+                "Content-Type",
+                "text/plain; version=0.0.4",
+            )
+            .into_response()
+        });
 
     let cors = warp::cors()
-        .allow_methods(vec!["GET", "PUT", "DELETE", "HEAD"])
+        .allow_methods(vec!["GET", "POST", "PUT", "DELETE", "HEAD"])
         .build();
 
-    let routes = home
-        .or(change_label)
+    let endpoints = change_label
+        .or(change_placement)
+        .or(get_sensor)
+        .or(change_settings)
         .or(get_state)
         .or(forget)
-        .or(script)
+        .or(get_pending)
+        .or(approve_pending)
+        .or(dismiss_pending)
         .or(api_log)
-        .or(css)
-        .or(detail)
+        .or(api_watch)
+        .or(get_log_filter)
+        .or(put_log_filter)
+        .or(api_events)
+        .or(api_replication)
+        .or(api_chart)
+        .or(api_gaps)
+        .or(api_bands)
+        .or(api_degree_days)
+        .or(api_stats)
+        .or(api_battery)
+        .or(import)
+        .or(api_forecast)
+        .or(api_health)
+        .or(api_presence)
+        .or(api_bluez)
+        .or(api_gatt)
+        .or(api_gatt_read)
+        .or(api_gatt_write)
+        .or(get_dashboard)
+        .or(put_dashboard)
+        .or(delete_dashboard)
+        .map(Reply::into_response)
+        .boxed();
+    #[cfg(feature = "graphql")]
+    let endpoints = endpoints
+        .or(api_graphql.map(Reply::into_response))
+        .unify()
+        .boxed();
+    let api = api_prefix.and(rate_limit.and(endpoints).recover(error::recover_api));
+
+    // only the features built in get added to this
+    let pages = warp::any()
+        .and_then(|| async { Err::<warp::reply::Response, _>(reject::not_found()) })
+        .boxed();
+    #[cfg(feature = "web-ui")]
+    let pages = pages.or(ui).unify().boxed();
+    #[cfg(feature = "metrics")]
+    let pages = pages.or(metrics).unify().boxed();
+    let pages = pages.recover({
+        let base_path = base_path.clone();
+        move |rejection| error::recover_html(rejection, base_path.clone())
+    });
+
+    let routes = prefix(&base_path)
+        .and(admit)
+        .and(api.or(pages).map(Reply::into_response))
+        .map(|_permit: Option<OwnedSemaphorePermit>, reply: warp::reply::Response| reply)
+        .recover(error::recover_api)
         .with(cors)
-        // TODO: split into html rejection replies and json api rejection replies
-        .recover(handle_rejection);
+        .with(log)
+        .with(warp::trace(|info| {
+            tracing::info_span!(
+                "request",
+                method = %info.method(),
+                path = %info.path(),
+                status = tracing::field::Empty,
+                latency_ms = tracing::field::Empty,
+            )
+        }));
+
+    let shutdown = shutdown.shared();
+    let (mut bound, mut servers): (Vec<_>, Vec<_>) = addrs
+        .iter()
+        .map(|addr| {
+            let (addr, server) =
+                warp::serve(routes.clone()).bind_with_graceful_shutdown(*addr, shutdown.clone());
+            (addr, server.boxed())
+        })
+        .unzip();
+    for listener in listeners {
+        if let Ok(addr) = listener.local_addr() {
+            bound.push(addr);
+        }
+        servers.push(
+            warp::serve(routes.clone())
+                .serve_incoming_with_graceful_shutdown(incoming(listener), shutdown.clone())
+                .boxed(),
+        );
+    }
+    (bound, future::join_all(servers).map(drop))
+}
 
-    warp::serve(routes).bind_with_graceful_shutdown(addr, shutdown)
+/// Connections accepted on `listener`, errors like running out of fds only delay the next one
+fn incoming(
+    listener: tokio::net::TcpListener,
+) -> impl futures_util::Stream<Item = Result<tokio::net::TcpStream, std::io::Error>> + Send {
+    futures_util::stream::unfold(listener, |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => return Some((Ok(stream), listener)),
+                Err(e) => {
+                    tracing::warn!("Could not accept connection: {}", e);
+                    time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    })
 }
 
-async fn handle_rejection(
-    rejection: warp::Rejection,
-) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let response = warp::http::Response::builder();
-    let render_error = |code: StatusCode| {
-        response
-            .status(code)
-            .body(askama::Template::render(&templates::Error::new(code)).unwrap())
-            .unwrap()
-    };
+/// `path` for requests whose token covers the sensor it names, for the others the sensor
+/// doesn't exist
+fn sensor_path(
+    path: impl Filter<Extract = (BluetoothAddress,), Error = warp::Rejection> + Clone,
+    access: warp::filters::BoxedFilter<(Access,)>,
+) -> impl Filter<Extract = (BluetoothAddress,), Error = warp::Rejection> + Clone {
+    path.and(access)
+        .and_then(|addr: BluetoothAddress, access: Access| async move {
+            if access.allows(addr) {
+                Ok(addr)
+            } else {
+                Err(reject::custom(Error::NotFound))
+            }
+        })
+}
 
-    if let Some(db_error) = rejection.find::<crate::db::Error>() {
-        let e: &dyn std::error::Error = db_error;
-        tracing::error!(e);
-        Ok(render_error(StatusCode::INTERNAL_SERVER_ERROR))
-    } else if rejection.is_not_found() {
-        Ok(render_error(StatusCode::NOT_FOUND))
-    } else if let Some(_) = rejection.find::<reject::MethodNotAllowed>() {
-        Ok(render_error(StatusCode::METHOD_NOT_ALLOWED))
-    } else {
-        tracing::error!("Unhandled rejection {:?}", rejection);
-        // FIXME:
-        Ok(render_error(StatusCode::IM_A_TEAPOT))
+/// Consumes the segments of the base path, matches everything for an empty one
+fn prefix(base_path: &str) -> warp::filters::BoxedFilter<()> {
+    base_path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(warp::any().boxed(), |prefix, segment| {
+            prefix.and(warp::path(segment.to_owned())).boxed()
+        })
+}
+
+fn strip_base<'a>(base_path: &str, path: &'a str) -> &'a str {
+    path.strip_prefix(base_path).unwrap_or(path)
+}
+
+/// Route of `path` for metrics, addresses and names in paths would make for too many labels
+fn route_name(path: &str) -> &'static str {
+    const PREFIXES: &[(&str, &str)] = &[
+        ("/api/log/", "api_log"),
+        ("/api/watch/", "api_watch"),
+        ("/api/log_filter", "api_log_filter"),
+        ("/api/events/", "api_events"),
+        ("/api/replication", "api_replication"),
+        ("/api/chart/", "api_chart"),
+        ("/api/bands/", "api_bands"),
+        ("/api/degree-days/", "api_degree_days"),
+        ("/api/import/", "api_import"),
+        ("/api/dashboard/", "api_dashboard"),
+        ("/api/sensor/", "api_sensor"),
+        ("/api/battery/", "api_battery"),
+        ("/api/state", "api_state"),
+        ("/api/forecast", "api_forecast"),
+        ("/api/health", "api_health"),
+        ("/api/presence", "api_presence"),
+        ("/api/debug/gatt/", "api_debug_gatt"),
+        ("/api/debug/bluez", "api_debug_bluez"),
+        ("/api/change_label", "api_change_label"),
+        ("/api/change_placement", "api_change_placement"),
+        ("/api/forget", "api_forget"),
+        ("/api/pending", "api_pending"),
+        ("/api/graphql", "api_graphql"),
+        ("/detail/", "detail"),
+        ("/static/", "static"),
+        ("/admin", "admin"),
+        ("/kiosk", "kiosk"),
+        ("/metrics", "metrics"),
+    ];
+    if path == "/" {
+        return "home";
     }
+    PREFIXES
+        .iter()
+        .find(|(prefix, _)| path.starts_with(prefix))
+        .map_or("other", |(_, name)| name)
 }
 
-async fn show_sensors(ctx: super::Context) -> Result<impl warp::Reply, warp::Rejection> {
-    let sensors = ctx.sensors.read().await;
-    let mut display = Vec::with_capacity(sensors.len());
-    let txn = ctx.db.read_txn()?;
-    for (addr, state) in sensors.iter() {
-        let label = match ctx.db.get_addr(&txn, *addr)? {
-            Some(entry) => entry.label,
-            None => None,
-        };
-        display.push((
-            *addr,
-            templates::SensorEntry {
-                state: *state,
-                label,
-            },
-        ))
+/// Rate limit tokens taken by a request
+fn request_cost(method: &Method, path: &str) -> u32 {
+    let read_only = [Method::GET, Method::HEAD, Method::OPTIONS].contains(method);
+    if !read_only
+        || path.starts_with("/api/log/")
+        || path.starts_with("/api/chart/")
+        || path.starts_with("/api/bands/")
+        || path.starts_with("/api/degree-days/")
+    {
+        EXPENSIVE_REQUEST_COST
+    } else {
+        1
     }
+}
 
-    let rendered = askama::Template::render(&templates::Home::new(&display)).unwrap();
-    Ok(warp::reply::html(rendered))
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct SensorEntry {
+    pub(crate) state: SensorState,
+    pub(crate) label: Option<String>,
+    pub(crate) room: Option<String>,
+    pub(crate) placement: Placement,
+    pub(crate) icon: Option<Icon>,
+    pub(crate) sort_order: Option<i32>,
+    pub(crate) comfort: Option<Comfort>,
+    /// quantities the anomaly detector finds unusual right now
+    pub(crate) anomalies: Vec<Quantity>,
+    /// when the latest reading was taken, for stations that report it
+    pub(crate) measured: Option<Measurement>,
+    /// rate of change over the last half hour of log
+    pub(crate) trend: Option<Trend>,
+}
+
+/// The layout called `name`, the default layout if there's none
+fn load_layout(ctx: &super::Context, name: &str) -> Result<Layout, db::Error> {
+    let txn = ctx.db.read_txn()?;
+    Ok(ctx.db.get_dashboard(&txn, name)?.unwrap_or_default())
 }
 
 #[derive(serde::Deserialize)]
@@ -153,12 +694,41 @@ async fn change_label(
     ctx: super::Context,
     req: ChangeLabel,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut txn = ctx.db.write_txn()?;
-    let entry = AddrDbEntry {
-        label: req.new_label,
-    };
-    ctx.db.put_addr(&mut txn, req.addr, &entry)?;
-    txn.commit().map_err(db::Error::from)?;
+    ctx.state.change_label(req.addr, req.new_label).await?;
+
+    Ok(warp::reply::with_status("", StatusCode::OK))
+}
+
+#[derive(serde::Deserialize)]
+struct ChangePlacement {
+    addr: BluetoothAddress,
+    placement: Placement,
+}
+
+async fn change_placement(
+    ctx: super::Context,
+    req: ChangePlacement,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    ctx.state.change_placement(req.addr, req.placement).await?;
+
+    Ok(warp::reply::with_status("", StatusCode::OK))
+}
+
+async fn get_sensor(
+    ctx: super::Context,
+    addr: BluetoothAddress,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let txn = ctx.db.read_txn()?;
+    let entry = ctx.db.get_addr(&txn, addr)?.ok_or(Error::NotFound)?;
+    Ok(warp::reply::json(&entry))
+}
+
+async fn change_settings(
+    ctx: super::Context,
+    addr: BluetoothAddress,
+    patch: AddrDbPatch,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    ctx.state.change_settings(addr, patch).await?;
 
     Ok(warp::reply::with_status("", StatusCode::OK))
 }
@@ -169,67 +739,913 @@ struct Forget {
 }
 
 async fn forget(ctx: super::Context, req: Forget) -> Result<impl warp::Reply, warp::Rejection> {
-    ctx.sensors.write().await.remove(&req.addr);
-    let mut txn = ctx.db.write_txn()?;
-    ctx.db.delete_addr(&mut txn, req.addr)?;
-    txn.commit().map_err(db::Error::from)?;
+    ctx.state.forget(req.addr).await?;
     Ok(warp::reply::with_status("", StatusCode::OK))
 }
 
-async fn get_state(ctx: super::Context) -> Result<impl warp::Reply, warp::Rejection> {
-    let sensors = ctx.sensors.read().await;
+/// The pending sensors, only there when new sensors need approval
+fn pending(ctx: &super::Context) -> Result<&state::PendingSensors, Error> {
+    ctx.pending
+        .as_ref()
+        .ok_or_else(|| Error::BadRequest("New sensors don't need approval".to_owned()))
+}
+
+async fn get_pending(ctx: super::Context) -> Result<impl warp::Reply, warp::Rejection> {
     #[derive(serde::Serialize)]
-    struct ReplyEntry {
-        state: crate::sensor::SensorState,
-        label: Option<String>,
+    struct Entry {
+        addr: BluetoothAddress,
+        #[serde(flatten)]
+        pending: state::Pending,
     }
-    let txn = ctx.db.read_txn()?;
 
-    let reply = sensors
+    let entries = pending(&ctx)?
+        .list()
+        .into_iter()
+        .map(|(addr, pending)| Entry { addr, pending })
+        .collect::<Vec<_>>();
+    Ok(warp::reply::json(&entries))
+}
+
+async fn approve_pending(
+    ctx: super::Context,
+    req: Forget,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !pending(&ctx)?.contains(req.addr) {
+        return Err(Error::NotFound.into());
+    }
+    ctx.state.memorize(req.addr).await?;
+    tracing::info!("Approved sensor {}", req.addr);
+    Ok(warp::reply::with_status("", StatusCode::OK))
+}
+
+/// Hides a pending sensor until the next start
+async fn dismiss_pending(
+    ctx: super::Context,
+    req: Forget,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !pending(&ctx)?.dismiss(req.addr) {
+        return Err(Error::NotFound.into());
+    }
+    Ok(warp::reply::with_status("", StatusCode::OK))
+}
+
+/// Whether an If-None-Match header contains `etag`, ignoring weakness since the state is
+/// compared as a whole anyway
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').map(str::trim).any(|tag| {
+        let tag = tag.strip_prefix("W/").unwrap_or(tag);
+        tag == "*" || tag == etag
+    })
+}
+
+/// Current state of all sensors matching the query, tagged with the state generation so polling
+/// clients can skip unchanged replies
+async fn get_state(
+    ctx: super::Context,
+    access: Access,
+    query: SensorQuery,
+    if_none_match: Option<String>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let filter = SensorFilter::parse(&query).map_err(Error::BadRequest)?;
+    // tenants and filters get different replies, so their tags mustn't match each other
+    let scope = {
+        let mut hasher = DefaultHasher::new();
+        access.tenant().hash(&mut hasher);
+        query.hash(&mut hasher);
+        hasher.finish()
+    };
+    let sensors = ctx.sensors.read().await;
+    let etag = format!(
+        "\"{:x}-{}-{:x}\"",
+        ctx.boot,
+        ctx.generation.load(Ordering::Acquire),
+        scope
+    );
+    if if_none_match.map_or(false, |tags| etag_matches(&tags, &etag)) {
+        return Ok(warp::http::Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header("ETag", etag)
+            .header("Vary", "Authorization")
+            .body(warp::hyper::Body::empty())
+            .unwrap());
+    }
+
+    let reply = filter.apply(describe_visible(&ctx, &access, &sensors)?);
+    drop(sensors);
+    let reply = warp::reply::with_header(warp::reply::json(&reply), "ETag", etag);
+    Ok(warp::reply::with_header(reply, "Vary", "Authorization").into_response())
+}
+
+/// Like [`describe_sensors`] for the part of `sensors` the `access` covers
+fn describe_visible(
+    ctx: &super::Context,
+    access: &Access,
+    sensors: &BTreeMap<BluetoothAddress, SensorState>,
+) -> Result<Vec<(BluetoothAddress, SensorEntry)>, db::Error> {
+    match access {
+        Access::All => describe_sensors(ctx, sensors),
+        // comfort is compared among the sensors of the tenant as well
+        Access::Tenant(_) => describe_sensors(ctx, &access.visible(sensors)),
+    }
+}
+
+/// Addr entries and comfort indicators of all `sensors`, in their sort order and by address
+/// otherwise
+fn describe_sensors(
+    ctx: &super::Context,
+    sensors: &BTreeMap<BluetoothAddress, SensorState>,
+) -> Result<Vec<(BluetoothAddress, SensorEntry)>, db::Error> {
+    let txn = ctx.db.read_txn()?;
+    let mut entries = sensors
         .iter()
         .map(|(addr, state)| {
-            let db_entry = ctx.db.get_addr(&txn, *addr)?;
-            Ok((
+            let entry = ctx.db.get_addr(&txn, *addr)?.unwrap_or_default();
+            Ok((*addr, *state, entry))
+        })
+        .collect::<Result<Vec<_>, db::Error>>()?;
+
+    let connected = entries
+        .iter()
+        .filter_map(|(addr, state, entry)| match state {
+            SensorState::Connected(values) => Some((*addr, entry.placement, *values)),
+            SensorState::Unconnected => None,
+        })
+        .collect::<Vec<_>>();
+    let comfort = analytics::comfort(&connected);
+    let now = Timestamp::now();
+    let recent = now.bottoming_sub(Timestamp::from(analytics::TREND_WINDOW))..now;
+    let trends = connected
+        .iter()
+        .map(|(addr, _, _)| {
+            let log = ctx.db.get_log(&txn, *addr, recent.clone(), None)?;
+            Ok((*addr, log.as_deref().and_then(analytics::trend)))
+        })
+        .collect::<Result<BTreeMap<_, _>, db::Error>>()?;
+
+    // stable so sensors without an order stay sorted by address
+    entries.sort_by_key(|(_, _, entry)| (entry.sort_order.is_none(), entry.sort_order));
+    Ok(entries
+        .into_iter()
+        .map(|(addr, state, entry)| {
+            (
                 addr,
-                ReplyEntry {
-                    state: *state,
-                    label: db_entry.and_then(|entry| entry.label),
+                SensorEntry {
+                    state,
+                    label: entry.label,
+                    room: entry.room,
+                    placement: entry.placement,
+                    icon: entry.icon,
+                    sort_order: entry.sort_order,
+                    comfort: comfort.get(&addr).copied(),
+                    anomalies: ctx
+                        .anomalies
+                        .as_ref()
+                        .map_or_else(Vec::new, |anomalies| anomalies.anomalies(addr)),
+                    measured: ctx.clocks.latest(addr),
+                    trend: trends.get(&addr).copied().flatten(),
                 },
-            ))
+            )
         })
-        .collect::<Result<Vec<_>, crate::db::Error>>()?;
+        .collect())
+}
 
-    Ok(warp::reply::json(&reply))
+#[derive(serde::Deserialize)]
+struct LogQuery {
+    /// seconds of history, one day if unset
+    window: Option<u32>,
 }
 
 async fn get_log(
     ctx: super::Context,
     addr: BluetoothAddress,
+    query: LogQuery,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let txn = ctx.db.read_txn()?;
-    let now = Timestamp::now();
-    let start = now.bottoming_sub(Timestamp::ONE_DAY);
+    let window = query.window.map_or(Timestamp::ONE_DAY, Timestamp::from);
+    let key = format!("log/{}?window={}", addr, window.as_u32());
+    ctx.queries
+        .get_or_compute(key, Some(addr), async {
+            let txn = ctx.db.read_txn()?;
+            let now = Timestamp::now();
+            let start = now.bottoming_sub(window);
+
+            let log = ctx
+                .db
+                .get_flagged_log(&txn, addr, start..now, ctx.max_log_entries)?
+                .ok_or(Error::NotFound)?;
+
+            Ok::<_, warp::Rejection>(Cached::json(&LogEntry::all(log)))
+        })
+        .await
+}
 
-    let log = ctx.db.get_log(&txn, addr, start..now)?.unwrap();
+#[derive(serde::Serialize)]
+struct LogEntry {
+    time: Timestamp,
+    values: SensorValues,
+    quality: Quality,
+}
+
+impl LogEntry {
+    fn all(log: Vec<(Timestamp, SensorValues, Quality)>) -> Vec<Self> {
+        log.into_iter()
+            .map(|(time, values, quality)| LogEntry {
+                time,
+                values,
+                quality,
+            })
+            .collect()
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct LogFilter {
+    /// like `info,ble_weatherstation_central::bluetooth=debug`
+    directives: String,
+}
+
+/// Changes which messages get logged until the next restart
+async fn put_log_filter(
+    ctx: super::Context,
+    filter: LogFilter,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    ctx.log_filter
+        .set(&filter.directives)
+        .map_err(|e| Error::BadRequest(format!("Invalid log filter: {}", e)))?;
+    Ok(warp::reply())
+}
+
+#[derive(serde::Deserialize)]
+struct WatchQuery {
+    /// unix timestamp of the newest entry the client has, now if unset
+    after: Option<u32>,
+    /// seconds to wait for a new entry, at most [`MAX_WATCH_WAIT`]
+    wait: Option<u64>,
+}
+
+/// Entries of `addr` newer than `after` as soon as there are any, an empty list if none got
+/// committed in time. Each waiting request holds one of the `max_concurrent_requests` slots.
+async fn watch_log(
+    ctx: super::Context,
+    addr: BluetoothAddress,
+    query: WatchQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let after = query.after.map_or_else(Timestamp::now, Timestamp::from);
+    let newer = Timestamp::from(after.as_u32().saturating_add(1))..Timestamp::MAX;
+    let wait = query.wait.unwrap_or(MAX_WATCH_WAIT).min(MAX_WATCH_WAIT);
+    let deadline = time::Instant::now() + Duration::from_secs(wait);
+    // subscribed before looking so a commit in between isn't missed
+    let mut commits = ctx.db.subscribe_commits();
+    loop {
+        let log = {
+            let txn = ctx.db.read_txn()?;
+            ctx.db
+                .get_flagged_log(&txn, addr, newer.clone(), None)?
+                .ok_or(Error::NotFound)?
+        };
+        if !log.is_empty() {
+            return Ok(warp::reply::json(&LogEntry::all(log)));
+        }
+        loop {
+            match time::timeout_at(deadline, commits.recv()).await {
+                Err(_) => return Ok(warp::reply::json(&Vec::<LogEntry>::new())),
+                Ok(Ok(committed)) if !committed.newest.contains_key(&addr) => continue,
+                // one with entries of `addr` or so many that some got missed
+                Ok(_) => break,
+            }
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ReplicationQuery {
+    /// unix timestamp of the oldest reading the replica wants of sensors missing in `sensors`,
+    /// everything if unset
+    since: Option<u32>,
+    /// newest reading the replica has of each sensor, see [`replication::Cursor`]
+    #[serde(default)]
+    sensors: String,
+}
+
+/// Stream of the log for replicas, only there with a replication token and only for requests
+/// bearing it
+async fn get_replication(
+    ctx: super::Context,
+    authorization: Option<String>,
+    query: ReplicationQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let token = ctx.replication_token.as_deref().ok_or(Error::NotFound)?;
+    match authorization
+        .as_deref()
+        .and_then(|auth| auth.strip_prefix("Bearer "))
+    {
+        Some(bearer) if replication::token_matches(bearer, token) => {}
+        _ => return Err(Error::NotFound.into()),
+    }
+
+    let cursor = replication::Cursor {
+        since: Timestamp::from(query.since.unwrap_or(0)),
+        sensors: replication::Cursor::parse_sensors(&query.sensors)
+            .map_err(|e| Error::BadRequest(format!("{:#}", e)))?,
+    };
+    let body = warp::hyper::Body::wrap_stream(replication::stream(ctx.clone(), cursor));
+    Ok(warp::reply::with_header(
+        warp::http::Response::new(body),
+        "Content-Type",
+        "application/x-ndjson",
+    ))
+}
+
+#[derive(serde::Deserialize)]
+struct EventsQuery {
+    /// like `24h` or `30d`, one week if unset
+    window: Option<String>,
+}
+
+/// When `addr` connected and got lost, to tell connection drops apart from other log gaps
+async fn get_events(
+    ctx: super::Context,
+    addr: BluetoothAddress,
+    query: EventsQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let window = match query.window {
+        Some(ref window) => {
+            chart::parse_window(window).map_err(|e| Error::BadRequest(e.to_string()))?
+        }
+        None => Timestamp::from(7 * Timestamp::ONE_DAY.as_u32()),
+    };
+    let now = Timestamp::now();
+    let connections = {
+        let txn = ctx.db.read_txn()?;
+        ctx.db
+            .get_connections(&txn, addr, now.bottoming_sub(window)..now)?
+            .ok_or(Error::NotFound)?
+    };
 
     #[derive(serde::Serialize)]
-    struct Entry {
+    struct Event {
         time: Timestamp,
-        values: SensorValues,
+        connection: db::Connection,
     }
 
     Ok(warp::reply::json(
-        &log.into_iter()
-            .map(|(time, values)| Entry { time, values })
+        &connections
+            .into_iter()
+            .map(|(time, connection)| Event { time, connection })
             .collect::<Vec<_>>(),
     ))
 }
 
-async fn detail(
-    _ctx: super::Context,
-    sensor: BluetoothAddress,
+/// Path segment of the form `<addr>.svg`
+struct SvgFile(BluetoothAddress);
+
+impl std::str::FromStr for SvgFile {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.strip_suffix(".svg")
+            .ok_or_else(|| eyre::format_err!("Not an svg file"))?
+            .parse()
+            .map(Self)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ChartQuery {
+    metric: Quantity,
+    /// like `24h` or `7d`, one day if unset
+    window: Option<String>,
+}
+
+/// Line chart of one metric of `addr` for embedding without javascript
+async fn get_chart(
+    ctx: super::Context,
+    SvgFile(addr): SvgFile,
+    query: ChartQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let window = match query.window {
+        Some(ref window) => {
+            chart::parse_window(window).map_err(|e| Error::BadRequest(e.to_string()))?
+        }
+        None => Timestamp::ONE_DAY,
+    };
+    let key = format!(
+        "chart/{}?metric={:?}&window={}",
+        addr,
+        query.metric,
+        window.as_u32()
+    );
+    ctx.queries
+        .get_or_compute(key, Some(addr), async {
+            let now = Timestamp::now();
+            let range = now.bottoming_sub(window)..now;
+            let limit = ctx
+                .max_log_entries
+                .map_or(MAX_CHART_POINTS, |max| max.min(MAX_CHART_POINTS));
+
+            let (label, log) = {
+                let txn = ctx.db.read_txn()?;
+                let log = ctx
+                    .db
+                    .get_log(&txn, addr, range.clone(), Some(limit))?
+                    .ok_or(Error::NotFound)?;
+                let label = ctx.db.get_addr(&txn, addr)?.and_then(|entry| entry.label);
+                (label, log)
+            };
+
+            let points = log
+                .into_iter()
+                .map(|(time, values)| (time, query.metric.of(values)))
+                .collect::<Vec<_>>();
+            let title = label.unwrap_or_else(|| addr.to_string());
+            let svg = chart::render_svg(&title, query.metric, range, &points);
+            Ok::<_, warp::Rejection>(Cached::new("image/svg+xml", svg))
+        })
+        .await
+}
+
+/// Size of the log of `addr` and how much of what should be there is
+async fn get_stats(
+    ctx: super::Context,
+    addr: BluetoothAddress,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    ctx.queries
+        .get_or_compute(format!("stats/{}", addr), Some(addr), async {
+            let txn = ctx.db.read_txn()?;
+            ctx.db.get_addr(&txn, addr)?.ok_or(Error::NotFound)?;
+            let log = ctx.db.log_stats(&txn, addr)?;
+            let availability = Availability::of(&ctx.db, &txn, addr, Timestamp::now())?;
+
+            #[derive(serde::Serialize)]
+            struct Stats {
+                entries: u64,
+                first: Option<Timestamp>,
+                last: Option<Timestamp>,
+                availability: Availability,
+            }
+
+            Ok::<_, warp::Rejection>(Cached::json(&Stats {
+                entries: log.as_ref().map_or(0, |log| log.entries),
+                first: log.as_ref().map(|log| log.first),
+                last: log.as_ref().map(|log| log.last),
+                availability,
+            }))
+        })
+        .await
+}
+
+/// Latest battery level of `addr` and when it's predicted to run empty, both none for stations
+/// without the battery service
+async fn get_battery(
+    ctx: super::Context,
+    addr: BluetoothAddress,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !ctx.sensors.read().await.contains_key(&addr) {
+        return Err(Error::NotFound.into());
+    }
+
+    #[derive(serde::Serialize)]
+    struct Battery {
+        latest: Option<battery::Level>,
+        prediction: Option<battery::Prediction>,
+    }
+
+    Ok(warp::reply::json(&Battery {
+        latest: ctx.batteries.latest(addr),
+        prediction: ctx.batteries.prediction(addr),
+    }))
+}
+
+#[derive(serde::Deserialize)]
+struct GapsQuery {
+    /// like `24h` or `30d`, one day if unset
+    window: Option<String>,
+    /// shortest outage reported, twice the log interval if unset
+    min: Option<String>,
+}
+
+/// Stretches of the window without log entries of `addr`, to see how often a station drops off
+async fn get_gaps(
+    ctx: super::Context,
+    addr: BluetoothAddress,
+    query: GapsQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let parse = |window: &Option<String>, default| match window {
+        Some(window) => chart::parse_window(window).map_err(|e| Error::BadRequest(e.to_string())),
+        None => Ok(default),
+    };
+    let window = parse(&query.window, Timestamp::ONE_DAY)?;
+    let min = parse(&query.min, Timestamp::from(2 * tasks::LOG_INTERVAL))?;
+    let now = Timestamp::now();
+
+    let times = {
+        let txn = ctx.db.read_txn()?;
+        ctx.db
+            .log_times(&txn, addr, now.bottoming_sub(window)..now)?
+            .ok_or(Error::NotFound)?
+    };
+    let gaps = gaps::find_gaps(&times, now, min.as_u32());
+
+    #[derive(serde::Serialize)]
+    struct Gaps {
+        /// seconds between two log entries when nothing is missing
+        expected_interval: u32,
+        /// summed up length of all gaps in seconds
+        downtime: u32,
+        gaps: Vec<gaps::Gap>,
+    }
+
+    Ok(warp::reply::json(&Gaps {
+        expected_interval: tasks::LOG_INTERVAL,
+        downtime: gaps.iter().map(gaps::Gap::seconds).sum(),
+        gaps,
+    }))
+}
+
+#[derive(serde::Deserialize)]
+struct BandsQuery {
+    /// weeks of history the percentiles are taken over, four if unset
+    weeks: Option<u32>,
+    /// like `30m`, width of a time of day bucket, half an hour if unset
+    bucket: Option<String>,
+    /// like `24h`, how far back the returned points go, one day if unset
+    window: Option<String>,
+}
+
+/// Most weeks of history a band may be computed over
+const MAX_BAND_WEEKS: u32 = 52;
+
+/// 10th, 50th and 90th percentiles of the past weeks by time of day, one point per bucket of
+/// the window, to draw today against what's typical
+async fn get_bands(
+    ctx: super::Context,
+    addr: BluetoothAddress,
+    query: BandsQuery,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    tracing::info!("Detail for {}", sensor);
-    let rendered = askama::Template::render(&templates::Detail::new(sensor)).unwrap();
-    Ok(warp::reply::html(rendered))
+    let parse = |window: &Option<String>, default| match window {
+        Some(window) => chart::parse_window(window).map_err(|e| Error::BadRequest(e.to_string())),
+        None => Ok(default),
+    };
+    let bucket = parse(&query.bucket, Timestamp::from(30 * 60))?.as_u32();
+    let window = parse(&query.window, Timestamp::ONE_DAY)?;
+    let weeks = query.weeks.unwrap_or(4);
+    if !(1..=MAX_BAND_WEEKS).contains(&weeks) {
+        return Err(
+            Error::BadRequest(format!("Weeks must be between 1 and {}", MAX_BAND_WEEKS)).into(),
+        );
+    }
+    if bucket < 60 || bucket > Timestamp::ONE_DAY.as_u32() {
+        return Err(
+            Error::BadRequest(String::from("Bucket must be between a minute and a day")).into(),
+        );
+    }
+
+    let profile = ctx
+        .bands
+        .get(&ctx.db, addr, weeks, bucket)?
+        .ok_or(Error::NotFound)?;
+
+    #[derive(serde::Serialize)]
+    struct Point {
+        time: Timestamp,
+        temperature: bands::Percentiles,
+        humidity: bands::Percentiles,
+        pressure: bands::Percentiles,
+    }
+
+    #[derive(serde::Serialize)]
+    struct Bands {
+        bucket: u32,
+        weeks: u32,
+        points: Vec<Point>,
+    }
+
+    let now = Timestamp::now().as_u32();
+    let start = now.saturating_sub(window.as_u32());
+    let points = (start / bucket * bucket..=now)
+        .step_by(bucket as usize)
+        .filter_map(|time| {
+            let time = Timestamp::from(time);
+            let [temperature, humidity, pressure] = profile.at(time)?;
+            Some(Point {
+                time,
+                temperature,
+                humidity,
+                pressure,
+            })
+        })
+        .collect();
+
+    Ok(warp::reply::json(&Bands {
+        bucket: profile.bucket(),
+        weeks,
+        points,
+    }))
+}
+
+#[derive(serde::Deserialize)]
+struct DegreeDaysQuery {
+    /// base temperature in °C, 18 if unset
+    base: Option<f64>,
+    /// like `30d`, or `season` for everything since the start of the heating season, the
+    /// default
+    window: Option<String>,
+}
+
+/// Heating and cooling degree days per day of the window and their sums
+async fn get_degree_days(
+    ctx: super::Context,
+    addr: BluetoothAddress,
+    query: DegreeDaysQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let base = query.base.unwrap_or(18.);
+    if !base.is_finite() {
+        return Err(Error::BadRequest(String::from("Base must be a number")).into());
+    }
+    let now = Timestamp::now();
+    let start = match query.window.as_deref() {
+        None | Some("season") => analytics::season_start(now),
+        Some(window) => now.bottoming_sub(
+            chart::parse_window(window).map_err(|e| Error::BadRequest(e.to_string()))?,
+        ),
+    };
+
+    // hourly means from the rollups at most, daily ones past the configured limit, so long
+    // windows don't read every entry. A day worth of entries is kept for short windows
+    let hours = (now.bottoming_sub(start).as_u32() / 60 / 60) as usize;
+    let limit = ctx
+        .max_log_entries
+        .map_or(hours, |max| max.min(hours))
+        .max(24);
+    let log = {
+        let txn = ctx.db.read_txn()?;
+        ctx.db
+            .get_log(&txn, addr, start..now, Some(limit))?
+            .ok_or(Error::NotFound)?
+    };
+    let days = analytics::degree_days(&log, base);
+
+    #[derive(serde::Serialize)]
+    struct DegreeDays {
+        base: f64,
+        heating: f64,
+        cooling: f64,
+        days: Vec<analytics::DegreeDay>,
+    }
+
+    Ok(warp::reply::json(&DegreeDays {
+        base,
+        heating: days.iter().map(|day| day.heating).sum(),
+        cooling: days.iter().map(|day| day.cooling).sum(),
+        days,
+    }))
+}
+
+#[derive(serde::Deserialize)]
+struct ImportQuery {
+    /// replace logged entries instead of skipping them
+    #[serde(default)]
+    overwrite: bool,
+}
+
+/// Adds the log entries in the body to the log of `addr`, csv if the content type says so and
+/// json lines otherwise
+async fn import(
+    ctx: super::Context,
+    addr: BluetoothAddress,
+    query: ImportQuery,
+    content_type: Option<String>,
+    body: bytes::Bytes,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let format = match content_type {
+        Some(content_type) if content_type.starts_with("text/csv") => LogFormat::Csv,
+        _ => LogFormat::Json,
+    };
+    let body = std::str::from_utf8(&body)
+        .map_err(|e| Error::BadRequest(format!("Body is not utf-8: {}", e)))?;
+    let log = parse_log(body, format).map_err(|e| Error::BadRequest(format!("{:#}", e)))?;
+
+    ctx.state.memorize(addr).await?;
+
+    let writer = ctx.clone();
+    let import = task::spawn_blocking(move || {
+        let import = import::prepare(&writer.db, addr, log, query.overwrite)?;
+        writer.db.write_log(&import.batch).map(|()| import)
+    })
+    .await
+    .expect("Log import panicked")?;
+    let entries = import.batch.len();
+    ctx.queries.invalidate(Some(addr));
+    state::bump_generation(&ctx);
+    replication::publish(&ctx, import.batch);
+
+    #[derive(serde::Serialize)]
+    struct Imported {
+        entries: usize,
+        /// logged already or repeated in the body
+        skipped: Vec<Timestamp>,
+    }
+
+    Ok(warp::reply::json(&Imported {
+        entries,
+        skipped: import.skipped,
+    }))
+}
+
+async fn get_forecast(ctx: super::Context) -> Result<impl warp::Reply, warp::Rejection> {
+    let forecaster = ctx.forecast.as_ref().ok_or(Error::NotFound)?;
+    // the forecast doesn't depend on any sensor, only its age invalidates it
+    ctx.queries
+        .get_or_compute("forecast".to_owned(), None, async {
+            let forecast = forecaster.get().ok_or(Error::ForecastUnavailable)?;
+            let hours = forecast.upcoming(Timestamp::now()).collect::<Vec<_>>();
+            Ok::<_, warp::Rejection>(Cached::json(&hours))
+        })
+        .await
+}
+
+/// Tracked beacons by address, 404 without a `[presence]` table
+async fn get_presence(ctx: super::Context) -> Result<impl warp::Reply, warp::Rejection> {
+    let presence = ctx.presence.as_ref().ok_or(Error::NotFound)?;
+    Ok(warp::reply::json(&presence.beacons()))
+}
+
+/// The BlueZ object tree as the bluetooth thread would interpret it right now
+async fn get_bluez(ctx: super::Context) -> Result<impl warp::Reply, warp::Rejection> {
+    let objects = task::spawn_blocking(move || bluetooth::dump_bluez(&ctx.profiles))
+        .await
+        .expect("BlueZ dump panicked")
+        .map_err(|e| {
+            tracing::error!("Could not dump BlueZ objects: {}", e);
+            Error::Internal
+        })?;
+    Ok(warp::reply::json(&objects))
+}
+
+/// Longest a raw gatt call of the debug console may take, stations that hang don't get to
+/// hold up a request forever
+const GATT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Raw value of a characteristic, hex encoded
+#[derive(serde::Deserialize, serde::Serialize)]
+struct GattValue {
+    value: String,
+}
+
+/// Runs a blocking call of the gatt debug console, 404 unless it's turned on
+async fn gatt_call<T: Send + 'static>(
+    ctx: &super::Context,
+    call: impl FnOnce() -> Result<T, bluetooth::Error> + Send + 'static,
+) -> Result<T, warp::Rejection> {
+    if !ctx.gatt_console {
+        return Err(Error::NotFound.into());
+    }
+    match time::timeout(GATT_TIMEOUT, task::spawn_blocking(call)).await {
+        Ok(Ok(Ok(value))) => Ok(value),
+        Ok(Ok(Err(bluetooth::Error::DeviceGone { .. })))
+        | Ok(Ok(Err(bluetooth::Error::MissingCharacteristic { .. }))) => {
+            Err(Error::NotFound.into())
+        }
+        Ok(Ok(Err(bluetooth::Error::Timeout { .. }))) | Err(_) => Err(Error::GatewayTimeout.into()),
+        Ok(Ok(Err(e))) => Err(Error::BadRequest(e.to_string()).into()),
+        Ok(Err(_)) => Err(Error::Internal.into()),
+    }
+}
+
+async fn get_gatt(
+    ctx: super::Context,
+    addr: BluetoothAddress,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let characteristics = gatt_call(&ctx, move || gatt::characteristics(addr)).await?;
+    Ok(warp::reply::json(&characteristics))
+}
+
+async fn read_gatt(
+    ctx: super::Context,
+    addr: BluetoothAddress,
+    uuid: String,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let value = gatt_call(&ctx, move || gatt::read(addr, &uuid)).await?;
+    Ok(warp::reply::json(&GattValue {
+        value: hex::encode(value),
+    }))
+}
+
+async fn write_gatt(
+    ctx: super::Context,
+    addr: BluetoothAddress,
+    uuid: String,
+    value: GattValue,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let value = hex::decode(value.value.replace(' ', ""))
+        .map_err(|e| Error::BadRequest(format!("Value isn't hex: {}", e)))?;
+    tracing::info!("Writing {} to {} of {}", hex::encode(&value), uuid, addr);
+    gatt_call(&ctx, move || gatt::write(addr, &uuid, &value)).await?;
+    Ok(warp::reply())
+}
+
+#[derive(serde::Serialize)]
+struct Health {
+    /// false if the central can't read any weatherstations right now
+    healthy: bool,
+    adapter: AdapterHealth,
+    /// by name, each polled by a thread of its own
+    adapters: BTreeMap<String, AdapterStats>,
+    uptime: Uptime,
+}
+
+#[derive(serde::Serialize)]
+struct Uptime {
+    started: Timestamp,
+    seconds: u64,
+    /// starts of the central before this one
+    restarts: u32,
+}
+
+#[derive(serde::Serialize)]
+struct AdapterHealth {
+    state: AdapterState,
+    /// since when it's been available or not
+    since: Timestamp,
+    detail: &'static str,
+}
+
+/// 503 while the bluetooth adapter is unavailable so monitoring can go by the status alone
+fn get_health(ctx: super::Context) -> warp::reply::Response {
+    let current = ctx.adapter.current();
+    let healthy = current.state.available();
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let health = Health {
+        healthy,
+        adapter: AdapterHealth {
+            state: current.state,
+            since: current.since,
+            detail: current.state.describe(),
+        },
+        adapters: ctx.adapter.adapters(),
+        uptime: Uptime {
+            started: Timestamp::from(ctx.metrics.start_time.get() as u32),
+            seconds: ctx.started.elapsed().as_secs(),
+            restarts: ctx.restarts,
+        },
+    };
+    warp::reply::with_status(warp::reply::json(&health), status).into_response()
+}
+
+async fn get_dashboard(
+    ctx: super::Context,
+    name: String,
+    access: Access,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !dashboard::valid_name(&name) {
+        return Err(Error::BadRequest(format!("Invalid dashboard name `{}`", name)).into());
+    }
+
+    let layout = load_layout(&ctx, &name)?.only(|addr| access.allows(addr));
+    Ok(warp::reply::json(&layout))
+}
+
+async fn put_dashboard(
+    ctx: super::Context,
+    name: String,
+    layout: Layout,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !dashboard::valid_name(&name) {
+        return Err(Error::BadRequest(format!("Invalid dashboard name `{}`", name)).into());
+    }
+
+    let mut txn = ctx.db.write_txn()?;
+    ctx.db.put_dashboard(&mut txn, &name, &layout)?;
+    txn.commit().map_err(db::Error::from)?;
+
+    Ok(warp::reply::with_status("", StatusCode::OK))
+}
+
+async fn delete_dashboard(
+    ctx: super::Context,
+    name: String,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut txn = ctx.db.write_txn()?;
+    if !ctx.db.delete_dashboard(&mut txn, &name)? {
+        return Err(Error::NotFound.into());
+    }
+    txn.commit().map_err(db::Error::from)?;
+
+    Ok(warp::reply::with_status("", StatusCode::OK))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn if_none_match() {
+        assert!(etag_matches("\"3\"", "\"3\""));
+        assert!(etag_matches("\"1\", W/\"3\"", "\"3\""));
+        assert!(etag_matches("*", "\"3\""));
+        assert!(!etag_matches("\"2\"", "\"3\""));
+    }
 }
@@ -0,0 +1,182 @@
+use crate::bluetooth::BluetoothAddress;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use warp::{http::Response, hyper::Body};
+
+/// Replies older than this get computed again even if nothing was logged in between, most
+/// queries are relative to now
+const MAX_AGE: Duration = Duration::from_secs(30);
+
+/// Beyond this the oldest replies make room for new ones
+const MAX_ENTRIES: usize = 256;
+
+/// Reply body as it went out the first time
+#[derive(Clone)]
+pub(super) struct Cached {
+    content_type: &'static str,
+    body: bytes::Bytes,
+}
+
+impl Cached {
+    pub(super) fn json(value: &impl serde::Serialize) -> Self {
+        Self {
+            content_type: "application/json",
+            body: serde_json::to_vec(value)
+                .expect("Replies always serialize")
+                .into(),
+        }
+    }
+
+    pub(super) fn new(content_type: &'static str, body: impl Into<bytes::Bytes>) -> Self {
+        Self {
+            content_type,
+            body: body.into(),
+        }
+    }
+}
+
+impl warp::Reply for Cached {
+    fn into_response(self) -> warp::reply::Response {
+        Response::builder()
+            .header("Content-Type", self.content_type)
+            .body(Body::from(self.body))
+            .unwrap()
+    }
+}
+
+struct Entry {
+    /// sensor whose log the reply is made of
+    addr: Option<BluetoothAddress>,
+    /// of `addr` when the computation started
+    generation: u64,
+    created: Instant,
+    reply: Cached,
+}
+
+#[derive(Default)]
+struct Entries {
+    replies: BTreeMap<String, Entry>,
+    /// bumped on every log write of a sensor, replies computed before that are stale
+    generations: BTreeMap<BluetoothAddress, u64>,
+}
+
+impl Entries {
+    fn generation(&self, addr: Option<BluetoothAddress>) -> u64 {
+        generation(&self.generations, addr)
+    }
+}
+
+fn generation(
+    generations: &BTreeMap<BluetoothAddress, u64>,
+    addr: Option<BluetoothAddress>,
+) -> u64 {
+    addr.and_then(|addr| generations.get(&addr).copied())
+        .unwrap_or(0)
+}
+
+fn fresh(generations: &BTreeMap<BluetoothAddress, u64>, entry: &Entry, now: Instant) -> bool {
+    now.duration_since(entry.created) < MAX_AGE
+        && entry.generation == generation(generations, entry.addr)
+}
+
+/// Replies of expensive api queries by their parameters, so many dashboards polling at once
+/// don't each go through the log
+#[derive(Default)]
+pub(crate) struct QueryCache {
+    entries: Mutex<Entries>,
+}
+
+impl QueryCache {
+    /// The cached reply of `key` if it's still fresh, otherwise the one `compute` comes up with.
+    /// Replies computed while the log of `addr` changed aren't kept.
+    pub(super) async fn get_or_compute(
+        &self,
+        key: String,
+        addr: Option<BluetoothAddress>,
+        compute: impl Future<Output = Result<Cached, warp::Rejection>>,
+    ) -> Result<Cached, warp::Rejection> {
+        let generation = {
+            let entries = self.entries.lock().unwrap();
+            if let Some(entry) = entries.replies.get(&key) {
+                if fresh(&entries.generations, entry, Instant::now()) {
+                    return Ok(entry.reply.clone());
+                }
+            }
+            entries.generation(addr)
+        };
+
+        let reply = compute.await?;
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.generation(addr) == generation {
+            if entries.replies.len() >= MAX_ENTRIES {
+                let now = Instant::now();
+                let Entries {
+                    replies,
+                    generations,
+                } = &mut *entries;
+                replies.retain(|_, entry| fresh(generations, entry, now));
+                if let Some(oldest) = replies
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.created)
+                    .map(|(key, _)| key.clone())
+                {
+                    replies.remove(&oldest);
+                }
+            }
+            entries.replies.insert(
+                key,
+                Entry {
+                    addr,
+                    generation,
+                    created: Instant::now(),
+                    reply: reply.clone(),
+                },
+            );
+        }
+        Ok(reply)
+    }
+
+    /// Called after every write to the logs of `addrs`
+    pub(crate) fn invalidate(&self, addrs: impl IntoIterator<Item = BluetoothAddress>) {
+        let mut entries = self.entries.lock().unwrap();
+        for addr in addrs {
+            *entries.generations.entry(addr).or_default() += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn writes_invalidate_replies() {
+        let cache = QueryCache::default();
+        let addr = BluetoothAddress::from(1);
+        let compute = |body: &'static str| async move {
+            Ok::<_, warp::Rejection>(Cached::new("text/plain", body))
+        };
+
+        let first = cache
+            .get_or_compute("stats".to_owned(), Some(addr), compute("first"))
+            .await
+            .unwrap();
+        let cached = cache
+            .get_or_compute("stats".to_owned(), Some(addr), compute("second"))
+            .await
+            .unwrap();
+        assert_eq!(cached.body, first.body);
+
+        cache.invalidate(vec![addr]);
+        let recomputed = cache
+            .get_or_compute("stats".to_owned(), Some(addr), compute("third"))
+            .await
+            .unwrap();
+        assert_eq!(&recomputed.body[..], b"third");
+    }
+}
@@ -0,0 +1,154 @@
+#[cfg(feature = "web-ui")]
+use super::templates;
+use crate::{db, state};
+use std::{convert::Infallible, time::Duration};
+use warp::{
+    http::{Response, StatusCode},
+    hyper::Body,
+    reject, Rejection,
+};
+
+/// Everything a request can fail with, replied to as json for the api and as a page otherwise
+#[derive(thiserror::Error, Debug, Clone)]
+pub(crate) enum Error {
+    #[error("{0}")]
+    BadRequest(String),
+
+    #[error("Not found")]
+    NotFound,
+
+    #[error("Missing or unknown api token")]
+    Unauthorized,
+
+    #[error("Only the admin token may do this")]
+    Forbidden,
+
+    #[error("Method not allowed")]
+    MethodNotAllowed,
+
+    #[error("Request body too large")]
+    PayloadTooLarge,
+
+    #[error("Too many requests")]
+    RateLimited(Duration),
+
+    #[error("Forecast currently unavailable")]
+    ForecastUnavailable,
+
+    #[error("Server busy")]
+    Busy,
+
+    /// a sensor didn't answer in time
+    #[error("Timed out")]
+    GatewayTimeout,
+
+    /// details only end up in the log
+    #[error("Internal server error")]
+    Internal,
+}
+
+impl reject::Reject for Error {}
+
+impl Error {
+    /// Internal errors get logged here since their details don't make it into the reply
+    fn from_rejection(rejection: &Rejection) -> Self {
+        if let Some(e) = rejection.find::<Error>() {
+            e.clone()
+        } else if let Some(e) = rejection.find::<db::Error>() {
+            let e: &dyn std::error::Error = e;
+            tracing::error!(e);
+            Error::Internal
+        } else if let Some(e) = rejection.find::<state::Error>() {
+            let e: &dyn std::error::Error = e;
+            tracing::error!(e);
+            Error::Internal
+        } else if let Some(e) = rejection.find::<reject::InvalidQuery>() {
+            Error::BadRequest(e.to_string())
+        } else if let Some(e) = rejection.find::<warp::body::BodyDeserializeError>() {
+            Error::BadRequest(e.to_string())
+        } else if let Some(e) = rejection.find::<reject::LengthRequired>() {
+            Error::BadRequest(e.to_string())
+        } else if rejection.find::<reject::PayloadTooLarge>().is_some() {
+            Error::PayloadTooLarge
+        } else if rejection.is_not_found() {
+            Error::NotFound
+        } else if rejection.find::<reject::MethodNotAllowed>().is_some() {
+            Error::MethodNotAllowed
+        } else {
+            tracing::error!("Unhandled rejection {:?}", rejection);
+            Error::Internal
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::NotFound => StatusCode::NOT_FOUND,
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::Forbidden => StatusCode::FORBIDDEN,
+            Error::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Error::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::ForecastUnavailable | Error::Busy => StatusCode::SERVICE_UNAVAILABLE,
+            Error::GatewayTimeout => StatusCode::GATEWAY_TIMEOUT,
+            Error::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn response(&self, content_type: &str, body: String) -> Response<Body> {
+        let mut response = Response::builder()
+            .status(self.status())
+            .header("Content-Type", content_type);
+        if let Error::RateLimited(wait) = self {
+            // rounded up so clients retrying right on time don't get limited again
+            let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            response = response.header("Retry-After", retry_after.to_string());
+        }
+        response.body(body.into()).unwrap()
+    }
+
+    pub(crate) fn json_response(&self) -> Response<Body> {
+        #[derive(serde::Serialize)]
+        struct Reply {
+            error: String,
+        }
+
+        let body = serde_json::to_string(&Reply {
+            error: self.to_string(),
+        })
+        .unwrap();
+        self.response("application/json", body)
+    }
+
+    #[cfg(feature = "web-ui")]
+    pub(crate) fn html_response(&self, base_path: &str) -> Response<Body> {
+        // the request isn't around anymore so there's nothing to negotiate with
+        let page = templates::Error::new(
+            self.status(),
+            self.to_string(),
+            base_path,
+            crate::i18n::Language::default(),
+        );
+        self.response(
+            "text/html; charset=utf-8",
+            askama::Template::render(&page).unwrap(),
+        )
+    }
+
+    /// Without the web ui there are no pages to fit the error into
+    #[cfg(not(feature = "web-ui"))]
+    pub(crate) fn html_response(&self, _base_path: &str) -> Response<Body> {
+        self.response("text/plain; charset=utf-8", self.to_string())
+    }
+}
+
+pub(crate) async fn recover_api(rejection: Rejection) -> Result<Response<Body>, Infallible> {
+    Ok(Error::from_rejection(&rejection).json_response())
+}
+
+pub(crate) async fn recover_html(
+    rejection: Rejection,
+    base_path: String,
+) -> Result<Response<Body>, Infallible> {
+    Ok(Error::from_rejection(&rejection).html_response(&base_path))
+}
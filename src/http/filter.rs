@@ -0,0 +1,211 @@
+use super::SensorEntry;
+use crate::{
+    bluetooth::BluetoothAddress,
+    db::Placement,
+    sensor::{Quantity, SensorState},
+};
+
+/// Query parameters narrowing down the sensors of the state api and the home page
+#[derive(serde::Deserialize, Default, Hash)]
+pub(super) struct SensorQuery {
+    /// comma separated `field:value` conditions like `room:kitchen`
+    filter: Option<String>,
+    /// comma separated comparisons like `temperature>25`
+    metric: Option<String>,
+    /// text the label, room or address contains
+    q: Option<String>,
+}
+
+impl SensorQuery {
+    #[cfg_attr(not(feature = "web-ui"), allow(dead_code))]
+    pub(super) fn search(&self) -> &str {
+        self.q.as_deref().unwrap_or("")
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Field {
+    Room,
+    Label,
+    Placement,
+    Addr,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+#[derive(Debug, PartialEq)]
+enum Condition {
+    Field(Field, String),
+    Metric(Quantity, Comparison, f64),
+    Search(String),
+}
+
+/// Sensors have to meet all conditions to pass, text matches ignore case
+#[derive(Debug, Default)]
+pub(super) struct SensorFilter(Vec<Condition>);
+
+impl SensorFilter {
+    pub(super) fn parse(query: &SensorQuery) -> Result<Self, String> {
+        let mut conditions = Vec::new();
+        for filter in list(&query.filter) {
+            let (field, value) = split_once(filter, ':')
+                .ok_or_else(|| format!("Filter `{}` isn't of the form field:value", filter))?;
+            let field = match field.trim() {
+                "room" => Field::Room,
+                "label" => Field::Label,
+                "placement" => Field::Placement,
+                "addr" => Field::Addr,
+                other => {
+                    return Err(format!(
+                        "Unknown filter field `{}`, must be one of room, label, placement or addr",
+                        other
+                    ))
+                }
+            };
+            conditions.push(Condition::Field(field, value.trim().to_lowercase()));
+        }
+        for metric in list(&query.metric) {
+            conditions.push(parse_metric(metric)?);
+        }
+        if let Some(search) = query.q.as_deref().map(str::trim) {
+            if !search.is_empty() {
+                conditions.push(Condition::Search(search.to_lowercase()));
+            }
+        }
+        Ok(Self(conditions))
+    }
+
+    pub(super) fn apply(
+        &self,
+        sensors: Vec<(BluetoothAddress, SensorEntry)>,
+    ) -> Vec<(BluetoothAddress, SensorEntry)> {
+        sensors
+            .into_iter()
+            .filter(|(addr, entry)| self.0.iter().all(|cond| matches(cond, *addr, entry)))
+            .collect()
+    }
+}
+
+fn list(param: &Option<String>) -> impl Iterator<Item = &str> {
+    param
+        .as_deref()
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+}
+
+fn split_once(s: &str, delimiter: char) -> Option<(&str, &str)> {
+    let i = s.find(delimiter)?;
+    Some((&s[..i], &s[i + delimiter.len_utf8()..]))
+}
+
+fn parse_metric(metric: &str) -> Result<Condition, String> {
+    // two character operators first so `>=` doesn't get taken for `>`
+    const OPERATORS: &[(&str, Comparison)] = &[
+        ("<=", Comparison::LessOrEqual),
+        (">=", Comparison::GreaterOrEqual),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater),
+        ("=", Comparison::Equal),
+    ];
+    for (op, comparison) in OPERATORS {
+        if let Some(i) = metric.find(op) {
+            let quantity = metric[..i].trim().parse()?;
+            let value = metric[i + op.len()..]
+                .trim()
+                .parse()
+                .map_err(|_| format!("Metric `{}` doesn't compare with a number", metric))?;
+            return Ok(Condition::Metric(quantity, *comparison, value));
+        }
+    }
+    Err(format!(
+        "Metric `{}` needs one of the operators <, <=, =, >= or >",
+        metric
+    ))
+}
+
+fn contains(haystack: Option<&str>, needle: &str) -> bool {
+    haystack.map_or(false, |haystack| haystack.to_lowercase().contains(needle))
+}
+
+fn matches(condition: &Condition, addr: BluetoothAddress, entry: &SensorEntry) -> bool {
+    match condition {
+        Condition::Field(Field::Room, room) => {
+            entry.room.as_deref().map(str::to_lowercase).as_ref() == Some(room)
+        }
+        Condition::Field(Field::Label, label) => contains(entry.label.as_deref(), label),
+        Condition::Field(Field::Placement, placement) => {
+            let name = match entry.placement {
+                Placement::Indoor => "indoor",
+                Placement::Outdoor => "outdoor",
+            };
+            name == placement
+        }
+        Condition::Field(Field::Addr, part) => contains(Some(&addr.to_string()), part),
+        Condition::Metric(quantity, comparison, threshold) => match entry.state {
+            SensorState::Connected(values) => {
+                let value = quantity.of(values);
+                match comparison {
+                    Comparison::Less => value < *threshold,
+                    Comparison::LessOrEqual => value <= *threshold,
+                    Comparison::Equal => (value - threshold).abs() < f64::EPSILON,
+                    Comparison::GreaterOrEqual => value >= *threshold,
+                    Comparison::Greater => value > *threshold,
+                }
+            }
+            SensorState::Unconnected => false,
+        },
+        Condition::Search(text) => {
+            contains(entry.label.as_deref(), text)
+                || contains(entry.room.as_deref(), text)
+                || contains(Some(&addr.to_string()), text)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_filters_and_metrics() {
+        let query = SensorQuery {
+            filter: Some(String::from("room:Kitchen, placement:indoor")),
+            metric: Some(String::from("temperature>=25,humidity<40")),
+            q: Some(String::from(" ")),
+        };
+        let filter = SensorFilter::parse(&query).unwrap();
+        assert_eq!(
+            filter.0,
+            vec![
+                Condition::Field(Field::Room, String::from("kitchen")),
+                Condition::Field(Field::Placement, String::from("indoor")),
+                Condition::Metric(Quantity::Temperature, Comparison::GreaterOrEqual, 25.),
+                Condition::Metric(Quantity::Humidity, Comparison::Less, 40.),
+            ]
+        );
+
+        for (filter, metric) in &[
+            (Some("room"), None),
+            (Some("color:red"), None),
+            (None, Some("temperature~25")),
+            (None, Some("wind>3")),
+            (None, Some("temperature>warm")),
+        ] {
+            let query = SensorQuery {
+                filter: filter.map(String::from),
+                metric: metric.map(String::from),
+                q: None,
+            };
+            assert!(SensorFilter::parse(&query).is_err());
+        }
+    }
+}
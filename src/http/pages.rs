@@ -0,0 +1,311 @@
+use super::{
+    describe_visible,
+    error::Error,
+    filter::{SensorFilter, SensorQuery},
+    load_layout, sensor_path, templates,
+};
+use crate::{
+    bluetooth::BluetoothAddress, dashboard, db, forecast, gaps::Availability, i18n::Language,
+    tenant::Access, timestamp::Timestamp,
+};
+use std::collections::BTreeMap;
+use warp::{filters::BoxedFilter, Filter, Reply};
+
+/// Kiosk displays refreshing faster than this would mostly show reloads
+const MIN_KIOSK_INTERVAL: u64 = 5;
+
+#[macro_use]
+macro_rules! static_file {
+    ($content_type:expr, $path:literal) => {{
+        const BIN: &[u8] = include_bytes!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/frontend/dist/",
+            $path
+        ));
+        warp::http::Response::builder()
+            .header("Content-Type", $content_type)
+            .status(warp::http::StatusCode::OK)
+            .body(BIN)
+    }};
+}
+
+/// The rendered pages of the web ui and their static assets, tenants only see their sensors and
+/// the admin pages need the admin token
+pub(super) fn routes(
+    ctx: BoxedFilter<(crate::Context,)>,
+    access: BoxedFilter<(Access,)>,
+    admin: BoxedFilter<()>,
+) -> BoxedFilter<(warp::reply::Response,)> {
+    let language = ctx
+        .clone()
+        .and(warp::header::optional::<String>("accept-language"))
+        .map(|ctx: crate::Context, accept_language: Option<String>| {
+            Language::negotiate(accept_language.as_deref(), ctx.language)
+        });
+
+    let home = warp::get()
+        .and(warp::path::end())
+        .and(ctx.clone())
+        .and(access.clone())
+        .and(warp::query())
+        .and(warp::query())
+        .and(language.clone())
+        .and_then(show_sensors);
+
+    let admin = warp::get()
+        .and(warp::path!("admin"))
+        .and(admin.clone())
+        .and(ctx.clone())
+        .and(language.clone())
+        .and_then(admin);
+
+    let gatt = warp::get()
+        .and(ctx.clone())
+        .and(warp::path!("admin" / "gatt" / BluetoothAddress))
+        .and(admin)
+        .and(language.clone())
+        .and_then(gatt);
+
+    let kiosk = warp::get()
+        .and(warp::path!("kiosk"))
+        .and(ctx.clone())
+        .and(access.clone())
+        .and(warp::query())
+        .and(language.clone())
+        .and_then(kiosk);
+
+    let detail = warp::get()
+        .and(ctx)
+        .and(sensor_path(
+            warp::path!("detail" / BluetoothAddress),
+            access,
+        ))
+        .and(warp::query())
+        .and(language)
+        .and_then(detail);
+
+    let script = warp::get()
+        .and(warp::path!("static" / "script.js"))
+        .map(|| static_file!("application/javascript", "main.bundle.js"));
+
+    let css = warp::get()
+        .and(warp::path!("static" / "style.css"))
+        .map(|| static_file!("text/css", "main.css"));
+
+    home.or(admin)
+        .or(gatt)
+        .or(detail)
+        .or(kiosk)
+        .or(script)
+        .or(css)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+#[derive(serde::Deserialize)]
+struct DashboardQuery {
+    dashboard: Option<String>,
+}
+
+impl DashboardQuery {
+    fn name(&self) -> &str {
+        self.dashboard.as_deref().unwrap_or(dashboard::DEFAULT_NAME)
+    }
+}
+
+async fn show_sensors(
+    ctx: crate::Context,
+    access: Access,
+    query: DashboardQuery,
+    search: SensorQuery,
+    lang: Language,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let filter = SensorFilter::parse(&search).map_err(Error::BadRequest)?;
+    let forecast = ctx
+        .forecast
+        .as_ref()
+        .and_then(|forecaster| forecaster.get());
+    let forecast_hours = match forecast {
+        Some(ref forecast) => forecast
+            .upcoming(Timestamp::now())
+            .take(forecast::HOME_HOURS)
+            .collect::<Vec<_>>(),
+        None => Vec::new(),
+    };
+
+    let layout = load_layout(&ctx, query.name())?;
+    let sensors = describe_visible(&ctx, &access, &*ctx.sensors.read().await)?;
+    let total = sensors.len();
+    let display = layout.arrange(sensors);
+    // only what the layout hides, filtered out sensors are what was asked for
+    let hidden = total - display.len();
+    let display = filter.apply(display);
+
+    let rendered = askama::Template::render(&templates::Home::new(
+        &display,
+        &forecast_hours,
+        query.name(),
+        &layout,
+        hidden,
+        search.search(),
+        &ctx.base_path,
+        lang,
+    ))
+    .unwrap();
+    Ok(warp::reply::html(rendered))
+}
+
+/// Table of all known sensors with everything that can be changed about them
+async fn admin(ctx: crate::Context, lang: Language) -> Result<impl warp::Reply, warp::Rejection> {
+    let now = Timestamp::now();
+    let entries = {
+        let sensors = ctx.sensors.read().await;
+        let txn = ctx.db.read_txn()?;
+        sensors
+            .keys()
+            .map(|addr| {
+                Ok((
+                    *addr,
+                    ctx.db.get_addr(&txn, *addr)?.unwrap_or_default(),
+                    Availability::of(&ctx.db, &txn, *addr, now)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, db::Error>>()?
+    };
+    let pending = ctx
+        .pending
+        .as_ref()
+        .map(|pending| pending.list())
+        .unwrap_or_default();
+
+    let rendered = askama::Template::render(&templates::Admin::new(
+        &entries,
+        &pending,
+        now.as_u32(),
+        ctx.gatt_console,
+        &ctx.base_path,
+        lang,
+    ))
+    .unwrap();
+    Ok(warp::reply::html(rendered))
+}
+
+/// Characteristics of a connected sensor with raw reads and writes, 404 unless the gatt console
+/// is turned on
+async fn gatt(
+    ctx: crate::Context,
+    addr: BluetoothAddress,
+    lang: Language,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !ctx.gatt_console {
+        return Err(Error::NotFound.into());
+    }
+    let rendered =
+        askama::Template::render(&templates::Gatt::new(addr, &ctx.base_path, lang)).unwrap();
+    Ok(warp::reply::html(rendered))
+}
+
+#[derive(serde::Deserialize)]
+struct KioskQuery {
+    /// comma separated addresses, all sensors if unset
+    sensors: Option<String>,
+    /// seconds between two refreshes
+    interval: Option<u64>,
+    /// show a single sensor and switch to the next one on every refresh
+    #[serde(default)]
+    rotate: bool,
+    /// index of the sensor shown when rotating
+    #[serde(default)]
+    page: usize,
+}
+
+/// Full screen view without any javascript for wall mounted displays, refreshed with a meta tag
+async fn kiosk(
+    ctx: crate::Context,
+    access: Access,
+    query: KioskQuery,
+    lang: Language,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let described = describe_visible(&ctx, &access, &*ctx.sensors.read().await)?;
+    let selected = match query.sensors {
+        Some(ref list) => list
+            .split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(BluetoothAddress::parse_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::BadRequest(e.to_string()))?,
+        None => described.iter().map(|(addr, _)| *addr).collect(),
+    };
+
+    let mut described = described.into_iter().collect::<BTreeMap<_, _>>();
+    // unknown sensors are left out so a display doesn't break when one gets forgotten
+    let mut display = selected
+        .iter()
+        .filter_map(|addr| described.remove(addr).map(|entry| (*addr, entry)))
+        .collect::<Vec<_>>();
+
+    let interval = query
+        .interval
+        .unwrap_or_else(|| ctx.kiosk_interval.as_secs())
+        .max(MIN_KIOSK_INTERVAL);
+
+    let next = if query.rotate && !display.is_empty() {
+        let page = query.page % display.len();
+        display = vec![display.swap_remove(page)];
+        // rebuilt from the parsed addresses so nothing from the query ends up in the url as is
+        let sensors = if query.sensors.is_some() {
+            let addrs = selected.iter().map(ToString::to_string).collect::<Vec<_>>();
+            format!("sensors={}&", addrs.join(","))
+        } else {
+            String::new()
+        };
+        Some(format!(
+            "kiosk?{}interval={}&rotate=true&page={}",
+            sensors,
+            interval,
+            page + 1
+        ))
+    } else {
+        None
+    };
+
+    let rendered = askama::Template::render(&templates::Kiosk::new(
+        &display,
+        interval,
+        next,
+        &ctx.base_path,
+        lang,
+    ))
+    .unwrap();
+    Ok(warp::reply::html(rendered))
+}
+
+async fn detail(
+    ctx: crate::Context,
+    sensor: BluetoothAddress,
+    query: DashboardQuery,
+    lang: Language,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    tracing::info!("Detail for {}", sensor);
+    let layout = load_layout(&ctx, query.name())?;
+    let entry = {
+        let txn = ctx.db.read_txn()?;
+        ctx.db.get_addr(&txn, sensor)?.unwrap_or_default()
+    };
+    let battery_age = entry
+        .battery_changed
+        .map(|changed| changed.days_until(Timestamp::now()));
+    let rendered = askama::Template::render(&templates::Detail::new(
+        sensor,
+        layout.chart_window().as_u32(),
+        entry,
+        battery_age,
+        ctx.batteries.latest(sensor),
+        ctx.batteries.prediction(sensor),
+        &ctx.base_path,
+        lang,
+    ))
+    .unwrap();
+    Ok(warp::reply::html(rendered))
+}
@@ -0,0 +1,88 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    num::NonZeroU32,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Forgetting about clients is only worth it once this many are tracked
+const MAX_TRACKED: usize = 1024;
+
+/// Token buckets per client ip, refilled with `limit` tokens per minute
+pub(crate) struct RateLimiter {
+    limit: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(per_minute: NonZeroU32) -> Self {
+        Self {
+            limit: f64::from(per_minute.get()),
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Takes `cost` tokens from the bucket of `ip`, returns how long to wait if there aren't enough
+    pub(crate) fn check(&self, ip: IpAddr, cost: u32) -> Result<(), Duration> {
+        self.check_at(ip, cost, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, cost: u32, now: Instant) -> Result<(), Duration> {
+        let per_sec = self.limit / 60.;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED {
+            // full buckets are the same as no bucket
+            let limit = self.limit;
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_sec < limit
+            });
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.limit,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(self.limit);
+        bucket.updated = now;
+
+        // requests costing more than the whole bucket would never go through otherwise
+        let cost = f64::from(cost).min(self.limit);
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((cost - bucket.tokens) / per_sec))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bucket_refills() {
+        let limiter = RateLimiter::new(NonZeroU32::new(60).unwrap());
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let other = IpAddr::from([127, 0, 0, 2]);
+        let start = Instant::now();
+
+        for _ in 0..60 {
+            assert!(limiter.check_at(ip, 1, start).is_ok());
+        }
+        let wait = limiter.check_at(ip, 1, start).unwrap_err();
+        assert_eq!(wait.as_secs(), 1);
+        assert!(limiter.check_at(other, 1, start).is_ok());
+
+        let later = start + Duration::from_secs(5);
+        assert!(limiter.check_at(ip, 5, later).is_ok());
+        assert!(limiter.check_at(ip, 1, later).is_err());
+    }
+}
@@ -1,4 +1,15 @@
-use crate::{bluetooth::BluetoothAddress, sensor::SensorState};
+use super::SensorEntry;
+use crate::{
+    battery::{Level, Prediction},
+    bluetooth::BluetoothAddress,
+    dashboard::Layout,
+    db::{AddrDbEntry, Icon, Placement},
+    forecast::ForecastHour,
+    gaps::Availability,
+    i18n::Language,
+    sensor::{Quantity, SensorState},
+    state::Pending,
+};
 use askama::Template;
 use derive_more::Constructor;
 
@@ -6,22 +17,71 @@ use derive_more::Constructor;
 #[template(path = "home.html")]
 pub(crate) struct Home<'a> {
     sensors: &'a Vec<(BluetoothAddress, SensorEntry)>,
+    forecast: &'a [&'a ForecastHour],
+    dashboard: &'a str,
+    layout: &'a Layout,
+    /// number of sensors the layout hides
+    hidden: usize,
+    /// text of the search box
+    search: &'a str,
+    base_path: &'a str,
+    lang: Language,
 }
 
-#[derive(Debug)]
-pub(crate) struct SensorEntry {
-    pub(crate) state: SensorState,
-    pub(crate) label: Option<String>,
+#[derive(Template, Constructor)]
+#[template(path = "kiosk.html")]
+pub(crate) struct Kiosk<'a> {
+    sensors: &'a [(BluetoothAddress, SensorEntry)],
+    /// seconds until the page refreshes
+    interval: u64,
+    /// page shown after the refresh when rotating, relative to the base path
+    next: Option<String>,
+    base_path: &'a str,
+    lang: Language,
+}
+
+#[derive(Template, Constructor)]
+#[template(path = "admin.html")]
+pub(crate) struct Admin<'a> {
+    sensors: &'a [(BluetoothAddress, AddrDbEntry, Availability)],
+    /// new sensors waiting for approval
+    pending: &'a [(BluetoothAddress, Pending)],
+    /// unix seconds the page was rendered at
+    now: u32,
+    /// link the gatt debug console of every sensor
+    gatt_console: bool,
+    base_path: &'a str,
+    lang: Language,
+}
+
+#[derive(Constructor, Template)]
+#[template(path = "gatt.html")]
+pub(crate) struct Gatt<'a> {
+    addr: BluetoothAddress,
+    base_path: &'a str,
+    lang: Language,
 }
 
 #[derive(Debug, Constructor, Template)]
 #[template(path = "error.html")]
-pub(crate) struct Error {
+pub(crate) struct Error<'a> {
     code: warp::http::StatusCode,
+    message: String,
+    base_path: &'a str,
+    lang: Language,
 }
 
 #[derive(Debug, Constructor, Template)]
 #[template(path = "detail.html")]
-pub(crate) struct Detail {
+pub(crate) struct Detail<'a> {
     pub(crate) addr: BluetoothAddress,
+    /// seconds of history shown in the chart
+    pub(crate) window: u32,
+    pub(crate) entry: AddrDbEntry,
+    /// days since the batteries were changed last
+    pub(crate) battery_age: Option<i64>,
+    pub(crate) battery: Option<Level>,
+    pub(crate) prediction: Option<Prediction>,
+    pub(crate) base_path: &'a str,
+    pub(crate) lang: Language,
 }
@@ -0,0 +1,158 @@
+use std::str::FromStr;
+
+/// Languages the web ui is translated to
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Language {
+    En,
+    De,
+}
+
+impl Default for Language {
+    fn default() -> Self {
+        Language::En
+    }
+}
+
+impl FromStr for Language {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // only the primary subtag matters, `de-AT` still gets german
+        let primary = s.split('-').next().unwrap_or("").trim();
+        if primary.eq_ignore_ascii_case("en") {
+            Ok(Language::En)
+        } else if primary.eq_ignore_ascii_case("de") {
+            Ok(Language::De)
+        } else {
+            Err(eyre::format_err!("Unsupported language `{}`", s))
+        }
+    }
+}
+
+/// German translations keyed by the english text
+#[cfg_attr(not(feature = "web-ui"), allow(dead_code))]
+const GERMAN: &[(&str, &str)] = &[
+    ("Forecast", "Vorhersage"),
+    ("Temperature", "Temperatur"),
+    ("Humidity", "Luftfeuchtigkeit"),
+    ("Relative humidity", "Relative Luftfeuchtigkeit"),
+    ("Pressure", "Luftdruck"),
+    ("No label", "Kein Name"),
+    ("Not connected", "Nicht verbunden"),
+    ("Humidex", "Gefühlt"),
+    ("Ventilate now", "Jetzt lüften"),
+    ("Indoor", "Innen"),
+    ("Outdoor", "Außen"),
+    ("Pin", "Anheften"),
+    ("Unpin", "Lösen"),
+    ("Hide", "Ausblenden"),
+    ("Forget", "Vergessen"),
+    ("hidden", "ausgeblendet"),
+    ("Show all", "Alle anzeigen"),
+    ("Toggle dark mode", "Dunkles Design umschalten"),
+    ("Sensors", "Sensoren"),
+    ("Address", "Adresse"),
+    ("Label", "Name"),
+    ("Room", "Raum"),
+    ("Icon", "Symbol"),
+    ("Order", "Reihenfolge"),
+    ("None", "Keins"),
+    ("Placement", "Platzierung"),
+    ("Temperature offset", "Temperaturkorrektur"),
+    ("Humidity offset", "Feuchtigkeitskorrektur"),
+    ("Pressure offset", "Luftdruckkorrektur"),
+    ("Log", "Aufzeichnen"),
+    ("Set clock", "Uhr stellen"),
+    ("Measurement interval", "Messintervall"),
+    ("Encrypted", "Verschlüsselt"),
+    ("Characteristics", "Charakteristiken"),
+    ("Service", "Dienst"),
+    ("Flags", "Eigenschaften"),
+    ("Value", "Wert"),
+    ("Read", "Lesen"),
+    ("Write", "Schreiben"),
+    ("Default", "Standard"),
+    ("Availability", "Verfügbarkeit"),
+    ("Day / week / month", "Tag / Woche / Monat"),
+    ("Save", "Speichern"),
+    ("Unusual values", "Ungewöhnliche Werte"),
+    ("Search", "Suchen"),
+    ("Pending sensors", "Wartende Sensoren"),
+    ("Last seen", "Zuletzt gesehen"),
+    ("Approve", "Freigeben"),
+    ("Dismiss", "Verwerfen"),
+    ("Purchased", "Gekauft"),
+    ("Battery type", "Batterietyp"),
+    ("Battery changed", "Batterie gewechselt"),
+    ("days ago", "Tage her"),
+    ("Notes", "Notizen"),
+    ("Battery", "Batterie"),
+    ("empty in", "leer in"),
+    ("days", "Tagen"),
+];
+
+#[cfg_attr(not(feature = "web-ui"), allow(dead_code))]
+impl Language {
+    pub(crate) fn code(self) -> &'static str {
+        match self {
+            Language::En => "en",
+            Language::De => "de",
+        }
+    }
+
+    /// Translation of the english `msg`, gettext style so missing translations show the english
+    /// text instead of breaking the page
+    pub(crate) fn t(self, msg: &'static str) -> &'static str {
+        let table = match self {
+            Language::En => return msg,
+            Language::De => GERMAN,
+        };
+        table
+            .iter()
+            .find(|(english, _)| *english == msg)
+            .map_or(msg, |(_, translated)| translated)
+    }
+
+    /// Most preferred supported language of an Accept-Language header, `fallback` if there's none
+    pub(crate) fn negotiate(accept_language: Option<&str>, fallback: Self) -> Self {
+        let mut best: Option<(f32, Language)> = None;
+        for range in accept_language.unwrap_or("").split(',') {
+            let mut parts = range.split(';');
+            let tag = parts.next().unwrap_or("");
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.);
+            if let Ok(language) = tag.parse::<Language>() {
+                // earlier entries win ties
+                if quality > 0. && best.map_or(true, |(best, _)| quality > best) {
+                    best = Some((quality, language));
+                }
+            }
+        }
+        best.map_or(fallback, |(_, language)| language)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn negotiate_accept_language() {
+        let negotiate = |header| Language::negotiate(Some(header), Language::En);
+        assert_eq!(negotiate("de-DE,de;q=0.9,en;q=0.8"), Language::De);
+        assert_eq!(negotiate("fr-FR,en;q=0.5,de;q=0.7"), Language::De);
+        assert_eq!(negotiate("de;q=0,en"), Language::En);
+        assert_eq!(negotiate("fr"), Language::En);
+        assert_eq!(Language::negotiate(None, Language::De), Language::De);
+    }
+
+    #[test]
+    fn translate() {
+        assert_eq!(Language::De.t("Forget"), "Vergessen");
+        assert_eq!(Language::En.t("Forget"), "Forget");
+        assert_eq!(Language::De.t("Untranslated"), "Untranslated");
+    }
+}
@@ -0,0 +1,193 @@
+use crate::{
+    bluetooth::BluetoothAddress,
+    db::{self, Db, LogBatch},
+    opt::LogFormat,
+    sensor::{Quality, RawSensorValues, SensorValues},
+    timestamp::Timestamp,
+};
+use eyre::Context;
+use std::{collections::BTreeSet, convert::TryFrom};
+
+#[derive(serde::Deserialize)]
+struct Entry {
+    time: Timestamp,
+    values: SensorValues,
+    /// dumps from before quality flags don't have them
+    #[serde(default)]
+    quality: Quality,
+}
+
+/// Parses log entries as written by the dump subcommand, rejecting invalid values and entries
+/// from the future. Everything gets flagged as imported on top of the flags of the dump.
+pub(crate) fn parse_log(
+    input: &str,
+    format: LogFormat,
+) -> Result<Vec<(Timestamp, SensorValues, Quality)>, eyre::Error> {
+    let now = Timestamp::now();
+    let mut ret = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let entry = match format {
+            LogFormat::Json => serde_json::from_str(line).map_err(eyre::Error::from),
+            LogFormat::Csv if i == 0 && line.starts_with("time") => continue,
+            LogFormat::Csv => parse_csv_line(line),
+        }
+        .with_context(|| format!("Invalid entry in line {}", i + 1))?;
+
+        if entry.time > now {
+            return Err(eyre::format_err!(
+                "Entry in line {} is from the future",
+                i + 1
+            ));
+        }
+        ret.push((entry.time, entry.values, entry.quality | Quality::IMPORTED));
+    }
+
+    Ok(ret)
+}
+
+/// What of a parsed log goes into the log of a sensor
+pub(crate) struct Import {
+    pub(crate) batch: LogBatch,
+    /// times that are logged already or appear more than once in the input
+    pub(crate) skipped: Vec<Timestamp>,
+}
+
+/// Batches `log` for `addr`, leaving out entries whose time is logged already unless
+/// `overwrite` is set. Repeated times of the input only get imported once.
+pub(crate) fn prepare(
+    db: &Db,
+    addr: BluetoothAddress,
+    log: Vec<(Timestamp, SensorValues, Quality)>,
+    overwrite: bool,
+) -> Result<Import, db::Error> {
+    let times = log.iter().map(|(time, _, _)| *time);
+    let logged = match (times.clone().min(), times.max()) {
+        (Some(first), Some(last)) if !overwrite => {
+            let end = Timestamp::from(last.as_u32().saturating_add(1));
+            db.log_times(&db.read_txn()?, addr, first..end)?
+                .unwrap_or_default()
+                .into_iter()
+                .collect()
+        }
+        _ => BTreeSet::new(),
+    };
+
+    let mut seen = BTreeSet::new();
+    let mut import = Import {
+        batch: LogBatch::default(),
+        skipped: Vec::new(),
+    };
+    for (time, values, quality) in log {
+        if logged.contains(&time) || !seen.insert(time) {
+            import.skipped.push(time);
+        } else {
+            import.batch.push(addr, time, values, quality);
+        }
+    }
+    Ok(import)
+}
+
+fn parse_csv_line(line: &str) -> Result<Entry, eyre::Error> {
+    let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+    let (time, temperature, humidity, pressure, quality) = match fields[..] {
+        [time, temperature, humidity, pressure] => (time, temperature, humidity, pressure, None),
+        [time, temperature, humidity, pressure, quality] => {
+            (time, temperature, humidity, pressure, Some(quality))
+        }
+        _ => {
+            return Err(eyre::format_err!(
+                "Expected 4 or 5 fields, got {}",
+                fields.len()
+            ))
+        }
+    };
+    let raw = RawSensorValues {
+        temperature: temperature.parse()?,
+        humidity: humidity.parse()?,
+        pressure: pressure.parse()?,
+    };
+    let quality = match quality {
+        Some(bits) => Quality::from_bits(bits.parse()?)
+            .ok_or_else(|| eyre::format_err!("Unknown quality flags {}", bits))?,
+        None => Quality::empty(),
+    };
+    Ok(Entry {
+        time: Timestamp::from(time.parse::<u32>()?),
+        values: SensorValues::try_from(raw)?,
+        quality,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::AddrDbEntry;
+
+    #[test]
+    fn parse_csv_and_json() {
+        let csv =
+            "time,temperature,humidity,pressure\n10,2150,4500,1013250\n20,2200,4400,1013000\n";
+        let json = concat!(
+            r#"{"time":10,"values":{"temperature":2150,"pressure":1013250,"humidity":4500}}"#,
+            "\n",
+            r#"{"time":20,"values":{"temperature":2200,"pressure":1013000,"humidity":4400}}"#,
+        );
+
+        for log in &[
+            parse_log(csv, LogFormat::Csv).unwrap(),
+            parse_log(json, LogFormat::Json).unwrap(),
+        ] {
+            assert!(log
+                .iter()
+                .all(|(_, _, quality)| *quality == Quality::IMPORTED));
+            let log = log
+                .iter()
+                .map(|(time, values, _)| (*time, RawSensorValues::from(*values).humidity))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                log,
+                vec![(Timestamp::from(10), 45_00), (Timestamp::from(20), 44_00)]
+            );
+        }
+    }
+
+    #[test]
+    fn reject_invalid_entries() {
+        assert!(parse_log("10,2150,10001,1013250", LogFormat::Csv).is_err());
+        assert!(parse_log("10,2150,4500", LogFormat::Csv).is_err());
+        assert!(parse_log(r#"{"time":10}"#, LogFormat::Json).is_err());
+        let future = format!("{},2150,4500,1013250", u32::MAX);
+        assert!(parse_log(&future, LogFormat::Csv).is_err());
+    }
+
+    #[test]
+    fn logged_entries_are_only_replaced_when_overwriting() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let addr = BluetoothAddress::from(1);
+        let mut txn = db.write_txn().unwrap();
+        db.put_addr(&mut txn, addr, &AddrDbEntry::default())
+            .unwrap();
+        txn.commit().unwrap();
+        let logged = parse_log("10,2150,4500,1013250", LogFormat::Csv).unwrap();
+        db.write_log(&prepare(&db, addr, logged, false).unwrap().batch)
+            .unwrap();
+
+        let log = "10,2000,4500,1013250\n20,2000,4500,1013250\n20,2100,4500,1013250";
+        let import = prepare(&db, addr, parse_log(log, LogFormat::Csv).unwrap(), false).unwrap();
+        assert_eq!(import.batch.len(), 1);
+        assert_eq!(
+            import.skipped,
+            vec![Timestamp::from(10), Timestamp::from(20)]
+        );
+
+        let import = prepare(&db, addr, parse_log(log, LogFormat::Csv).unwrap(), true).unwrap();
+        assert_eq!(import.batch.len(), 2);
+        assert_eq!(import.skipped, vec![Timestamp::from(20)]);
+    }
+}
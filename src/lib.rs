@@ -0,0 +1,651 @@
+mod activation;
+#[cfg(feature = "alerts")]
+mod alert;
+mod analytics;
+mod anomaly;
+mod bands;
+mod battery;
+#[doc(hidden)]
+pub mod bench;
+mod bluetooth;
+mod chart;
+mod clock;
+mod cmd;
+mod coap;
+mod config;
+mod crash;
+mod dashboard;
+mod db;
+mod dbus;
+mod dummy;
+mod export;
+mod forecast;
+mod gaps;
+#[cfg(feature = "graphql")]
+mod graphql;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod history;
+#[cfg(feature = "mqtt")]
+mod homeassistant;
+mod hook;
+mod http;
+mod i18n;
+mod import;
+mod logging;
+mod metrics;
+mod opt;
+mod presence;
+mod privileges;
+mod pws;
+mod record;
+mod replication;
+mod script;
+mod sensor;
+mod sink;
+mod snapshot;
+mod state;
+mod tasks;
+mod tenant;
+mod timestamp;
+
+use crate::{
+    bluetooth::BluetoothAddress,
+    dummy::{dummy_sensor, Scenario},
+    opt::Opt,
+    record::Recording,
+    timestamp::Timestamp,
+};
+use clap::Clap;
+use config::Config;
+use eyre::Context as _;
+use futures_util::{
+    future,
+    stream::{self, Stream},
+};
+use sensor::SensorState;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{atomic::AtomicU64, Arc},
+};
+use tokio::{
+    sync::{broadcast, mpsc, RwLock},
+    task,
+};
+
+/// Everything the `ble-weatherstation-central` binary does, it's a library only so the benches
+/// and the db stress binary can reach the database
+pub fn main() -> Result<(), eyre::Error> {
+    let args = Opt::parse();
+    let config = Config::load(args.config_overrides, args.config.as_deref())?;
+    if let Some(ref address) = config.dbus_system_bus {
+        dbus::use_system_bus(address);
+    }
+
+    let source = match args.cmd {
+        Some(opt::Command::Dump(dump)) => return cmd::dump::run(&config, dump),
+        Some(opt::Command::BenchDb(bench)) => return cmd::bench_db::run(bench),
+        Some(opt::Command::BenchHttp(bench)) => return cmd::bench_http::run(&config, bench),
+        Some(opt::Command::Import(import)) => return cmd::import::run(&config, import),
+        Some(opt::Command::Fsck(fsck)) => return cmd::fsck::run(&config, fsck),
+        Some(opt::Command::Export(export)) => return cmd::export::run(&config, export),
+        Some(opt::Command::Restore(restore)) => return cmd::restore::run(&config, restore),
+        Some(opt::Command::Simulate(simulate)) => {
+            Source::Scenario(Scenario::from_file(&simulate.scenario)?)
+        }
+        Some(opt::Command::Replay(replay)) => {
+            if replay.speed.is_nan() || replay.speed <= 0. {
+                return Err(eyre::format_err!("Replay speed must be greater than 0"));
+            }
+            Source::Replay(Recording::from_file(&replay.recording)?, replay.speed)
+        }
+        None => Source::Bluetooth,
+    };
+
+    let logs = crash::RecentLogs::default();
+    let log_filter =
+        logging::init(&config.log_filter, logs.clone()).context("Invalid log_filter")?;
+    let reporter =
+        crash::CrashReporter::new(config.crash_dir.clone(), config.config_file.clone(), logs);
+    reporter.install();
+    let listeners = activation::listeners().context("Invalid socket activation")?;
+
+    let result = match args.executor {
+        opt::Rt::MultiThread => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            let rt = if let Some(n) = args.workers {
+                builder.worker_threads(n.get())
+            } else {
+                &mut builder
+            }
+            .enable_all()
+            .build()?;
+
+            rt.block_on(run(config, source, log_filter, reporter.clone(), listeners))
+        }
+        opt::Rt::CurrentThread => {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            rt.block_on(run(config, source, log_filter, reporter.clone(), listeners))
+        }
+    };
+    // panics already got their report from the hook
+    if let Err(ref e) = result {
+        reporter.report(&format!("{:?}", e));
+    }
+    result
+}
+
+/// Resolves once the process is asked to stop
+#[cfg(unix)]
+fn shutdown_signal() -> impl Future<Output = ()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut term = signal(SignalKind::terminate()).unwrap();
+    let mut int = signal(SignalKind::interrupt()).unwrap();
+    async move {
+        tokio::select! {
+            _ = term.recv() => (),
+            _ = int.recv() => ()
+        }
+    }
+}
+
+/// Resolves once the process is asked to stop, there's only ctrl-c outside of unix
+#[cfg(not(unix))]
+fn shutdown_signal() -> impl Future<Output = ()> {
+    async {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Connection to the mqtt server of the config, which can't exist without the mqtt feature
+#[cfg(feature = "mqtt")]
+pub(crate) type MqttConnection = tokio_mqtt::Connection;
+#[cfg(not(feature = "mqtt"))]
+pub(crate) type MqttConnection = std::convert::Infallible;
+
+type UpdateSource = dyn Stream<Item = BTreeMap<BluetoothAddress, SensorState>> + Unpin + Send;
+
+/// Where sensor values come from, besides the dummy sensors of demo mode
+enum Source {
+    Bluetooth,
+    Scenario(Scenario),
+    Replay(Recording, f64),
+}
+
+/// Whether to run without bluetooth, demo mode also does so when there's no bluetooth stack
+/// so the ui can be worked on in a container or on a laptop
+fn without_bluetooth(config: &Config) -> bool {
+    match config.bluetooth_backend {
+        bluetooth::Backend::Off => true,
+        _ if config.demo.is_none() => false,
+        backend => match backend.probe() {
+            Ok(()) => false,
+            Err(e) => {
+                tracing::warn!(
+                    "Running only the dummy sensors, bluetooth is unusable: {}",
+                    e
+                );
+                true
+            }
+        },
+    }
+}
+
+async fn run(
+    config: Config,
+    source: Source,
+    log_filter: logging::LogFilter,
+    reporter: crash::CrashReporter,
+    listeners: Vec<std::net::TcpListener>,
+) -> Result<(), eyre::Error> {
+    let (ctx, commands) = Context::create(&config, log_filter)?;
+    reporter.attach(ctx.clone());
+    let listen_ips = config.listen_ips();
+
+    let (stopped_tx, stopped_rx) = flume::bounded(1);
+    let mut sources: Vec<Box<UpdateSource>> = Vec::new();
+
+    let (bluetooth_thread, bluetooth_failed, bluetooth_connected) = match source {
+        Source::Scenario(scenario) => {
+            tracing::info!("Running scenario instead of bluetooth");
+            ctx.adapter.set(bluetooth::AdapterState::Off);
+            let (scenario_task, scenario_stream) = dummy::scenario_source(scenario);
+            task::spawn(scenario_task);
+            sources.push(Box::new(scenario_stream));
+            (None, None, None)
+        }
+        Source::Replay(recording, speed) => {
+            tracing::info!(
+                "Replaying recording at {}x speed instead of bluetooth",
+                speed
+            );
+            ctx.adapter.set(bluetooth::AdapterState::Off);
+            let (replay_task, replay_stream) = record::replay_source(recording, speed);
+            task::spawn(replay_task);
+            sources.push(Box::new(replay_stream));
+            (None, None, None)
+        }
+        Source::Bluetooth if config.replicate_from.is_some() => {
+            let primary = config.replicate_from.clone().unwrap();
+            tracing::info!("Replicating {} instead of using bluetooth", primary);
+            ctx.adapter.set(bluetooth::AdapterState::Off);
+            let token = config.replication_token.clone().unwrap_or_default();
+            task::spawn(replication::follow(ctx.clone(), primary, token));
+            // keeps the update task around for the settings and states of replicated sensors
+            sources.push(Box::new(stream::pending::<
+                BTreeMap<BluetoothAddress, SensorState>,
+            >()));
+            (None, None, None)
+        }
+        Source::Bluetooth if without_bluetooth(&config) => {
+            tracing::info!("Bluetooth is turned off");
+            ctx.adapter.set(bluetooth::AdapterState::Off);
+            (None, None, None)
+        }
+        Source::Bluetooth => {
+            config
+                .bluetooth_backend
+                .probe()
+                .context("Bluetooth is unusable, --no-bluetooth true runs without it")?;
+            let (history_tx, history_rx) = flume::unbounded();
+            task::spawn(history::backfill(ctx.clone(), history_rx));
+            let (bluetooth_thread, bluetooth_failed, bluetooth_connected, bluetooth_update) =
+                bluetooth::bluetooth_thread(
+                    config.bluetooth_backend,
+                    stopped_rx,
+                    bluetooth::BackendContext {
+                        metrics: ctx.metrics.clone(),
+                        clocks: ctx.clocks.clone(),
+                        batteries: ctx.batteries.clone(),
+                        history: history_tx,
+                        settings: ctx.stations.clone(),
+                        adapter: ctx.adapter.clone(),
+                        presence: ctx.presence.clone(),
+                        profiles: ctx.profiles.clone(),
+                        steering: Arc::new(bluetooth::Steering::new(config.steering)),
+                        runtime: tokio::runtime::Handle::current(),
+                        timeouts: config.bluetooth_timeouts,
+                        low_memory: config.low_memory,
+                    },
+                );
+            match config.record {
+                Some(ref path) => {
+                    tracing::info!("Recording bluetooth updates to {}", path.display());
+                    sources.push(Box::new(record::record(
+                        bluetooth_update.into_stream(),
+                        path,
+                    )?));
+                }
+                None => sources.push(Box::new(bluetooth_update.into_stream())),
+            }
+            (
+                Some(bluetooth_thread),
+                Some(bluetooth_failed),
+                Some(bluetooth_connected),
+            )
+        }
+    };
+    let bluetooth_failed = async move {
+        match bluetooth_failed {
+            Some(failed) => {
+                let _ = failed.await;
+            }
+            None => future::pending().await,
+        }
+    };
+
+    if let Some(ref demo) = config.demo {
+        tracing::info!("Simulating {} dummy sensors", demo.sensors);
+        for i in 0..demo.sensors.get() {
+            let (dummy_task, dummy_stream) =
+                dummy_sensor(BluetoothAddress::from(u64::from(i)), demo, i);
+            task::spawn(dummy_task);
+            sources.push(Box::new(dummy_stream));
+        }
+    }
+
+    let update_task = task::spawn(tasks::update(
+        ctx.clone(),
+        stream::select_all(sources),
+        commands,
+    ));
+    task::spawn(tasks::seal_blocks(ctx.clone()));
+    task::spawn(battery::run(ctx.clone()));
+    task::spawn(forecast::run(ctx.clone()));
+
+    #[cfg(feature = "mqtt")]
+    let mqtt = match config.mqtt_options.clone() {
+        Some(options) => {
+            let (cxn, _) =
+                tokio_mqtt::Connection::connect(options, config.mqtt_session.clone()).await?;
+            Some(cxn)
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "mqtt"))]
+    let mqtt: Option<MqttConnection> = None;
+
+    let sinks = sink::build(&config.sinks, mqtt.clone())?;
+    if !sinks.is_empty() {
+        task::spawn(sink::run(ctx.clone(), sinks));
+    }
+
+    #[cfg(feature = "alerts")]
+    if !config.rules.is_empty() {
+        tracing::info!("Checking {} alert rules", config.rules.len());
+        task::spawn(alert::run(ctx.clone(), config.rules, mqtt.clone()));
+    }
+
+    match config.adapter {
+        #[cfg(feature = "alerts")]
+        Some(adapter) => {
+            task::spawn(bluetooth::watch_adapter(ctx.clone(), adapter, mqtt.clone()));
+        }
+        #[cfg(not(feature = "alerts"))]
+        Some(_) => {
+            return Err(eyre::format_err!(
+                "Adapter alerts are configured but this build doesn't include the alerts feature"
+            ));
+        }
+        None => (),
+    }
+
+    if let Some(presence) = config.presence {
+        tracing::info!("Looking out for beacons");
+        task::spawn(presence::run(ctx.clone(), presence, mqtt.clone()));
+    }
+
+    #[cfg(feature = "mqtt")]
+    if let (Some(topic), Some(cxn)) = (config.mqtt_snapshot_topic.clone(), &mqtt) {
+        tracing::info!("Publishing state snapshots to {}", topic);
+        task::spawn(sink::publish_snapshots(ctx.clone(), cxn.clone(), topic));
+    }
+
+    #[cfg(feature = "mqtt")]
+    if let (Some(homeassistant), Some(cxn)) = (config.homeassistant, &mqtt) {
+        tracing::info!("Announcing sensors to Home Assistant");
+        task::spawn(homeassistant::run(ctx.clone(), homeassistant, cxn.clone()));
+    }
+
+    if let Some(snapshot) = config.snapshot {
+        snapshot::start(ctx.clone(), snapshot, mqtt.clone())?;
+    }
+
+    if let Some(ref script) = config.script {
+        script::start(ctx.clone(), script, mqtt).await?;
+        tracing::info!("Running script on sensor updates");
+    }
+
+    if let Some(hook) = config.hook {
+        task::spawn(hook::run(ctx.clone(), hook));
+    }
+
+    if let Some(ref pws) = config.pws {
+        task::spawn(pws::upload(ctx.clone(), pws.clone()));
+    }
+
+    let signal = shutdown_signal();
+    let shutdown = async move {
+        tokio::select! {
+            // TODO: unify crash error cases
+            Err(e) = update_task => {
+                tracing::error!("Update task failed: {}", e);
+            }
+            _ = bluetooth_failed => {
+            }
+            _ = signal => {
+                drop(stopped_tx);
+            }
+        }
+    };
+
+    match config.grpc_port {
+        #[cfg(feature = "grpc")]
+        Some(port) => {
+            for ip in &listen_ips {
+                let addr = SocketAddr::from((*ip, port));
+                tracing::info!("Starting grpc server on {}", addr);
+                task::spawn(grpc::serve(ctx.clone(), addr));
+            }
+        }
+        #[cfg(not(feature = "grpc"))]
+        Some(_) => {
+            return Err(eyre::format_err!(
+                "grpc_port is set but this build doesn't include the grpc feature"
+            ));
+        }
+        None => (),
+    }
+
+    if let Some(port) = config.coap_port {
+        for ip in &listen_ips {
+            let addr = SocketAddr::from((*ip, port));
+            let socket = tokio::net::UdpSocket::bind(addr)
+                .await
+                .with_context(|| format!("Binding coap server to {}", addr))?;
+            tracing::info!("Started coap server on {}", addr);
+            task::spawn(coap::serve(ctx.clone(), socket));
+        }
+    }
+
+    if let Some(bus) = config.dbus {
+        dbus::serve(ctx.clone(), bus)
+            .await
+            .context("Starting dbus service")?;
+        tracing::info!("Offering dbus service on the {:?} bus", bus);
+    }
+
+    // systemd already bound the sockets, binding listen as well would clash with them
+    let listen = if listeners.is_empty() {
+        &config.listen[..]
+    } else {
+        tracing::info!("Serving on {} sockets passed by systemd", listeners.len());
+        &[]
+    };
+    let listeners = listeners
+        .into_iter()
+        .map(tokio::net::TcpListener::from_std)
+        .collect::<Result<Vec<_>, _>>()?;
+    let (addrs, svr) = http::serve(ctx, listen, listeners, shutdown);
+    for addr in addrs {
+        tracing::info!("Started server on {}", addr);
+    }
+
+    // bus connections opened as root keep working afterwards, so bluetooth gets to open them first
+    let drop_privileges = async {
+        if let Some(connected) = bluetooth_connected {
+            let _ = connected.await;
+        }
+        match config.run_as_user {
+            Some(ref user) => {
+                privileges::drop_to(user).with_context(|| format!("Could not switch to {}", user))
+            }
+            None => Ok(()),
+        }
+    };
+    tokio::pin!(svr);
+    tokio::select! {
+        dropped = drop_privileges => {
+            dropped?;
+            (&mut svr).await;
+        }
+        _ = &mut svr => {}
+    }
+
+    if let Some(bluetooth_thread) = bluetooth_thread {
+        bluetooth_thread.join().expect("Bluetooth thread crashed")?;
+    }
+
+    Ok(())
+}
+
+#[derive(derive_more::Deref, Clone)]
+pub(crate) struct Context(Arc<ContextInner>);
+
+impl Context {
+    /// Also returns the receiving end of [`Context::state`], to be handled by [`tasks::update`]
+    pub fn create(
+        config: &Config,
+        log_filter: logging::LogFilter,
+    ) -> Result<(Self, mpsc::Receiver<state::Command>), eyre::Error> {
+        let db = db::Db::open_with(&config.db_path, config.db_key.as_ref(), config.max_dbs)
+            .with_context(|| format!("Opening database in {}", config.db_path.display()))?;
+
+        let restarts = db.count_start()? - 1;
+        let metrics = metrics::Metrics::default();
+        metrics.start_time.set(u64::from(Timestamp::now().as_u32()));
+        metrics.restarts.set(u64::from(restarts));
+
+        let clocks = clock::DeviceClocks::new(config.clock_sync_interval);
+        let stations = bluetooth::StationSettings::default();
+        stations.set_connection(config.connection);
+        let mut sensors = BTreeMap::new();
+        {
+            let txn = db.read_txn()?;
+
+            for addr in db.known_addrs(&txn)? {
+                let addr = addr?;
+                sensors.insert(addr, sensor::SensorState::Unconnected);
+                if let Some(entry) = db.get_addr(&txn, addr)? {
+                    clocks.set_sync(addr, entry.sync_clock);
+                    stations.set_interval(addr, entry.measurement_interval);
+                    stations.set_encryption(addr, entry.require_encryption);
+                }
+            }
+        }
+
+        // lagging subscribers catch up from the sensor map, so small boards keep fewer updates
+        let capacity = if config.low_memory { 2 } else { 16 };
+        let (state, commands) = state::StateManager::channel(capacity);
+
+        let ctx = Self(Arc::new(ContextInner {
+            db,
+            max_log_entries: config.max_log_entries,
+            kiosk_interval: config.kiosk_interval,
+            base_path: config.base_path.clone(),
+            language: config.language,
+            rate_limit: config.rate_limit,
+            max_concurrent_requests: config.max_concurrent_requests,
+            max_body_size: config.max_body_size,
+            gatt_console: config.gatt_console,
+            sensors: RwLock::new(sensors),
+            boot: rand::random(),
+            generation: AtomicU64::new(0),
+            updates: broadcast::channel(capacity).0,
+            replication: broadcast::channel(capacity).0,
+            replication_token: config.replication_token.clone(),
+            replica: config.replicate_from.is_some(),
+            tenants: tenant::Tenants::new(config.tenants.clone(), config.admin_token.clone())?,
+            pending: if config.approve_new_sensors {
+                Some(state::PendingSensors::default())
+            } else {
+                None
+            },
+            #[cfg(feature = "alerts")]
+            alerts: alert::AlertStatus::default(),
+            metrics: Arc::new(metrics),
+            started: std::time::Instant::now(),
+            log_filter,
+            restarts,
+            clocks: Arc::new(clocks),
+            batteries: Arc::default(),
+            stations: Arc::new(stations),
+            adapter: Arc::new(bluetooth::AdapterStatus::new(
+                match config.bluetooth_backend {
+                    bluetooth::Backend::Off => bluetooth::AdapterState::Off,
+                    _ => bluetooth::AdapterState::Starting,
+                },
+            )),
+            presence: config
+                .presence
+                .as_ref()
+                .map(|presence| Arc::new(presence::Presence::new(presence))),
+            profiles: Arc::new(config.profiles.clone()),
+            state,
+            forecast: config.forecast.clone().map(forecast::Forecaster::new),
+            anomalies: config.anomaly.clone().map(anomaly::Detector::new),
+            bands: bands::Profiles::default(),
+            queries: http::QueryCache::default(),
+            script: config.script.as_ref().map(|_| script::Hooks::default()),
+        }));
+
+        Ok((ctx, commands))
+    }
+}
+
+pub(crate) struct ContextInner {
+    /// only to be written to by the update task, go through `state` for changes
+    pub(crate) sensors: RwLock<BTreeMap<BluetoothAddress, sensor::SensorState>>,
+    /// random per process so tags of the state from before a restart never match
+    pub(crate) boot: u64,
+    /// bumped with `sensors` locked for writing on every change of it or the addr db, and after
+    /// log writes since the trends of the state come from the log
+    pub(crate) generation: AtomicU64,
+    /// changes of the calibrated sensor states as they come in, for clients that want them pushed
+    pub(crate) updates: broadcast::Sender<Vec<state::SensorEvent>>,
+    pub(crate) db: db::Db,
+    /// batches written to the log and changed settings, for the replicas following this central
+    pub(crate) replication: broadcast::Sender<replication::Change>,
+    /// states come from the primary, which logs them itself
+    pub(crate) replica: bool,
+    /// replicas have to bear this, no replication without it
+    pub(crate) replication_token: Option<String>,
+    /// which sensors the api shows to which token
+    pub(crate) tenants: tenant::Tenants,
+    /// new sensors waiting for approval, none if they're memorized right away
+    pub(crate) pending: Option<state::PendingSensors>,
+    /// firing state of the alert rules
+    #[cfg(feature = "alerts")]
+    pub(crate) alerts: alert::AlertStatus,
+    /// log replies with more entries get thinned out
+    pub(crate) max_log_entries: Option<usize>,
+    /// default refresh interval of the kiosk view
+    #[cfg_attr(not(feature = "web-ui"), allow(dead_code))]
+    pub(crate) kiosk_interval: std::time::Duration,
+    /// prefix of every route and link, empty or like `/weather`
+    pub(crate) base_path: String,
+    /// language of the web ui for browsers that don't ask for a supported one
+    #[cfg_attr(not(feature = "web-ui"), allow(dead_code))]
+    pub(crate) language: i18n::Language,
+    /// api requests per minute and client
+    pub(crate) rate_limit: Option<std::num::NonZeroU32>,
+    /// requests handled at once
+    pub(crate) max_concurrent_requests: Option<std::num::NonZeroUsize>,
+    /// largest request body in bytes
+    pub(crate) max_body_size: u64,
+    /// raw gatt reads and writes over http are allowed
+    pub(crate) gatt_console: bool,
+    pub(crate) metrics: Arc<metrics::Metrics>,
+    /// when the process started, monotonic for the uptime
+    pub(crate) started: std::time::Instant,
+    /// starts of the central before this one
+    pub(crate) restarts: u32,
+    pub(crate) log_filter: logging::LogFilter,
+    /// measurement times reported by the stations, filled by the bluetooth thread, and when
+    /// their clocks were set
+    pub(crate) clocks: Arc<clock::DeviceClocks>,
+    /// battery levels filled by the bluetooth thread and how long they last
+    pub(crate) batteries: Arc<battery::Batteries>,
+    /// settings the bluetooth thread writes to the stations
+    pub(crate) stations: Arc<bluetooth::StationSettings>,
+    /// what the bluetooth thread last saw of the adapter
+    pub(crate) adapter: Arc<bluetooth::AdapterStatus>,
+    /// beacons filled by the bluetooth thread, set if there's a `[presence]` table
+    pub(crate) presence: Option<Arc<presence::Presence>>,
+    /// what the bluetooth thread recognizes devices by
+    pub(crate) profiles: Arc<bluetooth::Profiles>,
+    pub(crate) state: state::StateManager,
+    pub(crate) forecast: Option<forecast::Forecaster>,
+    /// fed once a minute by the update task
+    pub(crate) anomalies: Option<anomaly::Detector>,
+    /// typical values by time of day, computed on demand
+    pub(crate) bands: bands::Profiles,
+    /// replies of expensive api queries, invalidated on every log write
+    pub(crate) queries: http::QueryCache,
+    /// set if a script runs on sensor updates
+    pub(crate) script: Option<script::Hooks>,
+}
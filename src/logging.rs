@@ -0,0 +1,50 @@
+use crate::crash::RecentLogs;
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::{
+    fmt::{
+        format::{DefaultFields, Format},
+        Formatter,
+    },
+    reload, EnvFilter,
+};
+
+/// Directives of the log filter when the config doesn't have any
+pub(crate) const DEFAULT_DIRECTIVES: &str = "info";
+
+/// Handle to the filter of the log, changing it takes effect right away so misbehaving stations
+/// can be looked at without restarting and losing their state
+#[derive(Clone)]
+pub(crate) struct LogFilter {
+    handle: reload::Handle<EnvFilter, Formatter<DefaultFields, Format, RecentLogs>>,
+    directives: Arc<Mutex<String>>,
+}
+
+impl LogFilter {
+    /// Directives like `info,ble_weatherstation_central::bluetooth=debug` as last set
+    pub(crate) fn directives(&self) -> String {
+        self.directives.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set(&self, directives: &str) -> Result<(), eyre::Error> {
+        let filter = EnvFilter::try_new(directives)?;
+        let mut current = self.directives.lock().unwrap();
+        self.handle.reload(filter)?;
+        tracing::info!("Log filter changed from {} to {}", current, directives);
+        *current = directives.to_owned();
+        Ok(())
+    }
+}
+
+/// Sets up logging to stdout filtered by `directives`, keeping the latest lines in `logs`
+pub(crate) fn init(directives: &str, logs: RecentLogs) -> Result<LogFilter, eyre::Error> {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_new(directives)?)
+        .with_writer(logs)
+        .with_filter_reloading();
+    let handle = builder.reload_handle();
+    builder.init();
+    Ok(LogFilter {
+        handle,
+        directives: Arc::new(Mutex::new(directives.to_owned())),
+    })
+}
@@ -0,0 +1,290 @@
+#[cfg(feature = "metrics")]
+use std::fmt::{self, Write};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// Upper bounds of the histogram buckets in seconds
+const BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 20., 30.];
+
+#[derive(Default)]
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "metrics")]
+    fn render(&self, out: &mut String, name: &str, help: &str) -> fmt::Result {
+        writeln!(out, "# HELP {} {}", name, help)?;
+        writeln!(out, "# TYPE {} histogram", name)?;
+        self.render_samples(out, name, "")
+    }
+
+    #[cfg(feature = "metrics")]
+    /// Renders the samples without the header, `labels` like `route="home"` get added to each
+    fn render_samples(&self, out: &mut String, name: &str, labels: &str) -> fmt::Result {
+        let (prefix, set) = if labels.is_empty() {
+            (String::new(), String::new())
+        } else {
+            (format!("{},", labels), format!("{{{}}}", labels))
+        };
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name,
+                prefix,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            )?;
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, prefix, count)?;
+        writeln!(
+            out,
+            "{}_sum{} {}",
+            name,
+            set,
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.
+        )?;
+        writeln!(out, "{}_count{} {}", name, set, count)
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn render_counter(out: &mut String, name: &str, help: &str, value: u64) -> fmt::Result {
+    writeln!(out, "# HELP {} {}", name, help)?;
+    writeln!(out, "# TYPE {} counter", name)?;
+    writeln!(out, "{} {}", name, value)
+}
+
+#[cfg(feature = "metrics")]
+fn render_gauge(out: &mut String, name: &str, help: &str, value: u64) -> fmt::Result {
+    writeln!(out, "# HELP {} {}", name, help)?;
+    writeln!(out, "# TYPE {} gauge", name)?;
+    writeln!(out, "{} {}", name, value)
+}
+
+#[derive(Default)]
+pub(crate) struct BluetoothMetrics {
+    pub(crate) poll: Histogram,
+    pub(crate) get_managed_objects: Histogram,
+    pub(crate) device_read: Histogram,
+    pub(crate) connect_failures: Counter,
+    pub(crate) connect_timeouts: Counter,
+    pub(crate) read_timeouts: Counter,
+    pub(crate) connected_devices: Gauge,
+    pub(crate) backend_restarts: Counter,
+}
+
+#[derive(Default)]
+struct RouteMetrics {
+    /// requests by status code
+    requests: BTreeMap<u16, u64>,
+    latency: Histogram,
+}
+
+/// Requests and latencies per route of the http server
+#[derive(Default)]
+pub(crate) struct HttpMetrics(Mutex<BTreeMap<&'static str, RouteMetrics>>);
+
+impl HttpMetrics {
+    pub fn observe(&self, route: &'static str, status: u16, latency: Duration) {
+        let mut routes = self.0.lock().unwrap();
+        let route = routes.entry(route).or_default();
+        *route.requests.entry(status).or_default() += 1;
+        route.latency.observe(latency);
+    }
+
+    #[cfg(feature = "metrics")]
+    fn render(&self, out: &mut String) -> fmt::Result {
+        let routes = self.0.lock().unwrap();
+        writeln!(out, "# HELP http_requests_total Handled http requests")?;
+        writeln!(out, "# TYPE http_requests_total counter")?;
+        for (name, route) in &*routes {
+            for (status, count) in &route.requests {
+                writeln!(
+                    out,
+                    "http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                    name, status, count
+                )?;
+            }
+        }
+
+        let name = "http_request_duration_seconds";
+        writeln!(out, "# HELP {} Time until the response was ready", name)?;
+        writeln!(out, "# TYPE {} histogram", name)?;
+        for (route_name, route) in &*routes {
+            route
+                .latency
+                .render_samples(out, name, &format!("route=\"{}\"", route_name))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) bluetooth: BluetoothMetrics,
+    pub(crate) http: HttpMetrics,
+    /// unix time the process started at
+    pub(crate) start_time: Gauge,
+    /// starts before this one, counted in the database
+    pub(crate) restarts: Gauge,
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    /// Renders all metrics in the prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.render_into(&mut out).unwrap();
+        out
+    }
+
+    fn render_into(&self, out: &mut String) -> fmt::Result {
+        let bt = &self.bluetooth;
+        bt.poll.render(
+            out,
+            "bluetooth_poll_duration_seconds",
+            "Duration of a whole bluetooth poll",
+        )?;
+        bt.get_managed_objects.render(
+            out,
+            "bluetooth_get_managed_objects_duration_seconds",
+            "Duration of fetching the BlueZ object tree",
+        )?;
+        bt.device_read.render(
+            out,
+            "bluetooth_device_read_duration_seconds",
+            "Duration of reading all values of a single device",
+        )?;
+        render_counter(
+            out,
+            "bluetooth_connect_failures_total",
+            "Failed attempts to connect to a weatherstation",
+            bt.connect_failures.get(),
+        )?;
+        render_counter(
+            out,
+            "bluetooth_connect_timeouts_total",
+            "Attempts to connect to a weatherstation that timed out",
+            bt.connect_timeouts.get(),
+        )?;
+        render_counter(
+            out,
+            "bluetooth_read_timeouts_total",
+            "Reads of weatherstation values that timed out",
+            bt.read_timeouts.get(),
+        )?;
+        render_gauge(
+            out,
+            "bluetooth_connected_devices",
+            "Currently connected weatherstations",
+            bt.connected_devices.get(),
+        )?;
+        render_counter(
+            out,
+            "bluetooth_backend_restarts_total",
+            "Bluetooth backends opened again after failing",
+            bt.backend_restarts.get(),
+        )?;
+        render_gauge(
+            out,
+            "process_start_time_seconds",
+            "Start time of the process since the unix epoch in seconds",
+            self.start_time.get(),
+        )?;
+        render_counter(
+            out,
+            "central_restarts_total",
+            "Starts of the central before the running one",
+            self.restarts.get(),
+        )?;
+        self.http.render(out)
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(300));
+        histogram.observe(Duration::from_secs(25));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "test", "Test").unwrap();
+        assert!(out.contains("test_bucket{le=\"0.25\"} 0\n"));
+        assert!(out.contains("test_bucket{le=\"0.5\"} 1\n"));
+        assert!(out.contains("test_bucket{le=\"30\"} 2\n"));
+        assert!(out.contains("test_bucket{le=\"+Inf\"} 2\n"));
+        assert!(out.contains("test_count 2\n"));
+    }
+
+    #[test]
+    fn http_metrics_per_route() {
+        let http = HttpMetrics::default();
+        http.observe("api_log", 200, Duration::from_millis(70));
+        http.observe("api_log", 200, Duration::from_millis(20));
+        http.observe("home", 500, Duration::from_millis(10));
+
+        let mut out = String::new();
+        http.render(&mut out).unwrap();
+        assert!(out.contains("http_requests_total{route=\"api_log\",status=\"200\"} 2\n"));
+        assert!(out.contains("http_requests_total{route=\"home\",status=\"500\"} 1\n"));
+        assert!(
+            out.contains("http_request_duration_seconds_bucket{route=\"api_log\",le=\"0.05\"} 1\n")
+        );
+        assert!(out.contains("http_request_duration_seconds_count{route=\"home\"} 1\n"));
+    }
+}
@@ -1,4 +1,6 @@
+use crate::{bluetooth::BluetoothAddress, config::ConfigSource};
 use clap::Clap;
+use std::path::PathBuf;
 
 /// Central server for a number of ble-weatherstations
 #[derive(Clap)]
@@ -9,6 +11,140 @@ pub(crate) struct Opt {
     /// kind of executor, either `current_thread` or `multi_thread`
     #[clap(short, long, default_value = "multi_thread")]
     pub executor: Rt,
+    /// toml config file, defaults to config.toml in the project config dir
+    #[clap(short, long)]
+    pub config: Option<PathBuf>,
+    #[clap(flatten)]
+    pub config_overrides: ConfigSource,
+    #[clap(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+#[derive(Clap)]
+pub(crate) enum Command {
+    /// print the contents of the database without starting the server
+    Dump(Dump),
+    /// run the server with sensors driven by a scenario file instead of bluetooth
+    Simulate(Simulate),
+    /// run the server with sensors replayed from a recording instead of bluetooth
+    Replay(Replay),
+    /// measure log write throughput and range query latency on a scratch database
+    BenchDb(BenchDb),
+    /// load a running server with the requests of many dashboards open at once
+    BenchHttp(BenchHttp),
+    /// add log entries of a sensor from a file in the format written by dump
+    Import(Import),
+    /// check the database for corrupted entries
+    Fsck(Fsck),
+    /// write all sensors and their logs in the versioned export format
+    Export(Export),
+    /// add the sensors and logs of a file in the export format
+    Restore(Restore),
+}
+
+#[derive(Clap)]
+pub(crate) struct Fsck {
+    /// delete broken log entries and reset broken sensor settings
+    #[clap(long)]
+    pub repair: bool,
+}
+
+#[derive(Clap)]
+pub(crate) struct Export {
+    /// file to write to, stdout if unset
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+pub(crate) struct Restore {
+    /// file written by export
+    pub file: PathBuf,
+}
+
+#[derive(Clap)]
+pub(crate) struct Import {
+    /// sensor the entries belong to, gets memorized if unknown
+    pub sensor: BluetoothAddress,
+    /// file with the log entries
+    pub file: PathBuf,
+    /// format of the file, either `json` or `csv`
+    #[clap(short, long, default_value = "json")]
+    pub format: LogFormat,
+    /// replace entries that are logged already instead of skipping them
+    #[clap(long)]
+    pub overwrite: bool,
+}
+
+#[derive(Clap)]
+pub(crate) struct BenchDb {
+    /// number of simulated sensors
+    #[clap(long, default_value = "10")]
+    pub sensors: u32,
+    /// samples written per sensor, one per simulated minute
+    #[clap(long, default_value = "100000")]
+    pub samples: u32,
+    /// number of one day range queries per sensor
+    #[clap(long, default_value = "100")]
+    pub queries: u32,
+    /// directory of the scratch database, defaults to a temporary directory
+    #[clap(long)]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+pub(crate) struct BenchHttp {
+    /// url of the server including its base path, defaults to the first listen address
+    #[clap(long)]
+    pub url: Option<url::Url>,
+    /// number of simulated dashboard visitors
+    #[clap(long, default_value = "30")]
+    pub clients: u32,
+    /// seconds to keep up the load
+    #[clap(long, default_value = "30")]
+    pub duration: u64,
+}
+
+#[derive(Clap)]
+pub(crate) struct Simulate {
+    /// toml file describing the scenario
+    pub scenario: PathBuf,
+}
+
+#[derive(Clap)]
+pub(crate) struct Replay {
+    /// json lines file written with --record
+    pub recording: PathBuf,
+    /// factor by which the replay is sped up
+    #[clap(short, long, default_value = "1")]
+    pub speed: f64,
+}
+
+#[derive(Clap)]
+pub(crate) struct Dump {
+    /// dump all log entries of this sensor instead of the summary
+    #[clap(short, long)]
+    pub sensor: Option<BluetoothAddress>,
+    /// format of dumped log entries, either `json` or `csv`
+    #[clap(short, long, default_value = "json")]
+    pub format: LogFormat,
+}
+
+/// Format of log entries, json lines or csv with raw sensor values
+pub(crate) enum LogFormat {
+    Json,
+    Csv,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            _ => Err(String::from("Format must be either `json` or `csv`")),
+        }
+    }
 }
 
 pub(crate) enum Rt {
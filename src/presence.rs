@@ -0,0 +1,200 @@
+#[cfg(feature = "alerts")]
+use crate::alert::{self, ActionConfig, Actions, Event, EventKind};
+use crate::{bluetooth::BluetoothAddress, timestamp::Timestamp};
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+/// Time between two checks for beacons that came or left
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The `[presence]` table, beacons like phones or keyfobs whose presence gets reported
+#[derive(serde::Deserialize, Clone)]
+pub(crate) struct PresenceConfig {
+    #[serde(rename = "beacon")]
+    beacons: Vec<BeaconConfig>,
+    /// seconds a beacon has to be out of sight before it counts as away
+    #[serde(default = "default_away_after")]
+    away_after: u32,
+    /// changes get published as json under `{topic}/{addr}` if there's an mqtt server
+    #[serde(default = "default_topic")]
+    #[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+    topic: String,
+    /// run when a beacon arrives and, as cleared, when it leaves
+    #[cfg(feature = "alerts")]
+    #[serde(rename = "action", default)]
+    actions: Vec<ActionConfig>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+struct BeaconConfig {
+    addr: BluetoothAddress,
+    name: String,
+}
+
+fn default_away_after() -> u32 {
+    120
+}
+
+fn default_topic() -> String {
+    "sensors/presence".to_owned()
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub(crate) struct Beacon {
+    pub(crate) name: String,
+    pub(crate) present: bool,
+    /// of the latest sighting, connected devices don't have one
+    pub(crate) rssi: Option<i16>,
+    pub(crate) last_seen: Option<Timestamp>,
+}
+
+/// Beacons as last seen by the bluetooth thread
+pub(crate) struct Presence {
+    beacons: Mutex<BTreeMap<BluetoothAddress, Beacon>>,
+    away_after: u32,
+}
+
+impl Presence {
+    pub(crate) fn new(config: &PresenceConfig) -> Self {
+        let beacons = config
+            .beacons
+            .iter()
+            .map(|beacon| {
+                (
+                    beacon.addr,
+                    Beacon {
+                        name: beacon.name.clone(),
+                        present: false,
+                        rssi: None,
+                        last_seen: None,
+                    },
+                )
+            })
+            .collect();
+        Self {
+            beacons: Mutex::new(beacons),
+            away_after: config.away_after,
+        }
+    }
+
+    pub(crate) fn tracks(&self, addr: BluetoothAddress) -> bool {
+        self.beacons.lock().unwrap().contains_key(&addr)
+    }
+
+    /// Called by the bluetooth thread for every sighting of a tracked beacon
+    pub(crate) fn seen(&self, addr: BluetoothAddress, rssi: Option<i16>, now: Timestamp) {
+        if let Some(beacon) = self.beacons.lock().unwrap().get_mut(&addr) {
+            beacon.rssi = rssi;
+            beacon.last_seen = Some(now);
+        }
+    }
+
+    /// Beacons that arrived or left since the last update
+    fn update(&self, now: Timestamp) -> Vec<(BluetoothAddress, Beacon)> {
+        let mut beacons = self.beacons.lock().unwrap();
+        let mut changed = Vec::new();
+        for (addr, beacon) in beacons.iter_mut() {
+            let present = beacon.last_seen.map_or(false, |seen| {
+                now.bottoming_sub(seen).as_u32() < self.away_after
+            });
+            if present != beacon.present {
+                beacon.present = present;
+                changed.push((*addr, beacon.clone()));
+            }
+        }
+        changed
+    }
+
+    pub(crate) fn beacons(&self) -> BTreeMap<BluetoothAddress, Beacon> {
+        self.beacons.lock().unwrap().clone()
+    }
+}
+
+/// Reports beacons that came or left over mqtt and to the actions of `config`
+#[cfg_attr(not(feature = "mqtt"), allow(unused_variables))]
+pub(crate) async fn run(
+    ctx: crate::Context,
+    config: PresenceConfig,
+    mqtt: Option<crate::MqttConnection>,
+) {
+    let presence = match ctx.presence {
+        Some(ref presence) => presence,
+        None => return,
+    };
+    #[cfg(feature = "alerts")]
+    let mut actions = Actions::new(
+        "presence",
+        &config.actions,
+        mqtt.clone(),
+        &alert::http_client(),
+    );
+    #[cfg(feature = "mqtt")]
+    let mut mqtt = mqtt;
+
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        for (addr, beacon) in presence.update(Timestamp::now()) {
+            tracing::info!(
+                "{} ({}) is {}",
+                beacon.name,
+                addr,
+                if beacon.present { "present" } else { "away" }
+            );
+
+            #[cfg(feature = "mqtt")]
+            if let Some(ref mut cxn) = mqtt {
+                let published = async {
+                    let topic = tokio_mqtt::TopicName::new(format!("{}/{}", config.topic, addr))?;
+                    cxn.publish(topic, serde_json::to_vec(&beacon).unwrap())
+                        .await?;
+                    Ok::<_, eyre::Error>(())
+                };
+                if let Err(e) = published.await {
+                    tracing::error!("Could not publish presence of {}: {}", addr, e);
+                }
+            }
+
+            #[cfg(feature = "alerts")]
+            actions
+                .perform(&Event {
+                    rule: "presence",
+                    sensor: addr,
+                    label: Some(beacon.name.clone()),
+                    value: beacon.rssi.map_or(0., f64::from),
+                    kind: if beacon.present {
+                        EventKind::Fired
+                    } else {
+                        EventKind::Cleared
+                    },
+                })
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn beacons_leave_once_out_of_sight() {
+        let config: PresenceConfig = toml::from_str(
+            "away_after = 60\n[[beacon]]\naddr = \"AA:BB:CC:DD:EE:FF\"\nname = \"phone\"",
+        )
+        .unwrap();
+        let presence = Presence::new(&config);
+        let phone = BluetoothAddress::parse_str("AA:BB:CC:DD:EE:FF").unwrap();
+        assert!(presence.tracks(phone));
+        assert!(presence.update(Timestamp::from(100)).is_empty());
+
+        presence.seen(phone, Some(-70), Timestamp::from(100));
+        let arrived = presence.update(Timestamp::from(110));
+        assert_eq!(arrived.len(), 1);
+        assert!(arrived[0].1.present);
+        assert!(presence.update(Timestamp::from(150)).is_empty());
+
+        let left = presence.update(Timestamp::from(160));
+        assert_eq!(left.len(), 1);
+        assert!(!left[0].1.present);
+    }
+}
@@ -0,0 +1,54 @@
+//! Dropping root once startup is done. Installs that have to start as root for early boot
+//! access switch to an unprivileged user after the database is open, the servers are bound and
+//! the bluetooth backend connected to the system bus.
+
+/// Switches to `name` with its groups and clears every capability when running as root, does
+/// nothing otherwise. The user still needs access to BlueZ on the system bus, usually by being
+/// in the `bluetooth` group, and to the files the central writes later like crash reports.
+#[cfg(target_os = "linux")]
+pub(crate) fn drop_to(name: &str) -> Result<(), eyre::Error> {
+    use nix::{
+        errno::Errno,
+        libc,
+        unistd::{self, Uid, User},
+    };
+    use std::ffi::CString;
+
+    if !Uid::effective().is_root() {
+        tracing::debug!("Not running as root, keeping the current user");
+        return Ok(());
+    }
+    let user =
+        User::from_name(name)?.ok_or_else(|| eyre::format_err!("User {} doesn't exist", name))?;
+    if user.uid.is_root() {
+        return Err(eyre::format_err!("{} is root itself", name));
+    }
+
+    // groups first, they can't be changed anymore without root
+    unistd::initgroups(&CString::new(name)?, user.gid)?;
+    unistd::setgid(user.gid)?;
+    unistd::setuid(user.uid)?;
+    // setuid clears the other sets but ambient capabilities were passed down on purpose
+    if unsafe { libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0) } != 0
+        // kernels before 4.3 don't have ambient capabilities at all
+        && Errno::last() != Errno::EINVAL
+    {
+        return Err(eyre::format_err!(
+            "Could not clear ambient capabilities: {}",
+            Errno::last()
+        ));
+    }
+    if unistd::setuid(Uid::from_raw(0)).is_ok() {
+        return Err(eyre::format_err!(
+            "Could still become root after dropping it"
+        ));
+    }
+
+    tracing::info!("Dropped root, running as {} ({})", name, user.uid);
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn drop_to(_name: &str) -> Result<(), eyre::Error> {
+    Err(eyre::format_err!("run_as_user is only supported on Linux"))
+}
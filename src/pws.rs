@@ -0,0 +1,234 @@
+use crate::{
+    bluetooth::BluetoothAddress,
+    sensor::{RawSensorValues, SensorState, SensorValues},
+    timestamp::Timestamp,
+};
+use futures_util::future;
+use std::time::Duration;
+
+const WUNDERGROUND_URL: &str =
+    "https://weatherstation.wunderground.com/weatherstation/updateweatherstation.php";
+const WINDY_URL: &str = "https://stations.windy.com/pws/update";
+const WOW_URL: &str = "https://wow.metoffice.gov.uk/automaticreading";
+
+const SOFTWARE_TYPE: &str = concat!(env!("CARGO_PKG_NAME"), "-", env!("CARGO_PKG_VERSION"));
+
+/// Uploads to personal weather station networks, only settable in the config file
+#[derive(serde::Deserialize, Clone)]
+pub(crate) struct PwsConfig {
+    /// sensor whose values get uploaded, should be the outdoor one
+    sensor: BluetoothAddress,
+    wunderground: Option<Wunderground>,
+    windy: Option<Windy>,
+    wow: Option<Wow>,
+}
+
+/// Weather Underground station credentials
+#[derive(serde::Deserialize, Clone)]
+struct Wunderground {
+    station_id: String,
+    password: String,
+    /// seconds between two uploads
+    interval: Option<u64>,
+}
+
+/// Windy station credentials
+#[derive(serde::Deserialize, Clone)]
+struct Windy {
+    api_key: String,
+    /// index of the station if the key is used for more than one
+    #[serde(default)]
+    station: u32,
+    /// seconds between two uploads
+    interval: Option<u64>,
+}
+
+/// Met Office Weather Observations Website site credentials
+#[derive(serde::Deserialize, Clone)]
+struct Wow {
+    site_id: String,
+    auth_key: String,
+    /// seconds between two uploads
+    interval: Option<u64>,
+}
+
+enum Network {
+    Wunderground(Wunderground),
+    Windy(Windy),
+    Wow(Wow),
+}
+
+impl Network {
+    fn name(&self) -> &'static str {
+        match self {
+            Network::Wunderground(_) => "Weather Underground",
+            Network::Windy(_) => "Windy",
+            Network::Wow(_) => "WOW",
+        }
+    }
+
+    /// Shortest interval between two uploads the network accepts
+    fn min_interval(&self) -> Duration {
+        match self {
+            Network::Wunderground(_) => Duration::from_secs(60),
+            Network::Windy(_) | Network::Wow(_) => Duration::from_secs(5 * 60),
+        }
+    }
+
+    fn interval(&self) -> Duration {
+        let configured = match self {
+            Network::Wunderground(network) => network.interval,
+            Network::Windy(network) => network.interval,
+            Network::Wow(network) => network.interval,
+        };
+        match configured.map(Duration::from_secs) {
+            Some(interval) if interval < self.min_interval() => {
+                tracing::warn!(
+                    "Upload interval for {} is too short, using {}s",
+                    self.name(),
+                    self.min_interval().as_secs()
+                );
+                self.min_interval()
+            }
+            Some(interval) => interval,
+            None => self.min_interval(),
+        }
+    }
+
+    fn request(&self, client: &reqwest::Client, obs: &Observation) -> reqwest::RequestBuilder {
+        match self {
+            Network::Wunderground(network) => client.get(WUNDERGROUND_URL).query(&[
+                ("ID", network.station_id.clone()),
+                ("PASSWORD", network.password.clone()),
+                ("action", "updateraw".to_owned()),
+                ("dateutc", obs.date_utc()),
+                ("softwaretype", SOFTWARE_TYPE.to_owned()),
+                ("tempf", format!("{:.1}", obs.fahrenheit())),
+                ("humidity", format!("{:.1}", obs.humidity)),
+                ("baromin", format!("{:.3}", obs.inches_of_mercury())),
+            ]),
+            Network::Windy(network) => client
+                .get(&format!("{}/{}", WINDY_URL, network.api_key))
+                .query(&[
+                    ("station", network.station.to_string()),
+                    ("ts", obs.time.as_u32().to_string()),
+                    ("temp", format!("{:.1}", obs.celsius)),
+                    ("humidity", format!("{:.1}", obs.humidity)),
+                    ("pressure", format!("{:.0}", obs.pascal)),
+                ]),
+            Network::Wow(network) => client.get(WOW_URL).query(&[
+                ("siteid", network.site_id.clone()),
+                ("siteAuthenticationKey", network.auth_key.clone()),
+                ("dateutc", obs.date_utc()),
+                ("softwaretype", SOFTWARE_TYPE.to_owned()),
+                ("tempf", format!("{:.1}", obs.fahrenheit())),
+                ("humidity", format!("{:.1}", obs.humidity)),
+                ("baromin", format!("{:.3}", obs.inches_of_mercury())),
+            ]),
+        }
+    }
+}
+
+/// Sensor values in the units the networks want
+struct Observation {
+    time: Timestamp,
+    celsius: f64,
+    humidity: f64,
+    pascal: f64,
+}
+
+impl Observation {
+    fn new(time: Timestamp, values: SensorValues) -> Self {
+        let raw = RawSensorValues::from(values);
+        Self {
+            time,
+            celsius: f64::from(raw.temperature) / 100.,
+            humidity: f64::from(raw.humidity) / 100.,
+            pascal: f64::from(raw.pressure) / 10.,
+        }
+    }
+
+    fn fahrenheit(&self) -> f64 {
+        self.celsius * 9. / 5. + 32.
+    }
+
+    fn inches_of_mercury(&self) -> f64 {
+        self.pascal / 3386.389
+    }
+
+    fn date_utc(&self) -> String {
+        chrono::NaiveDateTime::from_timestamp(i64::from(self.time.as_u32()), 0)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    }
+}
+
+/// Periodically uploads the current values of the configured sensor to every configured network
+pub(crate) async fn upload(ctx: super::Context, config: PwsConfig) {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .expect("Could not create http client");
+
+    let sensor = config.sensor;
+    let networks = config
+        .wunderground
+        .map(Network::Wunderground)
+        .into_iter()
+        .chain(config.windy.map(Network::Windy))
+        .chain(config.wow.map(Network::Wow));
+
+    future::join_all(
+        networks.map(|network| upload_to(ctx.clone(), client.clone(), sensor, network)),
+    )
+    .await;
+}
+
+async fn upload_to(
+    ctx: super::Context,
+    client: reqwest::Client,
+    sensor: BluetoothAddress,
+    network: Network,
+) {
+    tracing::info!("Uploading values of {} to {}", sensor, network.name());
+    let mut interval = tokio::time::interval(network.interval());
+    loop {
+        interval.tick().await;
+        let values = match ctx.sensors.read().await.get(&sensor) {
+            Some(SensorState::Connected(values)) => *values,
+            _ => continue,
+        };
+
+        let obs = Observation::new(Timestamp::now(), values);
+        let sent = network
+            .request(&client, &obs)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status());
+        if let Err(e) = sent {
+            tracing::error!("Failed uploading to {}: {}", network.name(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn observation_units() {
+        let values = SensorValues::try_from(RawSensorValues {
+            temperature: 20_00,
+            humidity: 55_50,
+            pressure: 1_013_250,
+        })
+        .unwrap();
+        let obs = Observation::new(Timestamp::from(86_400), values);
+
+        assert!((obs.fahrenheit() - 68.).abs() < 1e-9);
+        assert!((obs.inches_of_mercury() - 29.921).abs() < 1e-3);
+        assert!((obs.humidity - 55.5).abs() < 1e-9);
+        assert_eq!(obs.date_utc(), "1970-01-02 00:00:00");
+    }
+}
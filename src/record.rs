@@ -0,0 +1,94 @@
+use crate::{bluetooth::BluetoothAddress, sensor::SensorState, timestamp::Timestamp};
+use eyre::Context;
+use futures_util::stream::{Stream, StreamExt};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    future::Future,
+    io::{BufWriter, Write},
+    path::Path,
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+/// Borrows the update while recording so it doesn't get copied for every frame
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Frame<'a> {
+    time: Timestamp,
+    sensors: Cow<'a, BTreeMap<BluetoothAddress, SensorState>>,
+}
+
+/// Appends every update of `updates` to the file at `path`
+pub(crate) fn record<S>(updates: S, path: &Path) -> Result<impl Stream<Item = S::Item>, eyre::Error>
+where
+    S: Stream<Item = BTreeMap<BluetoothAddress, SensorState>>,
+{
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Could not open recording file {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+
+    Ok(updates.inspect(move |sensors| {
+        let frame = Frame {
+            time: Timestamp::now(),
+            sensors: Cow::Borrowed(sensors),
+        };
+        let written = serde_json::to_writer(&mut out, &frame)
+            .map_err(std::io::Error::from)
+            .and_then(|()| writeln!(out))
+            .and_then(|()| out.flush());
+        if let Err(e) = written {
+            tracing::error!("Failed writing recording: {}", e);
+        }
+    }))
+}
+
+pub(crate) struct Recording(Vec<Frame<'static>>);
+
+impl Recording {
+    pub fn from_file(path: &Path) -> Result<Self, eyre::Error> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Could not read recording {}", path.display()))?;
+        content
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line)
+                    .with_context(|| format!("Invalid frame in line {} of recording", i + 1))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Plays back `recording` with the original delays between frames divided by `speed`
+pub(crate) fn replay_source(
+    recording: Recording,
+    speed: f64,
+) -> (
+    impl Future<Output = ()>,
+    impl Stream<Item = BTreeMap<BluetoothAddress, SensorState>> + Sync + Send,
+) {
+    let (tx, rx) = mpsc::channel(1);
+    let replay_task = async move {
+        let mut last_time = None;
+        for frame in recording.0 {
+            if let Some(last_time) = last_time {
+                let delay = frame.time.bottoming_sub(last_time).as_u32();
+                tokio::time::sleep(Duration::from_secs(u64::from(delay)).div_f64(speed)).await;
+            }
+            last_time = Some(frame.time);
+
+            if tx.send(frame.sensors.into_owned()).await.is_err() {
+                return;
+            }
+        }
+        tracing::info!("Replay finished");
+    };
+
+    (replay_task, tokio_stream::wrappers::ReceiverStream::new(rx))
+}
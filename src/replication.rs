@@ -0,0 +1,459 @@
+//! Replicas follow the log of a primary central over http. They ask the primary for
+//! `/api/replication?sensors=<addr>@<time>,...` with the newest reading they have of each
+//! sensor and get every sensor and its readings after those in the export format, followed by
+//! each batch the primary writes and the settings of every sensor that changes for as long as
+//! the connection stays open. A replica that falls behind or loses the connection reconnects
+//! from its newest readings, writing a reading twice changes nothing. Replicas show the newest
+//! reading of each sensor as its state so they can take over from the primary.
+
+use crate::{
+    bluetooth::BluetoothAddress,
+    db::{Db, LogBatch},
+    export::{self, Record},
+    sensor::SensorState,
+    timestamp::Timestamp,
+};
+use bytes::Bytes;
+use eyre::Context as _;
+use futures_util::stream::{self, Stream};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::Infallible,
+    io::{self, Write},
+    mem,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::mpsc, task, time};
+
+/// Seconds between two empty lines that keep an idle stream alive
+const HEARTBEAT: u64 = 30;
+
+/// Replicas give up on a stream that stayed silent for this long
+const STREAM_TIMEOUT: Duration = Duration::from_secs(3 * HEARTBEAT);
+
+/// Seconds a replica waits before connecting again
+const RECONNECT_DELAY: u64 = 10;
+
+/// Bytes of the catch up sent at once
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// What connected replicas get sent as it happens
+#[derive(Clone, Debug)]
+pub(crate) enum Change {
+    Log(Arc<LogBatch>),
+    /// the settings of the sensor are to be sent again
+    Settings(BluetoothAddress),
+}
+
+/// Hands a written batch to every connected replica
+pub(crate) fn publish(ctx: &super::Context, batch: LogBatch) {
+    // without replicas there's nobody to send it to
+    let _ = ctx.replication.send(Change::Log(Arc::new(batch)));
+}
+
+/// Sends the settings of `addr` to every connected replica again
+pub(crate) fn publish_settings(ctx: &super::Context, addr: BluetoothAddress) {
+    let _ = ctx.replication.send(Change::Settings(addr));
+}
+
+/// Where a replica continues, the newest reading it has of every sensor. Sensors without one
+/// get sent from `since` on.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Cursor {
+    pub(crate) since: Timestamp,
+    pub(crate) sensors: BTreeMap<BluetoothAddress, Timestamp>,
+}
+
+impl Cursor {
+    fn of(&self, addr: BluetoothAddress) -> Timestamp {
+        self.sensors.get(&addr).copied().unwrap_or(self.since)
+    }
+
+    /// Sensors as `AA:BB:CC:DD:EE:FF@<unix time>`, separated by commas
+    pub(crate) fn parse_sensors(
+        sensors: &str,
+    ) -> Result<BTreeMap<BluetoothAddress, Timestamp>, eyre::Error> {
+        sensors
+            .split(',')
+            .filter(|sensor| !sensor.is_empty())
+            .map(|sensor| {
+                let mut parts = sensor.splitn(2, '@');
+                let addr = parts.next().unwrap_or_default().parse()?;
+                let time = parts
+                    .next()
+                    .ok_or_else(|| eyre::format_err!("Cursor `{}` has no time", sensor))?
+                    .parse::<u32>()?;
+                Ok((addr, Timestamp::from(time)))
+            })
+            .collect()
+    }
+
+    fn format_sensors(&self) -> String {
+        self.sensors
+            .iter()
+            .map(|(addr, time)| format!("{}@{}", addr, time.as_u32()))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// Whether the bearer token of a request is `token`, taking as long for every wrong guess of the
+/// same length so timing doesn't give it away
+pub(crate) fn token_matches(bearer: &str, token: &str) -> bool {
+    let (bearer, token) = (bearer.as_bytes(), token.as_bytes());
+    bearer.len() == token.len()
+        && bearer
+            .iter()
+            .zip(token)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Body of a replication reply, json lines of the export format
+pub(crate) fn stream(
+    ctx: super::Context,
+    cursor: Cursor,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    // subscribed before the catch up so no change falls between the two
+    let mut changes = ctx.replication.subscribe();
+    let (tx, rx) = mpsc::channel(4);
+    task::spawn(async move {
+        let catch_up = {
+            let ctx = ctx.clone();
+            let tx = tx.clone();
+            task::spawn_blocking(move || catch_up(&ctx.db, &cursor, tx))
+        };
+        let mut known = match catch_up.await.expect("Replication catch up panicked") {
+            Ok(known) => known,
+            Err(e) => {
+                tracing::debug!("Replication catch up stopped: {:?}", e);
+                return;
+            }
+        };
+
+        let mut heartbeat = time::interval(Duration::from_secs(HEARTBEAT));
+        loop {
+            let chunk = tokio::select! {
+                change = changes.recv() => match change {
+                    Ok(change) => match live_chunk(&ctx.db, &change, &mut known) {
+                        Ok(chunk) => chunk,
+                        Err(e) => {
+                            tracing::error!("Failed encoding a change for a replica: {:?}", e);
+                            return;
+                        }
+                    },
+                    // the replica catches up from its newest readings once it reconnects
+                    Err(_) => return,
+                },
+                _ = heartbeat.tick() => Bytes::from_static(b"\n"),
+            };
+            if tx.send(chunk).await.is_err() {
+                return;
+            }
+        }
+    });
+    stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (Ok(chunk), rx))
+    })
+}
+
+/// Sends whole lines through a channel in chunks of about [`CHUNK_SIZE`], from a blocking thread
+struct ChunkWriter {
+    buf: Vec<u8>,
+    tx: mpsc::Sender<Bytes>,
+}
+
+impl ChunkWriter {
+    fn send(&mut self) -> io::Result<()> {
+        let chunk = Bytes::from(mem::take(&mut self.buf));
+        self.tx
+            .blocking_send(chunk)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Replica went away"))
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_SIZE && self.buf.ends_with(b"\n") {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            self.send()
+        }
+    }
+}
+
+/// Sends the header, every sensor and its readings after `cursor`, returns the sensors sent
+fn catch_up(
+    db: &Db,
+    cursor: &Cursor,
+    tx: mpsc::Sender<Bytes>,
+) -> Result<BTreeSet<BluetoothAddress>, eyre::Error> {
+    let known = {
+        let txn = db.read_txn()?;
+        db.known_addrs(&txn)?.collect::<Result<BTreeSet<_>, _>>()?
+    };
+    let mut out = ChunkWriter {
+        buf: Vec::with_capacity(CHUNK_SIZE),
+        tx,
+    };
+    let readings = export::export_since(db, |addr| cursor.of(addr), &mut out)?;
+    out.flush()?;
+    tracing::info!(
+        "Sent {} readings of {} sensors to a replica",
+        readings,
+        known.len()
+    );
+    Ok(known)
+}
+
+/// Readings of a batch preceded by the settings of sensors the replica didn't get yet, or the
+/// changed settings of a sensor
+fn live_chunk(
+    db: &Db,
+    change: &Change,
+    known: &mut BTreeSet<BluetoothAddress>,
+) -> Result<Bytes, eyre::Error> {
+    let txn = db.read_txn()?;
+    let mut out = Vec::new();
+    let batch = match change {
+        Change::Log(batch) => batch,
+        Change::Settings(addr) => {
+            // forgotten again in the meantime
+            if let Some(settings) = db.get_addr(&txn, *addr)? {
+                known.insert(*addr);
+                let record = Record::Sensor {
+                    addr: *addr,
+                    settings,
+                };
+                export::write_record(&mut out, &record)?;
+            }
+            return Ok(Bytes::from(out));
+        }
+    };
+    for (addr, time, values, quality) in batch.entries() {
+        if known.insert(addr) {
+            let settings = db.get_addr(&txn, addr)?.unwrap_or_default();
+            export::write_record(&mut out, &Record::Sensor { addr, settings })?;
+        }
+        export::write_record(
+            &mut out,
+            &Record::Reading {
+                addr,
+                time,
+                values,
+                quality,
+            },
+        )?;
+    }
+    Ok(Bytes::from(out))
+}
+
+/// Replicates the log of the central at `primary` into this one forever
+pub(crate) async fn follow(ctx: super::Context, primary: url::Url, token: String) {
+    // no overall timeout, the stream stays open
+    let client = reqwest::Client::new();
+    loop {
+        match replicate(&ctx, &client, &primary, &token).await {
+            Ok(()) => tracing::info!("{} ended the replication stream", primary),
+            Err(e) => tracing::warn!("Replicating from {} failed: {:?}", primary, e),
+        }
+        time::sleep(Duration::from_secs(RECONNECT_DELAY)).await;
+    }
+}
+
+/// Newest reading of every sensor, where a replica continues
+fn cursor(db: &Db) -> Result<Cursor, eyre::Error> {
+    let txn = db.read_txn()?;
+    let mut cursor = Cursor::default();
+    for addr in db.known_addrs(&txn)? {
+        let addr = addr?;
+        if let Some(stats) = db.log_stats(&txn, addr)? {
+            cursor.sensors.insert(addr, stats.last);
+        }
+    }
+    Ok(cursor)
+}
+
+async fn replicate(
+    ctx: &super::Context,
+    client: &reqwest::Client,
+    primary: &url::Url,
+    token: &str,
+) -> Result<(), eyre::Error> {
+    let cursor = cursor(&ctx.db)?;
+    let mut url = primary.clone();
+    url.path_segments_mut()
+        .map_err(|()| eyre::format_err!("{} can't be a primary", primary))?
+        .pop_if_empty()
+        .extend(&["api", "replication"]);
+    let mut response = client
+        .get(url)
+        .bearer_auth(token)
+        .query(&[
+            ("since", cursor.since.as_u32().to_string()),
+            ("sensors", cursor.format_sensors()),
+        ])
+        .send()
+        .await?
+        .error_for_status()?;
+    tracing::info!(
+        "Replicating from {} after the newest readings of {} sensors",
+        primary,
+        cursor.sensors.len()
+    );
+
+    let mut header = false;
+    // an incomplete line at the end of the latest chunk
+    let mut pending = Vec::new();
+    while let Some(chunk) = time::timeout(STREAM_TIMEOUT, response.chunk())
+        .await
+        .map_err(|_| eyre::format_err!("Stream went silent"))??
+    {
+        pending.extend_from_slice(&chunk);
+        let end = match pending.iter().rposition(|&byte| byte == b'\n') {
+            Some(end) => end + 1,
+            None => continue,
+        };
+        let lines = pending.drain(..end).collect::<Vec<_>>();
+        apply(ctx, std::str::from_utf8(&lines)?, &mut header).await?;
+    }
+    Ok(())
+}
+
+/// Stores the records of `lines`, settings go through the state manager so the sensor shows up
+/// and the newest reading of each sensor becomes its state
+async fn apply(ctx: &super::Context, lines: &str, header: &mut bool) -> Result<(), eyre::Error> {
+    let mut batch = LogBatch::default();
+    for line in lines.lines().filter(|line| !line.trim().is_empty()) {
+        let record = serde_json::from_str(line).context("Invalid record")?;
+        match record {
+            Record::Header {
+                format, version, ..
+            } => {
+                export::check_header(&format, version)?;
+                *header = true;
+            }
+            _ if !*header => return Err(eyre::format_err!("Stream doesn't start with a header")),
+            Record::Sensor { addr, settings } => {
+                ctx.state.change_settings(addr, settings.into()).await?
+            }
+            Record::Reading {
+                addr,
+                time,
+                values,
+                quality,
+            } => batch.push(addr, time, values, quality),
+            Record::Unknown => {}
+        }
+    }
+    if batch.is_empty() {
+        return Ok(());
+    }
+
+    let addrs = batch.addrs();
+    let mut newest = BTreeMap::new();
+    for (addr, time, values, _) in batch.entries() {
+        match newest.get(&addr) {
+            Some(&(newest_time, _)) if newest_time > time => {}
+            _ => {
+                newest.insert(addr, (time, values));
+            }
+        }
+    }
+    let writer = ctx.clone();
+    task::spawn_blocking(move || writer.db.write_log(&batch))
+        .await
+        .expect("Replicated log write panicked")?;
+    ctx.queries.invalidate(addrs);
+    crate::state::bump_generation(ctx);
+    ctx.state
+        .mirror(
+            newest
+                .into_iter()
+                .map(|(addr, (_, values))| (addr, SensorState::Connected(values)))
+                .collect(),
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        db::AddrDbEntry,
+        sensor::{Quality, RawSensorValues, SensorValues},
+    };
+    use std::convert::TryFrom;
+
+    #[test]
+    fn live_batches_introduce_new_sensors() {
+        let addr = BluetoothAddress::from(1);
+        let values = SensorValues::try_from(RawSensorValues {
+            temperature: 21_50,
+            humidity: 45_00,
+            pressure: 1_013_250,
+        })
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let mut txn = db.write_txn().unwrap();
+        let settings = AddrDbEntry {
+            label: Some("attic".to_owned()),
+            ..AddrDbEntry::default()
+        };
+        db.put_addr(&mut txn, addr, &settings).unwrap();
+        txn.commit().unwrap();
+
+        let mut batch = LogBatch::default();
+        batch.push(addr, Timestamp::from(60), values, Quality::empty());
+        batch.push(addr, Timestamp::from(120), values, Quality::empty());
+        let batch = Change::Log(Arc::new(batch));
+        let mut known = BTreeSet::new();
+        let first = live_chunk(&db, &batch, &mut known).unwrap();
+        let lines = std::str::from_utf8(&first)
+            .unwrap()
+            .lines()
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains("\"attic\""));
+
+        let second = live_chunk(&db, &batch, &mut known).unwrap();
+        assert_eq!(std::str::from_utf8(&second).unwrap().lines().count(), 2);
+
+        let changed = live_chunk(&db, &Change::Settings(addr), &mut known).unwrap();
+        assert!(std::str::from_utf8(&changed).unwrap().contains("\"attic\""));
+    }
+
+    #[test]
+    fn cursors_round_trip_and_tokens_compare() {
+        let cursor = Cursor {
+            since: Timestamp::UNIX_EPOCH,
+            sensors: vec![
+                (BluetoothAddress::from(1), Timestamp::from(60)),
+                (BluetoothAddress::from(2), Timestamp::from(120)),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        assert_eq!(
+            Cursor::parse_sensors(&cursor.format_sensors()).unwrap(),
+            cursor.sensors
+        );
+        assert!(Cursor::parse_sensors("").unwrap().is_empty());
+        assert!(Cursor::parse_sensors("00:00:00:00:00:01").is_err());
+
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secreT", "secret"));
+        assert!(!token_matches("secret2", "secret"));
+    }
+}
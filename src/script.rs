@@ -0,0 +1,63 @@
+#[cfg(feature = "scripting")]
+mod lua;
+
+#[cfg(feature = "scripting")]
+use crate::alert::ActionConfig;
+use crate::bluetooth::BluetoothAddress;
+use std::{collections::BTreeSet, path::PathBuf, sync::Mutex};
+
+/// The `[script]` table of the config file
+#[derive(serde::Deserialize, Clone)]
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+pub(crate) struct ScriptConfig {
+    /// lua script defining `on_update(addr, values)`
+    path: PathBuf,
+    /// run when the script returns a `trigger`
+    #[cfg(feature = "scripting")]
+    #[serde(rename = "action", default)]
+    actions: Vec<ActionConfig>,
+}
+
+/// What the script decided about past updates, looked at outside of the script thread
+#[derive(Default)]
+pub(crate) struct Hooks {
+    /// sensors whose latest update the script doesn't want logged
+    vetoed: Mutex<BTreeSet<BluetoothAddress>>,
+}
+
+impl Hooks {
+    pub(crate) fn vetoes(&self, addr: BluetoothAddress) -> bool {
+        self.vetoed.lock().unwrap().contains(&addr)
+    }
+
+    #[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+    fn set_veto(&self, addr: BluetoothAddress, veto: bool) {
+        let mut vetoed = self.vetoed.lock().unwrap();
+        if veto {
+            vetoed.insert(addr);
+        } else {
+            vetoed.remove(&addr);
+        }
+    }
+}
+
+/// Loads the script and feeds it every sensor update from now on
+#[cfg(feature = "scripting")]
+pub(crate) async fn start(
+    ctx: super::Context,
+    config: &ScriptConfig,
+    mqtt: Option<crate::MqttConnection>,
+) -> Result<(), eyre::Error> {
+    lua::start(ctx, config, mqtt).await
+}
+
+#[cfg(not(feature = "scripting"))]
+pub(crate) async fn start(
+    _ctx: super::Context,
+    _config: &ScriptConfig,
+    _mqtt: Option<crate::MqttConnection>,
+) -> Result<(), eyre::Error> {
+    Err(eyre::format_err!(
+        "A script is configured but this build doesn't include the scripting feature"
+    ))
+}
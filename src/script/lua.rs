@@ -0,0 +1,212 @@
+use super::ScriptConfig;
+use crate::{
+    alert::{self, Actions, Event, EventKind},
+    bluetooth::BluetoothAddress,
+    sensor::{Quantity, SensorState, SensorValues},
+};
+use eyre::WrapErr;
+use mlua::{Function, Lua, Table, Value};
+use std::thread;
+use tokio::sync::broadcast;
+
+/// What `on_update` returned for one sensor
+struct Outcome {
+    addr: BluetoothAddress,
+    /// `publish = { name = value }`, sent to mqtt under the topic of the sensor
+    publish: Vec<(String, f64)>,
+    /// `trigger = { name = "...", value = ... }`, runs the script actions
+    trigger: Option<(String, f64)>,
+}
+
+fn call(
+    lua: &Lua,
+    on_update: &Function,
+    addr: BluetoothAddress,
+    values: SensorValues,
+) -> mlua::Result<(bool, Outcome)> {
+    let table = lua.create_table()?;
+    table.set("temperature", Quantity::Temperature.of(values))?;
+    table.set("humidity", Quantity::Humidity.of(values))?;
+    table.set("pressure", Quantity::Pressure.of(values))?;
+
+    let mut outcome = Outcome {
+        addr,
+        publish: Vec::new(),
+        trigger: None,
+    };
+    let result = match on_update.call::<_, Value>((addr.to_string(), table))? {
+        Value::Table(result) => result,
+        // returning nothing keeps everything as it is
+        _ => return Ok((true, outcome)),
+    };
+
+    let log = result.get::<_, Option<bool>>("log")?.unwrap_or(true);
+    if let Some(publish) = result.get::<_, Option<Table>>("publish")? {
+        for pair in publish.pairs::<String, f64>() {
+            outcome.publish.push(pair?);
+        }
+    }
+    if let Some(trigger) = result.get::<_, Option<Table>>("trigger")? {
+        outcome.trigger = Some((trigger.get("name")?, trigger.get("value")?));
+    }
+    Ok((log, outcome))
+}
+
+pub(super) async fn start(
+    ctx: crate::Context,
+    config: &ScriptConfig,
+    mqtt: Option<crate::MqttConnection>,
+) -> Result<(), eyre::Error> {
+    let source = std::fs::read_to_string(&config.path)
+        .with_context(|| format!("Reading script {}", config.path.display()))?;
+    let name = config.path.display().to_string();
+
+    let (update_tx, update_rx) = flume::bounded::<(BluetoothAddress, SensorValues)>(64);
+    let (outcome_tx, outcome_rx) = flume::bounded(64);
+    let (loaded_tx, loaded_rx) = flume::bounded(1);
+    let script_ctx = ctx.clone();
+    // lua states aren't Send so the script gets a thread of its own
+    thread::spawn(move || {
+        let lua = Lua::new();
+        let on_update = lua
+            .load(&source)
+            .set_name(&name)
+            .and_then(|chunk| chunk.exec())
+            .and_then(|()| lua.globals().get::<_, Function>("on_update"));
+        let on_update = match on_update {
+            Ok(on_update) => {
+                let _ = loaded_tx.send(Ok(()));
+                on_update
+            }
+            Err(e) => {
+                let _ = loaded_tx.send(Err(e.to_string()));
+                return;
+            }
+        };
+
+        for (addr, values) in update_rx {
+            match call(&lua, &on_update, addr, values) {
+                Ok((log, outcome)) => {
+                    if let Some(ref hooks) = script_ctx.script {
+                        hooks.set_veto(addr, !log);
+                    }
+                    if (!outcome.publish.is_empty() || outcome.trigger.is_some())
+                        && outcome_tx.send(outcome).is_err()
+                    {
+                        return;
+                    }
+                }
+                Err(e) => tracing::error!("Script failed on update of {}: {}", addr, e),
+            }
+        }
+    });
+    loaded_rx
+        .recv_async()
+        .await
+        .map_err(|_| eyre::format_err!("Script thread died"))?
+        .map_err(|e| eyre::format_err!("Loading script {}: {}", config.path.display(), e))?;
+
+    let mut updates = ctx.updates.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let events = match updates.recv().await {
+                Ok(events) => events,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Script lagged behind by {} updates", skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            for (addr, state) in crate::state::states(&events) {
+                if let SensorState::Connected(values) = state {
+                    if update_tx.send_async((addr, values)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+
+    let mut actions = Actions::new(
+        "script",
+        &config.actions,
+        mqtt.clone(),
+        &alert::http_client(),
+    );
+    tokio::spawn(async move {
+        while let Ok(outcome) = outcome_rx.recv_async().await {
+            handle(&ctx, outcome, mqtt.as_ref(), &mut actions).await;
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(feature = "mqtt")]
+async fn publish(
+    cxn: &crate::MqttConnection,
+    topic: String,
+    value: f64,
+) -> Result<(), eyre::Error> {
+    let topic = tokio_mqtt::TopicName::new(topic)?;
+    cxn.clone()
+        .publish(topic, value.to_string().into_bytes())
+        .await?;
+    Ok(())
+}
+
+#[cfg(not(feature = "mqtt"))]
+async fn publish(
+    cxn: &crate::MqttConnection,
+    _topic: String,
+    _value: f64,
+) -> Result<(), eyre::Error> {
+    match *cxn {}
+}
+
+async fn handle(
+    ctx: &crate::Context,
+    outcome: Outcome,
+    mqtt: Option<&crate::MqttConnection>,
+    actions: &mut Actions,
+) {
+    for (name, value) in outcome.publish {
+        let cxn = match mqtt {
+            Some(cxn) => cxn,
+            None => {
+                tracing::debug!("Script published {} = {} for {}", name, value, outcome.addr);
+                continue;
+            }
+        };
+        let topic = format!("sensors/weatherstation/{}/{}", outcome.addr, name);
+        if let Err(e) = publish(cxn, topic, value).await {
+            tracing::error!("Failed publishing script value to mqtt server: {}", e);
+        }
+    }
+
+    if let Some((name, value)) = outcome.trigger {
+        let label = match ctx
+            .db
+            .read_txn()
+            .and_then(|txn| ctx.db.get_addr(&txn, outcome.addr))
+        {
+            Ok(entry) => entry.and_then(|entry| entry.label),
+            Err(e) => {
+                tracing::error!("Could not look up label of {}: {}", outcome.addr, e);
+                None
+            }
+        };
+        let event = Event {
+            rule: &name,
+            sensor: outcome.addr,
+            label,
+            value,
+            kind: EventKind::Fired,
+        };
+        tracing::warn!(
+            "{}",
+            event.render("Script triggered {rule} for {label} at {value}")
+        );
+        actions.perform(&event).await;
+    }
+}
@@ -1,11 +1,12 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::{
     convert::TryFrom,
     fmt::{self, Display},
 };
 
 /// Temperature with a precision of 2
-#[derive(Copy, Clone, Debug, Serialize)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "i16")]
 pub(crate) struct Celsius(i16);
 
 impl TryFrom<i16> for Celsius {
@@ -31,7 +32,8 @@ impl Display for Celsius {
 }
 
 /// Humidity with a precision of 2 in percent
-#[derive(Copy, Clone, Debug, Serialize)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "u16")]
 pub(crate) struct RelativeHumidity(u16);
 
 impl Display for RelativeHumidity {
@@ -56,7 +58,7 @@ impl TryFrom<u16> for RelativeHumidity {
 }
 
 /// Pressure in with a precision of 1
-#[derive(Copy, Clone, Debug, Serialize)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct Pascal(u32);
 
 impl From<u32> for Pascal {
@@ -71,7 +73,7 @@ impl Display for Pascal {
     }
 }
 
-#[derive(Copy, Clone, Debug, Serialize)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct SensorValues {
     pub(crate) temperature: Celsius,
     pub(crate) pressure: Pascal,
@@ -88,13 +90,156 @@ impl Display for SensorValues {
     }
 }
 
-#[derive(Clone, Copy, Debug, Serialize)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 #[serde(tag = "state")]
 pub(crate) enum SensorState {
     Connected(SensorValues),
     Unconnected,
 }
 
+/// One of the values a sensor measures
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Quantity {
+    /// °C
+    Temperature,
+    /// relative humidity in percent
+    Humidity,
+    /// Pa
+    Pressure,
+}
+
+impl Quantity {
+    /// The value of this quantity in `values` in the unit documented above
+    pub(crate) fn of(self, values: SensorValues) -> f64 {
+        let raw = RawSensorValues::from(values);
+        match self {
+            Quantity::Temperature => f64::from(raw.temperature) / 100.,
+            Quantity::Humidity => f64::from(raw.humidity) / 100.,
+            Quantity::Pressure => f64::from(raw.pressure) / 10.,
+        }
+    }
+}
+
+impl std::str::FromStr for Quantity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "temperature" => Ok(Quantity::Temperature),
+            "humidity" => Ok(Quantity::Humidity),
+            "pressure" => Ok(Quantity::Pressure),
+            _ => Err(format!(
+                "Unknown quantity `{}`, must be one of temperature, humidity or pressure",
+                s
+            )),
+        }
+    }
+}
+
+bitflags::bitflags! {
+    /// How a logged value came to be, readings as the station reported them have none of these
+    #[derive(Default)]
+    pub(crate) struct Quality: u8 {
+        /// offsets of the calibration of the sensor were added
+        const CALIBRATED = 1;
+        /// filled in between two readings instead of measured
+        const INTERPOLATED = 1 << 1;
+        /// the calibration pushed a value out of range so it got clamped
+        const CLAMPED = 1 << 2;
+        /// came from an import instead of a station
+        const IMPORTED = 1 << 3;
+    }
+}
+
+const QUALITY_NAMES: [(Quality, &str); 4] = [
+    (Quality::CALIBRATED, "calibrated"),
+    (Quality::INTERPOLATED, "interpolated"),
+    (Quality::CLAMPED, "clamped"),
+    (Quality::IMPORTED, "imported"),
+];
+
+/// A list of the names of the set flags
+impl Serialize for Quality {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            QUALITY_NAMES
+                .iter()
+                .filter(|(flag, _)| self.contains(*flag))
+                .map(|(_, name)| name),
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for Quality {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|name| {
+                QUALITY_NAMES
+                    .iter()
+                    .find(|(_, known)| known == name)
+                    .map(|(flag, _)| *flag)
+                    .ok_or_else(|| {
+                        serde::de::Error::custom(format!("Unknown quality flag {}", name))
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Offsets added to everything a sensor reports to make up for inaccurate hardware
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Calibration {
+    /// °C
+    #[serde(default)]
+    pub(crate) temperature: f64,
+    /// percentage points of relative humidity
+    #[serde(default)]
+    pub(crate) humidity: f64,
+    /// Pa
+    #[serde(default)]
+    pub(crate) pressure: f64,
+}
+
+impl Calibration {
+    /// `values` with the offsets added, clamped to what the value types can represent, and flags
+    /// of what was done to them. Values that can't be calibrated are passed through unflagged.
+    pub(crate) fn apply(&self, values: SensorValues) -> (SensorValues, Quality) {
+        if *self == Calibration::default() {
+            return (values, Quality::empty());
+        }
+
+        let raw = RawSensorValues::from(values);
+        let mut clamped = false;
+        let mut offset = |value: f64, offset: f64, min: f64, max: f64| {
+            let value = (value + offset).round();
+            clamped |= value < min || value > max;
+            value.max(min).min(max)
+        };
+        let calibrated = RawSensorValues {
+            temperature: offset(
+                f64::from(raw.temperature),
+                self.temperature * 100.,
+                -273_15.,
+                f64::from(i16::MAX),
+            ) as i16,
+            humidity: offset(f64::from(raw.humidity), self.humidity * 100., 0., 100_00.) as u16,
+            pressure: offset(
+                f64::from(raw.pressure),
+                self.pressure * 10.,
+                0.,
+                f64::from(u32::MAX),
+            ) as u32,
+        };
+        match SensorValues::try_from(calibrated) {
+            Ok(calibrated) if clamped => (calibrated, Quality::CALIBRATED | Quality::CLAMPED),
+            Ok(calibrated) => (calibrated, Quality::CALIBRATED),
+            Err(_) => (values, Quality::empty()),
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub(crate) struct RawSensorValues {
@@ -125,6 +270,17 @@ impl TryFrom<RawSensorValues> for SensorValues {
     }
 }
 
+/// Values for tests that don't care about the pressure
+#[cfg(test)]
+pub(crate) fn test_values(temperature: i16, humidity: u16) -> SensorValues {
+    SensorValues::try_from(RawSensorValues {
+        temperature,
+        humidity,
+        pressure: 1_000_000,
+    })
+    .unwrap()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -165,4 +321,36 @@ mod test {
     fn pascal_display() {
         assert_eq!(Pascal::from(1000).to_string(), "100.0Pa".to_string())
     }
+
+    #[test]
+    fn calibration_offsets_and_clamps() {
+        let values = SensorValues::try_from(RawSensorValues {
+            temperature: 20_00,
+            humidity: 98_00,
+            pressure: 1_000_000,
+        })
+        .unwrap();
+        let calibration = Calibration {
+            temperature: -0.5,
+            humidity: 3.,
+            pressure: 120.,
+        };
+
+        let (calibrated, quality) = calibration.apply(values);
+        let raw = RawSensorValues::from(calibrated);
+        assert_eq!(raw.temperature, 19_50);
+        assert_eq!(raw.humidity, 100_00);
+        assert_eq!(raw.pressure, 1_001_200);
+        assert_eq!(quality, Quality::CALIBRATED | Quality::CLAMPED);
+        assert!(Calibration::default().apply(values).1.is_empty());
+
+        // ending up exactly at a limit isn't clamping
+        let calibration = Calibration {
+            humidity: 2.,
+            ..Calibration::default()
+        };
+        let (calibrated, quality) = calibration.apply(values);
+        assert_eq!(RawSensorValues::from(calibrated).humidity, 100_00);
+        assert_eq!(quality, Quality::CALIBRATED);
+    }
 }
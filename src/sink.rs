@@ -0,0 +1,114 @@
+mod influx;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+
+#[cfg(feature = "mqtt")]
+pub(crate) use mqtt::publish_snapshots;
+
+use crate::{
+    bluetooth::BluetoothAddress,
+    sensor::{SensorState, SensorValues},
+    timestamp::Timestamp,
+};
+use futures_util::future::BoxFuture;
+use std::time::Duration;
+
+/// Time between two batches sent to the sinks
+const SINK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The current values of all connected sensors, taken at once
+pub(crate) type Batch = [(BluetoothAddress, Timestamp, SensorValues)];
+
+/// Somewhere sensor values get sent to once a minute
+pub(crate) trait OutputSink: Send {
+    /// Shows up in the log when writing fails
+    fn name(&self) -> &str;
+
+    fn write<'a>(&'a mut self, batch: &'a Batch) -> BoxFuture<'a, Result<(), eyre::Error>>;
+}
+
+/// One of the `[[sink]]` tables of the config file
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum SinkConfig {
+    /// publishes the values as json under `{topic}/{addr}` to the mqtt server of the config
+    #[cfg(feature = "mqtt")]
+    Mqtt {
+        #[serde(default = "mqtt::default_topic")]
+        topic: String,
+    },
+    /// writes the values to an influxdb 2 bucket
+    Influx {
+        url: url::Url,
+        org: String,
+        bucket: String,
+        token: Option<String>,
+    },
+}
+
+/// Sets up the sinks of `configs`, a configured mqtt server without any sinks gets the default
+/// mqtt sink like before sinks were configurable
+#[cfg_attr(not(feature = "mqtt"), allow(unused_variables))]
+pub(crate) fn build(
+    configs: &[SinkConfig],
+    mqtt: Option<crate::MqttConnection>,
+) -> Result<Vec<Box<dyn OutputSink>>, eyre::Error> {
+    if configs.is_empty() {
+        #[cfg(feature = "mqtt")]
+        return Ok(mqtt
+            .map(|cxn| Box::new(mqtt::Mqtt::new(cxn, mqtt::default_topic())) as Box<dyn OutputSink>)
+            .into_iter()
+            .collect());
+        #[cfg(not(feature = "mqtt"))]
+        return Ok(Vec::new());
+    }
+
+    configs
+        .iter()
+        .map(|config| -> Result<Box<dyn OutputSink>, eyre::Error> {
+            Ok(match config {
+                #[cfg(feature = "mqtt")]
+                SinkConfig::Mqtt { topic } => {
+                    let cxn = mqtt
+                        .clone()
+                        .ok_or_else(|| eyre::format_err!("mqtt sink needs an mqtt_server_url"))?;
+                    Box::new(mqtt::Mqtt::new(cxn, topic.clone()))
+                }
+                SinkConfig::Influx {
+                    url,
+                    org,
+                    bucket,
+                    token,
+                } => Box::new(influx::Influx::new(url, org, bucket, token.clone())?),
+            })
+        })
+        .collect()
+}
+
+/// Periodically sends the values of all connected sensors to every sink
+pub(crate) async fn run(ctx: super::Context, mut sinks: Vec<Box<dyn OutputSink>>) {
+    let mut interval = tokio::time::interval(SINK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Timestamp::now();
+        let batch = ctx
+            .sensors
+            .read()
+            .await
+            .iter()
+            .filter_map(|(addr, state)| match state {
+                SensorState::Connected(values) => Some((*addr, now, *values)),
+                SensorState::Unconnected => None,
+            })
+            .collect::<Vec<_>>();
+        if batch.is_empty() {
+            continue;
+        }
+
+        for sink in &mut sinks {
+            if let Err(e) = sink.write(&batch).await {
+                tracing::error!("Failed writing to {} sink: {}", sink.name(), e);
+            }
+        }
+    }
+}
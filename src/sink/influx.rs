@@ -0,0 +1,98 @@
+use super::{Batch, OutputSink};
+use crate::sensor::Quantity;
+use futures_util::future::BoxFuture;
+use std::{fmt::Write, time::Duration};
+
+pub(super) struct Influx {
+    client: reqwest::Client,
+    /// write endpoint with org, bucket and precision already in the query
+    url: url::Url,
+    token: Option<String>,
+}
+
+impl Influx {
+    pub(super) fn new(
+        base: &url::Url,
+        org: &str,
+        bucket: &str,
+        token: Option<String>,
+    ) -> Result<Self, eyre::Error> {
+        let mut url = base.join("api/v2/write")?;
+        url.query_pairs_mut()
+            .append_pair("org", org)
+            .append_pair("bucket", bucket)
+            .append_pair("precision", "s");
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        Ok(Self { client, url, token })
+    }
+}
+
+/// One line of influx line protocol per sensor
+fn line_protocol(batch: &Batch) -> String {
+    let mut lines = String::new();
+    for (addr, time, values) in batch {
+        // writing into a String can't fail, colons need no escaping in tag values
+        let _ = writeln!(
+            lines,
+            "weatherstation,sensor={} temperature={},humidity={},pressure={} {}",
+            addr,
+            Quantity::Temperature.of(*values),
+            Quantity::Humidity.of(*values),
+            Quantity::Pressure.of(*values),
+            time.as_u32()
+        );
+    }
+    lines
+}
+
+impl OutputSink for Influx {
+    fn name(&self) -> &str {
+        "influx"
+    }
+
+    fn write<'a>(&'a mut self, batch: &'a Batch) -> BoxFuture<'a, Result<(), eyre::Error>> {
+        Box::pin(async move {
+            let mut request = self
+                .client
+                .post(self.url.clone())
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .body(line_protocol(batch));
+            if let Some(ref token) = self.token {
+                request = request.header("Authorization", format!("Token {}", token));
+            }
+            request.send().await?.error_for_status()?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        bluetooth::BluetoothAddress,
+        sensor::{Celsius, Pascal, RelativeHumidity, SensorValues},
+        timestamp::Timestamp,
+    };
+    use std::convert::TryFrom;
+
+    #[test]
+    fn influx_lines() {
+        let values = SensorValues {
+            temperature: Celsius::try_from(2150).unwrap(),
+            humidity: RelativeHumidity::try_from(4500).unwrap(),
+            pressure: Pascal::from(1_013_250),
+        };
+        let batch = [(
+            BluetoothAddress::from(1),
+            Timestamp::from(1_600_000_000),
+            values,
+        )];
+        assert_eq!(
+            line_protocol(&batch),
+            "weatherstation,sensor=00:00:00:00:00:01 temperature=21.5,humidity=45,pressure=101325 1600000000\n"
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use super::{Batch, OutputSink};
+use crate::{bluetooth::BluetoothAddress, db, sensor::SensorState, timestamp::Timestamp};
+use futures_util::future::BoxFuture;
+use std::{collections::BTreeMap, time::Duration};
+use tokio::sync::broadcast;
+
+pub(super) fn default_topic() -> String {
+    "sensors/weatherstation".to_owned()
+}
+
+pub(super) struct Mqtt {
+    cxn: tokio_mqtt::Connection,
+    topic: String,
+}
+
+impl Mqtt {
+    pub(super) fn new(cxn: tokio_mqtt::Connection, topic: String) -> Self {
+        Self { cxn, topic }
+    }
+}
+
+impl OutputSink for Mqtt {
+    fn name(&self) -> &str {
+        "mqtt"
+    }
+
+    fn write<'a>(&'a mut self, batch: &'a Batch) -> BoxFuture<'a, Result<(), eyre::Error>> {
+        Box::pin(async move {
+            for (addr, _, values) in batch {
+                // both get moved into the packet so there's no point in keeping buffers around
+                let topic = tokio_mqtt::TopicName::new(format!("{}/{}", self.topic, addr))?;
+                let payload = serde_json::to_vec(values).unwrap();
+                // TODO: figure out what happens when mqtt server dies
+                self.cxn.publish(topic, payload).await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Seconds between two checks for changed labels, they don't come with an update
+const SNAPSHOT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(serde::Serialize)]
+struct SnapshotEntry {
+    label: Option<String>,
+    room: Option<String>,
+    #[serde(flatten)]
+    state: SensorState,
+    /// when an update of the sensor last came in, none if it didn't since the start
+    last_seen: Option<Timestamp>,
+}
+
+/// The complete state map, the same document for the same state
+fn snapshot(
+    ctx: &crate::Context,
+    sensors: &BTreeMap<BluetoothAddress, SensorState>,
+    last_seen: &BTreeMap<BluetoothAddress, Timestamp>,
+) -> Result<Vec<u8>, db::Error> {
+    let txn = ctx.db.read_txn()?;
+    let entries = sensors
+        .iter()
+        .map(|(addr, state)| {
+            let entry = ctx.db.get_addr(&txn, *addr)?.unwrap_or_default();
+            Ok((
+                *addr,
+                SnapshotEntry {
+                    label: entry.label,
+                    room: entry.room,
+                    state: *state,
+                    last_seen: last_seen.get(addr).copied(),
+                },
+            ))
+        })
+        .collect::<Result<BTreeMap<_, _>, db::Error>>()?;
+    Ok(serde_json::to_vec(&entries).unwrap())
+}
+
+/// Publishes the state of every sensor as one retained document to `topic`, again whenever a
+/// sensor or its settings change
+pub(crate) async fn publish_snapshots(
+    ctx: crate::Context,
+    mut cxn: tokio_mqtt::Connection,
+    topic: String,
+) {
+    let mut updates = ctx.updates.subscribe();
+    let mut interval = tokio::time::interval(SNAPSHOT_CHECK_INTERVAL);
+    let mut last_seen = BTreeMap::new();
+    let mut published = None;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            update = updates.recv() => match update {
+                Ok(events) => {
+                    let now = Timestamp::now();
+                    for event in events {
+                        match event.state() {
+                            Some(SensorState::Connected(_)) => {
+                                last_seen.insert(event.addr(), now);
+                            }
+                            Some(SensorState::Unconnected) => {}
+                            None => {
+                                last_seen.remove(&event.addr());
+                            }
+                        }
+                    }
+                }
+                // the snapshot gets taken from the sensor map anyway
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+
+        let document = match snapshot(&ctx, &*ctx.sensors.read().await, &last_seen) {
+            Ok(document) => document,
+            Err(e) => {
+                tracing::error!("Could not take state snapshot: {}", e);
+                continue;
+            }
+        };
+        if published.as_ref() == Some(&document) {
+            continue;
+        }
+        let sent = async {
+            let topic = tokio_mqtt::TopicName::new(topic.clone())?;
+            cxn.publish_retained(topic, document.clone()).await?;
+            Ok::<_, eyre::Error>(())
+        };
+        match sent.await {
+            Ok(()) => published = Some(document),
+            Err(e) => tracing::error!("Could not publish state snapshot: {}", e),
+        }
+    }
+}
@@ -0,0 +1,246 @@
+#[cfg(feature = "s3")]
+mod s3;
+
+use crate::db::Db;
+#[cfg(feature = "alerts")]
+use crate::{
+    alert::{self, ActionConfig, Actions, Event, EventKind},
+    bluetooth::BluetoothAddress,
+};
+use std::{
+    fs, io,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::task;
+use url::Url;
+
+const PREFIX: &str = "weatherstation-";
+const SUFFIX: &str = ".mdbx";
+
+/// Periodic copies of the database to another disk, only settable in the config file
+#[derive(serde::Deserialize, Clone)]
+pub(crate) struct SnapshotConfig {
+    /// directory the snapshots get written to, like a mounted usb drive or nfs share
+    dir: PathBuf,
+    /// hours between two snapshots
+    #[serde(default = "default_interval")]
+    interval: u64,
+    /// number of snapshots kept, older ones get deleted
+    #[serde(default = "default_keep")]
+    keep: NonZeroUsize,
+    /// bucket every snapshot gets uploaded to after it was written to `dir`
+    s3: Option<S3Config>,
+    /// run when a snapshot fails and once they work again
+    #[cfg(feature = "alerts")]
+    #[serde(rename = "action", default)]
+    actions: Vec<ActionConfig>,
+}
+
+/// The `[snapshot.s3]` table, works with anything that speaks the S3 api like MinIO
+#[derive(serde::Deserialize, Clone)]
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+pub(crate) struct S3Config {
+    /// like `https://s3.eu-central-1.amazonaws.com` or `http://nas:9000`
+    endpoint: Url,
+    bucket: String,
+    #[serde(default = "default_region")]
+    region: String,
+    access_key: String,
+    secret_key: String,
+    /// put in front of the snapshot names, like `weatherstation/`
+    #[serde(default)]
+    prefix: String,
+    /// `endpoint/bucket` instead of `bucket.endpoint`, what MinIO wants
+    #[serde(default = "path_style_by_default")]
+    path_style: bool,
+}
+
+fn default_region() -> String {
+    String::from("us-east-1")
+}
+
+fn path_style_by_default() -> bool {
+    true
+}
+
+fn default_interval() -> u64 {
+    24
+}
+
+fn default_keep() -> NonZeroUsize {
+    NonZeroUsize::new(7).unwrap()
+}
+
+/// Takes a snapshot every interval from now on, starting right away
+pub(crate) fn start(
+    ctx: crate::Context,
+    config: SnapshotConfig,
+    mqtt: Option<crate::MqttConnection>,
+) -> Result<(), eyre::Error> {
+    #[cfg(not(feature = "s3"))]
+    if config.s3.is_some() {
+        return Err(eyre::format_err!(
+            "Snapshots are uploaded to S3 but this build doesn't include the s3 feature"
+        ));
+    }
+    task::spawn(run(ctx, config, mqtt));
+    Ok(())
+}
+
+async fn run(ctx: crate::Context, config: SnapshotConfig, mqtt: Option<crate::MqttConnection>) {
+    #[cfg(feature = "s3")]
+    let bucket = config.s3.clone().map(s3::Bucket::new);
+    #[cfg(feature = "alerts")]
+    let mut actions = Actions::new("snapshot", &config.actions, mqtt, &alert::http_client());
+    #[cfg(not(feature = "alerts"))]
+    drop(mqtt);
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval * 60 * 60));
+    let mut failures = 0_u32;
+    loop {
+        interval.tick().await;
+        let taken = async {
+            let (ctx, dir, keep) = (ctx.clone(), config.dir.clone(), config.keep);
+            let path = task::spawn_blocking(move || take(&ctx.db, &dir, keep))
+                .await
+                .expect("Snapshot panicked")?;
+            #[cfg(feature = "s3")]
+            if let Some(ref bucket) = bucket {
+                upload(bucket, &path, config.keep).await?;
+            }
+            Ok::<_, eyre::Error>(path)
+        }
+        .await;
+        match taken {
+            Ok(path) => {
+                tracing::info!("Wrote database snapshot {}", path.display());
+                if failures > 0 {
+                    #[cfg(feature = "alerts")]
+                    notify(&mut actions, &config.dir, failures, EventKind::Cleared).await;
+                }
+                failures = 0;
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Could not write database snapshot to {}: {:#}",
+                    config.dir.display(),
+                    e
+                );
+                failures += 1;
+                // only once, failing snapshots mostly keep failing until someone plugs the drive
+                // back in
+                if failures == 1 {
+                    #[cfg(feature = "alerts")]
+                    notify(&mut actions, &config.dir, failures, EventKind::Fired).await;
+                }
+            }
+        }
+    }
+}
+
+/// Snapshots fire events of a rule named `snapshot` with the directory as label and the number of
+/// failed snapshots in a row as value
+#[cfg(feature = "alerts")]
+async fn notify(actions: &mut Actions, dir: &Path, failures: u32, kind: EventKind) {
+    actions
+        .perform(&Event {
+            rule: "snapshot",
+            sensor: BluetoothAddress::from(0),
+            label: Some(dir.display().to_string()),
+            value: f64::from(failures),
+            kind,
+        })
+        .await;
+}
+
+/// Copies the database into `dir` under a temporary name first so a half written snapshot never
+/// looks like a complete one, then deletes all but the newest `keep` snapshots
+fn take(db: &Db, dir: &Path, keep: NonZeroUsize) -> Result<PathBuf, eyre::Error> {
+    fs::create_dir_all(dir)?;
+    let name = format!(
+        "{}{}{}",
+        PREFIX,
+        chrono::Utc::now().format("%Y%m%dT%H%M%SZ"),
+        SUFFIX
+    );
+    let path = dir.join(&name);
+    let partial = dir.join(format!(".{}.partial", name));
+    if partial.exists() {
+        fs::remove_file(&partial)?;
+    }
+    db.snapshot(&partial)?;
+    fs::rename(&partial, &path)?;
+    rotate(dir, keep)?;
+    Ok(path)
+}
+
+/// Uploads the snapshot at `path`, then deletes all but the newest `keep` snapshots in the bucket
+#[cfg(feature = "s3")]
+async fn upload(bucket: &s3::Bucket, path: &Path, keep: NonZeroUsize) -> Result<(), eyre::Error> {
+    bucket.put(path).await?;
+    tracing::info!("Uploaded {} to {}", path.display(), bucket.describe());
+    for name in expired(bucket.list().await?, keep) {
+        tracing::info!("Deleting old snapshot {} from {}", name, bucket.describe());
+        bucket.delete(&name).await?;
+    }
+    Ok(())
+}
+
+fn rotate(dir: &Path, keep: NonZeroUsize) -> Result<(), io::Error> {
+    let names = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    for name in expired(names, keep) {
+        tracing::info!("Deleting old snapshot {}", name);
+        fs::remove_file(dir.join(name))?;
+    }
+    Ok(())
+}
+
+/// Snapshots among `names` that are older than the newest `keep` ones, anything else is left alone
+fn expired(names: Vec<String>, keep: NonZeroUsize) -> Vec<String> {
+    let mut snapshots = names
+        .into_iter()
+        .filter(|name| name.starts_with(PREFIX) && name.ends_with(SUFFIX))
+        .collect::<Vec<_>>();
+    // sorting by name sorts by time
+    snapshots.sort();
+    let excess = snapshots.len().saturating_sub(keep.get());
+    snapshots.truncate(excess);
+    snapshots
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rotation_keeps_newest() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in &[
+            "weatherstation-20210101T000000Z.mdbx",
+            "weatherstation-20210103T000000Z.mdbx",
+            "weatherstation-20210102T000000Z.mdbx",
+            "notes.txt",
+        ] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+        rotate(dir.path(), NonZeroUsize::new(2).unwrap()).unwrap();
+        let mut left = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(
+            left,
+            vec![
+                "notes.txt",
+                "weatherstation-20210102T000000Z.mdbx",
+                "weatherstation-20210103T000000Z.mdbx"
+            ]
+        );
+    }
+}
@@ -0,0 +1,598 @@
+use crate::{
+    bluetooth::BluetoothAddress,
+    db::{self, AddrDbEntry, AddrDbPatch, Connection, Placement},
+    sensor::{Calibration, Quality, SensorState, SensorValues},
+    timestamp::Timestamp,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{atomic::Ordering, Mutex},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task,
+};
+
+/// Changes to the known sensors requested from outside the update task
+pub(crate) enum Command {
+    ChangeLabel {
+        addr: BluetoothAddress,
+        label: Option<String>,
+        reply: oneshot::Sender<Result<(), db::Error>>,
+    },
+    ChangePlacement {
+        addr: BluetoothAddress,
+        placement: Placement,
+        reply: oneshot::Sender<Result<(), db::Error>>,
+    },
+    ChangeSettings {
+        addr: BluetoothAddress,
+        patch: AddrDbPatch,
+        reply: oneshot::Sender<Result<(), db::Error>>,
+    },
+    Forget {
+        addr: BluetoothAddress,
+        reply: oneshot::Sender<Result<(), db::Error>>,
+    },
+    Memorize {
+        addr: BluetoothAddress,
+        reply: oneshot::Sender<Result<(), db::Error>>,
+    },
+    Mirror {
+        states: BTreeMap<BluetoothAddress, SensorState>,
+        reply: oneshot::Sender<Result<(), db::Error>>,
+    },
+}
+
+/// Handle to the update task, which is the only one mutating the addr db and the sensor map so
+/// both always agree on which sensors are known
+#[derive(Clone)]
+pub(crate) struct StateManager(mpsc::Sender<Command>);
+
+impl StateManager {
+    pub fn channel(capacity: usize) -> (Self, mpsc::Receiver<Command>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        (Self(tx), rx)
+    }
+
+    pub async fn change_label(
+        &self,
+        addr: BluetoothAddress,
+        label: Option<String>,
+    ) -> Result<(), Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::ChangeLabel { addr, label, reply }, rx)
+            .await
+    }
+
+    pub async fn change_placement(
+        &self,
+        addr: BluetoothAddress,
+        placement: Placement,
+    ) -> Result<(), Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(
+            Command::ChangePlacement {
+                addr,
+                placement,
+                reply,
+            },
+            rx,
+        )
+        .await
+    }
+
+    /// Changes what `patch` sets of everything stored about `addr`
+    pub async fn change_settings(
+        &self,
+        addr: BluetoothAddress,
+        patch: AddrDbPatch,
+    ) -> Result<(), Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::ChangeSettings { addr, patch, reply }, rx)
+            .await
+    }
+
+    pub async fn forget(&self, addr: BluetoothAddress) -> Result<(), Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Forget { addr, reply }, rx).await
+    }
+
+    /// Makes `addr` a known sensor if it isn't one yet
+    pub async fn memorize(&self, addr: BluetoothAddress) -> Result<(), Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Memorize { addr, reply }, rx).await
+    }
+
+    /// Takes over states that were calibrated elsewhere, like the ones a replica gets from its
+    /// primary
+    pub async fn mirror(
+        &self,
+        states: BTreeMap<BluetoothAddress, SensorState>,
+    ) -> Result<(), Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Mirror { states, reply }, rx).await
+    }
+
+    async fn send(
+        &self,
+        command: Command,
+        rx: oneshot::Receiver<Result<(), db::Error>>,
+    ) -> Result<(), Error> {
+        self.0.send(command).await.map_err(|_| Error::Stopped)?;
+        rx.await.map_err(|_| Error::Stopped)?.map_err(Error::from)
+    }
+}
+
+/// Applies `command`, replying with the result to the sender
+pub(crate) async fn apply(ctx: &super::Context, command: Command) {
+    match command {
+        Command::ChangeLabel { addr, label, reply } => {
+            let _ = reply.send(edit(ctx, addr, |entry| entry.label = label).await);
+        }
+        Command::ChangePlacement {
+            addr,
+            placement,
+            reply,
+        } => {
+            let _ = reply.send(edit(ctx, addr, |entry| entry.placement = placement).await);
+        }
+        Command::ChangeSettings { addr, patch, reply } => {
+            let _ = reply.send(edit(ctx, addr, |entry| patch.apply(entry)).await);
+        }
+        Command::Forget { addr, reply } => {
+            let _ = reply.send(forget(ctx, addr).await);
+        }
+        Command::Memorize { addr, reply } => {
+            let _ = reply.send(memorize(ctx, addr).await);
+        }
+        Command::Mirror { states, reply } => {
+            let _ = reply.send(store(ctx, states).await);
+        }
+    }
+}
+
+/// A device that showed up while new sensors need approval
+#[derive(Clone, Copy, Debug, serde::Serialize)]
+pub(crate) struct Pending {
+    pub(crate) first_seen: Timestamp,
+    pub(crate) last_seen: Timestamp,
+    pub(crate) state: SensorState,
+}
+
+#[derive(Default)]
+struct PendingInner {
+    pending: BTreeMap<BluetoothAddress, Pending>,
+    /// dismissed until the next start
+    dismissed: BTreeSet<BluetoothAddress>,
+}
+
+/// New devices waiting for approval before they get memorized, logged and published
+#[derive(Default)]
+pub(crate) struct PendingSensors(Mutex<PendingInner>);
+
+impl PendingSensors {
+    fn saw(&self, addr: BluetoothAddress, state: SensorState, now: Timestamp) {
+        let mut inner = self.0.lock().unwrap();
+        if inner.dismissed.contains(&addr) {
+            return;
+        }
+        match inner.pending.get_mut(&addr) {
+            Some(pending) => {
+                pending.last_seen = now;
+                pending.state = state;
+            }
+            None => {
+                tracing::info!("New sensor {} waits for approval", addr);
+                inner.pending.insert(
+                    addr,
+                    Pending {
+                        first_seen: now,
+                        last_seen: now,
+                        state,
+                    },
+                );
+            }
+        }
+    }
+
+    pub(crate) fn list(&self) -> Vec<(BluetoothAddress, Pending)> {
+        let inner = self.0.lock().unwrap();
+        inner
+            .pending
+            .iter()
+            .map(|(addr, pending)| (*addr, *pending))
+            .collect()
+    }
+
+    pub(crate) fn contains(&self, addr: BluetoothAddress) -> bool {
+        self.0.lock().unwrap().pending.contains_key(&addr)
+    }
+
+    /// Stops showing `addr` until the next start, false if it wasn't pending
+    pub(crate) fn dismiss(&self, addr: BluetoothAddress) -> bool {
+        let mut inner = self.0.lock().unwrap();
+        let removed = inner.pending.remove(&addr).is_some();
+        if removed {
+            inner.dismissed.insert(addr);
+        }
+        removed
+    }
+
+    fn approve(&self, addr: BluetoothAddress) {
+        let mut inner = self.0.lock().unwrap();
+        inner.pending.remove(&addr);
+        inner.dismissed.remove(&addr);
+    }
+}
+
+/// A change of one sensor as sent to everyone subscribed to `Context::updates`, carrying what
+/// it was before so they don't have to keep a copy of their own to see transitions
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum SensorEvent {
+    /// a sensor the central didn't know about yet
+    Added {
+        addr: BluetoothAddress,
+        state: SensorState,
+    },
+    /// new values of a sensor that stayed connected
+    Updated {
+        addr: BluetoothAddress,
+        old: SensorValues,
+        new: SensorValues,
+    },
+    /// a known sensor connected or disconnected
+    StateChanged {
+        addr: BluetoothAddress,
+        old: SensorState,
+        new: SensorState,
+    },
+    /// the sensor got forgotten
+    Removed { addr: BluetoothAddress },
+}
+
+impl SensorEvent {
+    pub(crate) fn addr(&self) -> BluetoothAddress {
+        match *self {
+            SensorEvent::Added { addr, .. }
+            | SensorEvent::Updated { addr, .. }
+            | SensorEvent::StateChanged { addr, .. }
+            | SensorEvent::Removed { addr } => addr,
+        }
+    }
+
+    /// State the sensor is in afterwards, None if it's gone
+    pub(crate) fn state(&self) -> Option<SensorState> {
+        match *self {
+            SensorEvent::Added { state, .. } => Some(state),
+            SensorEvent::Updated { new, .. } => Some(SensorState::Connected(new)),
+            SensorEvent::StateChanged { new, .. } => Some(new),
+            SensorEvent::Removed { .. } => None,
+        }
+    }
+
+    /// What happened to the connection of the sensor, if anything
+    fn connection(&self) -> Option<Connection> {
+        match *self {
+            SensorEvent::Added {
+                state: SensorState::Connected(_),
+                ..
+            }
+            | SensorEvent::StateChanged {
+                new: SensorState::Connected(_),
+                ..
+            } => Some(Connection::Connected),
+            SensorEvent::StateChanged {
+                new: SensorState::Unconnected,
+                ..
+            } => Some(Connection::Lost),
+            _ => None,
+        }
+    }
+}
+
+/// The states `events` leave their sensors in, for consumers that only care about the latest
+/// values
+pub(crate) fn states(events: &[SensorEvent]) -> BTreeMap<BluetoothAddress, SensorState> {
+    events
+        .iter()
+        .filter_map(|event| Some((event.addr(), event.state()?)))
+        .collect()
+}
+
+/// What applying `update` to `sensors` changes, sensors staying unconnected don't change
+fn events(
+    sensors: &BTreeMap<BluetoothAddress, SensorState>,
+    update: &BTreeMap<BluetoothAddress, SensorState>,
+) -> Vec<SensorEvent> {
+    update
+        .iter()
+        .filter_map(|(&addr, &new)| match (sensors.get(&addr).copied(), new) {
+            (None, state) => Some(SensorEvent::Added { addr, state }),
+            (Some(SensorState::Connected(old)), SensorState::Connected(new)) => {
+                Some(SensorEvent::Updated { addr, old, new })
+            }
+            (Some(SensorState::Unconnected), SensorState::Unconnected) => None,
+            (Some(old), new) => Some(SensorEvent::StateChanged { addr, old, new }),
+        })
+        .collect()
+}
+
+/// Calibrates and stores new states of sensors with [`store`]. Returns the quality of each
+/// connected state for logging it.
+pub(crate) async fn update(
+    ctx: &super::Context,
+    mut update: BTreeMap<BluetoothAddress, SensorState>,
+) -> Result<BTreeMap<BluetoothAddress, Quality>, db::Error> {
+    let mut qualities = BTreeMap::new();
+    {
+        let txn = ctx.db.read_txn()?;
+        for (addr, state) in &mut update {
+            if let SensorState::Connected(values) = state {
+                let calibration = ctx
+                    .db
+                    .get_addr(&txn, *addr)?
+                    .map_or_else(Calibration::default, |entry| entry.calibration);
+                let (calibrated, quality) = calibration.apply(*values);
+                *values = calibrated;
+                qualities.insert(*addr, quality);
+            }
+        }
+    }
+    store(ctx, update).await?;
+    Ok(qualities)
+}
+
+/// Memorizes sensors seen for the first time and stores their new states. Sensors seen for the
+/// first time only become pending instead when they need approval.
+async fn store(
+    ctx: &super::Context,
+    mut update: BTreeMap<BluetoothAddress, SensorState>,
+) -> Result<(), db::Error> {
+    // the update task is the only writer, so the map can't change until it's written below
+    let (events, new_sensors) = {
+        let sensors = ctx.sensors.read().await;
+        if let Some(ref pending) = ctx.pending {
+            let now = Timestamp::now();
+            update.retain(|addr, state| {
+                let known = sensors.contains_key(addr);
+                if !known {
+                    pending.saw(*addr, *state, now);
+                }
+                known
+            });
+        }
+        let new_sensors = update
+            .keys()
+            .filter(|addr| !sensors.contains_key(*addr))
+            .copied()
+            .collect::<Vec<_>>();
+        (events(&sensors, &update), new_sensors)
+    };
+    let connections = events
+        .iter()
+        .filter_map(|event| Some((event.addr(), event.connection()?)))
+        .collect::<Vec<_>>();
+    if !new_sensors.is_empty() || !connections.is_empty() {
+        let now = Timestamp::now();
+        write(ctx, move |db, txn| {
+            for addr in new_sensors {
+                db.put_addr(txn, addr, &AddrDbEntry::default())?;
+                tracing::info!("Memorized new sensor {}", addr);
+            }
+            for (addr, connection) in connections {
+                db.put_connection(txn, addr, now, connection)?;
+            }
+            Ok(())
+        })
+        .await?;
+    }
+
+    let mut sensors = ctx.sensors.write().await;
+    if !events.is_empty() {
+        // nobody listening isn't an error
+        let _ = ctx.updates.send(events);
+    }
+    sensors.extend(update);
+    bump_generation(ctx);
+    Ok(())
+}
+
+/// Needs to be called with the sensor map locked for writing so readers see a consistent state,
+/// or right after the change for anything outside of it like the log
+pub(crate) fn bump_generation(ctx: &super::Context) {
+    ctx.generation.fetch_add(1, Ordering::Release);
+}
+
+/// Runs `f` in a write txn on the blocking thread pool. Callers mustn't hold the sensor map
+/// meanwhile, readers would wait for the disk otherwise.
+async fn write<T: Send + 'static>(
+    ctx: &super::Context,
+    f: impl FnOnce(&db::Db, &mut heed::RwTxn<'_, '_>) -> Result<T, db::Error> + Send + 'static,
+) -> Result<T, db::Error> {
+    let ctx = ctx.clone();
+    task::spawn_blocking(move || {
+        let mut txn = ctx.db.write_txn()?;
+        let ret = f(&ctx.db, &mut txn)?;
+        txn.commit()?;
+        Ok(ret)
+    })
+    .await
+    .expect("Writing the addr database panicked")
+}
+
+/// Changes the addr entry of `addr` with `f`, memorizing it if it's unknown
+async fn edit(
+    ctx: &super::Context,
+    addr: BluetoothAddress,
+    f: impl FnOnce(&mut AddrDbEntry) + Send + 'static,
+) -> Result<(), db::Error> {
+    let entry = write(ctx, {
+        let ctx = ctx.clone();
+        move |db, txn| edit_entry(db, txn, addr, ctx.pending.as_ref(), f)
+    })
+    .await?;
+    crate::replication::publish_settings(ctx, addr);
+    ctx.clocks.set_sync(addr, entry.sync_clock);
+    ctx.stations.set_interval(addr, entry.measurement_interval);
+    ctx.stations.set_encryption(addr, entry.require_encryption);
+    let mut sensors = ctx.sensors.write().await;
+    sensors.entry(addr).or_insert(SensorState::Unconnected);
+    bump_generation(ctx);
+    Ok(())
+}
+
+/// The addr entry of `addr` changed with `f`, approving `addr` since editing a pending sensor
+/// makes it known just like memorizing it
+fn edit_entry(
+    db: &db::Db,
+    txn: &mut heed::RwTxn<'_, '_>,
+    addr: BluetoothAddress,
+    pending: Option<&PendingSensors>,
+    f: impl FnOnce(&mut AddrDbEntry),
+) -> Result<AddrDbEntry, db::Error> {
+    let mut entry = db.get_addr(&*txn, addr)?.unwrap_or_default();
+    f(&mut entry);
+    db.put_addr(txn, addr, &entry)?;
+    if let Some(pending) = pending {
+        pending.approve(addr);
+    }
+    Ok(entry)
+}
+
+/// Makes `addr` known, which also approves it if it's pending
+async fn memorize(ctx: &super::Context, addr: BluetoothAddress) -> Result<(), db::Error> {
+    if let Some(ref pending) = ctx.pending {
+        pending.approve(addr);
+    }
+    if !ctx.sensors.read().await.contains_key(&addr) {
+        write(ctx, move |db, txn| {
+            db.put_addr(txn, addr, &AddrDbEntry::default())
+        })
+        .await?;
+        crate::replication::publish_settings(ctx, addr);
+        let mut sensors = ctx.sensors.write().await;
+        sensors.insert(addr, SensorState::Unconnected);
+        bump_generation(ctx);
+        tracing::info!("Memorized new sensor {}", addr);
+    }
+    Ok(())
+}
+
+async fn forget(ctx: &super::Context, addr: BluetoothAddress) -> Result<(), db::Error> {
+    write(ctx, move |db, txn| db.delete_addr(txn, addr)).await?;
+    let mut sensors = ctx.sensors.write().await;
+    if sensors.remove(&addr).is_some() {
+        let _ = ctx.updates.send(vec![SensorEvent::Removed { addr }]);
+    }
+    if let Some(ref anomalies) = ctx.anomalies {
+        anomalies.forget(addr);
+    }
+    ctx.clocks.forget(addr);
+    ctx.bands.forget(addr);
+    ctx.queries.invalidate(Some(addr));
+    ctx.stations.forget(addr);
+    bump_generation(ctx);
+    Ok(())
+}
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum Error {
+    #[error("Sensor state manager stopped")]
+    Stopped,
+
+    #[error(transparent)]
+    Db(#[from] db::Error),
+}
+
+impl warp::reject::Reject for Error {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn unconnected_sensors_staying_unconnected_dont_change() {
+        let values = SensorValues::try_from(crate::sensor::RawSensorValues {
+            temperature: 20_00,
+            humidity: 50_00,
+            pressure: 1_000_000,
+        })
+        .unwrap();
+        let (known, gone, new) = (
+            BluetoothAddress::from(1),
+            BluetoothAddress::from(2),
+            BluetoothAddress::from(3),
+        );
+        let sensors = vec![
+            (known, SensorState::Connected(values)),
+            (gone, SensorState::Unconnected),
+        ]
+        .into_iter()
+        .collect();
+        let update = vec![
+            (known, SensorState::Unconnected),
+            (gone, SensorState::Unconnected),
+            (new, SensorState::Connected(values)),
+        ]
+        .into_iter()
+        .collect();
+
+        let events = events(&sensors, &update);
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            events[0],
+            SensorEvent::StateChanged {
+                new: SensorState::Unconnected,
+                ..
+            }
+        ));
+        assert!(matches!(events[1], SensorEvent::Added { addr, .. } if addr == new));
+    }
+
+    #[test]
+    fn dismissed_sensors_stay_away_until_approved() {
+        let pending = PendingSensors::default();
+        let (kept, dismissed) = (BluetoothAddress::from(1), BluetoothAddress::from(2));
+        pending.saw(kept, SensorState::Unconnected, Timestamp::from(10));
+        pending.saw(dismissed, SensorState::Unconnected, Timestamp::from(10));
+        pending.saw(kept, SensorState::Unconnected, Timestamp::from(20));
+
+        assert!(pending.dismiss(dismissed));
+        assert!(!pending.dismiss(dismissed));
+        pending.saw(dismissed, SensorState::Unconnected, Timestamp::from(30));
+        let list = pending.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].0, kept);
+        assert_eq!(list[0].1.first_seen, Timestamp::from(10));
+        assert_eq!(list[0].1.last_seen, Timestamp::from(20));
+
+        pending.approve(kept);
+        pending.approve(dismissed);
+        assert!(pending.list().is_empty());
+        pending.saw(dismissed, SensorState::Unconnected, Timestamp::from(40));
+        assert!(pending.contains(dismissed));
+    }
+
+    #[test]
+    fn editing_pending_sensors_approves_them() {
+        let addr = BluetoothAddress::from(1);
+        let pending = PendingSensors::default();
+        pending.saw(addr, SensorState::Unconnected, Timestamp::from(10));
+        let dir = tempfile::tempdir().unwrap();
+        let db = db::Db::open(dir.path()).unwrap();
+
+        let mut txn = db.write_txn().unwrap();
+        edit_entry(&db, &mut txn, addr, Some(&pending), |entry| {
+            entry.label = Some("attic".to_owned())
+        })
+        .unwrap();
+        txn.commit().unwrap();
+
+        assert!(!pending.contains(addr));
+        let txn = db.read_txn().unwrap();
+        let entry = db.get_addr(&txn, addr).unwrap().unwrap();
+        assert_eq!(entry.label.as_deref(), Some("attic"));
+    }
+}
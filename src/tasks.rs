@@ -1,33 +1,55 @@
-use crate::{bluetooth::BluetoothAddress, db, sensor::SensorState, timestamp::Timestamp};
-use std::{collections::BTreeMap, fmt::Write, time::Duration};
+use crate::{
+    bluetooth::BluetoothAddress,
+    db, replication,
+    sensor::{Quality, SensorState},
+    state,
+    timestamp::Timestamp,
+};
+use std::{collections::BTreeMap, mem, time::Duration};
+use tokio::{sync::mpsc, task};
 use tokio_stream::{Stream, StreamExt};
 
-pub(crate) async fn mqtt_publish(
-    ctx: super::Context,
-    mut cxn: tokio_mqtt::Connection,
-) -> Result<(), tokio_mqtt::Error> {
-    let mut topic_buf = String::new();
-    let mut interval = tokio::time::interval(Duration::from_secs(60));
-    let mut json_buf = Vec::new();
+/// Seconds between two log entries of a connected sensor
+pub(crate) const LOG_INTERVAL: u32 = 60;
+
+/// Maximum number of log entries kept around while the database can't be written to
+const MAX_PENDING_LOG_ENTRIES: usize = 100_000;
+
+/// Seconds between two runs of [`seal_blocks`]
+const SEAL_INTERVAL: u64 = 60 * 60;
+
+/// Days of the log stay rows until they're this old so late entries don't unseal them again
+const SEAL_AFTER: u32 = 24 * 60 * 60;
+
+type LogWrite = task::JoinHandle<Result<(), (db::LogBatch, db::Error)>>;
+
+/// Writes `batch` on the blocking thread pool so a stalled disk doesn't stall the executor
+fn spawn_log_write(ctx: super::Context, batch: db::LogBatch) -> LogWrite {
+    task::spawn_blocking(move || match ctx.db.write_log(&batch) {
+        Ok(()) => {
+            ctx.queries.invalidate(batch.addrs());
+            state::bump_generation(&ctx);
+            replication::publish(&ctx, batch);
+            Ok(())
+        }
+        Err(e) => Err((batch, e)),
+    })
+}
+
+/// Compresses finished days of the log every hour
+pub(crate) async fn seal_blocks(ctx: super::Context) {
+    let mut interval = tokio::time::interval(Duration::from_secs(SEAL_INTERVAL));
     loop {
         interval.tick().await;
-        let sensors = ctx.sensors.read().await;
-        for (addr, state) in &*sensors {
-            if let SensorState::Connected(values) = state {
-                topic_buf.clear();
-                write!(topic_buf, "sensors/weatherstation/{}", addr).unwrap();
-                serde_json::to_writer(std::io::Cursor::new(&mut json_buf), &values).unwrap();
-                // TODO: figure out what happens when mqtt server dies
-                if let Err(e) = cxn
-                    .publish(
-                        tokio_mqtt::TopicName::new(topic_buf.clone()).unwrap(),
-                        json_buf.clone(),
-                    )
-                    .await
-                {
-                    tracing::error!("Failed publishing to mqtt server: {}", e);
-                }
-            }
+        let ctx = ctx.clone();
+        let before = Timestamp::now().bottoming_sub(Timestamp::from(SEAL_AFTER));
+        match task::spawn_blocking(move || ctx.db.seal_blocks(before))
+            .await
+            .expect("Sealing log blocks panicked")
+        {
+            Ok(0) => {}
+            Ok(sealed) => tracing::info!("Sealed {} days of the log", sealed),
+            Err(e) => tracing::error!("Failed sealing days of the log: {}", e),
         }
     }
 }
@@ -35,45 +57,55 @@ pub(crate) async fn mqtt_publish(
 pub(crate) async fn update(
     ctx: super::Context,
     mut updates: impl Stream<Item = BTreeMap<BluetoothAddress, SensorState>> + Unpin,
+    mut commands: mpsc::Receiver<state::Command>,
 ) -> Result<(), db::Error> {
-    let mut interval = tokio::time::interval(Duration::from_secs(1 * 60));
+    let mut interval = tokio::time::interval(Duration::from_secs(u64::from(LOG_INTERVAL)));
+    // intervals that pass while a write is still running get written together afterwards
+    let mut pending = db::LogBatch::default();
+    let mut write: Option<LogWrite> = None;
+    // of the calibration the current states got, which can be older than the one in the db
+    let mut qualities = BTreeMap::new();
     loop {
         // TODO: make both arms a function
         tokio::select! {
             _ = interval.tick() => {
-                let sensors = ctx.sensors.read().await;
                 let now = Timestamp::now();
-                let mut txn  = ctx.db.log_txn()?;
+                let sensors = ctx.sensors.read().await;
+                let txn = ctx.db.read_txn()?;
                 for (addr, state) in &*sensors {
                     if let SensorState::Connected(values) = state {
-                        txn.log(*addr, now, *values)?;
+                        if let Some(ref anomalies) = ctx.anomalies {
+                            anomalies.observe(*addr, *values);
+                        }
+                        let entry = ctx.db.get_addr(&txn, *addr)?.unwrap_or_default();
+                        let vetoed = ctx.script.as_ref().map_or(false, |script| script.vetoes(*addr));
+                        if entry.log && !vetoed && !ctx.replica {
+                            // readings of stations with a clock get logged at the moment they were taken
+                            let time = ctx.clocks.latest(*addr).map_or(now, |measured| measured.corrected);
+                            let quality = qualities.get(addr).copied().unwrap_or_else(Quality::empty);
+                            pending.push(*addr, time, *values, quality);
+                        }
                     }
                 }
-                txn.commit()?;
+                drop(txn);
+                drop(sensors);
+                if write.is_none() && !pending.is_empty() {
+                    write = Some(spawn_log_write(ctx.clone(), mem::take(&mut pending)));
+                }
             }
+            written = async { write.as_mut().unwrap().await }, if write.is_some() => {
+                write = None;
+                if let Err((batch, e)) = written.expect("Log writer panicked") {
+                    tracing::error!("Failed writing {} log entries: {}", batch.len(), e);
+                    pending.prepend(batch, MAX_PENDING_LOG_ENTRIES);
+                } else if !pending.is_empty() {
+                    write = Some(spawn_log_write(ctx.clone(), mem::take(&mut pending)));
+                }
+            }
+            Some(command) = commands.recv() => state::apply(&ctx, command).await,
             update = updates.next() => {
                 match update {
-                    Some(update) => {
-                        let mut new_sensors = Vec::new();
-                        {
-                            let txn = ctx.db.read_txn()?;
-                            for &addr in update.keys() {
-                                if ctx.db.get_addr(&txn, addr)?.is_none() {
-                                    new_sensors.push(addr);
-                                    tracing::info!("Memorized new sensor {}", addr);
-                                }
-                            }
-                        }
-                        if !new_sensors.is_empty() {
-                            let mut txn = ctx.db.write_txn()?;
-                            for addr in new_sensors {
-                                ctx.db.put_addr(&mut txn, addr, &db::AddrDbEntry::default())?;
-                            }
-                            txn.commit()?;
-                        }
-
-                        ctx.sensors.write().await.extend(update);
-                    }
+                    Some(update) => qualities.extend(state::update(&ctx, update).await?),
                     None => break Ok(()),
                 }
             }
@@ -0,0 +1,152 @@
+//! Tenants share one central, like the flats of an apartment building. Each one gets an api
+//! token that only shows the sensors of its tenant, with tenants configured requests without a
+//! known token are turned away. The web ui shows tenants their sensors as well while the admin
+//! pages and metrics are only for the admin token. The other servers don't know about tenants,
+//! a central with tenants refuses to start with them.
+
+use crate::{bluetooth::BluetoothAddress, replication::token_matches, sensor::SensorState};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Arc,
+};
+
+/// A `[[tenant]]` table of the config file
+#[derive(serde::Deserialize, Clone, Debug)]
+pub(crate) struct TenantConfig {
+    name: String,
+    /// sent as `Authorization: Bearer <token>`
+    token: String,
+    sensors: BTreeSet<BluetoothAddress>,
+}
+
+/// What the token of a request gives access to
+#[derive(Clone, Debug)]
+pub(crate) enum Access {
+    /// every sensor and the settings of the central, without tenants or with the admin token
+    All,
+    Tenant(Arc<TenantConfig>),
+}
+
+impl Access {
+    pub(crate) fn allows(&self, addr: BluetoothAddress) -> bool {
+        match self {
+            Access::All => true,
+            Access::Tenant(tenant) => tenant.sensors.contains(&addr),
+        }
+    }
+
+    /// Name of the tenant, none for access to everything
+    pub(crate) fn tenant(&self) -> Option<&str> {
+        match self {
+            Access::All => None,
+            Access::Tenant(tenant) => Some(&tenant.name),
+        }
+    }
+
+    /// The part of `sensors` this access covers
+    pub(crate) fn visible(
+        &self,
+        sensors: &BTreeMap<BluetoothAddress, SensorState>,
+    ) -> BTreeMap<BluetoothAddress, SensorState> {
+        sensors
+            .iter()
+            .filter(|(addr, _)| self.allows(**addr))
+            .map(|(addr, state)| (*addr, *state))
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Tenants {
+    by_token: HashMap<String, Arc<TenantConfig>>,
+    admin_token: Option<String>,
+}
+
+impl Tenants {
+    pub(crate) fn new(
+        tenants: Vec<TenantConfig>,
+        admin_token: Option<String>,
+    ) -> Result<Self, eyre::Error> {
+        let mut by_token = HashMap::new();
+        for tenant in tenants {
+            if admin_token.as_ref() == Some(&tenant.token) {
+                return Err(eyre::format_err!(
+                    "Tenant {} has the admin token",
+                    tenant.name
+                ));
+            }
+            let name = tenant.name.clone();
+            if by_token
+                .insert(tenant.token.clone(), Arc::new(tenant))
+                .is_some()
+            {
+                return Err(eyre::format_err!(
+                    "Tenant {} has the token of another tenant",
+                    name
+                ));
+            }
+        }
+        Ok(Self {
+            by_token,
+            admin_token,
+        })
+    }
+
+    /// Access granted by the `Authorization` header of a request, none if tenants are configured
+    /// and it doesn't carry a known token
+    pub(crate) fn access(&self, authorization: Option<&str>) -> Option<Access> {
+        if self.by_token.is_empty() {
+            return Some(Access::All);
+        }
+        let token = authorization?.strip_prefix("Bearer ")?;
+        // every token gets compared so the time taken doesn't tell how close a guess came
+        let admin = self
+            .admin_token
+            .as_deref()
+            .map_or(false, |admin| token_matches(token, admin));
+        let tenant = self.by_token.iter().fold(None, |found, (known, tenant)| {
+            if token_matches(token, known) {
+                Some(tenant)
+            } else {
+                found
+            }
+        });
+        if admin {
+            Some(Access::All)
+        } else {
+            tenant.cloned().map(Access::Tenant)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tokens_only_show_their_tenant() {
+        let tenant = |name: &str, token: &str, addr| TenantConfig {
+            name: name.to_owned(),
+            token: token.to_owned(),
+            sensors: std::iter::once(BluetoothAddress::from(addr)).collect(),
+        };
+        let tenants = Tenants::new(
+            vec![tenant("1a", "one", 1), tenant("2b", "two", 2)],
+            Some("admin".to_owned()),
+        )
+        .unwrap();
+
+        let one = tenants.access(Some("Bearer one")).unwrap();
+        assert!(one.allows(BluetoothAddress::from(1)));
+        assert!(!one.allows(BluetoothAddress::from(2)));
+        assert!(tenants
+            .access(Some("Bearer admin"))
+            .unwrap()
+            .allows(BluetoothAddress::from(2)));
+        assert!(tenants.access(Some("Bearer three")).is_none());
+        assert!(tenants.access(None).is_none());
+
+        assert!(Tenants::default().access(None).is_some());
+        assert!(Tenants::new(vec![tenant("1a", "one", 1), tenant("1b", "one", 3)], None).is_err());
+    }
+}
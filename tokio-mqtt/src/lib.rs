@@ -42,15 +42,33 @@ pub enum Error {
     InvalidUrl { url: Url },
 }
 
+#[derive(Clone)]
 pub struct Connection {
     sink: PacketSink,
 }
 
+/// How a client introduces itself to the server and how it gets back after losing it
+#[derive(Clone, Debug)]
+pub struct Session {
+    /// has to be unique per server, it kicks out the other client otherwise
+    pub client_id: String,
+    /// seconds between pings, 0 turns them off
+    pub keep_alive: u16,
+    /// whether the server forgets the session of the client when it disconnects
+    pub clean_session: bool,
+    /// waiting time before the first reconnect, doubled after every failed one
+    pub reconnect_delay: Duration,
+    /// longest waiting time between two reconnects
+    pub max_reconnect_delay: Duration,
+}
+
+#[derive(Clone)]
 pub enum Scheme {
     Mqtt,
     MqttS { ca_pem: Vec<u8>, domain: DNSName },
 }
 
+#[derive(Clone)]
 pub struct ConnectOptions {
     pub host: String,
     pub port: u16,
@@ -110,6 +128,28 @@ impl ConnectOptions {
         }
     }
 
+    /// Opens the stream and goes through the connect handshake
+    async fn handshake(&self, session: &Session) -> Result<(MqttStream, MqttSink), Error> {
+        let (mut r, mut w) = self.connect().await?;
+
+        let mut packet = ConnectPacket::new(&session.client_id);
+        packet.set_user_name(self.username.clone());
+        packet.set_password(self.password.clone());
+        packet.set_clean_session(session.clean_session);
+        packet.set_keep_alive(session.keep_alive);
+        w.send(packet).await?;
+
+        match r.next().await {
+            Some(Ok(VariablePacket::ConnackPacket(packet))) => match packet.connect_return_code() {
+                ConnectReturnCode::ConnectionAccepted => Ok((r, w)),
+                return_code => Err(Error::ConnectionRefused { return_code }),
+            },
+            Some(Err(e)) => Err(e.into()),
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Some(Ok(_)) => Err(Error::UnexpectedPacket),
+        }
+    }
+
     pub fn new(url: &Url, ssl: Ssl) -> Result<Self, Error> {
         let invalid_url = || Error::InvalidUrl { url: url.clone() };
         let host = url
@@ -149,11 +189,12 @@ impl ConnectOptions {
 }
 
 impl Connection {
+    /// Connects once, after that the connection keeps reconnecting in the background whenever
+    /// the server goes away
     pub async fn connect(
         // see: https://github.com/mqtt/mqtt.org/wiki/URI-Scheme
-        connect_options: &ConnectOptions,
-        client_id: &str,
-        keep_alive: u16,
+        connect_options: ConnectOptions,
+        session: Session,
     ) -> Result<
         (
             Self,
@@ -161,29 +202,19 @@ impl Connection {
         ),
         Error,
     > {
-        let (mut r, w) = connect_options.connect().await.unwrap();
+        let (r, w) = connect_options.handshake(&session).await?;
         let sink = PacketSink::new(w);
 
-        let mut packet = ConnectPacket::new(client_id);
-        packet.set_user_name(connect_options.username.clone());
-        packet.set_password(connect_options.password.clone());
-        packet.set_clean_session(true);
-        packet.set_keep_alive(keep_alive);
-        sink.send_packet(packet).await?;
-
-        match r.next().await.unwrap() {
-            Ok(VariablePacket::ConnackPacket(packet)) => match packet.connect_return_code() {
-                ConnectReturnCode::ConnectionAccepted => {}
-                return_code => return Err(Error::ConnectionRefused { return_code }),
-            },
-            _ => {
-                return Err(Error::UnexpectedPacket);
-            }
-        }
-
         let (pub_tx, pub_rx) = mpsc::channel(1);
 
-        task::spawn(driver_task(sink.clone(), r, pub_tx));
+        let keep_alive = session.keep_alive;
+        task::spawn(driver_task(
+            sink.clone(),
+            r,
+            pub_tx,
+            connect_options,
+            session,
+        ));
 
         if let Ok(keep_alive) = NonZeroU16::try_from(keep_alive) {
             task::spawn(ping_task(sink.clone(), keep_alive));
@@ -204,6 +235,20 @@ impl Connection {
         Ok(())
     }
 
+    /// Like `publish` but the server keeps the message for clients that subscribe later
+    pub async fn publish_retained(
+        &mut self,
+        topic_name: mqtt::TopicName,
+        msg: Vec<u8>,
+    ) -> Result<(), Error> {
+        let mut packet = PublishPacket::new(topic_name, QoSWithPacketIdentifier::Level0, msg);
+        packet.set_retain(true);
+
+        self.sink.send_packet(packet).await?;
+
+        Ok(())
+    }
+
     //pub async fn subscribe_many(
     //    &mut self,
     //    topic_filter: Vec<(TopicFilter, QualityOfService)>,
@@ -222,7 +267,48 @@ async fn ping_task(sink: PacketSink, keep_alive: NonZeroU16) {
     }
 }
 
-async fn driver_task(sink: PacketSink, mut r: MqttStream, pub_tx: mpsc::Sender<(String, Vec<u8>)>) {
+async fn driver_task(
+    sink: PacketSink,
+    mut r: MqttStream,
+    pub_tx: mpsc::Sender<(String, Vec<u8>)>,
+    connect_options: ConnectOptions,
+    session: Session,
+) {
+    loop {
+        read_packets(&sink, &mut r, &pub_tx).await;
+        log::error!("Lost connection to mqtt server, reconnecting");
+        r = reconnect(&sink, &connect_options, &session).await;
+    }
+}
+
+/// Tries until it gets through, publishing in the meantime fails on the old stream
+async fn reconnect(
+    sink: &PacketSink,
+    connect_options: &ConnectOptions,
+    session: &Session,
+) -> MqttStream {
+    let mut delay = session.reconnect_delay;
+    loop {
+        tokio::time::sleep(delay).await;
+        match connect_options.handshake(session).await {
+            Ok((r, w)) => {
+                *sink.0.lock().await = w;
+                log::info!("Reconnected to mqtt server");
+                return r;
+            }
+            Err(e) => {
+                log::error!("Could not reconnect to mqtt server: {}", e);
+                delay = (delay * 2).min(session.max_reconnect_delay);
+            }
+        }
+    }
+}
+
+async fn read_packets(
+    sink: &PacketSink,
+    r: &mut MqttStream,
+    pub_tx: &mpsc::Sender<(String, Vec<u8>)>,
+) {
     while let Some(packet) = r.next().await {
         match packet {
             Ok(VariablePacket::PingreqPacket(_)) => {
@@ -248,7 +334,6 @@ async fn driver_task(sink: PacketSink, mut r: MqttStream, pub_tx: mpsc::Sender<(
             }
         }
     }
-    log::error!("PacketSink stream stopped");
 }
 
 #[derive(Clone)]